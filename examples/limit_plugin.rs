@@ -0,0 +1,53 @@
+//! Example plugin: refuses withdrawals above 1000 and counts applied records
+//!
+//! cargo build --example limit_plugin
+//! mkdir plugins && cp target/debug/examples/liblimit_plugin.so plugins/
+//! cargo run --features plugins -- transactions.csv --plugins plugins/
+
+use core_tx_runner::plugin::{PluginVtable, ABI_VERSION};
+use std::ffi::{c_char, c_void, CStr};
+
+const LIMIT: f64 = 1000.0;
+
+struct State {
+    applied: u64,
+}
+
+unsafe extern "C" fn check(
+    _state: *mut c_void,
+    record: *const c_char,
+    _account: *const c_char,
+) -> i32 {
+    let Ok(record) = serde_json::from_slice::<serde_json::Value>(CStr::from_ptr(record).to_bytes())
+    else {
+        return 1;
+    };
+    let amount = record["amount"]
+        .as_str()
+        .and_then(|a| a.parse::<f64>().ok())
+        .unwrap_or(0.0);
+    i32::from(record["type"] == "withdrawal" && amount > LIMIT)
+}
+
+unsafe extern "C" fn applied(state: *mut c_void, _event: *const c_char) {
+    (*(state as *mut State)).applied += 1;
+}
+
+unsafe extern "C" fn destroy(state: *mut c_void) {
+    let state = Box::from_raw(state as *mut State);
+    eprintln!("limit plugin: {} records applied", state.applied);
+}
+
+/// # Safety
+/// Called once by the runner when loading the library
+#[no_mangle]
+pub unsafe extern "C" fn core_tx_runner_plugin_v1() -> PluginVtable {
+    PluginVtable {
+        abi_version: ABI_VERSION,
+        name: c"withdrawal-limit".as_ptr(),
+        state: Box::into_raw(Box::new(State { applied: 0 })) as *mut c_void,
+        check: Some(check),
+        applied: Some(applied),
+        destroy: Some(destroy),
+    }
+}
@@ -0,0 +1,286 @@
+use crate::hold::PendingRelease;
+use crate::types::{Account, ClientId, StoredTransaction, TransactionId};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// One failed audit check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discrepancy {
+    pub client: ClientId,
+    /// Name of the failed check, e.g. `total` or `held_vs_open_disputes`
+    pub check: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Discrepancy {
+    fn new(
+        client: ClientId,
+        check: &'static str,
+        expected: impl ToString,
+        actual: impl ToString,
+    ) -> Self {
+        Self {
+            client,
+            check,
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        }
+    }
+}
+
+/// Read an accounts CSV as produced by the engine
+/// Amounts are parsed from their text form so no float conversion is involved
+pub fn read_accounts<P: AsRef<Path>>(
+    path: P,
+) -> Result<HashMap<ClientId, Account>, Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h == name)
+            .ok_or_else(|| format!("accounts file has no '{}' column", name))
+    };
+    let (client, available, held, total, locked) = (
+        column("client")?,
+        column("available")?,
+        column("held")?,
+        column("total")?,
+        column("locked")?,
+    );
+
+    let mut accounts = HashMap::new();
+    for row in reader.records() {
+        let row = row?;
+        let field = |index: usize| row.get(index).unwrap_or_default();
+
+        let mut account = Account::new(field(client).parse()?);
+        account.available = field(available).parse()?;
+        account.held = field(held).parse()?;
+        account.total = field(total).parse()?;
+        account.locked = field(locked).parse()?;
+        accounts.insert(account.client, account);
+    }
+
+    Ok(accounts)
+}
+
+/// Cross-check reported accounts against a recomputation and the transaction store
+///
+/// Checks, per client:
+/// - every recomputed account is reported and nothing else is
+/// - reported available/held/total (at output precision) and locked match the recomputation
+/// - `total == available + held` in the recomputation
+/// - `held` equals the sum of the client's open disputes and deposits still on hold
+pub fn audit(
+    reported: &HashMap<ClientId, Account>,
+    recomputed: &HashMap<ClientId, Account>,
+    transactions: &HashMap<TransactionId, StoredTransaction>,
+    pending_holds: &[PendingRelease],
+) -> Vec<Discrepancy> {
+    // A deposit disputed while on hold leaves the hold queue, so none is counted twice
+    let mut open_disputes: HashMap<ClientId, Decimal> = HashMap::new();
+    for stored_tx in transactions.values().filter(|tx| tx.is_open_dispute()) {
+        *open_disputes.entry(stored_tx.client_id).or_default() += stored_tx.amount;
+    }
+    for release in pending_holds {
+        *open_disputes.entry(release.client).or_default() += release.amount;
+    }
+
+    // Deterministic report order
+    let mut clients: BTreeMap<ClientId, (Option<&Account>, Option<&Account>)> = BTreeMap::new();
+    for (client, account) in recomputed {
+        clients.entry(*client).or_default().0 = Some(account);
+    }
+    for (client, account) in reported {
+        clients.entry(*client).or_default().1 = Some(account);
+    }
+
+    let mut discrepancies = Vec::new();
+    for (client, accounts) in clients {
+        let expected = match accounts {
+            (Some(expected), Some(actual)) => {
+                let fields = [
+                    ("available", expected.available, actual.available),
+                    ("held", expected.held, actual.held),
+                    ("total", expected.total, actual.total),
+                ];
+                for (check, expected, actual) in fields {
+                    // Reported values are rounded to 4 decimal places
+                    if expected.round_dp(4) != actual {
+                        discrepancies.push(Discrepancy::new(client, check, expected, actual));
+                    }
+                }
+                if expected.locked != actual.locked {
+                    discrepancies.push(Discrepancy::new(
+                        client,
+                        "locked",
+                        expected.locked,
+                        actual.locked,
+                    ));
+                }
+                expected
+            }
+            (Some(expected), None) => {
+                discrepancies.push(Discrepancy::new(
+                    client,
+                    "missing_account",
+                    "present",
+                    "absent",
+                ));
+                expected
+            }
+            (None, Some(_)) => {
+                discrepancies.push(Discrepancy::new(
+                    client,
+                    "unexpected_account",
+                    "absent",
+                    "present",
+                ));
+                continue;
+            }
+            (None, None) => continue,
+        };
+
+        if expected.total != expected.available + expected.held {
+            discrepancies.push(Discrepancy::new(
+                client,
+                "total_vs_available_plus_held",
+                expected.available + expected.held,
+                expected.total,
+            ));
+        }
+
+        let disputed = open_disputes.get(&client).copied().unwrap_or_default();
+        if expected.held != disputed {
+            discrepancies.push(Discrepancy::new(
+                client,
+                "held_vs_open_disputes",
+                disputed,
+                expected.held,
+            ));
+        }
+    }
+
+    discrepancies
+}
+
+/// Write discrepancies as CSV
+pub fn write_report<W: Write>(discrepancies: &[Discrepancy], writer: W) -> io::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["client", "check", "expected", "actual"])?;
+    for d in discrepancies {
+        writer.write_record([
+            d.client.to_string().as_str(),
+            d.check,
+            &d.expected,
+            &d.actual,
+        ])?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TransactionType;
+    use rust_decimal_macros::dec;
+
+    fn account(client: ClientId, available: Decimal, held: Decimal) -> Account {
+        let mut account = Account::new(client);
+        account.available = available;
+        account.held = held;
+        account.total = available + held;
+        account
+    }
+
+    #[test]
+    fn test_clean_audit() {
+        let mut transactions = HashMap::new();
+        let mut disputed = StoredTransaction::new(1, TransactionType::Deposit, dec!(5));
+        disputed.mark_disputed();
+        transactions.insert(1, disputed);
+
+        let recomputed = HashMap::from([(1, account(1, dec!(10.12345), dec!(5)))]);
+        let reported = HashMap::from([(1, account(1, dec!(10.1234), dec!(5)))]);
+
+        // Rounding to output precision is not a discrepancy; total rounds the same
+        let mut reported = reported;
+        reported.get_mut(&1).unwrap().total = dec!(15.1234);
+        assert!(audit(&reported, &recomputed, &transactions, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_discrepancies() {
+        let recomputed = HashMap::from([
+            (1, account(1, dec!(10), dec!(0))),
+            (2, account(2, dec!(0), dec!(3))),
+        ]);
+        let reported = HashMap::from([
+            (1, account(1, dec!(11), dec!(0))),
+            (3, account(3, dec!(1), dec!(0))),
+        ]);
+
+        let discrepancies = audit(&reported, &recomputed, &HashMap::new(), &[]);
+        let checks: Vec<_> = discrepancies.iter().map(|d| (d.client, d.check)).collect();
+        assert_eq!(
+            checks,
+            vec![
+                (1, "available"),
+                (1, "total"),
+                (2, "missing_account"),
+                (2, "held_vs_open_disputes"),
+                (3, "unexpected_account"),
+            ]
+        );
+        assert_eq!(discrepancies[0].expected, "10");
+        assert_eq!(discrepancies[0].actual, "11");
+    }
+
+    #[test]
+    fn test_held_includes_pending_holds() {
+        use crate::clock::SystemClock;
+        use crate::hold::{HoldPeriod, HoldQueue};
+
+        let mut holds = HoldQueue::new(Some(HoldPeriod::Records(100)));
+        holds.hold(1, &SystemClock, 1, 1, dec!(100));
+        holds.hold(2, &SystemClock, 1, 2, dec!(50));
+        // Disputed during its hold, counted as an open dispute instead
+        holds.cancel(2);
+        let mut disputed = StoredTransaction::new(1, TransactionType::Deposit, dec!(50));
+        disputed.mark_disputed();
+        let transactions = HashMap::from([(2, disputed)]);
+
+        let accounts = HashMap::from([(1, account(1, dec!(0), dec!(150)))]);
+        let pending = holds.into_pending();
+        assert!(audit(&accounts, &accounts, &transactions, &pending).is_empty());
+
+        let discrepancies = audit(&accounts, &accounts, &transactions, &[]);
+        assert_eq!(discrepancies.len(), 1);
+        assert_eq!(discrepancies[0].check, "held_vs_open_disputes");
+        assert_eq!(discrepancies[0].expected, "50");
+    }
+
+    #[test]
+    fn test_read_accounts() {
+        let dir = std::env::temp_dir().join(format!("core-tx-runner-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("accounts.csv");
+        std::fs::write(
+            &path,
+            "client,available,held,total,locked\n1,1000.5678,0.0,1000.5678,false\n2,0,0,0,true\n",
+        )
+        .unwrap();
+
+        let accounts = read_accounts(&path).expect("Failed to read");
+        assert_eq!(accounts[&1].available, dec!(1000.5678));
+        assert!(accounts[&2].locked);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
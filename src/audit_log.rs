@@ -0,0 +1,225 @@
+//! Event log of every balance mutation
+//!
+//! Where the `audit` subcommand checks final balances, this explains them: the
+//! engine reports each change it makes to an account, and each withdrawal it
+//! refuses, to an `AuditSink` with the balances before and after.
+
+use crate::types::{
+    serialize_decimal_str, Account, ClientId, RejectionReason, TransactionId, TransactionType,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/// What happened to the account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventKind {
+    /// Deposit credited, to held while under a deposit hold
+    DepositApplied,
+    WithdrawalApplied,
+    /// Withdrawal refused, balances unchanged
+    WithdrawalDenied,
+    /// Dispute moved funds to held
+    FundsHeld,
+    /// Resolve, or the end of a deposit hold, made held funds available
+    FundsReleased,
+    /// Chargeback removed disputed funds
+    Chargeback,
+    /// The account was locked, following the chargeback before it
+    AccountLocked,
+    /// The account was closed, its available balance paid out or written off
+    AccountClosed,
+    /// A locked account was unfrozen
+    AccountUnlocked,
+}
+
+impl AuditEventKind {
+    /// Event for an applied record of type `tx_type`
+    pub fn applied(tx_type: TransactionType) -> Self {
+        match tx_type {
+            TransactionType::Deposit => AuditEventKind::DepositApplied,
+            TransactionType::Withdrawal => AuditEventKind::WithdrawalApplied,
+            TransactionType::Dispute => AuditEventKind::FundsHeld,
+            TransactionType::Resolve => AuditEventKind::FundsReleased,
+            TransactionType::Chargeback => AuditEventKind::Chargeback,
+            TransactionType::Close => AuditEventKind::AccountClosed,
+            TransactionType::Unlock => AuditEventKind::AccountUnlocked,
+        }
+    }
+}
+
+/// Balances of an account at one point
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Balances {
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub available: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub held: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl Balances {
+    /// Balances of `account`, all zero for a client without one
+    pub fn of(account: Option<&Account>) -> Self {
+        account.map_or_else(Self::default, |a| Self {
+            available: a.available,
+            held: a.held,
+            total: a.total,
+            locked: a.locked,
+        })
+    }
+}
+
+/// One balance mutation, or refused withdrawal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AuditEvent {
+    /// Position of the record that caused it
+    pub position: u64,
+    pub event: AuditEventKind,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// Amount moved, for disputes and chargebacks the deposit's, for a close the balance
+    #[serde(serialize_with = "serialize_optional_decimal_str")]
+    pub amount: Option<Decimal>,
+    pub before: Balances,
+    pub after: Balances,
+    /// Why a withdrawal was denied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<RejectionReason>,
+}
+
+fn serialize_optional_decimal_str<S>(
+    value: &Option<Decimal>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match value {
+        Some(value) => serializer.collect_str(value),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Receives the engine's audit events, in the order they happen
+pub trait AuditSink: Send + fmt::Debug {
+    fn record(&mut self, event: &AuditEvent);
+}
+
+/// One engine owns the sink, the caller keeps a handle to finish it after the run
+impl<S: AuditSink> AuditSink for Arc<Mutex<S>> {
+    fn record(&mut self, event: &AuditEvent) {
+        self.lock().expect("audit sink lock poisoned").record(event);
+    }
+}
+
+/// Writes events as JSON lines
+/// Write errors are kept and returned by `finish`, so the engine never stops on them
+pub struct JsonLinesAudit<W: Write> {
+    writer: W,
+    events: u64,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonLinesAudit<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            events: 0,
+            error: None,
+        }
+    }
+
+    /// Events written so far
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+
+    /// Flush and hand back the writer, or the first write error
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write + Send> AuditSink for JsonLinesAudit<W> {
+    fn record(&mut self, event: &AuditEvent) {
+        if self.error.is_some() {
+            return;
+        }
+        let written = serde_json::to_writer(&mut self.writer, event)
+            .map_err(io::Error::from)
+            .and_then(|()| self.writer.write_all(b"\n"));
+        match written {
+            Ok(()) => self.events += 1,
+            Err(e) => self.error = Some(e),
+        }
+    }
+}
+
+impl<W: Write> fmt::Debug for JsonLinesAudit<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonLinesAudit")
+            .field("events", &self.events)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_json_lines() {
+        let mut account = Account::new(3);
+        let before = Balances::of(Some(&account));
+        account.deposit(dec!(2.5));
+
+        let mut sink = JsonLinesAudit::new(Vec::new());
+        sink.record(&AuditEvent {
+            position: 1,
+            event: AuditEventKind::applied(TransactionType::Deposit),
+            client: 3,
+            tx: 9,
+            amount: Some(dec!(2.5)),
+            before,
+            after: Balances::of(Some(&account)),
+            reason: None,
+        });
+        sink.record(&AuditEvent {
+            position: 2,
+            event: AuditEventKind::WithdrawalDenied,
+            client: 3,
+            tx: 10,
+            amount: Some(dec!(4)),
+            before: Balances::of(Some(&account)),
+            after: Balances::of(Some(&account)),
+            reason: Some(RejectionReason::InsufficientFunds),
+        });
+        assert_eq!(sink.events(), 2);
+
+        let output = String::from_utf8(sink.finish().unwrap()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[0],
+            r#"{"position":1,"event":"deposit_applied","client":3,"tx":9,"amount":"2.5","before":{"available":"0","held":"0","total":"0","locked":false},"after":{"available":"2.5","held":"0","total":"2.5","locked":false}}"#
+        );
+        assert!(lines[1].contains(r#""event":"withdrawal_denied""#));
+        assert!(lines[1].ends_with(r#""reason":"insufficient_funds"}"#));
+    }
+
+    #[test]
+    fn test_balances_of_missing_account() {
+        assert_eq!(Balances::of(None), Balances::default());
+    }
+}
@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Default allowed slowdown/growth relative to the baseline (10%)
+pub const DEFAULT_TOLERANCE: f64 = 0.1;
+
+/// Stored performance baseline for a reference workload
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Baseline {
+    pub records_per_sec: f64,
+    /// Peak resident set size, `None` where it can't be measured
+    #[serde(default)]
+    pub peak_memory_kb: Option<u64>,
+    /// Allowed regression as a fraction, e.g. 0.1 for 10%
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+}
+
+fn default_tolerance() -> f64 {
+    DEFAULT_TOLERANCE
+}
+
+impl Baseline {
+    /// Load a baseline from JSON
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Save a baseline as pretty JSON
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)? + "\n")?;
+        Ok(())
+    }
+}
+
+/// Throughput and memory of one processing run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    /// Records read, applied or not
+    pub records: u64,
+    pub elapsed: Duration,
+    pub peak_memory_kb: Option<u64>,
+}
+
+impl Measurement {
+    pub fn records_per_sec(&self) -> f64 {
+        self.records as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Baseline recording this measurement, with the default tolerance
+    pub fn to_baseline(&self) -> Baseline {
+        Baseline {
+            records_per_sec: self.records_per_sec(),
+            peak_memory_kb: self.peak_memory_kb,
+            tolerance: DEFAULT_TOLERANCE,
+        }
+    }
+}
+
+/// A metric that regressed beyond the tolerance
+#[derive(Debug, Clone, PartialEq)]
+pub struct Regression {
+    pub metric: &'static str,
+    pub baseline: f64,
+    pub measured: f64,
+}
+
+impl fmt::Display for Regression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} regressed: baseline {:.0}, measured {:.0} ({:+.1}%)",
+            self.metric,
+            self.baseline,
+            self.measured,
+            (self.measured / self.baseline - 1.0) * 100.0
+        )
+    }
+}
+
+/// Compare a measurement against the baseline
+/// Throughput may drop and memory may grow by at most the tolerance
+pub fn check(baseline: &Baseline, measurement: &Measurement) -> Vec<Regression> {
+    let mut regressions = Vec::new();
+
+    let throughput = measurement.records_per_sec();
+    if throughput < baseline.records_per_sec * (1.0 - baseline.tolerance) {
+        regressions.push(Regression {
+            metric: "records_per_sec",
+            baseline: baseline.records_per_sec,
+            measured: throughput,
+        });
+    }
+
+    if let (Some(base), Some(peak)) = (baseline.peak_memory_kb, measurement.peak_memory_kb) {
+        if peak as f64 > base as f64 * (1.0 + baseline.tolerance) {
+            regressions.push(Regression {
+                metric: "peak_memory_kb",
+                baseline: base as f64,
+                measured: peak as f64,
+            });
+        }
+    }
+
+    regressions
+}
+
+/// Peak resident set size of this process (Linux `VmHWM`), `None` elsewhere
+pub fn peak_memory_kb() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(records: u64, millis: u64, peak: Option<u64>) -> Measurement {
+        Measurement {
+            records,
+            elapsed: Duration::from_millis(millis),
+            peak_memory_kb: peak,
+        }
+    }
+
+    #[test]
+    fn test_within_tolerance() {
+        let baseline = measurement(1000, 1000, Some(10_000)).to_baseline();
+        assert_eq!(baseline.records_per_sec, 1000.0);
+
+        // 5% slower and 5% more memory is fine at 10% tolerance
+        assert!(check(&baseline, &measurement(950, 1000, Some(10_500))).is_empty());
+        // No memory figure on either side skips the memory check
+        assert!(check(&baseline, &measurement(1000, 1000, None)).is_empty());
+    }
+
+    #[test]
+    fn test_regressions() {
+        let baseline = Baseline {
+            records_per_sec: 1000.0,
+            peak_memory_kb: Some(10_000),
+            tolerance: 0.1,
+        };
+        let regressions = check(&baseline, &measurement(800, 1000, Some(12_000)));
+
+        let metrics: Vec<_> = regressions.iter().map(|r| r.metric).collect();
+        assert_eq!(metrics, vec!["records_per_sec", "peak_memory_kb"]);
+        assert_eq!(
+            regressions[0].to_string(),
+            "records_per_sec regressed: baseline 1000, measured 800 (-20.0%)"
+        );
+    }
+
+    #[test]
+    fn test_baseline_json() {
+        let baseline: Baseline =
+            serde_json::from_str(r#"{"records_per_sec": 5000}"#).expect("Failed to parse");
+        assert_eq!(baseline.peak_memory_kb, None);
+        assert_eq!(baseline.tolerance, DEFAULT_TOLERANCE);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_peak_memory() {
+        assert!(peak_memory_kb().is_some_and(|kb| kb > 0));
+    }
+}
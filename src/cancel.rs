@@ -0,0 +1,116 @@
+//! Cooperative cancellation for embedders
+//!
+//! `PaymentsEngine::run` checks a `CancellationToken` before every record and
+//! stops with `TxError::Cancelled` once the token is cancelled or past its
+//! deadline. It never stops inside a record, so the engine keeps every record
+//! before that point applied and can be snapshotted, reported on, or run on the
+//! rest of the source later. Async embedders get the same by dropping an
+//! `AsyncPaymentsEngine` future, which also only gives way between records.
+
+use crate::engine::RunStats;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Stops a run from another thread, or once a deadline passes
+/// Clones share the cancellation, so the caller keeps one to cancel the run it
+/// handed another to
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    /// Token only cancelled by `cancel`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Token that also stops runs from `deadline` on
+    pub fn with_deadline(deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..Self::default()
+        }
+    }
+
+    /// Token that also stops runs `timeout` from now
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self::with_deadline(Instant::now() + timeout)
+    }
+
+    /// Stop runs holding this token or a clone of it, at their next record
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Why a run should stop now, if it should
+    pub fn stop_reason(&self) -> Option<CancelReason> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            Some(CancelReason::Cancelled)
+        } else if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            Some(CancelReason::DeadlineExceeded)
+        } else {
+            None
+        }
+    }
+}
+
+/// Why a run stopped early
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelReason {
+    /// `CancellationToken::cancel` was called
+    Cancelled,
+    /// The token's deadline passed
+    DeadlineExceeded,
+}
+
+/// A run stopped by its token, with what it did before
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled {
+    pub reason: CancelReason,
+    /// Records taken from the source and dealt with before stopping
+    pub stats: RunStats,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.reason {
+            CancelReason::Cancelled => "run cancelled",
+            CancelReason::DeadlineExceeded => "run deadline exceeded",
+        };
+        write!(
+            f,
+            "{} after {} records ({} applied)",
+            reason,
+            self.stats.applied + self.stats.rejected + self.stats.malformed,
+            self.stats.applied
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token() {
+        let token = CancellationToken::new();
+        let handle = token.clone();
+        assert_eq!(token.stop_reason(), None);
+        handle.cancel();
+        assert_eq!(token.stop_reason(), Some(CancelReason::Cancelled));
+
+        let expired = CancellationToken::with_deadline(Instant::now());
+        assert_eq!(expired.stop_reason(), Some(CancelReason::DeadlineExceeded));
+        expired.cancel();
+        assert_eq!(expired.stop_reason(), Some(CancelReason::Cancelled));
+        let later = CancellationToken::with_timeout(Duration::from_secs(3600));
+        assert_eq!(later.stop_reason(), None);
+    }
+}
@@ -0,0 +1,2086 @@
+use core_tx_runner::config::{AccountPolicy, EngineConfig};
+use core_tx_runner::dormancy::DormancyThreshold;
+use core_tx_runner::input;
+use core_tx_runner::mismatch::MismatchPolicy;
+use core_tx_runner::output::{OutputFormat, Schema, Sink};
+use core_tx_runner::rules;
+use core_tx_runner::schema::SchemaFormat;
+#[cfg(feature = "serve")]
+use core_tx_runner::serve::ShedPolicy;
+use core_tx_runner::statement::StatementFormat;
+use core_tx_runner::store::StoreKind;
+use core_tx_runner::summary::DEFAULT_TOP_CLIENTS;
+use core_tx_runner::types::ClientId;
+use core_tx_runner::validation::ValidationConfig;
+use core_tx_runner::what_if::Scenario;
+use core_tx_runner::workload::WorkloadSpec;
+use rust_decimal::Decimal;
+use std::error::Error;
+use std::fs::File;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Command line options for a processing run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Input transaction files processed in order into one engine, `-` for stdin
+    pub inputs: Vec<String>,
+    /// Account output sinks, stdout when none were given
+    pub outputs: Vec<Sink>,
+    /// Accounts layout
+    pub schema: Schema,
+    /// Accounts as CSV, JSON or a table
+    pub output_format: OutputFormat,
+    /// Engine settings
+    pub config: EngineConfig,
+    /// Hypothetical balances for this scenario and where to write them, next to the real ones
+    pub what_if: Option<(Scenario, Sink)>,
+    /// Write the held funds / open dispute exposure report here
+    pub exposure_report: Option<PathBuf>,
+    /// Write references to unknown tx ids here
+    pub dead_letter: Option<PathBuf>,
+    /// Dead letters from an earlier run, processed after the input
+    pub refeed: Option<String>,
+    /// `client,group` file and where to write per-group totals
+    pub groups: Option<(PathBuf, Sink)>,
+    /// Performance baseline JSON to check the run against
+    pub benchmark_gate: Option<PathBuf>,
+    /// Write rejected records with their reason here
+    pub rejects: Option<PathBuf>,
+    /// Recovered deficits CSV, written under `--recovery-sweep`
+    pub recoveries: Option<PathBuf>,
+    /// Closed accounts CSV with the payout each is owed
+    pub closures: Option<PathBuf>,
+    /// Records or days without activity after which an account counts as dormant
+    pub dormant_after: Option<DormancyThreshold>,
+    /// Dormant accounts CSV, written under `--dormant-after`
+    pub dormant: Option<PathBuf>,
+    /// Worker threads, records are sharded over them by client
+    pub threads: usize,
+    /// Snapshot to resume from instead of starting empty
+    pub load_state: Option<PathBuf>,
+    /// Where to write the final state as a snapshot
+    pub save_state: Option<PathBuf>,
+    /// Where stored deposits are kept
+    pub store: StoreKind,
+    /// Directory of risk rule and sink plugins to load
+    pub plugins: Option<PathBuf>,
+    /// JSON-lines log of every balance mutation
+    pub audit: Option<PathBuf>,
+    /// Sandboxed rules and transformers
+    pub wasm: WasmOptions,
+    /// Where to write the end-of-run proof
+    pub proof: Option<PathBuf>,
+    /// Key to sign the proof with, the signature goes to `<proof>.sig`
+    pub proof_key: Option<PathBuf>,
+    /// Where to write the run summary
+    pub summary: Option<Sink>,
+    /// Clients listed in the summary by largest total
+    pub summary_top: usize,
+    /// Engine settings of a shadow engine fed the same records, whose results are only compared
+    pub shadow: Option<EngineConfig>,
+    /// Divergences CSV, written under `--shadow`
+    pub shadow_log: Option<PathBuf>,
+    /// Where to bundle the run if it breaks an invariant or panics
+    pub record_repro: Option<PathBuf>,
+}
+
+/// Which statements to produce and where to write them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatementTarget {
+    /// A single client's statement
+    Client { client: ClientId, output: Sink },
+    /// One file per client in `out_dir`
+    AllClients { out_dir: PathBuf },
+}
+
+/// Options for the `statement` subcommand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatementOptions {
+    /// Input transactions CSV replayed to build the statement
+    pub input: String,
+    pub target: StatementTarget,
+    /// First record position included (1-based), from the start when `None`
+    pub from: Option<u64>,
+    /// Last record position included, to the end when `None`
+    pub to: Option<u64>,
+    pub format: StatementFormat,
+    /// Engine settings used for the replay
+    pub config: EngineConfig,
+}
+
+/// Options for the `replay` subcommand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayOptions {
+    /// Input transactions CSV
+    pub input: String,
+    /// Engine settings of the run being modelled, shared by both runs
+    pub base: EngineConfig,
+    /// Engine settings of the hypothetical run: `base` with the `--override-*` flags
+    pub config: EngineConfig,
+    pub output: Sink,
+}
+
+/// Options for the `audit` subcommand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditOptions {
+    /// Input transactions CSV to recompute accounts from
+    pub input: String,
+    /// Accounts CSV to verify
+    pub accounts: PathBuf,
+    /// Discrepancy report destination
+    pub output: Sink,
+    pub config: EngineConfig,
+}
+
+/// Options for the `reconcile` subcommand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconcileOptions {
+    /// Input transactions CSV to compute balances from
+    pub input: String,
+    /// Externally reported balances, e.g. from the bank
+    pub external: PathBuf,
+    /// Break report destination
+    pub output: Sink,
+    pub config: EngineConfig,
+}
+
+/// Options for the `serve` subcommand
+#[cfg(feature = "serve")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServeOptions {
+    /// HTTP listen address
+    pub http: Option<String>,
+    /// Line-delimited TCP listen address
+    pub tcp: Option<String>,
+    /// Queue depths at which HTTP requests are answered 503
+    pub shed: ShedPolicy,
+    /// JSON-lines ledger of applied records, appended to
+    pub ledger: Option<PathBuf>,
+    pub config: EngineConfig,
+    /// Sandboxed rules and transformers
+    pub wasm: WasmOptions,
+}
+
+/// Options for the `consume` subcommand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsumeOptions {
+    /// Kafka bootstrap servers, `host:port[,host:port...]`
+    pub brokers: String,
+    pub topic: String,
+    /// Consumer group the committed offsets belong to
+    pub group: String,
+    /// Engine state, written every `snapshot_every` messages and on exit
+    pub save_state: PathBuf,
+    /// State to resume from, usually the `save_state` of the previous run
+    pub load_state: Option<PathBuf>,
+    pub snapshot_every: u64,
+    /// Stop after this many messages
+    pub max_messages: Option<u64>,
+    /// Stop once no message arrived for this many seconds
+    pub idle_exit: Option<u64>,
+    /// Account output sinks, written on exit
+    pub outputs: Vec<Sink>,
+    pub output_format: OutputFormat,
+    /// Engine settings; `input_format` is the message format
+    pub config: EngineConfig,
+}
+
+/// Options for the `gen` subcommand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenOptions {
+    pub spec: WorkloadSpec,
+    /// Generated CSV destination
+    pub output: Sink,
+}
+
+/// Options for the `schema` subcommand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaOptions {
+    pub format: SchemaFormat,
+    /// Description destination
+    pub output: Sink,
+}
+
+/// Options for the `repro run` subcommand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReproOptions {
+    /// Bundle written by `--record-repro`
+    pub dir: PathBuf,
+}
+
+/// WebAssembly modules to load as risk rules and transformers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WasmOptions {
+    pub modules: Vec<PathBuf>,
+    /// Fuel per call, the sandbox default if `None`
+    pub fuel: Option<u64>,
+    /// Linear memory cap in bytes, the sandbox default if `None`
+    pub memory: Option<usize>,
+}
+
+/// HTTP address `serve` listens on when no listener was given
+#[cfg(feature = "serve")]
+pub const DEFAULT_HTTP_ADDR: &str = "127.0.0.1:8080";
+
+/// Consumer group of `consume` when none was given
+pub const DEFAULT_GROUP: &str = "core-tx-runner";
+
+/// Messages `consume` applies between snapshots unless `--snapshot-every` says otherwise
+pub const DEFAULT_SNAPSHOT_EVERY: u64 = 10_000;
+
+/// Subcommand selected on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Default: process transactions and output accounts
+    Run(Box<Options>),
+    /// Per-client statement
+    Statement(StatementOptions),
+    /// Re-run under different engine settings and diff against the default run
+    Replay(ReplayOptions),
+    /// Verify an accounts file against the input it was produced from
+    Audit(AuditOptions),
+    /// Compare computed totals against externally reported balances
+    Reconcile(ReconcileOptions),
+    /// Keep an engine running and accept transactions over HTTP or TCP
+    #[cfg(feature = "serve")]
+    Serve(ServeOptions),
+    /// Apply transactions from a Kafka topic, snapshotting as it goes
+    Consume(ConsumeOptions),
+    /// Write a random but valid transaction file
+    Gen(GenOptions),
+    /// Describe the input and output layouts this build reads and writes
+    Schema(SchemaOptions),
+    /// Run a `--record-repro` bundle again
+    Repro(ReproOptions),
+}
+
+/// Usage text printed on invalid arguments
+pub fn usage(program: &str) -> String {
+    format!(
+        "Usage: {program} <transactions.csv|->... [--output <accounts.csv|->]... [--output-format csv|json|table] [--schema v1|v2] [--what-if chargeback-all-open --what-if-output <path|->] [--exposure-report <path>] [--dead-letter <path>] [--refeed <dead_letters.csv>] [--groups <groups.csv> --group-output <path|->] [--benchmark-gate <baseline.json>] [--rejects <rejects.csv|.ndjson>] [--threads <n>] [--store memory|disk:<path>] [--format csv|ndjson|auto] [--load-state <path>] [--save-state <path>] [--recoveries <path>] [--closures <path>] [--dormant-after <records|<n>d> [--dormant <path>]] [--plugins <dir>] [--audit <events.jsonl>] [--proof <proof.json> [--proof-key <key>]] [--summary <path|-> [--summary-top <n>]] [--shadow '<engine options>' [--shadow-log <path>]] [--as-of <RFC 3339 time>] [--record-repro <dir>] [WASM OPTIONS] [ENGINE OPTIONS]
+       {program} statement <transactions.csv> (--client <id> [--output <path>] | --all-clients --out-dir <dir>) [--from <n>] [--to <n>] [--format csv|json] [ENGINE OPTIONS]
+       {program} replay <transactions.csv> [--output <path>] [ENGINE OPTIONS] [--override-<engine option>]...
+       {program} audit <transactions.csv> <accounts.csv> [--output <report.csv>] [ENGINE OPTIONS]
+       {program} reconcile <transactions.csv> --external <balances.csv> [--output <breaks.csv>] [ENGINE OPTIONS]
+       {program} serve [--http <addr>] [--tcp <addr>] [--max-queue <n>] [--max-read-queue <n>] [--ledger <ledger.jsonl>] [WASM OPTIONS] [ENGINE OPTIONS]
+       {program} consume --brokers <host:port> --topic <topic> [--group <id>] --save-state <path> [--load-state <path>] [--snapshot-every <n>] [--max-messages <n>] [--idle-exit <secs>] [--output <path|->]... [--output-format csv|json|table] [ENGINE OPTIONS]
+       {program} gen [--rows <n>] [--clients <n>] [--dispute-ratio <0..1>] [--seed <n>] [--output <path|->]
+       {program} schema [--format json] [--output <path|->]
+       {program} repro run <dir>
+
+Engine options: [--input-format csv|ndjson|auto] [--seq-window <n>] [--max-amount <n|none>]
+                [--ref-dedup off|report|drop|fail] [--client-mismatch ignore|report|trust-stored]
+                [--block-withdrawals-held <amount>] [--block-withdrawals-disputes <n>]
+                [--allow-withdrawal-disputes] [--deposit-hold <records|duration>]
+                [--tier-rules <rules.csv>] [--tiers <client_tiers.csv>] [--recovery-sweep]
+                [--strict] [--fail-fast] [--close-dust <amount>]
+                [--locked-accepts <type,...|none>] [--allow-unlock] [--dedup-events] [--check]
+                [--virtual-time-from-timestamps]
+
+WASM options:   [--wasm <module.wasm>]... [--wasm-fuel <n>] [--wasm-memory <bytes>]"
+    )
+}
+
+/// Parse a WebAssembly sandbox flag, returning whether `flag` was one
+fn parse_wasm_flag<I: Iterator<Item = String>>(
+    flag: &str,
+    args: &mut I,
+    wasm: &mut WasmOptions,
+) -> Result<bool, String> {
+    match flag {
+        "--wasm" => wasm.modules.push(PathBuf::from(value(args, flag)?)),
+        "--wasm-fuel" => wasm.fuel = Some(parsed(args, flag)?),
+        "--wasm-memory" => wasm.memory = Some(parsed(args, flag)?),
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Check sandbox flags once all are parsed
+fn check_wasm_options(wasm: &WasmOptions) -> Result<(), String> {
+    if wasm.modules.is_empty() && (wasm.fuel.is_some() || wasm.memory.is_some()) {
+        return Err("--wasm-fuel and --wasm-memory need --wasm".to_string());
+    }
+    if wasm.fuel == Some(0) {
+        return Err("--wasm-fuel must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+/// Fetch the value following `flag`
+fn value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("{} requires a value", flag))
+}
+
+/// Fetch and parse the value following `flag`
+fn parsed<T: FromStr, I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<T, String> {
+    let value = value(args, flag)?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {} value: {}", flag, value))
+}
+
+/// Read a settings file given on the command line
+fn read_file<T, F>(path: &str, read: F) -> Result<T, String>
+where
+    F: FnOnce(File) -> Result<T, Box<dyn Error>>,
+{
+    File::open(path)
+        .map_err(Into::into)
+        .and_then(read)
+        .map_err(|e| format!("Error reading {}: {}", path, e))
+}
+
+/// Apply an engine setting flag shared by all subcommands
+/// Returns `Ok(false)` if `flag` is not an engine setting
+fn parse_engine_flag<I: Iterator<Item = String>>(
+    flag: &str,
+    args: &mut I,
+    config: &mut EngineConfig,
+) -> Result<bool, String> {
+    match flag {
+        "--input-format" => config.input_format = value(args, flag)?.parse()?,
+        "--seq-window" => config.seq_window = parsed(args, flag)?,
+        "--max-amount" => {
+            config.max_amount = match value(args, flag)?.as_str() {
+                "none" => None,
+                // A bound of zero or less would refuse every amount
+                amount => Some(
+                    amount
+                        .parse()
+                        .ok()
+                        .filter(|max: &Decimal| *max > Decimal::ZERO)
+                        .ok_or_else(|| format!("Invalid {} value: {}", flag, amount))?,
+                ),
+            };
+        }
+        "--ref-dedup" => config.ref_dedup = value(args, flag)?.parse()?,
+        "--client-mismatch" => config.client_mismatch = value(args, flag)?.parse()?,
+        "--block-withdrawals-held" => {
+            config.withdrawal_block.max_held = Some(parsed(args, flag)?);
+        }
+        "--block-withdrawals-disputes" => {
+            config.withdrawal_block.max_open_disputes = Some(parsed(args, flag)?);
+        }
+        "--allow-withdrawal-disputes" => config.allow_withdrawal_disputes = true,
+        "--deposit-hold" => config.deposit_hold = Some(value(args, flag)?.parse()?),
+        "--recovery-sweep" => config.recovery_sweep = true,
+        "--close-dust" => config.close_dust = parsed(args, flag)?,
+        "--locked-accepts" => {
+            config.account_policy.locked_accepts =
+                AccountPolicy::parse_locked_accepts(&value(args, flag)?)?;
+        }
+        "--allow-unlock" => config.account_policy.allow_unlock = true,
+        "--dedup-events" => config.dedup_events = true,
+        "--check" => config.check_invariants = true,
+        "--virtual-time-from-timestamps" => config.virtual_time = true,
+        // Fail-fast implies the strict checks, whichever comes first
+        "--strict" => {
+            config.validation = ValidationConfig {
+                abort_on_violation: config.validation.abort_on_violation,
+                ..ValidationConfig::strict()
+            };
+        }
+        "--fail-fast" => {
+            config.validation = ValidationConfig {
+                abort_on_violation: true,
+                ..ValidationConfig::strict()
+            };
+        }
+        "--tier-rules" => {
+            config.withdrawal_rules.rules = read_file(&value(args, flag)?, rules::read_rules)?;
+        }
+        "--tiers" => {
+            config.withdrawal_rules.tiers = read_file(&value(args, flag)?, rules::read_tiers)?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Parse command line arguments (excluding the program name), dispatching on subcommand
+pub fn parse_command<I>(args: I) -> Result<Command, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter().peekable();
+    match args.peek().map(String::as_str) {
+        Some("statement") => {
+            args.next();
+            parse_statement_args(args).map(Command::Statement)
+        }
+        Some("replay") => {
+            args.next();
+            parse_replay_args(args).map(Command::Replay)
+        }
+        Some("audit") => {
+            args.next();
+            parse_audit_args(args).map(Command::Audit)
+        }
+        Some("reconcile") => {
+            args.next();
+            parse_reconcile_args(args).map(Command::Reconcile)
+        }
+        Some("serve") => {
+            args.next();
+            serve_command(args)
+        }
+        Some("consume") => {
+            args.next();
+            parse_consume_args(args).map(Command::Consume)
+        }
+        Some("gen") => {
+            args.next();
+            parse_gen_args(args).map(Command::Gen)
+        }
+        Some("schema") => {
+            args.next();
+            parse_schema_args(args).map(Command::Schema)
+        }
+        Some("repro") => {
+            args.next();
+            parse_repro_args(args).map(Command::Repro)
+        }
+        _ => parse_args(args).map(|options| Command::Run(Box::new(options))),
+    }
+}
+
+/// Parse arguments of the default processing run
+/// Kept hand-rolled: the option surface is small enough not to need a library
+pub fn parse_args<I>(args: I) -> Result<Options, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut inputs = Vec::new();
+    let mut outputs = Vec::new();
+    let mut schema = Schema::default();
+    let mut output_format = OutputFormat::default();
+    let mut config = EngineConfig::default();
+    let mut what_if = None;
+    let mut what_if_output = None;
+    let mut exposure_report = None;
+    let mut dead_letter = None;
+    let mut refeed = None;
+    let mut groups = None;
+    let mut group_output = None;
+    let mut benchmark_gate = None;
+    let mut rejects = None;
+    let mut threads = 1;
+    let mut store = StoreKind::default();
+    let mut load_state = None;
+    let mut recoveries = None;
+    let mut closures = None;
+    let mut dormant_after = None;
+    let mut dormant = None;
+    let mut save_state = None;
+    let mut plugins = None;
+    let mut audit = None;
+    let mut wasm = WasmOptions::default();
+    let mut proof = None;
+    let mut proof_key = None;
+    let mut summary = None;
+    let mut summary_top = None;
+    let mut shadow = None;
+    let mut shadow_log = None;
+    let mut record_repro = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if parse_engine_flag(&arg, &mut args, &mut config)?
+            || parse_wasm_flag(&arg, &mut args, &mut wasm)?
+        {
+            continue;
+        }
+
+        match arg.as_str() {
+            "-o" | "--output" => outputs.push(Sink::parse(&value(&mut args, &arg)?)),
+            "--output-format" => output_format = value(&mut args, &arg)?.parse()?,
+            "--schema" => schema = value(&mut args, &arg)?.parse()?,
+            "--what-if" => what_if = Some(value(&mut args, &arg)?.parse()?),
+            "--what-if-output" => what_if_output = Some(Sink::parse(&value(&mut args, &arg)?)),
+            "--exposure-report" => {
+                exposure_report = Some(PathBuf::from(value(&mut args, &arg)?));
+            }
+            "--dead-letter" => dead_letter = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--refeed" => refeed = Some(value(&mut args, &arg)?),
+            "--groups" => groups = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--group-output" => group_output = Some(Sink::parse(&value(&mut args, &arg)?)),
+            "--benchmark-gate" => benchmark_gate = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--rejects" => rejects = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--recoveries" => recoveries = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--closures" => closures = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--dormant-after" => dormant_after = Some(value(&mut args, &arg)?.parse()?),
+            "--dormant" => dormant = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--threads" => threads = parsed(&mut args, &arg)?,
+            "--store" => store = value(&mut args, &arg)?.parse()?,
+            "--load-state" => load_state = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--save-state" => save_state = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--plugins" => plugins = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--audit" => audit = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--proof" => proof = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--proof-key" => proof_key = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--summary" => summary = Some(Sink::parse(&value(&mut args, &arg)?)),
+            "--summary-top" => summary_top = Some(parsed(&mut args, &arg)?),
+            "--shadow" => shadow = Some(value(&mut args, &arg)?),
+            "--shadow-log" => shadow_log = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--record-repro" => record_repro = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--as-of" => config.as_of = Some(value(&mut args, &arg)?.parse()?),
+            // Short for --input-format, statements use --format for their output
+            "--format" => config.input_format = value(&mut args, &arg)?.parse()?,
+            flag if flag.starts_with("--") => {
+                return Err(format!("Unknown option: {}", flag));
+            }
+            _ => inputs.push(arg),
+        }
+    }
+
+    if inputs.is_empty() {
+        return Err("Missing input file".to_string());
+    }
+    if inputs.iter().filter(|i| *i == input::STDIN).count() > 1 {
+        return Err("Standard input (-) can only be read once".to_string());
+    }
+    if outputs.is_empty() {
+        outputs.push(Sink::Stdout);
+    }
+    // Hypothetical balances never go where the real ones are published
+    let what_if = match (what_if, what_if_output) {
+        (Some(_), Some(sink)) if outputs.contains(&sink) => {
+            return Err("--what-if-output cannot be an accounts --output".to_string());
+        }
+        (Some(scenario), Some(sink)) => Some((scenario, sink)),
+        (None, None) => None,
+        (Some(_), None) => return Err("--what-if needs --what-if-output".to_string()),
+        (None, Some(_)) => return Err("--what-if-output needs --what-if".to_string()),
+    };
+    let groups = match (groups, group_output) {
+        (Some(path), Some(sink)) => Some((path, sink)),
+        (None, None) => None,
+        _ => return Err("--groups and --group-output go together".to_string()),
+    };
+    if threads == 0 {
+        return Err("--threads must be at least 1".to_string());
+    }
+    // Shards only see their own clients' deposits
+    if threads > 1 && config.client_mismatch == MismatchPolicy::TrustStored {
+        return Err("--client-mismatch trust-stored needs --threads 1".to_string());
+    }
+    if threads > 1 && store != StoreKind::Memory {
+        return Err("--store disk needs --threads 1".to_string());
+    }
+    // A shard only counts hold periods in records it sees
+    if threads > 1 && config.deposit_hold.is_some() {
+        return Err("--deposit-hold needs --threads 1".to_string());
+    }
+    if recoveries.is_some() && !config.recovery_sweep {
+        return Err("--recoveries needs --recovery-sweep".to_string());
+    }
+    if dormant.is_some() && dormant_after.is_none() {
+        return Err("--dormant needs --dormant-after".to_string());
+    }
+    // A loaded snapshot is restored into one in-memory engine
+    if load_state.is_some() && (threads > 1 || store != StoreKind::Memory) {
+        return Err("--load-state needs --threads 1 and --store memory".to_string());
+    }
+    // Plugins are loaded into one engine
+    if plugins.is_some() && threads > 1 {
+        return Err("--plugins needs --threads 1".to_string());
+    }
+    // One log in application order
+    if audit.is_some() && threads > 1 {
+        return Err("--audit needs --threads 1".to_string());
+    }
+    check_wasm_options(&wasm)?;
+    if !wasm.modules.is_empty() && threads > 1 {
+        return Err("--wasm needs --threads 1".to_string());
+    }
+    if summary_top.is_some() && summary.is_none() {
+        return Err("--summary-top needs --summary".to_string());
+    }
+    if proof_key.is_some() && proof.is_none() {
+        return Err("--proof-key needs --proof".to_string());
+    }
+    // Hypothetical balances don't add up to what flowed through the engine
+    if proof.is_some() && what_if.is_some() {
+        return Err("--proof cannot be combined with --what-if".to_string());
+    }
+    // Dead letters are written without their timestamp
+    if config.as_of.is_some() && refeed.is_some() {
+        return Err("--as-of cannot be combined with --refeed".to_string());
+    }
+    if shadow_log.is_some() && shadow.is_none() {
+        return Err("--shadow-log needs --shadow".to_string());
+    }
+    // The shadow is one more engine fed alongside the primary
+    if shadow.is_some() && threads > 1 {
+        return Err("--shadow needs --threads 1".to_string());
+    }
+    // Plugins live in the process, a shadow cannot have its own copy
+    if shadow.is_some() && plugins.is_some() {
+        return Err("--shadow cannot be combined with --plugins".to_string());
+    }
+    // A bundle holds copies of the files read, stdin is gone once read
+    if record_repro.is_some() && inputs.iter().any(|i| i == input::STDIN) {
+        return Err("--record-repro cannot be combined with stdin input".to_string());
+    }
+    // Plugins are native code outside the bundle
+    if record_repro.is_some() && plugins.is_some() {
+        return Err("--record-repro cannot be combined with --plugins".to_string());
+    }
+    // Invariant violations are what it records
+    if record_repro.is_some() {
+        config.check_invariants = true;
+    }
+    // Shadow flags change the primary's settings, wherever those were given
+    let shadow = shadow
+        .map(|flags| parse_shadow_config(&flags, &config))
+        .transpose()?;
+
+    Ok(Options {
+        inputs,
+        outputs,
+        schema,
+        output_format,
+        config,
+        what_if,
+        exposure_report,
+        dead_letter,
+        refeed,
+        groups,
+        benchmark_gate,
+        rejects,
+        recoveries,
+        closures,
+        dormant_after,
+        dormant,
+        threads,
+        store,
+        load_state,
+        save_state,
+        plugins,
+        audit,
+        wasm,
+        proof,
+        proof_key,
+        summary,
+        summary_top: summary_top.unwrap_or(DEFAULT_TOP_CLIENTS),
+        shadow,
+        shadow_log,
+        record_repro,
+    })
+}
+
+/// Engine settings of a shadow engine: the primary's, changed by the engine options in `flags`
+fn parse_shadow_config(flags: &str, primary: &EngineConfig) -> Result<EngineConfig, String> {
+    let mut config = primary.clone();
+    let mut args = flags.split_whitespace().map(str::to_string);
+    while let Some(flag) = args.next() {
+        if !parse_engine_flag(&flag, &mut args, &mut config)? {
+            return Err(format!("--shadow takes engine options, not {}", flag));
+        }
+    }
+    Ok(config)
+}
+
+/// Parse arguments of the `statement` subcommand
+fn parse_statement_args<I>(args: I) -> Result<StatementOptions, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut input = None;
+    let mut client = None;
+    let mut from = None;
+    let mut to = None;
+    let mut format = StatementFormat::Csv;
+    let mut output = Sink::Stdout;
+    let mut all_clients = false;
+    let mut out_dir = None;
+    let mut config = EngineConfig::default();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if parse_engine_flag(&arg, &mut args, &mut config)? {
+            continue;
+        }
+
+        match arg.as_str() {
+            "--client" => client = Some(parsed(&mut args, &arg)?),
+            "--from" => from = Some(parsed(&mut args, &arg)?),
+            "--to" => to = Some(parsed(&mut args, &arg)?),
+            "--format" => format = value(&mut args, &arg)?.parse()?,
+            "-o" | "--output" => output = Sink::parse(&value(&mut args, &arg)?),
+            "--all-clients" => all_clients = true,
+            "--out-dir" => out_dir = Some(PathBuf::from(value(&mut args, &arg)?)),
+            flag if flag.starts_with("--") => {
+                return Err(format!("Unknown option: {}", flag));
+            }
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    let target = match (client, all_clients) {
+        (Some(client), false) => StatementTarget::Client { client, output },
+        (None, true) => StatementTarget::AllClients {
+            out_dir: out_dir.ok_or_else(|| "--all-clients requires --out-dir".to_string())?,
+        },
+        (Some(_), true) => return Err("Use either --client or --all-clients".to_string()),
+        (None, false) => return Err("statement requires --client or --all-clients".to_string()),
+    };
+
+    Ok(StatementOptions {
+        input: input.ok_or_else(|| "Missing input file".to_string())?,
+        target,
+        from,
+        to,
+        format,
+        config,
+    })
+}
+
+/// Parse arguments of the `replay` subcommand
+fn parse_replay_args<I>(args: I) -> Result<ReplayOptions, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut input = None;
+    let mut output = Sink::Stdout;
+    let mut base = EngineConfig::default();
+    // Engine flags and their values, applied on top of `base` whatever their order
+    let mut overrides: Vec<(String, Vec<String>)> = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if parse_engine_flag(&arg, &mut args, &mut base)? {
+            continue;
+        }
+        if let Some(name) = arg.strip_prefix("--override-") {
+            let flag = format!("--{}", name);
+            let mut values = Vec::new();
+            let mut recorded = args.by_ref().inspect(|value| values.push(value.clone()));
+            if !parse_engine_flag(&flag, &mut recorded, &mut EngineConfig::default())? {
+                return Err(format!("Unknown option: {}", arg));
+            }
+            overrides.push((flag, values));
+            continue;
+        }
+
+        match arg.as_str() {
+            "-o" | "--output" => output = Sink::parse(&value(&mut args, &arg)?),
+            flag if flag.starts_with("--") => {
+                return Err(format!("Unknown option: {}", flag));
+            }
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    let input = input.ok_or_else(|| "Missing input file".to_string())?;
+    // The input is read twice, once per engine configuration
+    if input == input::STDIN {
+        return Err("replay cannot read standard input (-)".to_string());
+    }
+
+    let mut config = base.clone();
+    for (flag, values) in overrides {
+        parse_engine_flag(&flag, &mut values.into_iter(), &mut config)?;
+    }
+
+    Ok(ReplayOptions {
+        input,
+        base,
+        config,
+        output,
+    })
+}
+
+/// Parse arguments of the `audit` subcommand
+fn parse_audit_args<I>(args: I) -> Result<AuditOptions, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut positional = Vec::new();
+    let mut output = Sink::Stdout;
+    let mut config = EngineConfig::default();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if parse_engine_flag(&arg, &mut args, &mut config)? {
+            continue;
+        }
+
+        match arg.as_str() {
+            "-o" | "--output" => output = Sink::parse(&value(&mut args, &arg)?),
+            flag if flag.starts_with("--") => {
+                return Err(format!("Unknown option: {}", flag));
+            }
+            _ if positional.len() < 2 => positional.push(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    Ok(AuditOptions {
+        input: positional
+            .next()
+            .ok_or_else(|| "Missing input file".to_string())?,
+        accounts: positional
+            .next()
+            .map(PathBuf::from)
+            .ok_or_else(|| "Missing accounts file".to_string())?,
+        output,
+        config,
+    })
+}
+
+#[cfg(feature = "serve")]
+fn serve_command<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    parse_serve_args(args).map(Command::Serve)
+}
+
+#[cfg(not(feature = "serve"))]
+fn serve_command<I: Iterator<Item = String>>(_args: I) -> Result<Command, String> {
+    Err("built without serve support, rebuild with --features serve".to_string())
+}
+
+/// Parse arguments of the `serve` subcommand
+/// Without `--http` or `--tcp`, serves HTTP on `DEFAULT_HTTP_ADDR`
+#[cfg(feature = "serve")]
+fn parse_serve_args<I>(args: I) -> Result<ServeOptions, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut http = None;
+    let mut tcp = None;
+    let mut shed = ShedPolicy::default();
+    let mut ledger = None;
+    let mut config = EngineConfig::default();
+    let mut wasm = WasmOptions::default();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if parse_engine_flag(&arg, &mut args, &mut config)?
+            || parse_wasm_flag(&arg, &mut args, &mut wasm)?
+        {
+            continue;
+        }
+
+        match arg.as_str() {
+            "--http" => http = Some(value(&mut args, &arg)?),
+            "--tcp" => tcp = Some(value(&mut args, &arg)?),
+            "--max-queue" => shed.max_queue = Some(parsed(&mut args, &arg)?),
+            "--max-read-queue" => shed.max_read_queue = Some(parsed(&mut args, &arg)?),
+            "--ledger" => ledger = Some(PathBuf::from(value(&mut args, &arg)?)),
+            flag if flag.starts_with("--") => {
+                return Err(format!("Unknown option: {}", flag));
+            }
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    if http.is_none() && tcp.is_none() {
+        http = Some(DEFAULT_HTTP_ADDR.to_string());
+    }
+    if shed.max_queue == Some(0) || shed.max_read_queue == Some(0) {
+        return Err("--max-queue and --max-read-queue must be at least 1".to_string());
+    }
+    check_wasm_options(&wasm)?;
+    Ok(ServeOptions {
+        http,
+        tcp,
+        shed,
+        ledger,
+        config,
+        wasm,
+    })
+}
+
+/// Parse arguments of the `consume` subcommand
+fn parse_consume_args<I>(args: I) -> Result<ConsumeOptions, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut brokers = None;
+    let mut topic = None;
+    let mut group = DEFAULT_GROUP.to_string();
+    let mut save_state = None;
+    let mut load_state = None;
+    let mut snapshot_every = DEFAULT_SNAPSHOT_EVERY;
+    let mut max_messages = None;
+    let mut idle_exit = None;
+    let mut outputs = Vec::new();
+    let mut output_format = OutputFormat::default();
+    let mut config = EngineConfig::default();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if parse_engine_flag(&arg, &mut args, &mut config)? {
+            continue;
+        }
+
+        match arg.as_str() {
+            "--brokers" => brokers = Some(value(&mut args, &arg)?),
+            "--topic" => topic = Some(value(&mut args, &arg)?),
+            "--group" => group = value(&mut args, &arg)?,
+            "--save-state" => save_state = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--load-state" => load_state = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "--snapshot-every" => snapshot_every = parsed(&mut args, &arg)?,
+            "--max-messages" => max_messages = Some(parsed(&mut args, &arg)?),
+            "--idle-exit" => idle_exit = Some(parsed(&mut args, &arg)?),
+            "-o" | "--output" => outputs.push(Sink::parse(&value(&mut args, &arg)?)),
+            "--output-format" => output_format = value(&mut args, &arg)?.parse()?,
+            flag if flag.starts_with("--") => {
+                return Err(format!("Unknown option: {}", flag));
+            }
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    if snapshot_every == 0 {
+        return Err("--snapshot-every must be at least 1".to_string());
+    }
+    // Redelivered messages are recognised by the event ids the snapshot keeps
+    config.dedup_events = true;
+    if outputs.is_empty() {
+        outputs.push(Sink::Stdout);
+    }
+    Ok(ConsumeOptions {
+        brokers: brokers.ok_or_else(|| "consume needs --brokers".to_string())?,
+        topic: topic.ok_or_else(|| "consume needs --topic".to_string())?,
+        group,
+        save_state: save_state.ok_or_else(|| "consume needs --save-state".to_string())?,
+        load_state,
+        snapshot_every,
+        max_messages,
+        idle_exit,
+        outputs,
+        output_format,
+        config,
+    })
+}
+
+/// Parse arguments of the `gen` subcommand
+fn parse_gen_args<I>(args: I) -> Result<GenOptions, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut spec = WorkloadSpec::default();
+    let mut output = Sink::Stdout;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rows" => spec.rows = parsed(&mut args, &arg)?,
+            "--clients" => spec.clients = parsed(&mut args, &arg)?,
+            "--dispute-ratio" => spec.dispute_ratio = parsed(&mut args, &arg)?,
+            "--seed" => spec.seed = parsed(&mut args, &arg)?,
+            "-o" | "--output" => output = Sink::parse(&value(&mut args, &arg)?),
+            flag if flag.starts_with("--") => {
+                return Err(format!("Unknown option: {}", flag));
+            }
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    if spec.clients == 0 {
+        return Err("--clients must be at least 1".to_string());
+    }
+    if spec.dispute_ratio < Decimal::ZERO || spec.dispute_ratio > Decimal::ONE {
+        return Err("--dispute-ratio must be between 0 and 1".to_string());
+    }
+    Ok(GenOptions { spec, output })
+}
+
+/// Parse arguments of the `schema` subcommand
+fn parse_schema_args<I>(args: I) -> Result<SchemaOptions, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut format = SchemaFormat::default();
+    let mut output = Sink::Stdout;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = parsed(&mut args, &arg)?,
+            "-o" | "--output" => output = Sink::parse(&value(&mut args, &arg)?),
+            flag if flag.starts_with("--") => {
+                return Err(format!("Unknown option: {}", flag));
+            }
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    Ok(SchemaOptions { format, output })
+}
+
+/// Parse arguments of the `repro` subcommand: `run <dir>`
+fn parse_repro_args<I>(args: I) -> Result<ReproOptions, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter();
+    match args.next().as_deref() {
+        Some("run") => {}
+        Some(other) => return Err(format!("Unknown repro command: {} (expected run)", other)),
+        None => return Err("repro requires a command: run <dir>".to_string()),
+    }
+    let dir = args
+        .next()
+        .ok_or_else(|| "Missing bundle directory".to_string())?;
+    if let Some(arg) = args.next() {
+        return Err(format!("Unexpected argument: {}", arg));
+    }
+    Ok(ReproOptions {
+        dir: PathBuf::from(dir),
+    })
+}
+
+/// Parse arguments of the `reconcile` subcommand
+fn parse_reconcile_args<I>(args: I) -> Result<ReconcileOptions, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut input = None;
+    let mut external = None;
+    let mut output = Sink::Stdout;
+    let mut config = EngineConfig::default();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if parse_engine_flag(&arg, &mut args, &mut config)? {
+            continue;
+        }
+
+        match arg.as_str() {
+            "--external" => external = Some(PathBuf::from(value(&mut args, &arg)?)),
+            "-o" | "--output" => output = Sink::parse(&value(&mut args, &arg)?),
+            flag if flag.starts_with("--") => {
+                return Err(format!("Unknown option: {}", flag));
+            }
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    Ok(ReconcileOptions {
+        input: input.ok_or_else(|| "Missing input file".to_string())?,
+        external: external.ok_or_else(|| "reconcile requires --external".to_string())?,
+        output,
+        config,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core_tx_runner::hold::HoldPeriod;
+    use core_tx_runner::input::InputFormat;
+    use core_tx_runner::types::TransactionType;
+    use std::time::Duration;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_input_only() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.inputs, vec!["tx.csv"]);
+        assert_eq!(options.outputs, vec![Sink::Stdout]);
+    }
+
+    #[test]
+    fn test_parse_multiple_inputs() {
+        let options = parse_args(args(&["day1.csv", "-o", "out.csv", "day2.csv", "-"]))
+            .expect("Failed to parse");
+        assert_eq!(options.inputs, vec!["day1.csv", "day2.csv", "-"]);
+
+        assert!(parse_args(args(&["-", "tx.csv", "-"])).is_err());
+        assert!(parse_args(args(&["--threads", "2"])).is_err());
+        assert!(parse_command(args(&["replay", "-", "--seq-window", "5"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let options =
+            parse_args(args(&["tx.csv", "--output", "out.csv"])).expect("Failed to parse");
+        assert_eq!(options.inputs, vec!["tx.csv"]);
+        assert_eq!(options.outputs, vec![Sink::File(PathBuf::from("out.csv"))]);
+
+        let options = parse_args(args(&["-o", "out.csv", "tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.outputs, vec![Sink::File(PathBuf::from("out.csv"))]);
+    }
+
+    #[test]
+    fn test_parse_multiple_outputs() {
+        let options =
+            parse_args(args(&["tx.csv", "-o", "-", "-o", "out.csv"])).expect("Failed to parse");
+        assert_eq!(
+            options.outputs,
+            vec![Sink::Stdout, Sink::File(PathBuf::from("out.csv"))]
+        );
+    }
+
+    #[test]
+    fn test_parse_seq_window() {
+        let options = parse_args(args(&["tx.csv", "--seq-window", "64"])).expect("Failed to parse");
+        assert_eq!(options.config.seq_window, 64);
+
+        assert!(parse_args(args(&["tx.csv", "--seq-window", "many"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_input_format() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.config.input_format, InputFormat::Auto);
+
+        let options =
+            parse_args(args(&["tx.json", "--format", "ndjson"])).expect("Failed to parse");
+        assert_eq!(options.config.input_format, InputFormat::Ndjson);
+        assert!(parse_args(args(&["tx.csv", "--format", "xml"])).is_err());
+
+        let command = parse_command(args(&[
+            "audit",
+            "tx",
+            "accounts.csv",
+            "--input-format",
+            "csv",
+        ]))
+        .expect("Failed to parse");
+        let Command::Audit(options) = command else {
+            panic!("Expected audit command");
+        };
+        assert_eq!(options.config.input_format, InputFormat::Csv);
+    }
+
+    #[test]
+    fn test_parse_max_amount() {
+        use rust_decimal_macros::dec;
+
+        let options =
+            parse_args(args(&["tx.csv", "--max-amount", "5000.5"])).expect("Failed to parse");
+        assert_eq!(options.config.max_amount, Some(dec!(5000.5)));
+
+        let options =
+            parse_args(args(&["tx.csv", "--max-amount", "none"])).expect("Failed to parse");
+        assert_eq!(options.config.max_amount, None);
+
+        assert!(parse_args(args(&["tx.csv", "--max-amount", "lots"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_what_if() {
+        let options = parse_args(args(&[
+            "tx.csv",
+            "--what-if",
+            "chargeback-all-open",
+            "--what-if-output",
+            "what_if.csv",
+        ]))
+        .expect("Failed to parse");
+        assert_eq!(
+            options.what_if,
+            Some((
+                Scenario::ChargebackAllOpen,
+                Sink::File(PathBuf::from("what_if.csv"))
+            ))
+        );
+        assert_eq!(options.outputs, vec![Sink::Stdout]);
+
+        assert!(parse_args(args(&["tx.csv", "--what-if", "sunshine"])).is_err());
+        assert_eq!(
+            parse_args(args(&["tx.csv", "--what-if", "chargeback-all-open"])),
+            Err("--what-if needs --what-if-output".to_string())
+        );
+        assert_eq!(
+            parse_args(args(&["tx.csv", "--what-if-output", "what_if.csv"])),
+            Err("--what-if-output needs --what-if".to_string())
+        );
+        // Both would go to stdout
+        assert_eq!(
+            parse_args(args(&[
+                "tx.csv",
+                "--what-if",
+                "chargeback-all-open",
+                "--what-if-output",
+                "-"
+            ])),
+            Err("--what-if-output cannot be an accounts --output".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_exposure_report() {
+        let options = parse_args(args(&["tx.csv", "--exposure-report", "exposure.txt"]))
+            .expect("Failed to parse");
+        assert_eq!(options.exposure_report, Some(PathBuf::from("exposure.txt")));
+    }
+
+    #[test]
+    fn test_parse_dead_letter() {
+        let options = parse_args(args(&[
+            "tx.csv",
+            "--dead-letter",
+            "dead.csv",
+            "--refeed",
+            "old_dead.csv",
+        ]))
+        .expect("Failed to parse");
+        assert_eq!(options.dead_letter, Some(PathBuf::from("dead.csv")));
+        assert_eq!(options.refeed.as_deref(), Some("old_dead.csv"));
+    }
+
+    #[test]
+    fn test_parse_groups() {
+        let options = parse_args(args(&[
+            "tx.csv",
+            "--groups",
+            "groups.csv",
+            "--group-output",
+            "-",
+        ]))
+        .expect("Failed to parse");
+        assert_eq!(
+            options.groups,
+            Some((PathBuf::from("groups.csv"), Sink::Stdout))
+        );
+
+        assert!(parse_args(args(&["tx.csv", "--groups", "groups.csv"])).is_err());
+        assert!(parse_args(args(&["tx.csv", "--group-output", "-"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects() {
+        let options =
+            parse_args(args(&["tx.csv", "--rejects", "rejects.ndjson"])).expect("Failed to parse");
+        assert_eq!(options.rejects, Some(PathBuf::from("rejects.ndjson")));
+    }
+
+    #[test]
+    fn test_parse_threads() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.threads, 1);
+
+        let options = parse_args(args(&["tx.csv", "--threads", "4"])).expect("Failed to parse");
+        assert_eq!(options.threads, 4);
+
+        assert!(parse_args(args(&["tx.csv", "--threads", "0"])).is_err());
+        assert!(parse_args(args(&[
+            "tx.csv",
+            "--threads",
+            "4",
+            "--client-mismatch",
+            "trust-stored"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_store() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.store, StoreKind::Memory);
+
+        let options =
+            parse_args(args(&["tx.csv", "--store", "disk:tx.store"])).expect("Failed to parse");
+        assert_eq!(options.store, StoreKind::Disk(PathBuf::from("tx.store")));
+
+        assert!(parse_args(args(&["tx.csv", "--store", "disk"])).is_err());
+        assert!(parse_args(args(&[
+            "tx.csv",
+            "--store",
+            "disk:tx.store",
+            "--threads",
+            "2"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_benchmark_gate() {
+        let options = parse_args(args(&["tx.csv", "--benchmark-gate", "throughput.json"]))
+            .expect("Failed to parse");
+        assert_eq!(
+            options.benchmark_gate,
+            Some(PathBuf::from("throughput.json"))
+        );
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let command = parse_command(args(&["tx.csv"])).expect("Failed to parse");
+        assert!(matches!(command, Command::Run(_)));
+
+        let command = parse_command(args(&[
+            "statement",
+            "tx.csv",
+            "--client",
+            "42",
+            "--from",
+            "10",
+            "--format",
+            "json",
+            "--max-amount",
+            "none",
+        ]))
+        .expect("Failed to parse");
+        let Command::Statement(options) = command else {
+            panic!("Expected statement command");
+        };
+        assert_eq!(options.input, "tx.csv");
+        assert_eq!(
+            options.target,
+            StatementTarget::Client {
+                client: 42,
+                output: Sink::Stdout
+            }
+        );
+        assert_eq!(options.from, Some(10));
+        assert_eq!(options.to, None);
+        assert_eq!(options.format, StatementFormat::Json);
+        assert_eq!(options.config.max_amount, None);
+
+        // A client selection is mandatory
+        assert!(parse_command(args(&["statement", "tx.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_statement_all_clients() {
+        let command = parse_command(args(&[
+            "statement",
+            "tx.csv",
+            "--all-clients",
+            "--out-dir",
+            "statements",
+        ]))
+        .expect("Failed to parse");
+        let Command::Statement(options) = command else {
+            panic!("Expected statement command");
+        };
+        assert_eq!(
+            options.target,
+            StatementTarget::AllClients {
+                out_dir: PathBuf::from("statements")
+            }
+        );
+
+        assert!(parse_command(args(&["statement", "tx.csv", "--all-clients"])).is_err());
+        assert!(parse_command(args(&[
+            "statement",
+            "tx.csv",
+            "--all-clients",
+            "--out-dir",
+            "d",
+            "--client",
+            "1"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_replay() {
+        use rust_decimal_macros::dec;
+
+        let command = parse_command(args(&[
+            "replay",
+            "tx.csv",
+            "--override-max-amount",
+            "none",
+            "--input-format",
+            "ndjson",
+            "--max-amount",
+            "5",
+            "--override-allow-withdrawal-disputes",
+        ]))
+        .expect("Failed to parse");
+        let Command::Replay(options) = command else {
+            panic!("Expected replay command");
+        };
+        assert_eq!(options.input, "tx.csv");
+        assert_eq!(options.output, Sink::Stdout);
+        // Base settings go to both runs, overrides win only in the replay
+        assert_eq!(options.base.input_format, InputFormat::Ndjson);
+        assert_eq!(options.base.max_amount, Some(dec!(5)));
+        assert!(!options.base.allow_withdrawal_disputes);
+        assert_eq!(options.config.input_format, InputFormat::Ndjson);
+        assert_eq!(options.config.max_amount, None);
+        assert!(options.config.allow_withdrawal_disputes);
+
+        assert!(parse_command(args(&["replay", "tx.csv", "--override-output", "x"])).is_err());
+        assert!(parse_command(args(&["replay", "tx.csv", "--override-max-amount"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_ref_dedup() {
+        use core_tx_runner::dedup::DedupPolicy;
+
+        let options =
+            parse_args(args(&["tx.csv", "--ref-dedup", "drop"])).expect("Failed to parse");
+        assert_eq!(options.config.ref_dedup, DedupPolicy::Drop);
+        assert!(parse_args(args(&["tx.csv", "--ref-dedup", "maybe"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_client_mismatch() {
+        let options = parse_args(args(&["tx.csv", "--client-mismatch", "trust-stored"]))
+            .expect("Failed to parse");
+        assert_eq!(options.config.client_mismatch, MismatchPolicy::TrustStored);
+        assert!(parse_args(args(&["tx.csv", "--client-mismatch", "guess"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_account_policy() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.config.account_policy, AccountPolicy::default());
+
+        let options = parse_args(args(&[
+            "tx.csv",
+            "--locked-accepts",
+            "deposit,resolve",
+            "--allow-unlock",
+        ]))
+        .expect("Failed to parse");
+        assert_eq!(
+            options.config.account_policy,
+            AccountPolicy {
+                locked_accepts: vec![TransactionType::Deposit, TransactionType::Resolve],
+                allow_unlock: true,
+            }
+        );
+
+        assert!(parse_args(args(&["tx.csv", "--locked-accepts", "refund"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_dedup_events() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert!(!options.config.dedup_events);
+
+        let options = parse_args(args(&["tx.csv", "--dedup-events"])).expect("Failed to parse");
+        assert!(options.config.dedup_events);
+    }
+
+    #[test]
+    fn test_parse_gen() {
+        use core_tx_runner::workload::{DEFAULT_CLIENTS, DEFAULT_ROWS};
+        use rust_decimal_macros::dec;
+
+        let Ok(Command::Gen(options)) = parse_command(args(&["gen"])) else {
+            panic!("Expected gen");
+        };
+        assert_eq!(options.spec.rows, DEFAULT_ROWS);
+        assert_eq!(options.spec.clients, DEFAULT_CLIENTS);
+        assert_eq!(options.output, Sink::Stdout);
+
+        let Ok(Command::Gen(options)) = parse_command(args(&[
+            "gen",
+            "--rows",
+            "500",
+            "--clients",
+            "7",
+            "--dispute-ratio",
+            "0.25",
+            "--seed",
+            "42",
+            "-o",
+            "w.csv",
+        ])) else {
+            panic!("Expected gen");
+        };
+        assert_eq!(
+            options.spec,
+            WorkloadSpec {
+                rows: 500,
+                clients: 7,
+                dispute_ratio: dec!(0.25),
+                seed: 42,
+            }
+        );
+        assert_eq!(options.output, Sink::File(PathBuf::from("w.csv")));
+
+        assert_eq!(
+            parse_command(args(&["gen", "--clients", "0"])),
+            Err("--clients must be at least 1".to_string())
+        );
+        assert_eq!(
+            parse_command(args(&["gen", "--dispute-ratio", "1.5"])),
+            Err("--dispute-ratio must be between 0 and 1".to_string())
+        );
+        assert!(parse_command(args(&["gen", "--check"])).is_err());
+
+        let options = parse_args(args(&["tx.csv", "--check"])).expect("Failed to parse");
+        assert!(options.config.check_invariants);
+    }
+
+    #[test]
+    fn test_parse_schema_command() {
+        assert_eq!(
+            parse_command(args(&["schema", "--format", "json", "-o", "schema.json"])),
+            Ok(Command::Schema(SchemaOptions {
+                format: SchemaFormat::Json,
+                output: Sink::File(PathBuf::from("schema.json")),
+            }))
+        );
+        let Ok(Command::Schema(options)) = parse_command(args(&["schema"])) else {
+            panic!("Expected schema");
+        };
+        assert_eq!(options.output, Sink::Stdout);
+        assert_eq!(
+            parse_command(args(&["schema", "--format", "avro"])),
+            Err("Invalid --format value: avro".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_record_repro() {
+        let options =
+            parse_args(args(&["tx.csv", "--record-repro", "bundle"])).expect("Failed to parse");
+        assert_eq!(options.record_repro, Some(PathBuf::from("bundle")));
+        assert!(options.config.check_invariants);
+        assert_eq!(
+            parse_args(args(&["-", "--record-repro", "bundle"])),
+            Err("--record-repro cannot be combined with stdin input".to_string())
+        );
+        assert_eq!(
+            parse_args(args(&["tx.csv", "--record-repro", "b", "--plugins", "p"])),
+            Err("--record-repro cannot be combined with --plugins".to_string())
+        );
+
+        assert_eq!(
+            parse_command(args(&["repro", "run", "bundle"])),
+            Ok(Command::Repro(ReproOptions {
+                dir: PathBuf::from("bundle"),
+            }))
+        );
+        assert_eq!(
+            parse_command(args(&["repro", "show", "bundle"])),
+            Err("Unknown repro command: show (expected run)".to_string())
+        );
+        assert!(parse_command(args(&["repro", "run"])).is_err());
+        assert!(parse_command(args(&["repro", "run", "a", "b"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_withdrawal_block() {
+        use rust_decimal_macros::dec;
+
+        let options = parse_args(args(&[
+            "tx.csv",
+            "--block-withdrawals-held",
+            "500.5",
+            "--block-withdrawals-disputes",
+            "2",
+        ]))
+        .expect("Failed to parse");
+        assert_eq!(options.config.withdrawal_block.max_held, Some(dec!(500.5)));
+        assert_eq!(options.config.withdrawal_block.max_open_disputes, Some(2));
+        assert!(parse_args(args(&["tx.csv", "--block-withdrawals-disputes", "-1"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_allow_withdrawal_disputes() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert!(!options.config.allow_withdrawal_disputes);
+
+        let options =
+            parse_args(args(&["tx.csv", "--allow-withdrawal-disputes"])).expect("Failed to parse");
+        assert!(options.config.allow_withdrawal_disputes);
+    }
+
+    #[test]
+    fn test_parse_deposit_hold() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.config.deposit_hold, None);
+
+        let options =
+            parse_args(args(&["tx.csv", "--deposit-hold", "100"])).expect("Failed to parse");
+        assert_eq!(options.config.deposit_hold, Some(HoldPeriod::Records(100)));
+        let options =
+            parse_args(args(&["tx.csv", "--deposit-hold", "30s"])).expect("Failed to parse");
+        assert_eq!(
+            options.config.deposit_hold,
+            Some(HoldPeriod::Time(Duration::from_secs(30)))
+        );
+
+        assert!(parse_args(args(&["tx.csv", "--deposit-hold", "soon"])).is_err());
+        assert!(parse_args(args(&["tx.csv", "--deposit-hold", "5", "--threads", "2"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_tier_rules() {
+        use rust_decimal_macros::dec;
+
+        let options = parse_args(args(&[
+            "tx.csv",
+            "--tier-rules",
+            "test_data/tier_rules.csv",
+            "--tiers",
+            "test_data/client_tiers.csv",
+        ]))
+        .expect("Failed to parse");
+        let rules = &options.config.withdrawal_rules;
+        assert_eq!(
+            rules.rule_for(1).and_then(|r| r.min_balance),
+            Some(dec!(10))
+        );
+        assert_eq!(rules.tiers.get(&2).map(String::as_str), Some("standard"));
+
+        assert!(parse_args(args(&["tx.csv", "--tier-rules", "missing.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_state() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!((options.load_state, options.save_state), (None, None));
+
+        let options = parse_args(args(&[
+            "new.csv",
+            "--load-state",
+            "day1.state",
+            "--save-state",
+            "day2.state",
+        ]))
+        .expect("Failed to parse");
+        assert_eq!(options.load_state, Some(PathBuf::from("day1.state")));
+        assert_eq!(options.save_state, Some(PathBuf::from("day2.state")));
+
+        assert!(parse_args(args(&["tx.csv", "--load-state", "s", "--threads", "2"])).is_err());
+        assert!(parse_args(args(&["tx.csv", "--load-state", "s", "--store", "disk:x"])).is_err());
+        assert!(parse_args(args(&["tx.csv", "--save-state", "s", "--threads", "2"])).is_ok());
+    }
+
+    #[cfg(feature = "serve")]
+    #[test]
+    fn test_parse_serve() {
+        let Command::Serve(options) = parse_command(args(&["serve"])).expect("Failed to parse")
+        else {
+            panic!("Expected serve command");
+        };
+        assert_eq!(options.http.as_deref(), Some(DEFAULT_HTTP_ADDR));
+        assert_eq!(options.tcp, None);
+        assert_eq!(options.ledger, None);
+
+        let Command::Serve(options) = parse_command(args(&[
+            "serve",
+            "--tcp",
+            "0.0.0.0:9000",
+            "--seq-window",
+            "3",
+        ]))
+        .expect("Failed to parse") else {
+            panic!("Expected serve command");
+        };
+        assert_eq!(options.http, None);
+        assert_eq!(options.tcp.as_deref(), Some("0.0.0.0:9000"));
+        assert_eq!(options.config.seq_window, 3);
+        assert_eq!(options.shed, ShedPolicy::default());
+
+        let Command::Serve(options) = parse_command(args(&[
+            "serve",
+            "--max-queue",
+            "64",
+            "--max-read-queue",
+            "8",
+            "--ledger",
+            "ledger.jsonl",
+        ]))
+        .expect("Failed to parse") else {
+            panic!("Expected serve command");
+        };
+        assert_eq!(
+            options.shed,
+            ShedPolicy {
+                max_queue: Some(64),
+                max_read_queue: Some(8),
+            }
+        );
+        assert_eq!(options.ledger, Some(PathBuf::from("ledger.jsonl")));
+        assert!(parse_command(args(&["serve", "--max-queue", "0"])).is_err());
+
+        assert!(parse_command(args(&["serve", "tx.csv"])).is_err());
+
+        let Command::Serve(options) =
+            parse_command(args(&["serve", "--wasm", "a.wasm"])).expect("Failed to parse")
+        else {
+            panic!("Expected serve command");
+        };
+        assert_eq!(options.wasm.modules, [PathBuf::from("a.wasm")]);
+    }
+
+    #[test]
+    fn test_parse_consume() {
+        let Command::Consume(options) = parse_command(args(&[
+            "consume",
+            "--brokers",
+            "localhost:9092",
+            "--topic",
+            "transactions",
+            "--save-state",
+            "state.bin",
+            "--input-format",
+            "ndjson",
+        ]))
+        .expect("Failed to parse") else {
+            panic!("Expected consume command");
+        };
+        assert_eq!(options.brokers, "localhost:9092");
+        assert_eq!(options.topic, "transactions");
+        assert_eq!(options.group, DEFAULT_GROUP);
+        assert_eq!(options.save_state, PathBuf::from("state.bin"));
+        assert_eq!(options.snapshot_every, DEFAULT_SNAPSHOT_EVERY);
+        assert_eq!(options.outputs, vec![Sink::Stdout]);
+        assert_eq!(options.config.input_format, InputFormat::Ndjson);
+        assert!(options.config.dedup_events);
+
+        let Command::Consume(options) = parse_command(args(&[
+            "consume",
+            "--brokers",
+            "b:9092",
+            "--topic",
+            "t",
+            "--group",
+            "g",
+            "--save-state",
+            "s.bin",
+            "--load-state",
+            "s.bin",
+            "--snapshot-every",
+            "100",
+            "--max-messages",
+            "5",
+            "--idle-exit",
+            "10",
+        ]))
+        .expect("Failed to parse") else {
+            panic!("Expected consume command");
+        };
+        assert_eq!(options.group, "g");
+        assert_eq!(options.load_state, Some(PathBuf::from("s.bin")));
+        assert_eq!(options.snapshot_every, 100);
+        assert_eq!(options.max_messages, Some(5));
+        assert_eq!(options.idle_exit, Some(10));
+
+        assert_eq!(
+            parse_command(args(&["consume", "--brokers", "b", "--topic", "t"])),
+            Err("consume needs --save-state".to_string())
+        );
+        assert!(parse_command(args(&["consume", "--topic", "t", "--save-state", "s"])).is_err());
+        assert!(parse_command(args(&[
+            "consume",
+            "--brokers",
+            "b",
+            "--topic",
+            "t",
+            "--save-state",
+            "s",
+            "--snapshot-every",
+            "0"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_recovery_sweep() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert!(!options.config.recovery_sweep);
+
+        let options = parse_args(args(&[
+            "tx.csv",
+            "--recovery-sweep",
+            "--recoveries",
+            "recovered.csv",
+        ]))
+        .expect("Failed to parse");
+        assert!(options.config.recovery_sweep);
+        assert_eq!(options.recoveries, Some(PathBuf::from("recovered.csv")));
+
+        assert!(parse_args(args(&["tx.csv", "--recoveries", "recovered.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_virtual_time() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert!(!options.config.virtual_time);
+
+        let options = parse_args(args(&["tx.csv", "--virtual-time-from-timestamps"]))
+            .expect("Failed to parse");
+        assert!(options.config.virtual_time);
+    }
+
+    #[test]
+    fn test_parse_plugins() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.plugins, None);
+
+        let options =
+            parse_args(args(&["tx.csv", "--plugins", "plugins/"])).expect("Failed to parse");
+        assert_eq!(options.plugins, Some(PathBuf::from("plugins/")));
+
+        assert!(parse_args(args(&["tx.csv", "--plugins", "plugins/", "--threads", "2"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_dormancy() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.dormant_after, None);
+
+        let options = parse_args(args(&[
+            "tx.csv",
+            "--dormant-after",
+            "1000",
+            "--dormant",
+            "dormant.csv",
+        ]))
+        .expect("Failed to parse");
+        assert_eq!(
+            options.dormant_after,
+            Some(DormancyThreshold::Records(1000))
+        );
+        assert_eq!(options.dormant, Some(PathBuf::from("dormant.csv")));
+
+        let options =
+            parse_args(args(&["tx.csv", "--dormant-after", "90d"])).expect("Failed to parse");
+        assert_eq!(options.dormant_after, Some(DormancyThreshold::Days(90)));
+
+        assert!(parse_args(args(&["tx.csv", "--dormant", "dormant.csv"])).is_err());
+        assert!(parse_args(args(&["tx.csv", "--dormant-after", "soon"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_summary() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.summary, None);
+        assert_eq!(options.summary_top, DEFAULT_TOP_CLIENTS);
+
+        let options = parse_args(args(&["tx.csv", "--summary", "-", "--summary-top", "3"]))
+            .expect("Failed to parse");
+        assert_eq!(options.summary, Some(Sink::Stdout));
+        assert_eq!(options.summary_top, 3);
+
+        assert!(parse_args(args(&["tx.csv", "--summary-top", "3"])).is_err());
+        assert!(parse_args(args(&["tx.csv", "--summary", "-", "--summary-top", "x"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_shadow() {
+        use rust_decimal_macros::dec;
+
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.shadow, None);
+
+        let options = parse_args(args(&[
+            "tx.csv",
+            "--shadow",
+            "--max-amount 100  --allow-withdrawal-disputes",
+            "--shadow-log",
+            "shadow.csv",
+            "--seq-window",
+            "4",
+        ]))
+        .expect("Failed to parse");
+        let shadow = options.shadow.expect("Expected a shadow config");
+        assert_eq!(shadow.max_amount, Some(dec!(100)));
+        assert!(shadow.allow_withdrawal_disputes);
+        // Primary settings carry over, also when given after --shadow
+        assert_eq!(shadow.seq_window, 4);
+        assert!(!options.config.allow_withdrawal_disputes);
+        assert_eq!(options.shadow_log, Some(PathBuf::from("shadow.csv")));
+
+        let options = parse_args(args(&["tx.csv", "--shadow", ""])).expect("Failed to parse");
+        assert_eq!(options.shadow, Some(options.config));
+
+        assert_eq!(
+            parse_args(args(&["tx.csv", "--shadow", "--threads 2"])),
+            Err("--shadow takes engine options, not --threads".to_string())
+        );
+        assert!(parse_args(args(&["tx.csv", "--shadow", "--max-amount"])).is_err());
+        assert!(parse_args(args(&["tx.csv", "--shadow-log", "s.csv"])).is_err());
+        assert!(parse_args(args(&["tx.csv", "--shadow", "", "--threads", "2"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_as_of() {
+        let options = parse_args(args(&["tx.csv", "--as-of", "2024-01-31T23:59:59+01:00"]))
+            .expect("Failed to parse");
+        assert_eq!(
+            options.config.as_of,
+            Some("2024-01-31T22:59:59Z".parse().unwrap())
+        );
+
+        assert_eq!(
+            parse_args(args(&["tx.csv", "--as-of", "2024-01-31"])),
+            Err(
+                "Invalid timestamp: 2024-01-31 (expected RFC 3339, like 2024-01-31T23:59:59Z)"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            parse_args(args(&[
+                "tx.csv",
+                "--as-of",
+                "2024-01-31T23:59:59Z",
+                "--refeed",
+                "dead.csv"
+            ])),
+            Err("--as-of cannot be combined with --refeed".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_proof() {
+        let options = parse_args(args(&[
+            "tx.csv",
+            "--proof",
+            "proof.json",
+            "--proof-key",
+            "proof.key",
+            "--threads",
+            "2",
+        ]))
+        .expect("Failed to parse");
+        assert_eq!(options.proof, Some(PathBuf::from("proof.json")));
+        assert_eq!(options.proof_key, Some(PathBuf::from("proof.key")));
+
+        assert!(parse_args(args(&["tx.csv", "--proof-key", "proof.key"])).is_err());
+        assert!(parse_args(args(&[
+            "tx.csv",
+            "--proof",
+            "proof.json",
+            "--what-if",
+            "chargeback-all-open",
+            "--what-if-output",
+            "what_if.csv"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_audit_log() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.audit, None);
+
+        let options =
+            parse_args(args(&["tx.csv", "--audit", "events.jsonl"])).expect("Failed to parse");
+        assert_eq!(options.audit, Some(PathBuf::from("events.jsonl")));
+
+        assert!(parse_args(args(&[
+            "tx.csv",
+            "--audit",
+            "events.jsonl",
+            "--threads",
+            "4"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_wasm() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.wasm, WasmOptions::default());
+
+        let options = parse_args(args(&[
+            "tx.csv",
+            "--wasm",
+            "a.wasm",
+            "--wasm",
+            "b.wat",
+            "--wasm-fuel",
+            "5000",
+            "--wasm-memory",
+            "65536",
+        ]))
+        .expect("Failed to parse");
+        assert_eq!(
+            options.wasm.modules,
+            [PathBuf::from("a.wasm"), PathBuf::from("b.wat")]
+        );
+        assert_eq!(options.wasm.fuel, Some(5000));
+        assert_eq!(options.wasm.memory, Some(65536));
+
+        assert!(parse_args(args(&["tx.csv", "--wasm-fuel", "10"])).is_err());
+        assert!(parse_args(args(&["tx.csv", "--wasm", "a.wasm", "--wasm-fuel", "0"])).is_err());
+        assert!(parse_args(args(&["tx.csv", "--wasm", "a.wasm", "--threads", "2"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_strict() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.config.validation, ValidationConfig::lenient());
+
+        let options = parse_args(args(&["tx.csv", "--strict"])).expect("Failed to parse");
+        assert_eq!(options.config.validation, ValidationConfig::strict());
+
+        let fail_fast = ValidationConfig {
+            abort_on_violation: true,
+            ..ValidationConfig::strict()
+        };
+        let options = parse_args(args(&["tx.csv", "--fail-fast"])).expect("Failed to parse");
+        assert_eq!(options.config.validation, fail_fast);
+        let options =
+            parse_args(args(&["tx.csv", "--fail-fast", "--strict"])).expect("Failed to parse");
+        assert_eq!(options.config.validation, fail_fast);
+    }
+
+    #[test]
+    fn test_parse_audit() {
+        let command =
+            parse_command(args(&["audit", "tx.csv", "accounts.csv"])).expect("Failed to parse");
+        let Command::Audit(options) = command else {
+            panic!("Expected audit command");
+        };
+        assert_eq!(options.input, "tx.csv");
+        assert_eq!(options.accounts, PathBuf::from("accounts.csv"));
+
+        assert!(parse_command(args(&["audit", "tx.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_reconcile() {
+        let command = parse_command(args(&["reconcile", "tx.csv", "--external", "bank.csv"]))
+            .expect("Failed to parse");
+        let Command::Reconcile(options) = command else {
+            panic!("Expected reconcile command");
+        };
+        assert_eq!(options.input, "tx.csv");
+        assert_eq!(options.external, PathBuf::from("bank.csv"));
+        assert_eq!(options.output, Sink::Stdout);
+
+        assert!(parse_command(args(&["reconcile", "tx.csv"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_schema() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.schema, Schema::V1);
+
+        let options = parse_args(args(&["tx.csv", "--schema", "v2"])).expect("Failed to parse");
+        assert_eq!(options.schema, Schema::V2);
+    }
+
+    #[test]
+    fn test_parse_output_format() {
+        let options = parse_args(args(&["tx.csv"])).expect("Failed to parse");
+        assert_eq!(options.output_format, OutputFormat::Csv);
+
+        let options =
+            parse_args(args(&["tx.csv", "--output-format", "json"])).expect("Failed to parse");
+        assert_eq!(options.output_format, OutputFormat::Json);
+
+        assert!(parse_args(args(&["tx.csv", "--output-format", "yaml"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_args(args(&[])).is_err());
+        assert!(parse_args(args(&["tx.csv", "--output"])).is_err());
+        assert!(parse_args(args(&["tx.csv", "--bogus"])).is_err());
+        for max in ["0", "0.0", "-5"] {
+            assert_eq!(
+                parse_args(args(&["tx.csv", "--max-amount", max])).unwrap_err(),
+                format!("Invalid --max-amount value: {}", max)
+            );
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Time source for time-based policies
+//!
+//! Policies that go by time, such as duration deposit holds, ask the engine's
+//! `Clock` rather than the system, so tests and embedders can control time.
+//! `SystemClock` is the default; `TestClock` only moves when told to;
+//! `VirtualClock` follows the record timestamps, for replays of historical files.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Source of the current time
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// Wall-clock time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Deterministic clock for tests
+/// Clones share the time, so a test can keep one to move the time of an engine
+/// it gave another to
+#[derive(Debug, Clone)]
+pub struct TestClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl TestClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    /// Move the time forward by `by`
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().expect("test clock poisoned") += by;
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().expect("test clock poisoned") = now;
+    }
+}
+
+impl Default for TestClock {
+    /// Starting at the Unix epoch
+    fn default() -> Self {
+        Self::new(SystemTime::UNIX_EPOCH)
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().expect("test clock poisoned")
+    }
+}
+
+/// Time as of the records: the latest record timestamp seen so far
+/// It never goes back, so a record stamped earlier than one before it is
+/// evaluated at the later time; the Unix epoch before the first timestamp
+#[derive(Debug, Clone, Default)]
+pub struct VirtualClock {
+    now: TestClock,
+}
+
+impl VirtualClock {
+    /// Move the time to `at`, unless it is already later
+    pub fn observe(&self, at: SystemTime) {
+        if at > self.now.now() {
+            self.now.set(at);
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        self.now.now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_test_clock() {
+        let clock = TestClock::default();
+        let shared = clock.clone();
+        assert_eq!(clock.now(), SystemTime::UNIX_EPOCH);
+
+        shared.advance(Duration::from_secs(90));
+        assert_eq!(
+            clock.now(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(90)
+        );
+
+        let later = SystemTime::UNIX_EPOCH + Duration::from_secs(86_400);
+        clock.set(later);
+        assert_eq!(shared.now(), later);
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock::default();
+        assert_eq!(clock.now(), SystemTime::UNIX_EPOCH);
+
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(86_400);
+        clock.observe(at);
+        assert_eq!(clock.now(), at);
+        clock.observe(at - Duration::from_secs(1));
+        assert_eq!(clock.clone().now(), at);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::types::{serialize_decimal_str, ClientId, TransactionId};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+
+/// What became of the balance of a closed account
+///
+/// A `close` record zeroes the account: the available balance is paid out as a
+/// final withdrawal, tx id of the close record, unless it is no more than
+/// `EngineConfig::close_dust` either way, in which case it is written off.
+/// Accounts with held funds or a larger debt cannot be closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Closure {
+    /// Position of the close record
+    pub position: u64,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// Amount to pay out to the client
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub payout: Decimal,
+    /// Dust dropped from the books, negative for a forgiven debt
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub written_off: Decimal,
+}
+
+impl Closure {
+    /// Disposition of `available` when closing the account under the `dust` limit
+    pub fn new(
+        position: u64,
+        client: ClientId,
+        tx: TransactionId,
+        available: Decimal,
+        dust: Decimal,
+    ) -> Self {
+        let (payout, written_off) = if available.abs() <= dust {
+            (Decimal::ZERO, available)
+        } else {
+            (available, Decimal::ZERO)
+        };
+        Self {
+            position,
+            client,
+            tx,
+            payout,
+            written_off,
+        }
+    }
+}
+
+impl fmt::Display for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record {}: account of client {} closed by tx {}, {} paid out, {} written off",
+            self.position, self.client, self.tx, self.payout, self.written_off
+        )
+    }
+}
+
+/// Write closures as CSV, in the order they happened
+pub fn write_closures<W: Write>(closures: &[Closure], writer: W) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for closure in closures {
+        writer.serialize(closure)?;
+    }
+    if closures.is_empty() {
+        writer.write_record(["position", "client", "tx", "payout", "written_off"])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_disposition() {
+        let paid = Closure::new(7, 1, 9, dec!(25.5), dec!(0.01));
+        assert_eq!((paid.payout, paid.written_off), (dec!(25.5), dec!(0)));
+
+        let dust = Closure::new(7, 1, 9, dec!(0.01), dec!(0.01));
+        assert_eq!((dust.payout, dust.written_off), (dec!(0), dec!(0.01)));
+
+        let debt = Closure::new(7, 1, 9, dec!(-0.005), dec!(0.01));
+        assert_eq!((debt.payout, debt.written_off), (dec!(0), dec!(-0.005)));
+
+        let empty = Closure::new(7, 1, 9, dec!(0), dec!(0));
+        assert_eq!((empty.payout, empty.written_off), (dec!(0), dec!(0)));
+    }
+
+    #[test]
+    fn test_write_closures() {
+        let mut out = Vec::new();
+        write_closures(&[Closure::new(3, 2, 8, dec!(10.25), dec!(0))], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "position,client,tx,payout,written_off\n3,2,8,10.25,0\n"
+        );
+
+        let mut out = Vec::new();
+        write_closures(&[], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "position,client,tx,payout,written_off\n"
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use crate::dedup::DedupPolicy;
+use crate::hold::HoldPeriod;
+use crate::input::InputFormat;
+use crate::mismatch::MismatchPolicy;
+use crate::rules::TierRules;
+use crate::sequence::DEFAULT_REORDER_WINDOW;
+use crate::timestamp::Timestamp;
+use crate::types::{Account, ClientId, TransactionId, TransactionType};
+use crate::validation::ValidationConfig;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::fmt;
+
+/// Default upper bound for a plausible deposit/withdrawal amount (10^12)
+pub const DEFAULT_MAX_AMOUNT: Decimal = dec!(1_000_000_000_000);
+
+/// Settings controlling how the engine applies transactions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EngineConfig {
+    /// Format of the input files
+    pub input_format: InputFormat,
+    /// Out-of-order records buffered per client when a `seq` column is present
+    pub seq_window: usize,
+    /// Read only records stamped at or before this instant, every record needs a timestamp
+    pub as_of: Option<Timestamp>,
+    /// Amounts above this are treated as malformed, `None` disables the check
+    /// Positive; the CLI refuses a bound that would reject every amount
+    pub max_amount: Option<Decimal>,
+    /// Handling of duplicated dispute/resolve/chargeback lines
+    pub ref_dedup: DedupPolicy,
+    /// Handling of references whose client differs from the deposit's
+    pub client_mismatch: MismatchPolicy,
+    /// Risk limits on open disputes above which withdrawals are refused
+    pub withdrawal_block: WithdrawalBlock,
+    /// Store withdrawals so they can be disputed too (the spec only disputes deposits)
+    pub allow_withdrawal_disputes: bool,
+    /// How long deposits stay held before they become available, `None` credits them at once
+    pub deposit_hold: Option<HoldPeriod>,
+    /// Minimum balance and increment rules for withdrawals, by client tier
+    pub withdrawal_rules: TierRules,
+    /// Count deposits to a negative available balance against the deficit and report them
+    pub recovery_sweep: bool,
+    /// Extra input checks and which rejections fail the run
+    pub validation: ValidationConfig,
+    /// Balances up to this much either way are written off on close instead of
+    /// paid out; a larger debt blocks the close
+    pub close_dust: Decimal,
+    /// What locked accounts still accept, and whether they can be unlocked
+    pub account_policy: AccountPolicy,
+    /// Call event sinks once per event id, also across runs resumed from a snapshot
+    pub dedup_events: bool,
+    /// Check account invariants after every applied record, failing on the first broken
+    pub check_invariants: bool,
+    /// Evaluate time rules as of the record timestamps instead of the wall clock
+    pub virtual_time: bool,
+}
+
+/// Open dispute limits beyond which a client may not withdraw
+/// Both `None` (the default) means withdrawals are only stopped by a lock
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WithdrawalBlock {
+    /// Block when funds held by open disputes exceed this amount
+    pub max_held: Option<Decimal>,
+    /// Block when the number of open disputes exceeds this count
+    pub max_open_disputes: Option<u32>,
+}
+
+impl WithdrawalBlock {
+    /// Check whether the account's open disputes exceed either limit
+    pub fn blocks(&self, account: &Account) -> bool {
+        self.max_held.is_some_and(|max| account.held > max)
+            || self
+                .max_open_disputes
+                .is_some_and(|max| account.open_disputes > max)
+    }
+}
+
+/// What a locked account still accepts, and whether it can be unlocked
+/// The default follows the spec: a locked account rejects every record for good
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountPolicy {
+    /// Record types a locked account still accepts, e.g. resolves of other open disputes
+    pub locked_accepts: Vec<TransactionType>,
+    /// Accept `unlock` records, which unfreeze an account locked by a chargeback
+    pub allow_unlock: bool,
+}
+
+impl AccountPolicy {
+    /// Whether a locked account accepts a record of type `tx_type`
+    pub fn accepts_when_locked(&self, tx_type: TransactionType) -> bool {
+        self.locked_accepts.contains(&tx_type)
+    }
+
+    /// Parse a comma separated list of record types, or `none`
+    pub fn parse_locked_accepts(list: &str) -> Result<Vec<TransactionType>, String> {
+        if list == "none" {
+            return Ok(Vec::new());
+        }
+        list.split(',')
+            .map(|name| match name.trim().parse()? {
+                TransactionType::Unlock => {
+                    Err("unlock records are allowed with --allow-unlock".to_string())
+                }
+                tx_type => Ok(tx_type),
+            })
+            .collect()
+    }
+}
+
+/// A withdrawal refused because of the client's open disputes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockedWithdrawal {
+    /// Position of the record in application order
+    pub position: u64,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub amount: Decimal,
+    /// Funds held by open disputes at the time
+    pub held: Decimal,
+    pub open_disputes: u32,
+}
+
+impl fmt::Display for BlockedWithdrawal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record {}: withdrawal tx {} of {} for client {} blocked by open disputes ({} open, {} held)",
+            self.position, self.tx, self.amount, self.client, self.open_disputes, self.held
+        )
+    }
+}
+
+impl EngineConfig {
+    /// Check an amount against the configured plausibility bound
+    /// Amounts beyond Decimal's 28 digits never get here: they fail to parse
+    pub fn is_plausible_amount(&self, amount: Decimal) -> bool {
+        match self.max_amount {
+            Some(max) => amount.abs() <= max,
+            None => true,
+        }
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            input_format: InputFormat::default(),
+            seq_window: DEFAULT_REORDER_WINDOW,
+            as_of: None,
+            max_amount: Some(DEFAULT_MAX_AMOUNT),
+            ref_dedup: DedupPolicy::default(),
+            client_mismatch: MismatchPolicy::default(),
+            withdrawal_block: WithdrawalBlock::default(),
+            allow_withdrawal_disputes: false,
+            deposit_hold: None,
+            withdrawal_rules: TierRules::default(),
+            recovery_sweep: false,
+            validation: ValidationConfig::default(),
+            close_dust: Decimal::ZERO,
+            account_policy: AccountPolicy::default(),
+            dedup_events: false,
+            check_invariants: false,
+            virtual_time: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plausible_amount() {
+        let config = EngineConfig::default();
+        assert!(config.is_plausible_amount(dec!(1_000_000_000_000)));
+        assert!(config.is_plausible_amount(dec!(0.0001)));
+        assert!(!config.is_plausible_amount(dec!(1_000_000_000_000.0001)));
+        assert!(!config.is_plausible_amount(dec!(99999999999999999999)));
+
+        let unbounded = EngineConfig {
+            max_amount: None,
+            ..EngineConfig::default()
+        };
+        assert!(unbounded.is_plausible_amount(dec!(99999999999999999999)));
+    }
+
+    #[test]
+    fn test_withdrawal_block() {
+        let mut account = Account::new(1);
+        account.deposit(dec!(100));
+        account.hold_funds(dec!(30));
+        account.hold_funds(dec!(20));
+
+        assert!(!WithdrawalBlock::default().blocks(&account));
+
+        let by_amount = WithdrawalBlock {
+            max_held: Some(dec!(50)),
+            max_open_disputes: None,
+        };
+        assert!(!by_amount.blocks(&account));
+        account.hold_funds(dec!(0.0001));
+        assert!(by_amount.blocks(&account));
+
+        let by_count = WithdrawalBlock {
+            max_held: None,
+            max_open_disputes: Some(2),
+        };
+        assert!(by_count.blocks(&account));
+        account.release_funds(dec!(20));
+        assert!(!by_count.blocks(&account));
+    }
+
+    #[test]
+    fn test_locked_accepts() {
+        assert_eq!(AccountPolicy::parse_locked_accepts("none"), Ok(Vec::new()));
+        let accepts = AccountPolicy::parse_locked_accepts("deposit, resolve,chargeback").unwrap();
+        assert_eq!(
+            accepts,
+            [
+                TransactionType::Deposit,
+                TransactionType::Resolve,
+                TransactionType::Chargeback
+            ]
+        );
+        assert!(AccountPolicy::parse_locked_accepts("deposit,refund").is_err());
+        assert!(AccountPolicy::parse_locked_accepts("unlock").is_err());
+
+        let policy = AccountPolicy {
+            locked_accepts: accepts,
+            allow_unlock: false,
+        };
+        assert!(policy.accepts_when_locked(TransactionType::Resolve));
+        assert!(!policy.accepts_when_locked(TransactionType::Withdrawal));
+        assert!(!AccountPolicy::default().accepts_when_locked(TransactionType::Deposit));
+    }
+}
@@ -1,5 +1,5 @@
 use crate::types::TransactionRecord;
-use csv::{ReaderBuilder, Trim};
+use csv::{ReaderBuilder, StringRecord, Trim};
 use std::fs::File;
 use std::io::{self, BufReader};
 use std::path::Path;
@@ -33,9 +33,12 @@ impl<R: io::Read> TransactionReader<R> {
 
     /// Get an iterator over transaction records
     /// Streams records one at a time for memory efficiency
-    pub fn records(self) -> TransactionRecordIterator<R> {
+    pub fn records(mut self) -> TransactionRecordIterator<R> {
+        // Missing/unreadable headers surface as errors on the first record
+        let headers = self.reader.headers().ok().cloned();
         TransactionRecordIterator {
-            inner: self.reader.into_deserialize(),
+            inner: self.reader.into_records(),
+            headers,
         }
     }
 }
@@ -43,17 +46,48 @@ impl<R: io::Read> TransactionReader<R> {
 /// Iterator over transaction records
 /// Yields Result<TransactionRecord, csv::Error> for error handling
 pub struct TransactionRecordIterator<R: io::Read> {
-    inner: csv::DeserializeRecordsIntoIter<R, TransactionRecord>,
+    inner: csv::StringRecordsIntoIter<R>,
+    headers: Option<StringRecord>,
 }
 
 impl<R: io::Read> Iterator for TransactionRecordIterator<R> {
     type Item = Result<TransactionRecord, csv::Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next()
+        loop {
+            let record = match self.inner.next()? {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e)),
+            };
+
+            // Skip whitespace-only lines (all fields empty after trimming)
+            if record.iter().all(|field| field.is_empty()) {
+                continue;
+            }
+
+            let line = record.position().map(|p| p.line());
+            return Some(
+                record
+                    .deserialize::<TransactionRecord>(self.headers.as_ref())
+                    .map(|parsed| TransactionRecord { line, ..parsed }),
+            );
+        }
     }
 }
 
+/// Split one CSV line into trimmed fields, as `TransactionReader` does
+/// For sources that deliver records a line at a time
+pub fn parse_line(text: &str) -> csv::Result<StringRecord> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let mut record = StringRecord::new();
+    reader.read_record(&mut record)?;
+    Ok(record)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -223,6 +257,59 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_parse_optional_seq_column() {
+        let data = "\
+            type,client,tx,amount,seq
+            deposit,1,1,1.0,7
+            dispute,1,1,,
+        ";
+        let reader = TransactionReader::from_reader(data.as_bytes());
+        let records: Result<Vec<_>, _> = reader.records().collect();
+        let records = records.expect("Failed to parse CSV");
+
+        assert_eq!(records[0].seq, Some(7));
+        assert_eq!(records[1].seq, None);
+
+        // Files without the column still parse
+        let data = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let reader = TransactionReader::from_reader(data.as_bytes());
+        let records: Vec<_> = reader.records().collect::<Result<_, _>>().unwrap();
+        assert_eq!(records[0].seq, None);
+    }
+
+    #[test]
+    fn test_parse_optional_timestamp_column() {
+        let data = "\
+            type,client,tx,amount,timestamp
+            deposit,1,1,1.0,2024-01-31T23:59:59Z
+            dispute,1,1,,
+            deposit,1,2,1.0,yesterday
+        ";
+        let reader = TransactionReader::from_reader(data.as_bytes());
+        let records: Vec<_> = reader.records().collect();
+
+        assert_eq!(
+            records[0].as_ref().unwrap().timestamp,
+            Some("2024-01-31T23:59:59Z".parse().unwrap())
+        );
+        assert_eq!(records[1].as_ref().unwrap().timestamp, None);
+        assert!(records[2].is_err());
+    }
+
+    #[test]
+    fn test_line_numbers() {
+        let data = "type,client,tx,amount\ndeposit,1,1,1.0\nbogus,1,2,1.0\n , , ,\ndispute,1,1,\n";
+        let records: Vec<_> = TransactionReader::from_reader(data.as_bytes())
+            .records()
+            .collect();
+
+        assert_eq!(records[0].as_ref().unwrap().line, Some(2));
+        let error = records[1].as_ref().expect_err("Bogus type parsed");
+        assert_eq!(error.position().map(|p| p.line()), Some(3));
+        assert_eq!(records[2].as_ref().unwrap().line, Some(5));
+    }
+
     #[test]
     fn test_empty_csv() {
         let data = "type,client,tx,amount\n";
@@ -241,7 +328,7 @@ mod tests {
         let records: Result<Vec<_>, _> = reader.records().collect();
         let records = records.expect("Failed to parse CSV");
 
-        assert!(records.len() > 0);
+        assert!(!records.is_empty());
         assert_eq!(records[0].tx_type, TransactionType::Deposit);
     }
 
@@ -0,0 +1,73 @@
+use crate::types::TransactionRecord;
+use std::error::Error;
+use std::io::Write;
+
+/// Write reference records whose tx was unknown, in the input CSV layout
+/// so the file can be re-fed with `--refeed` on a later run
+pub fn write_dead_letters<W: Write>(
+    records: &[TransactionRecord],
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["type", "client", "tx", "amount"])?;
+
+    for record in records {
+        let amount = record.amount.map(|a| a.to_string()).unwrap_or_default();
+        writer.write_record([
+            record.tx_type.as_str(),
+            &record.client.to_string(),
+            &record.tx.to_string(),
+            &amount,
+        ])?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_parser::TransactionReader;
+    use crate::types::TransactionType;
+
+    #[test]
+    fn test_dead_letters_round_trip() {
+        let records = vec![
+            TransactionRecord {
+                tx_type: TransactionType::Dispute,
+                client: 1,
+                tx: 9,
+                amount: None,
+                seq: None,
+                timestamp: None,
+                line: None,
+            },
+            TransactionRecord {
+                tx_type: TransactionType::Chargeback,
+                client: 2,
+                tx: 10,
+                amount: None,
+                seq: None,
+                timestamp: None,
+                line: None,
+            },
+        ];
+
+        let mut buf = Vec::new();
+        write_dead_letters(&records, &mut buf).expect("Failed to write");
+        assert_eq!(
+            String::from_utf8(buf.clone()).expect("Invalid UTF-8"),
+            "type,client,tx,amount\ndispute,1,9,\nchargeback,2,10,\n"
+        );
+
+        let parsed: Vec<_> = TransactionReader::from_reader(buf.as_slice())
+            .records()
+            .collect::<Result<_, _>>()
+            .expect("Failed to parse");
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1].tx_type, TransactionType::Chargeback);
+        assert_eq!(parsed[1].tx, 10);
+        assert_eq!(parsed[1].amount, None);
+    }
+}
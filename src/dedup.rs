@@ -0,0 +1,241 @@
+use crate::types::{ClientId, TransactionId, TransactionRecord, TransactionType};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// What to do with duplicated dispute/resolve/chargeback lines
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DedupPolicy {
+    /// No tracking, every line goes to the state machine (spec behavior)
+    #[default]
+    Off,
+    /// Detect and report duplicates but still process them
+    Report,
+    /// Detect, report and skip duplicates
+    Drop,
+    /// Abort the run on the first duplicate
+    Fail,
+}
+
+impl FromStr for DedupPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(DedupPolicy::Off),
+            "report" => Ok(DedupPolicy::Report),
+            "drop" => Ok(DedupPolicy::Drop),
+            "fail" => Ok(DedupPolicy::Fail),
+            _ => Err(format!("Unknown dedup policy: {}", s)),
+        }
+    }
+}
+
+/// A reference transaction line that repeated the previous one for its tx
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DuplicateReference {
+    /// Position of the duplicate record in application order
+    pub position: u64,
+    pub tx_type: TransactionType,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// True if the record was skipped
+    pub dropped: bool,
+}
+
+impl fmt::Display for DuplicateReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record {}: duplicate {} for client {} tx {} ({})",
+            self.position,
+            self.tx_type.as_str(),
+            self.client,
+            self.tx,
+            if self.dropped { "dropped" } else { "processed" }
+        )
+    }
+}
+
+/// Idempotency check for dispute/resolve/chargeback records
+///
+/// A reference record is a duplicate when it repeats the last reference
+/// (same type and client) applied to its tx, e.g. a retransmitted chargeback.
+/// A dispute after a resolve is a new dispute, not a duplicate, and neither is
+/// one repeating a reference that was rejected, e.g. for arriving before its
+/// deposit.
+#[derive(Debug)]
+pub struct ReferenceDeduplicator {
+    policy: DedupPolicy,
+    last_reference: HashMap<TransactionId, (TransactionType, ClientId)>,
+    duplicates: Vec<DuplicateReference>,
+}
+
+impl ReferenceDeduplicator {
+    pub fn new(policy: DedupPolicy) -> Self {
+        Self {
+            policy,
+            last_reference: HashMap::new(),
+            duplicates: Vec::new(),
+        }
+    }
+
+    /// Check a record, returning whether it should be processed
+    /// Fails with the duplicate under `DedupPolicy::Fail`
+    /// Only records passed to `applied` count as seen
+    pub fn check(
+        &mut self,
+        position: u64,
+        record: &TransactionRecord,
+    ) -> Result<bool, DuplicateReference> {
+        let is_reference = matches!(
+            record.tx_type,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        );
+        if self.policy == DedupPolicy::Off || !is_reference {
+            return Ok(true);
+        }
+
+        let key = (record.tx_type, record.client);
+        if self.last_reference.get(&record.tx) != Some(&key) {
+            return Ok(true);
+        }
+
+        let duplicate = DuplicateReference {
+            position,
+            tx_type: record.tx_type,
+            client: record.client,
+            tx: record.tx,
+            dropped: self.policy != DedupPolicy::Report,
+        };
+        self.duplicates.push(duplicate);
+
+        match self.policy {
+            DedupPolicy::Fail => Err(duplicate),
+            DedupPolicy::Drop => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    /// Remember a record that was checked and then applied
+    pub fn applied(&mut self, record: &TransactionRecord) {
+        let is_reference = matches!(
+            record.tx_type,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        );
+        if self.policy != DedupPolicy::Off && is_reference {
+            self.last_reference
+                .insert(record.tx, (record.tx_type, record.client));
+        }
+    }
+
+    /// Duplicates seen so far
+    pub fn into_duplicates(self) -> Vec<DuplicateReference> {
+        self.duplicates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(tx_type: TransactionType, client: ClientId, tx: TransactionId) -> TransactionRecord {
+        TransactionRecord {
+            tx_type,
+            client,
+            tx,
+            amount: None,
+            seq: None,
+            timestamp: None,
+            line: None,
+        }
+    }
+
+    #[test]
+    fn test_off_tracks_nothing() {
+        let mut dedup = ReferenceDeduplicator::new(DedupPolicy::Off);
+        let dispute = record(TransactionType::Dispute, 1, 1);
+        assert_eq!(dedup.check(1, &dispute), Ok(true));
+        dedup.applied(&dispute);
+        assert_eq!(dedup.check(2, &dispute), Ok(true));
+        assert!(dedup.into_duplicates().is_empty());
+    }
+
+    /// Check a record and apply it if it passes
+    fn apply(dedup: &mut ReferenceDeduplicator, position: u64, record: TransactionRecord) -> bool {
+        let passed = dedup.check(position, &record) == Ok(true);
+        if passed {
+            dedup.applied(&record);
+        }
+        passed
+    }
+
+    #[test]
+    fn test_drop_duplicates() {
+        let mut dedup = ReferenceDeduplicator::new(DedupPolicy::Drop);
+        assert!(apply(&mut dedup, 1, record(TransactionType::Dispute, 1, 1)));
+        assert!(!apply(
+            &mut dedup,
+            2,
+            record(TransactionType::Dispute, 1, 1)
+        ));
+
+        // Re-dispute after resolve is legitimate
+        assert!(apply(&mut dedup, 3, record(TransactionType::Resolve, 1, 1)));
+        assert!(apply(&mut dedup, 4, record(TransactionType::Dispute, 1, 1)));
+
+        // Same type from another client is not a duplicate
+        assert!(apply(&mut dedup, 5, record(TransactionType::Dispute, 2, 1)));
+
+        // Deposits are never deduplicated here
+        let deposit = record(TransactionType::Deposit, 1, 9);
+        assert!(apply(&mut dedup, 6, deposit));
+        assert!(apply(&mut dedup, 7, deposit));
+
+        let duplicates = dedup.into_duplicates();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].position, 2);
+        assert!(duplicates[0].dropped);
+        assert_eq!(
+            duplicates[0].to_string(),
+            "record 2: duplicate dispute for client 1 tx 1 (dropped)"
+        );
+    }
+
+    #[test]
+    fn test_report_keeps_processing() {
+        let mut dedup = ReferenceDeduplicator::new(DedupPolicy::Report);
+        let chargeback = record(TransactionType::Chargeback, 1, 1);
+        assert!(apply(&mut dedup, 1, chargeback));
+        assert!(apply(&mut dedup, 2, chargeback));
+
+        let duplicates = dedup.into_duplicates();
+        assert_eq!(duplicates.len(), 1);
+        assert!(!duplicates[0].dropped);
+    }
+
+    #[test]
+    fn test_fail_on_duplicate() {
+        let mut dedup = ReferenceDeduplicator::new(DedupPolicy::Fail);
+        let resolve = record(TransactionType::Resolve, 3, 7);
+        assert!(apply(&mut dedup, 1, resolve));
+        let err = dedup.check(2, &resolve).unwrap_err();
+        assert_eq!(err.tx, 7);
+    }
+
+    #[test]
+    fn test_rejected_reference_not_seen() {
+        let mut dedup = ReferenceDeduplicator::new(DedupPolicy::Drop);
+        let dispute = record(TransactionType::Dispute, 1, 5);
+        // Checked, then rejected for naming a tx not seen yet
+        assert_eq!(dedup.check(1, &dispute), Ok(true));
+        assert!(apply(&mut dedup, 3, dispute));
+        assert!(dedup.into_duplicates().is_empty());
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("drop".parse(), Ok(DedupPolicy::Drop));
+        assert!("sometimes".parse::<DedupPolicy>().is_err());
+    }
+}
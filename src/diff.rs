@@ -0,0 +1,172 @@
+use crate::types::{Account, ClientId};
+use rust_decimal::Decimal;
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::io::Write;
+
+/// Difference of one client's account between two states (runs or checkpoints)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountDelta {
+    pub client: ClientId,
+    /// State in the first run, `None` if the account did not exist
+    pub before: Option<Account>,
+    /// State in the second run, `None` if the account did not exist
+    pub after: Option<Account>,
+}
+
+impl AccountDelta {
+    /// Change of total funds from the first to the second run
+    pub fn total_delta(&self) -> Decimal {
+        self.delta(|a| a.total)
+    }
+
+    /// Change of available funds from the first to the second run
+    pub fn available_delta(&self) -> Decimal {
+        self.delta(|a| a.available)
+    }
+
+    /// Change of held funds from the first to the second run
+    pub fn held_delta(&self) -> Decimal {
+        self.delta(|a| a.held)
+    }
+
+    /// Whether the account got locked in between
+    pub fn newly_locked(&self) -> bool {
+        let locked = |account: &Option<Account>| account.as_ref().is_some_and(|a| a.locked);
+        locked(&self.after) && !locked(&self.before)
+    }
+
+    /// Missing accounts count as zero
+    fn delta(&self, field: impl Fn(&Account) -> Decimal) -> Decimal {
+        let value = |account: &Option<Account>| account.as_ref().map_or(Decimal::ZERO, &field);
+        value(&self.after) - value(&self.before)
+    }
+}
+
+/// Compare two sets of accounts, returning only clients that differ, ordered by client id
+pub fn diff_accounts(
+    before: &HashMap<ClientId, Account>,
+    after: &HashMap<ClientId, Account>,
+) -> Vec<AccountDelta> {
+    let clients: BTreeSet<_> = before.keys().chain(after.keys()).copied().collect();
+
+    clients
+        .into_iter()
+        .filter_map(|client| {
+            let before = before.get(&client);
+            let after = after.get(&client);
+            (before != after).then(|| AccountDelta {
+                client,
+                before: before.cloned(),
+                after: after.cloned(),
+            })
+        })
+        .collect()
+}
+
+/// Write deltas as CSV, one row per differing client
+pub fn write_deltas_csv<W: Write>(
+    deltas: &[AccountDelta],
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record([
+        "client",
+        "original_available",
+        "original_held",
+        "original_total",
+        "original_locked",
+        "replayed_available",
+        "replayed_held",
+        "replayed_total",
+        "replayed_locked",
+        "total_delta",
+    ])?;
+
+    let columns = |account: &Option<Account>| match account {
+        Some(a) => [
+            a.available.to_string(),
+            a.held.to_string(),
+            a.total.to_string(),
+            a.locked.to_string(),
+        ],
+        None => Default::default(),
+    };
+
+    for delta in deltas {
+        let mut row = vec![delta.client.to_string()];
+        row.extend(columns(&delta.before));
+        row.extend(columns(&delta.after));
+        row.push(delta.total_delta().to_string());
+        writer.write_record(&row)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn account(client: ClientId, amount: Decimal) -> Account {
+        let mut account = Account::new(client);
+        account.deposit(amount);
+        account
+    }
+
+    #[test]
+    fn test_diff_accounts() {
+        let before = HashMap::from([(1, account(1, dec!(10))), (2, account(2, dec!(5)))]);
+        let after = HashMap::from([
+            (1, account(1, dec!(10))),
+            (2, account(2, dec!(7))),
+            (3, account(3, dec!(1))),
+        ]);
+
+        let deltas = diff_accounts(&before, &after);
+
+        assert_eq!(deltas.len(), 2);
+        assert_eq!(deltas[0].client, 2);
+        assert_eq!(deltas[0].total_delta(), dec!(2));
+        assert_eq!(deltas[1].client, 3);
+        assert_eq!(deltas[1].before, None);
+        assert_eq!(deltas[1].total_delta(), dec!(1));
+        assert_eq!(deltas[1].available_delta(), dec!(1));
+        assert!(!deltas[1].newly_locked());
+    }
+
+    #[test]
+    fn test_field_deltas() {
+        let before = account(1, dec!(10));
+        let mut after = before.clone();
+        after.hold_funds(dec!(4));
+        after.chargeback(dec!(4));
+
+        let delta = AccountDelta {
+            client: 1,
+            before: Some(before),
+            after: Some(after),
+        };
+        assert_eq!(delta.total_delta(), dec!(-4));
+        assert_eq!(delta.available_delta(), dec!(-4));
+        assert_eq!(delta.held_delta(), dec!(0));
+        assert!(delta.newly_locked());
+    }
+
+    #[test]
+    fn test_write_deltas_csv() {
+        let deltas = vec![AccountDelta {
+            client: 4,
+            before: None,
+            after: Some(account(4, dec!(2.5))),
+        }];
+
+        let mut buf = Vec::new();
+        write_deltas_csv(&deltas, &mut buf).expect("Failed to write");
+
+        let text = String::from_utf8(buf).unwrap();
+        assert_eq!(text.lines().nth(1), Some("4,,,,,2.5,0,2.5,false,2.5"));
+    }
+}
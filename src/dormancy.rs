@@ -0,0 +1,274 @@
+//! Accounts with no recent activity
+//!
+//! An account is dormant once at least the threshold went by since the last
+//! record applied to it, counted either in records or in whole days on the
+//! engine's clock. Under `--virtual-time-from-timestamps` the clock follows the
+//! record timestamps, so days are those of the input rather than of the run.
+
+use crate::types::{serialize_decimal_str, Account, ClientId};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// How long an account must go without an applied record to be dormant
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DormancyThreshold {
+    /// Records processed since
+    Records(u64),
+    /// Whole days on the engine's clock since
+    Days(u64),
+}
+
+impl FromStr for DormancyThreshold {
+    type Err = String;
+
+    /// `<n>` records, or `<n>d` days
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid dormancy threshold: {} (expected a record count or days like 30d)",
+                s
+            )
+        };
+        match s.strip_suffix('d') {
+            Some(days) => days.parse().map(DormancyThreshold::Days),
+            None => s.parse().map(DormancyThreshold::Records),
+        }
+        .map_err(|_| invalid())
+    }
+}
+
+impl fmt::Display for DormancyThreshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DormancyThreshold::Records(n) => write!(f, "{} records", n),
+            DormancyThreshold::Days(n) => write!(f, "{} days", n),
+        }
+    }
+}
+
+/// When the accounts were last active, at the end of a run
+#[derive(Debug, Clone, Copy)]
+pub struct Activity<'a> {
+    /// Position of the last record applied to each account
+    pub positions: &'a HashMap<ClientId, u64>,
+    /// Clock time of the last record applied to each account
+    pub times: &'a HashMap<ClientId, SystemTime>,
+    pub records_processed: u64,
+    /// Clock time at the first record, for accounts idle since before the run
+    pub started: SystemTime,
+    /// Clock time at the end of the run
+    pub now: SystemTime,
+}
+
+/// An account idle for at least the dormancy threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DormantAccount {
+    pub client: ClientId,
+    /// Position of the last record applied to the account
+    pub last_active: u64,
+    /// Records processed since
+    pub idle: u64,
+    /// Whole days on the engine's clock since
+    pub idle_days: u64,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub available: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub held: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl fmt::Display for DormantAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "client {}: idle for {} records ({} days) since record {} ({} total)",
+            self.client, self.idle, self.idle_days, self.last_active, self.total
+        )
+    }
+}
+
+/// Accounts idle for `threshold` or more at the end of the run, longest idle first
+/// Closed accounts are not dormant, they are gone
+pub fn find_dormant(
+    accounts: &HashMap<ClientId, Account>,
+    activity: &Activity,
+    threshold: DormancyThreshold,
+) -> Vec<DormantAccount> {
+    let mut dormant: Vec<DormantAccount> = accounts
+        .values()
+        .filter(|a| !a.is_closed())
+        .filter_map(|a| {
+            let last_active = activity.positions.get(&a.client).copied().unwrap_or(0);
+            let idle = activity.records_processed.saturating_sub(last_active);
+            let since = activity.times.get(&a.client).unwrap_or(&activity.started);
+            let idle_days = activity
+                .now
+                .duration_since(*since)
+                .unwrap_or_default()
+                .as_secs()
+                / SECS_PER_DAY;
+            let is_dormant = match threshold {
+                DormancyThreshold::Records(n) => idle >= n,
+                DormancyThreshold::Days(n) => idle_days >= n,
+            };
+            is_dormant.then_some(DormantAccount {
+                client: a.client,
+                last_active,
+                idle,
+                idle_days,
+                available: a.available,
+                held: a.held,
+                total: a.total,
+                locked: a.locked,
+            })
+        })
+        .collect();
+    dormant.sort_by(|a, b| {
+        b.idle_days
+            .cmp(&a.idle_days)
+            .then(b.idle.cmp(&a.idle))
+            .then(a.client.cmp(&b.client))
+    });
+    dormant
+}
+
+/// Write dormant accounts as CSV, longest idle first
+pub fn write_dormant<W: Write>(
+    dormant: &[DormantAccount],
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for account in dormant {
+        writer.serialize(account)?;
+    }
+    if dormant.is_empty() {
+        writer.write_record([
+            "client",
+            "last_active",
+            "idle",
+            "idle_days",
+            "available",
+            "held",
+            "total",
+            "locked",
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::time::Duration;
+
+    fn days(n: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(n * SECS_PER_DAY)
+    }
+
+    fn activity<'a>(
+        positions: &'a HashMap<ClientId, u64>,
+        times: &'a HashMap<ClientId, SystemTime>,
+        records_processed: u64,
+    ) -> Activity<'a> {
+        Activity {
+            positions,
+            times,
+            records_processed,
+            started: days(0),
+            now: days(100),
+        }
+    }
+
+    fn accounts() -> HashMap<ClientId, Account> {
+        (1..=4)
+            .map(|client| {
+                let mut account = Account::new(client);
+                account.deposit(dec!(5));
+                (client, account)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_find_dormant() {
+        let mut accounts = accounts();
+        accounts.get_mut(&4).unwrap().close();
+        let last_active = HashMap::from([(1, 10), (2, 95), (3, 50), (4, 1)]);
+        let times = HashMap::new();
+        let activity = activity(&last_active, &times, 100);
+
+        let dormant = find_dormant(&accounts, &activity, DormancyThreshold::Records(50));
+        let clients: Vec<_> = dormant.iter().map(|d| (d.client, d.idle)).collect();
+        assert_eq!(clients, [(1, 90), (3, 50)]);
+        assert_eq!(dormant[0].last_active, 10);
+        assert_eq!(dormant[0].total, dec!(5));
+
+        assert!(find_dormant(&accounts, &activity, DormancyThreshold::Records(91)).is_empty());
+    }
+
+    #[test]
+    fn test_find_dormant_days() {
+        let accounts = accounts();
+        let positions = HashMap::from([(1, 1), (2, 2), (3, 3)]);
+        // Client 4 has been idle since the run started
+        let times = HashMap::from([
+            (1, days(60)),
+            (2, days(10)),
+            (3, days(71) - Duration::from_secs(1)),
+        ]);
+        let activity = activity(&positions, &times, 3);
+
+        let dormant = find_dormant(&accounts, &activity, DormancyThreshold::Days(30));
+        let clients: Vec<_> = dormant.iter().map(|d| (d.client, d.idle_days)).collect();
+        assert_eq!(clients, [(4, 100), (2, 90), (1, 40)]);
+        assert_eq!(dormant[1].idle, 1);
+    }
+
+    #[test]
+    fn test_parse_threshold() {
+        assert_eq!("100".parse(), Ok(DormancyThreshold::Records(100)));
+        assert_eq!("30d".parse(), Ok(DormancyThreshold::Days(30)));
+        assert_eq!(DormancyThreshold::Days(30).to_string(), "30 days");
+        assert_eq!(DormancyThreshold::Records(1).to_string(), "1 records");
+        for invalid in ["", "d", "soon", "30h", "-1d", "1.5d"] {
+            assert!(invalid.parse::<DormancyThreshold>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_write_dormant() {
+        let accounts = accounts();
+        let last_active = HashMap::from([(1, 2)]);
+        let times = HashMap::from([(1, days(100))]);
+        let dormant = find_dormant(
+            &accounts,
+            &activity(&last_active, &times, 2),
+            DormancyThreshold::Records(0),
+        );
+        let mut out = Vec::new();
+        write_dormant(&dormant[..1], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,last_active,idle,idle_days,available,held,total,locked\n2,0,2,100,5,0,5,false\n"
+        );
+
+        let mut out = Vec::new();
+        write_dormant(&[], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,last_active,idle,idle_days,available,held,total,locked\n"
+        );
+    }
+}
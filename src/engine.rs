@@ -0,0 +1,1965 @@
+use crate::audit_log::{AuditEvent, AuditEventKind, AuditSink, Balances};
+use crate::cancel::{CancellationToken, Cancelled};
+use crate::clock::{Clock, SystemClock, VirtualClock};
+use crate::closure::Closure;
+use crate::config::{BlockedWithdrawal, EngineConfig};
+use crate::dedup::{DuplicateReference, ReferenceDeduplicator};
+use crate::diff::{self, AccountDelta};
+use crate::dormancy::{self, Activity, DormancyThreshold, DormantAccount};
+use crate::events::EventId;
+use crate::history::BalanceHistory;
+use crate::hold::{HoldQueue, PendingRelease};
+use crate::input::RecordSource;
+use crate::invariants;
+use crate::mismatch::{ClientMismatch, MismatchHandler};
+use crate::plugin::{RecordTransformer, RiskRule};
+use crate::proof::Flows;
+use crate::recovery::Recovery;
+use crate::rejects::Rejection;
+use crate::snapshot::Snapshot;
+use crate::stats::ClientStats;
+use crate::store::{MemoryStore, StoreError, TransactionStore};
+use crate::summary::RunSummary;
+use crate::timestamp::Timestamp;
+use crate::types::{
+    Account, ClientId, RejectionReason, StoredTransaction, TransactionId, TransactionRecord,
+    TransactionType, TxError,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Payments engine owning account and transaction state
+///
+/// Records are applied in the order they are given. Reordering by the
+/// optional `seq` column is up to the caller (see `SequenceTracker`).
+#[derive(Debug)]
+pub struct PaymentsEngine {
+    config: EngineConfig,
+    // Account storage - created on demand
+    accounts: HashMap<ClientId, Account>,
+    // Transaction storage - only deposits stored for dispute tracking
+    // Note: Withdrawals are not stored unless withdrawal disputes are allowed
+    transactions: Box<dyn TransactionStore>,
+    // Position of each record in application order, used to age disputes
+    position: u64,
+    // Idempotency check for retransmitted dispute/resolve/chargeback lines
+    dedup: ReferenceDeduplicator,
+    // Policy for references whose client differs from the deposit's
+    mismatch: MismatchHandler,
+    // References to a tx not seen (yet), kept so they can be re-fed later
+    dead_letters: Vec<TransactionRecord>,
+    // Withdrawals refused while the client has too many open disputes
+    blocked_withdrawals: Vec<BlockedWithdrawal>,
+    // Applied/rejected counts per client, by the client named on the record
+    client_stats: HashMap<ClientId, ClientStats>,
+    // Deposits waiting out the hold period before they become available
+    holds: HoldQueue,
+    // Time as time-based policies see it
+    clock: Arc<dyn Clock>,
+    // The clock under `virtual_time`, moved by the record timestamps
+    virtual_clock: Option<VirtualClock>,
+    // Deposits that covered a negative balance, under the recovery sweep
+    recoveries: Vec<Recovery>,
+    // Rewrite records before anything else looks at them, in order
+    transformers: Vec<Box<dyn RecordTransformer>>,
+    // Extra checks a record must pass before it is applied, in order
+    risk_rules: Vec<Box<dyn RiskRule>>,
+    // Receives every balance mutation and denied withdrawal
+    audit: Option<Box<dyn AuditSink>>,
+    // Money moved by applied records, checked against the totals at the end
+    flows: Flows,
+    // Rejected records by reason
+    rejections: HashMap<RejectionReason, u64>,
+    // Closed accounts and what became of their balance
+    closures: Vec<Closure>,
+    // Position of the last record applied to each account
+    last_active: HashMap<ClientId, u64>,
+    // Clock time of the last record applied to each account
+    last_active_at: HashMap<ClientId, SystemTime>,
+    // Clock time at the first record
+    started_at: Option<SystemTime>,
+    // Clock time each open dispute of this run was opened at
+    disputed_since: HashMap<TransactionId, SystemTime>,
+    // Events sent to event sinks, in this and earlier runs, under `dedup_events`
+    emitted_events: HashSet<EventId>,
+    // Applied records whose event had already been sent
+    suppressed_events: u64,
+    // Account states by record timestamp, once `keep_balance_history` is called
+    history: Option<BalanceHistory>,
+}
+
+/// What a run over a record source did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunStats {
+    pub applied: u64,
+    pub rejected: u64,
+    /// Unparseable records, skipped; only readers have them
+    pub malformed: u64,
+}
+
+/// Final state of an engine and what it noticed along the way
+#[derive(Debug)]
+pub struct EngineReport {
+    pub accounts: HashMap<ClientId, Account>,
+    pub transactions: Box<dyn TransactionStore>,
+    /// Records given to the engine, applied or not
+    pub records_processed: u64,
+    pub duplicates: Vec<DuplicateReference>,
+    /// Dispute/resolve/chargeback records whose tx was never seen
+    pub dead_letters: Vec<TransactionRecord>,
+    /// References naming another client than the deposit's
+    pub mismatches: Vec<ClientMismatch>,
+    /// Withdrawals refused under the open dispute limits
+    pub blocked_withdrawals: Vec<BlockedWithdrawal>,
+    /// Applied/rejected operation counts per client
+    pub client_stats: HashMap<ClientId, ClientStats>,
+    /// Deposits applied to negative balances, under the recovery sweep
+    pub recoveries: Vec<Recovery>,
+    /// Money moved by applied records, for the conservation check
+    pub flows: Flows,
+    /// Rejected records by reason
+    pub rejections: HashMap<RejectionReason, u64>,
+    /// Closed accounts, in the order they were closed
+    pub closures: Vec<Closure>,
+    /// Position of the last record applied to each account
+    pub last_active: HashMap<ClientId, u64>,
+    /// Clock time of the last record applied to each account
+    pub last_active_at: HashMap<ClientId, SystemTime>,
+    /// Clock time at the first record, `None` if there was none
+    pub started_at: Option<SystemTime>,
+    /// Clock time at the end of the run
+    pub ended_at: SystemTime,
+    /// Clock time each dispute opened in the run and still open was opened at
+    pub disputed_since: HashMap<TransactionId, SystemTime>,
+    /// Events sent to event sinks, in this and earlier runs
+    pub emitted_events: HashSet<EventId>,
+    /// Applied records not sent to event sinks, their event had already been sent
+    pub suppressed_events: u64,
+    /// Deposits still held at the end of the run, waiting out `deposit_hold`
+    pub pending_holds: Vec<PendingRelease>,
+}
+
+impl PaymentsEngine {
+    pub fn new(config: EngineConfig) -> Self {
+        Self::with_store(config, Box::new(MemoryStore::default()))
+    }
+
+    /// Engine keeping its transactions in `store`
+    pub fn with_store(config: EngineConfig, store: Box<dyn TransactionStore>) -> Self {
+        let virtual_clock = config.virtual_time.then(VirtualClock::default);
+        Self {
+            holds: HoldQueue::new(config.deposit_hold),
+            clock: match &virtual_clock {
+                Some(clock) => Arc::new(clock.clone()),
+                None => Arc::new(SystemClock),
+            },
+            virtual_clock,
+            dedup: ReferenceDeduplicator::new(config.ref_dedup),
+            mismatch: MismatchHandler::new(config.client_mismatch),
+            config,
+            accounts: HashMap::new(),
+            transactions: store,
+            position: 0,
+            dead_letters: Vec::new(),
+            blocked_withdrawals: Vec::new(),
+            client_stats: HashMap::new(),
+            recoveries: Vec::new(),
+            transformers: Vec::new(),
+            risk_rules: Vec::new(),
+            audit: None,
+            flows: Flows::default(),
+            rejections: HashMap::new(),
+            closures: Vec::new(),
+            last_active: HashMap::new(),
+            last_active_at: HashMap::new(),
+            started_at: None,
+            disputed_since: HashMap::new(),
+            emitted_events: HashSet::new(),
+            suppressed_events: 0,
+            history: None,
+        }
+    }
+
+    /// Pass records through `transformer` before processing them
+    /// A rejection counts against the client of the original record
+    pub fn add_transformer(&mut self, transformer: Box<dyn RecordTransformer>) {
+        self.transformers.push(transformer);
+    }
+
+    /// Evaluate time-based policies, such as duration deposit holds, on `clock`
+    /// instead of the system clock, or of the record timestamps under `virtual_time`
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+        self.virtual_clock = None;
+    }
+
+    /// Report every balance mutation and denied withdrawal to `sink`
+    pub fn set_audit_sink(&mut self, sink: Box<dyn AuditSink>) {
+        self.audit = Some(sink);
+    }
+
+    /// Refuse records `rule` rejects, after the engine's own pre-checks
+    /// (dedup, client mismatch, withdrawal block) and before any balance checks
+    pub fn add_risk_rule(&mut self, rule: Box<dyn RiskRule>) {
+        self.risk_rules.push(rule);
+    }
+
+    /// Keep the account states left by applied records from now on, keyed by
+    /// their timestamp, for `balances_as_of`
+    pub fn keep_balance_history(&mut self) {
+        self.history = Some(BalanceHistory::new(self.accounts.clone()));
+    }
+
+    /// Accounts as they stood at `at`, after every applied record stamped at or
+    /// before it; accounts opened later are left out
+    /// `None` without `keep_balance_history`, or once an applied record had no
+    /// timestamp or an earlier one than the record before it
+    pub fn balances_as_of(&self, at: Timestamp) -> Option<HashMap<ClientId, Account>> {
+        self.history.as_ref()?.as_of(at)
+    }
+
+    /// Apply one record
+    /// Fails with `TxError::Rejected` for records the spec says to ignore
+    pub fn process(&mut self, record: TransactionRecord) -> Result<(), TxError> {
+        self.process_with(record, |_, _, _| {})
+    }
+
+    /// Apply every record of `source`, until it ends or `cancel` stops the run
+    /// Rejections are counted and malformed records skipped, unless validation
+    /// aborts on them; any other error stops at the record that caused it. A
+    /// cancelled run stops between records with `TxError::Cancelled`, every
+    /// record before it applied; `seq` reordering is left to the caller
+    pub fn run(
+        &mut self,
+        source: &mut dyn RecordSource,
+        cancel: &CancellationToken,
+    ) -> Result<RunStats, TxError> {
+        let mut stats = RunStats::default();
+        loop {
+            if let Some(reason) = cancel.stop_reason() {
+                return Err(TxError::Cancelled(Cancelled { reason, stats }));
+            }
+            match source.next_record() {
+                None => return Ok(stats),
+                Some(Ok(record)) => match self.process(record) {
+                    Ok(()) => stats.applied += 1,
+                    Err(TxError::Rejected(_)) => stats.rejected += 1,
+                    Err(e) => return Err(e),
+                },
+                Some(Err(e)) => {
+                    let rejection = Rejection::malformed(e.line);
+                    if self.config.validation.aborts_on(rejection.reason) {
+                        return Err(TxError::Violation(rejection));
+                    }
+                    stats.malformed += 1;
+                }
+            }
+        }
+    }
+
+    /// Like `process`, calling `on_applied` with the record position, the record as
+    /// applied and the resulting account state if the record was applied
+    /// Under `dedup_events` it is only called for events not sent before
+    pub fn process_with<F>(
+        &mut self,
+        record: TransactionRecord,
+        on_applied: F,
+    ) -> Result<(), TxError>
+    where
+        F: FnOnce(u64, &TransactionRecord, &Account),
+    {
+        self.position += 1;
+        let position = self.position;
+        if let (Some(clock), Some(at)) = (&self.virtual_clock, record.timestamp) {
+            clock.observe(at.into());
+        }
+        let now = self.clock.now();
+        self.started_at.get_or_insert(now);
+        self.release_holds(position);
+
+        match self.apply(position, record, on_applied) {
+            Ok(applied) => {
+                self.last_active.insert(applied.client, position);
+                self.last_active_at.insert(applied.client, now);
+                match applied.tx_type {
+                    TransactionType::Dispute => {
+                        self.disputed_since.insert(applied.tx, now);
+                    }
+                    TransactionType::Resolve | TransactionType::Chargeback => {
+                        self.disputed_since.remove(&applied.tx);
+                    }
+                    _ => {}
+                }
+                self.client_stats
+                    .entry(applied.client)
+                    .or_default()
+                    .record_applied(&applied);
+                Ok(())
+            }
+            Err(TxError::Rejected(reason)) => {
+                self.client_stats
+                    .entry(record.client)
+                    .or_default()
+                    .record_rejected(&record);
+                *self.rejections.entry(reason).or_default() += 1;
+                if record.tx_type == TransactionType::Withdrawal {
+                    let balances = Balances::of(self.accounts.get(&record.client));
+                    self.audit(AuditEvent {
+                        position,
+                        event: AuditEventKind::WithdrawalDenied,
+                        client: record.client,
+                        tx: record.tx,
+                        amount: record.amount,
+                        before: balances,
+                        after: balances,
+                        reason: Some(reason),
+                    });
+                }
+                if self.config.validation.aborts_on(reason) {
+                    return Err(TxError::Violation(Rejection::new(&record, reason)));
+                }
+                Err(TxError::Rejected(reason))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Make deposits whose hold period is over available
+    fn release_holds(&mut self, position: u64) {
+        for release in self.holds.take_due(position, self.clock.as_ref()) {
+            if let Some(account) = self.accounts.get_mut(&release.client) {
+                let before = Balances::of(Some(account));
+                if self.config.recovery_sweep {
+                    self.recoveries.extend(Recovery::from_credit(
+                        position,
+                        release.client,
+                        release.tx,
+                        account.available,
+                        release.amount,
+                    ));
+                }
+                account.release_deposit(release.amount);
+                let after = Balances::of(Some(account));
+                self.audit(AuditEvent {
+                    position,
+                    event: AuditEventKind::FundsReleased,
+                    client: release.client,
+                    tx: release.tx,
+                    amount: Some(release.amount),
+                    before,
+                    after,
+                    reason: None,
+                });
+            }
+        }
+    }
+
+    fn audit(&mut self, event: AuditEvent) {
+        if let Some(sink) = &mut self.audit {
+            sink.record(&event);
+        }
+    }
+
+    /// Audit an applied record, and the lock a chargeback caused
+    fn audit_applied(&mut self, position: u64, record: &TransactionRecord, before: Balances) {
+        if self.audit.is_none() {
+            return;
+        }
+        let after = Balances::of(self.accounts.get(&record.client));
+        // References carry no amount, they move the referenced transaction's
+        let amount = match (record.tx_type, record.amount) {
+            (TransactionType::Close, _) => Some(before.available),
+            (TransactionType::Unlock, _) => None,
+            (_, Some(amount)) => Some(amount),
+            (_, None) => self
+                .transactions
+                .get(record.tx)
+                .ok()
+                .flatten()
+                .map(|t| t.amount),
+        };
+        let event = AuditEvent {
+            position,
+            event: AuditEventKind::applied(record.tx_type),
+            client: record.client,
+            tx: record.tx,
+            amount,
+            before,
+            after,
+            reason: None,
+        };
+        self.audit(event);
+        // A closed account is locked too, that needs no event of its own
+        if after.locked && !before.locked && record.tx_type != TransactionType::Close {
+            self.audit(AuditEvent {
+                event: AuditEventKind::AccountLocked,
+                amount: None,
+                ..event
+            });
+        }
+    }
+
+    /// Run a record through the checks and apply it, returning it as applied
+    fn apply<F>(
+        &mut self,
+        position: u64,
+        record: TransactionRecord,
+        on_applied: F,
+    ) -> Result<TransactionRecord, TxError>
+    where
+        F: FnOnce(u64, &TransactionRecord, &Account),
+    {
+        let mut record = record;
+        for transformer in &mut self.transformers {
+            record = transformer.transform(record)?;
+        }
+
+        if !self
+            .dedup
+            .check(position, &record)
+            .map_err(TxError::Duplicate)?
+        {
+            return Err(RejectionReason::DuplicateReference.into());
+        }
+        // As checked, before the mismatch policy may change its client
+        let checked = record;
+
+        let stored_client = self.transactions.get(record.tx)?.map(|t| t.client_id);
+        let Some(record) = self.mismatch.check(position, record, stored_client) else {
+            return Err(RejectionReason::ClientMismatch.into());
+        };
+
+        if let Some(blocked) =
+            check_withdrawal_block(position, &record, &self.accounts, &self.config)
+        {
+            self.blocked_withdrawals.push(blocked);
+            return Err(RejectionReason::WithdrawalBlocked.into());
+        }
+
+        let account = self.accounts.get(&record.client);
+        for rule in &mut self.risk_rules {
+            rule.check(&record, account)?;
+        }
+
+        // Available before a deposit straight to available, to see what it recovers
+        let credited_to = match (record.tx_type, record.amount) {
+            (TransactionType::Deposit, Some(_))
+                if self.config.recovery_sweep && !self.holds.is_enabled() =>
+            {
+                self.accounts.get(&record.client).map(|a| a.available)
+            }
+            _ => None,
+        };
+
+        let before = Balances::of(self.accounts.get(&record.client));
+        match process_transaction(
+            &record,
+            &mut self.accounts,
+            self.transactions.as_mut(),
+            &mut self.holds,
+            self.clock.as_ref(),
+            position,
+            &self.config,
+        ) {
+            Ok(()) => {
+                self.dedup.applied(&checked);
+                if self.config.check_invariants {
+                    invariants::check(
+                        position,
+                        &record,
+                        before,
+                        &self.accounts[&record.client],
+                        &self.config.account_policy,
+                    )
+                    .map_err(TxError::Invariant)?;
+                }
+                if let (Some(available), Some(amount)) = (credited_to, record.amount) {
+                    self.recoveries.extend(Recovery::from_credit(
+                        position,
+                        record.client,
+                        record.tx,
+                        available,
+                        amount,
+                    ));
+                }
+                // References move the amount of the transaction they name
+                let referenced = match record.tx_type {
+                    TransactionType::Deposit
+                    | TransactionType::Withdrawal
+                    | TransactionType::Close
+                    | TransactionType::Unlock => None,
+                    _ => self.transactions.get(record.tx)?,
+                };
+                self.flows.record(&record, referenced.as_ref());
+                if record.tx_type == TransactionType::Close {
+                    let closure = Closure::new(
+                        position,
+                        record.client,
+                        record.tx,
+                        before.available,
+                        self.config.close_dust,
+                    );
+                    self.flows.record_closure(&closure);
+                    self.closures.push(closure);
+                }
+                self.audit_applied(position, &record, before);
+                if !self.config.dedup_events || self.emitted_events.insert(EventId::of(&record)) {
+                    on_applied(position, &record, &self.accounts[&record.client]);
+                } else {
+                    self.suppressed_events += 1;
+                }
+                if let Some(history) = &mut self.history {
+                    history.record(record.timestamp, &self.accounts[&record.client]);
+                }
+                Ok(record)
+            }
+            Err(e) => {
+                if e == TxError::Rejected(RejectionReason::UnknownTx) {
+                    self.dead_letters.push(record);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Resume from a snapshot of an earlier engine
+    /// Duplicate and mismatch tracking and client stats start afresh, they are not
+    /// part of a snapshot. Neither are pending deposit releases: deposits still on
+    /// hold when the snapshot was taken stay held. Accounts count as last active
+    /// when the snapshot was taken, which on the clock is the first record of the run
+    pub fn from_snapshot(config: EngineConfig, snapshot: Snapshot) -> Self {
+        let opening = snapshot.accounts.values().map(|a| a.total).sum();
+        let last_active = snapshot
+            .accounts
+            .keys()
+            .map(|client| (*client, snapshot.records_processed))
+            .collect();
+        Self {
+            last_active,
+            flows: Flows {
+                opening,
+                ..Flows::default()
+            },
+            accounts: snapshot.accounts,
+            transactions: Box::new(MemoryStore::from(snapshot.transactions)),
+            position: snapshot.records_processed,
+            emitted_events: snapshot.emitted_events,
+            ..Self::new(config)
+        }
+    }
+
+    /// Capture account and transaction state
+    /// Fails if the transaction store cannot be read
+    pub fn snapshot(&self) -> Result<Snapshot, StoreError> {
+        Ok(Snapshot {
+            accounts: self.accounts.clone(),
+            transactions: self.transactions.to_map()?,
+            records_processed: self.position,
+            emitted_events: self.emitted_events.clone(),
+        })
+    }
+
+    /// Current account states
+    pub fn accounts(&self) -> &HashMap<ClientId, Account> {
+        &self.accounts
+    }
+
+    /// Whether the event has been sent to event sinks, in this or an earlier run
+    /// Only events sent under `dedup_events` are remembered
+    pub fn has_emitted(&self, id: &EventId) -> bool {
+        self.emitted_events.contains(id)
+    }
+
+    /// Current state of one client's account
+    pub fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    /// Applied and rejected operations of one client so far
+    /// Counted under the client named on the record, `None` if it named no record yet
+    pub fn client_stats(&self, client: ClientId) -> Option<&ClientStats> {
+        self.client_stats.get(&client)
+    }
+
+    /// Aggregate statistics so far, listing the `top` clients with the largest totals
+    pub fn summary(&self, top: usize) -> RunSummary {
+        RunSummary::compute(
+            &self.accounts,
+            &self.client_stats,
+            &self.rejections,
+            self.position,
+            top,
+        )
+    }
+
+    /// Stored deposits, used for dispute tracking
+    pub fn transactions(&self) -> &dyn TransactionStore {
+        self.transactions.as_ref()
+    }
+
+    /// Accounts that differ in `other`, ordered by client id
+    /// `self` is the earlier state: deltas go from this engine's accounts to `other`'s
+    pub fn diff(&self, other: &PaymentsEngine) -> Vec<AccountDelta> {
+        diff::diff_accounts(&self.accounts, &other.accounts)
+    }
+
+    /// Accounts that changed since `checkpoint` was taken, ordered by client id
+    pub fn diff_since(&self, checkpoint: &Snapshot) -> Vec<AccountDelta> {
+        diff::diff_accounts(&checkpoint.accounts, &self.accounts)
+    }
+
+    /// Number of records given to the engine so far
+    pub fn records_processed(&self) -> u64 {
+        self.position
+    }
+
+    /// Set the record count, for callers spreading one input over several engines
+    /// The next record is taken to be at position `records + 1`
+    pub fn set_records_processed(&mut self, records: u64) {
+        self.position = records;
+    }
+
+    /// Consume the engine, returning its final state
+    pub fn into_report(self) -> EngineReport {
+        EngineReport {
+            accounts: self.accounts,
+            transactions: self.transactions,
+            records_processed: self.position,
+            duplicates: self.dedup.into_duplicates(),
+            dead_letters: self.dead_letters,
+            mismatches: self.mismatch.into_mismatches(),
+            blocked_withdrawals: self.blocked_withdrawals,
+            client_stats: self.client_stats,
+            recoveries: self.recoveries,
+            flows: self.flows,
+            rejections: self.rejections,
+            closures: self.closures,
+            last_active: self.last_active,
+            last_active_at: self.last_active_at,
+            started_at: self.started_at,
+            ended_at: self.clock.now(),
+            disputed_since: self.disputed_since,
+            emitted_events: self.emitted_events,
+            suppressed_events: self.suppressed_events,
+            pending_holds: self.holds.into_pending(),
+        }
+    }
+}
+
+impl EngineReport {
+    /// Flag accounts without an applied record for `threshold` or more as dormant,
+    /// returning them longest idle first
+    pub fn flag_dormant(&mut self, threshold: DormancyThreshold) -> Vec<DormantAccount> {
+        let activity = Activity {
+            positions: &self.last_active,
+            times: &self.last_active_at,
+            records_processed: self.records_processed,
+            started: self.started_at.unwrap_or(self.ended_at),
+            now: self.ended_at,
+        };
+        let dormant = dormancy::find_dormant(&self.accounts, &activity, threshold);
+        for account in &dormant {
+            if let Some(account) = self.accounts.get_mut(&account.client) {
+                account.dormant = true;
+            }
+        }
+        dormant
+    }
+
+    /// Aggregate statistics of the run, listing the `top` clients with the largest totals
+    pub fn summary(&self, top: usize) -> RunSummary {
+        RunSummary::compute(
+            &self.accounts,
+            &self.client_stats,
+            &self.rejections,
+            self.records_processed,
+            top,
+        )
+    }
+
+    /// Capture the final account and transaction state, to resume from later
+    pub fn snapshot(&self) -> Result<Snapshot, StoreError> {
+        Ok(Snapshot {
+            accounts: self.accounts.clone(),
+            transactions: self.transactions.to_map()?,
+            records_processed: self.records_processed,
+            emitted_events: self.emitted_events.clone(),
+        })
+    }
+}
+
+/// Check a withdrawal against the open dispute limits
+/// Locked accounts refusing withdrawals are left to `process_transaction`, which rejects them anyway
+fn check_withdrawal_block(
+    position: u64,
+    record: &TransactionRecord,
+    accounts: &HashMap<ClientId, Account>,
+    config: &EngineConfig,
+) -> Option<BlockedWithdrawal> {
+    if record.tx_type != TransactionType::Withdrawal {
+        return None;
+    }
+    let account = accounts.get(&record.client)?;
+    let refused = account.is_locked()
+        && !config
+            .account_policy
+            .accepts_when_locked(TransactionType::Withdrawal);
+    if refused || !config.withdrawal_block.blocks(account) {
+        return None;
+    }
+
+    Some(BlockedWithdrawal {
+        position,
+        client: record.client,
+        tx: record.tx,
+        amount: record.amount?,
+        held: account.held,
+        open_disputes: account.open_disputes,
+    })
+}
+
+/// Process a single transaction record
+/// Returns why the record was rejected if it was not applied
+/// The store is written before the account, so a store error leaves the account as it was
+fn process_transaction(
+    record: &TransactionRecord,
+    accounts: &mut HashMap<ClientId, Account>,
+    transactions: &mut dyn TransactionStore,
+    holds: &mut HoldQueue,
+    clock: &dyn Clock,
+    position: u64,
+    config: &EngineConfig,
+) -> Result<(), TxError> {
+    // Reject absurd amounts before they touch (or even create) an account
+    if let Some(amount) = record.amount {
+        if !config.is_plausible_amount(amount) {
+            return Err(RejectionReason::ImplausibleAmount.into());
+        }
+        config.validation.check_amount(amount)?;
+    }
+
+    // Get or create account for this client
+    let account = accounts
+        .entry(record.client)
+        .or_insert_with(|| Account::new(record.client));
+
+    // Skip all operations if account is closed, and those the policy keeps from locked accounts
+    let policy = &config.account_policy;
+    if account.is_closed() {
+        return Err(RejectionReason::AccountClosed.into());
+    }
+    if account.is_locked()
+        && record.tx_type != TransactionType::Unlock
+        && !policy.accepts_when_locked(record.tx_type)
+    {
+        return Err(RejectionReason::AccountLocked.into());
+    }
+
+    // Process transaction based on type
+    match record.tx_type {
+        TransactionType::Deposit => {
+            // Skip if amount is missing (malformed)
+            let amount = record.amount.ok_or(RejectionReason::MissingAmount)?;
+
+            // A reused tx id would overwrite the stored deposit and its dispute state
+            if transactions.get(record.tx)?.is_some() {
+                return Err(RejectionReason::DuplicateTxId.into());
+            }
+
+            // Store transaction for potential disputes
+            transactions.put(
+                record.tx,
+                StoredTransaction {
+                    timestamp: record.timestamp,
+                    ..StoredTransaction::new(record.client, TransactionType::Deposit, amount)
+                },
+            )?;
+
+            // Credit account, as held until the hold period is over if there is one
+            if holds.is_enabled() {
+                account.deposit_held(amount);
+                holds.hold(position, clock, record.client, record.tx, amount);
+            } else {
+                account.deposit(amount);
+            }
+        }
+
+        TransactionType::Withdrawal => {
+            // Skip if amount is missing (malformed)
+            let amount = record.amount.ok_or(RejectionReason::MissingAmount)?;
+
+            let rule = config.withdrawal_rules.rule_for(record.client);
+            if let Some(rule) = rule {
+                rule.check_increment(amount)?;
+            }
+
+            // Attempt to debit account
+            if !account.can_withdraw(amount) {
+                return Err(RejectionReason::InsufficientFunds.into());
+            }
+            if let Some(rule) = rule {
+                rule.check_min_balance(account.available, amount)?;
+            }
+
+            // Per spec withdrawals cannot be disputed, so they are only stored on request
+            if config.allow_withdrawal_disputes {
+                if transactions.get(record.tx)?.is_some() {
+                    return Err(RejectionReason::DuplicateTxId.into());
+                }
+                transactions.put(
+                    record.tx,
+                    StoredTransaction {
+                        timestamp: record.timestamp,
+                        ..StoredTransaction::new(record.client, TransactionType::Withdrawal, amount)
+                    },
+                )?;
+            }
+
+            account.withdraw(amount);
+        }
+
+        TransactionType::Dispute => {
+            // Look up the referenced transaction
+            let mut stored_tx = referenced(record, transactions)?;
+
+            // Only deposits (and stored withdrawals) can be disputed, and only if not already disputed
+            if !stored_tx.can_dispute() {
+                return Err(RejectionReason::NotDisputable.into());
+            }
+
+            // Mark transaction as disputed
+            stored_tx.mark_disputed();
+            stored_tx.disputed_at = Some(position);
+            transactions.put(record.tx, stored_tx)?;
+
+            // Hold the funds; a deposit still on hold keeps them held instead of being released
+            if stored_tx.tx_type == TransactionType::Withdrawal {
+                account.hold_withdrawn(stored_tx.amount);
+            } else if holds.cancel(record.tx) {
+                account.dispute_held_deposit();
+            } else {
+                account.hold_funds(stored_tx.amount);
+            }
+        }
+
+        TransactionType::Resolve => {
+            // Look up the referenced transaction
+            let mut stored_tx = referenced(record, transactions)?;
+
+            // Only resolve if transaction is currently disputed; a chargeback
+            // settled it for good, even on an account unlocked since
+            if stored_tx.is_charged_back() {
+                return Err(RejectionReason::ChargedBack.into());
+            }
+            if !stored_tx.is_open_dispute() {
+                return Err(RejectionReason::NotDisputed.into());
+            }
+
+            // Mark transaction as resolved (no longer disputed)
+            stored_tx.mark_resolved();
+            transactions.put(record.tx, stored_tx)?;
+
+            // Release the held funds
+            if stored_tx.tx_type == TransactionType::Withdrawal {
+                account.release_withdrawn(stored_tx.amount);
+            } else {
+                account.release_funds(stored_tx.amount);
+            }
+        }
+
+        TransactionType::Chargeback => {
+            // Look up the referenced transaction
+            let mut stored_tx = referenced(record, transactions)?;
+
+            // Only chargeback if transaction is currently disputed, and only once
+            if stored_tx.is_charged_back() {
+                return Err(RejectionReason::ChargedBack.into());
+            }
+            if !stored_tx.is_open_dispute() {
+                return Err(RejectionReason::NotDisputed.into());
+            }
+
+            // Transaction remains disputed (terminal state)
+            // Note: We don't remove the transaction from storage
+            stored_tx.mark_charged_back();
+            transactions.put(record.tx, stored_tx)?;
+
+            // Remove held funds (credit them back for a withdrawal) and lock account
+            if stored_tx.tx_type == TransactionType::Withdrawal {
+                account.chargeback_withdrawn(stored_tx.amount);
+            } else {
+                account.chargeback(stored_tx.amount);
+            }
+        }
+
+        TransactionType::Close => {
+            // Held funds are still in play, and a debt beyond the dust limit is not ours to forgive
+            if !account.held.is_zero() || account.open_disputes > 0 {
+                return Err(RejectionReason::FundsHeld.into());
+            }
+            if account.available < -config.close_dust {
+                return Err(RejectionReason::NegativeBalance.into());
+            }
+            // The caller records the payout or write-off from the balance before
+            account.close();
+        }
+
+        TransactionType::Unlock => {
+            if !policy.allow_unlock {
+                return Err(RejectionReason::UnlockDisabled.into());
+            }
+            if !account.is_locked() {
+                return Err(RejectionReason::NotLocked.into());
+            }
+            account.unlock();
+        }
+    }
+
+    // Remember the last transaction that changed the account
+    account.last_tx = Some(record.tx);
+    Ok(())
+}
+
+/// Look up the transaction a dispute/resolve/chargeback refers to
+/// The tx must exist and belong to the record's client
+fn referenced(
+    record: &TransactionRecord,
+    transactions: &dyn TransactionStore,
+) -> Result<StoredTransaction, TxError> {
+    let stored_tx = transactions
+        .get(record.tx)?
+        .ok_or(RejectionReason::UnknownTx)?;
+    if stored_tx.client_id != record.client {
+        return Err(RejectionReason::ClientMismatch.into());
+    }
+    Ok(stored_tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+    use crate::config::AccountPolicy;
+    use crate::dedup::DedupPolicy;
+    use crate::hold::HoldPeriod;
+    use crate::types::TransactionId;
+    use crate::validation::ValidationConfig;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use std::time::Duration;
+
+    fn record(
+        tx_type: TransactionType,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tx_type,
+            client,
+            tx,
+            amount,
+            seq: None,
+            timestamp: None,
+            line: None,
+        }
+    }
+
+    #[test]
+    fn test_process() {
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        assert_eq!(
+            engine.process(record(TransactionType::Deposit, 1, 1, Some(dec!(10)))),
+            Ok(())
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 2, Some(dec!(20)))),
+            Err(TxError::Rejected(RejectionReason::InsufficientFunds))
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Dispute, 1, 1, None)),
+            Ok(())
+        );
+
+        let account = engine.account(1).expect("Client 1 not found");
+        assert_eq!(account.held, dec!(10));
+        assert_eq!(account.last_tx, Some(1));
+
+        let stats = engine.client_stats(1).expect("No stats for client 1");
+        assert_eq!(stats.deposit.applied, 1);
+        assert_eq!(stats.deposit.applied_amount, dec!(10));
+        assert_eq!(stats.withdrawal.rejected, 1);
+        assert_eq!(stats.withdrawal.rejected_amount, dec!(20));
+        assert_eq!(stats.dispute.applied, 1);
+        assert_eq!(engine.client_stats(2), None);
+        assert_eq!(engine.records_processed(), 3);
+        assert!(engine
+            .transactions()
+            .get(1)
+            .unwrap()
+            .unwrap()
+            .is_open_dispute());
+
+        let report = engine.into_report();
+        assert_eq!(report.accounts.len(), 1);
+        assert_eq!(report.records_processed, 3);
+    }
+
+    #[test]
+    fn test_process_with_reports_applied_records() {
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        let mut applied = Vec::new();
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(5))),
+            record(TransactionType::Chargeback, 1, 1, None),
+            record(TransactionType::Withdrawal, 1, 2, Some(dec!(2))),
+        ] {
+            let _ = engine.process_with(r, |position, r, account| {
+                applied.push((position, r.tx, account.available))
+            });
+        }
+        assert_eq!(applied, vec![(1, 1, dec!(5)), (3, 2, dec!(3))]);
+    }
+
+    #[test]
+    fn test_dedup_events_across_runs() {
+        let config = EngineConfig {
+            dedup_events: true,
+            ..EngineConfig::default()
+        };
+        let day1 = [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+            record(TransactionType::Withdrawal, 1, 2, Some(dec!(3))),
+        ];
+        let mut engine = PaymentsEngine::new(config.clone());
+        let mut sent = Vec::new();
+        for r in day1 {
+            engine
+                .process_with(r, |_, r, _| sent.push(r.tx))
+                .expect("Record failed");
+        }
+        let snapshot = engine.snapshot().expect("Snapshot failed");
+        assert_eq!(snapshot.emitted_events.len(), 2);
+
+        // Day 2 re-sends the withdrawal: applied again, but not notified again
+        let mut engine = PaymentsEngine::from_snapshot(config, snapshot);
+        for r in [
+            record(TransactionType::Withdrawal, 1, 2, Some(dec!(3))),
+            record(TransactionType::Deposit, 1, 3, Some(dec!(1))),
+        ] {
+            engine
+                .process_with(r, |_, r, _| sent.push(r.tx))
+                .expect("Record failed");
+        }
+        assert_eq!(sent, vec![1, 2, 3]);
+        assert_eq!(engine.account(1).unwrap().available, dec!(5));
+        let report = engine.into_report();
+        assert_eq!(report.suppressed_events, 1);
+        assert_eq!(report.emitted_events.len(), 3);
+    }
+
+    #[test]
+    fn test_rejection_reasons() {
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        let rejected = |engine: &mut PaymentsEngine, r| match engine.process(r) {
+            Err(TxError::Rejected(reason)) => Some(reason),
+            _ => None,
+        };
+
+        let deposit = record(TransactionType::Deposit, 1, 1, Some(dec!(5)));
+        assert_eq!(rejected(&mut engine, deposit), None);
+        assert_eq!(
+            rejected(&mut engine, deposit),
+            Some(RejectionReason::DuplicateTxId)
+        );
+        assert_eq!(
+            rejected(
+                &mut engine,
+                record(
+                    TransactionType::Deposit,
+                    1,
+                    2,
+                    Some(dec!(2_000_000_000_000))
+                )
+            ),
+            Some(RejectionReason::ImplausibleAmount)
+        );
+        assert_eq!(
+            rejected(&mut engine, record(TransactionType::Withdrawal, 1, 3, None)),
+            Some(RejectionReason::MissingAmount)
+        );
+        assert_eq!(
+            rejected(&mut engine, record(TransactionType::Resolve, 1, 1, None)),
+            Some(RejectionReason::NotDisputed)
+        );
+        assert_eq!(
+            rejected(&mut engine, record(TransactionType::Dispute, 2, 1, None)),
+            Some(RejectionReason::ClientMismatch)
+        );
+        assert_eq!(
+            rejected(&mut engine, record(TransactionType::Dispute, 1, 9, None)),
+            Some(RejectionReason::UnknownTx)
+        );
+        assert_eq!(
+            rejected(&mut engine, record(TransactionType::Dispute, 1, 1, None)),
+            None
+        );
+        assert_eq!(
+            rejected(&mut engine, record(TransactionType::Dispute, 1, 1, None)),
+            Some(RejectionReason::NotDisputable)
+        );
+        assert_eq!(
+            rejected(&mut engine, record(TransactionType::Chargeback, 1, 1, None)),
+            None
+        );
+        assert_eq!(
+            rejected(
+                &mut engine,
+                record(TransactionType::Deposit, 1, 4, Some(dec!(1)))
+            ),
+            Some(RejectionReason::AccountLocked)
+        );
+
+        // Only unknown tx references are dead-lettered
+        assert_eq!(engine.into_report().dead_letters.len(), 1);
+    }
+
+    #[test]
+    fn test_resume_from_snapshot() {
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        engine
+            .process(record(TransactionType::Deposit, 1, 1, Some(dec!(5))))
+            .expect("Deposit failed");
+
+        let mut resumed =
+            PaymentsEngine::from_snapshot(EngineConfig::default(), engine.snapshot().unwrap());
+        assert_eq!(resumed.records_processed(), 1);
+        resumed
+            .process(record(TransactionType::Dispute, 1, 1, None))
+            .expect("Dispute of a snapshotted deposit failed");
+        assert_eq!(resumed.account(1).map(|a| a.held), Some(dec!(5)));
+        assert_eq!(
+            resumed
+                .transactions()
+                .get(1)
+                .unwrap()
+                .map(|t| t.disputed_at),
+            Some(Some(2))
+        );
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_holds() {
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            allow_withdrawal_disputes: true,
+            ..EngineConfig::default()
+        });
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+            record(TransactionType::Withdrawal, 1, 2, Some(dec!(4))),
+            record(TransactionType::Dispute, 1, 2, None),
+        ] {
+            engine.process(r).expect("Record rejected");
+        }
+
+        let account = engine.account(1).expect("Client 1 not found");
+        assert_eq!(account.available, dec!(6));
+        assert_eq!(account.held, dec!(4));
+        assert_eq!(account.total, dec!(10));
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 1, Some(dec!(1)))),
+            Err(TxError::Rejected(RejectionReason::DuplicateTxId))
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 3, Some(dec!(7)))),
+            Err(TxError::Rejected(RejectionReason::InsufficientFunds))
+        );
+        assert_eq!(engine.transactions().get(3), Ok(None));
+    }
+
+    #[test]
+    fn test_deposit_hold_period() {
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            deposit_hold: Some(HoldPeriod::Records(3)),
+            ..EngineConfig::default()
+        });
+        engine
+            .process(record(TransactionType::Deposit, 1, 1, Some(dec!(10))))
+            .expect("Deposit failed");
+        engine
+            .process(record(TransactionType::Deposit, 1, 2, Some(dec!(5))))
+            .expect("Deposit failed");
+
+        let account = engine.account(1).expect("Client 1 not found");
+        assert_eq!((account.available, account.held), (dec!(0), dec!(15)));
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 3, Some(dec!(1)))),
+            Err(TxError::Rejected(RejectionReason::InsufficientFunds))
+        );
+
+        // Disputing tx 2 during its hold cancels the release, tx 1 is released
+        engine
+            .process(record(TransactionType::Dispute, 1, 2, None))
+            .expect("Dispute failed");
+        let account = engine.account(1).expect("Client 1 not found");
+        assert_eq!((account.available, account.held), (dec!(10), dec!(5)));
+        assert_eq!(account.total, dec!(15));
+        assert_eq!(account.open_disputes, 1);
+
+        engine
+            .process(record(TransactionType::Withdrawal, 1, 4, Some(dec!(10))))
+            .expect("Withdrawal failed");
+        engine
+            .process(record(TransactionType::Deposit, 2, 5, Some(dec!(1))))
+            .expect("Deposit failed");
+        let account = engine.account(1).expect("Client 1 not found");
+        assert_eq!((account.available, account.held), (dec!(0), dec!(5)));
+
+        engine
+            .process(record(TransactionType::Resolve, 1, 2, None))
+            .expect("Resolve failed");
+        let account = engine.account(1).expect("Client 1 not found");
+        assert_eq!((account.available, account.held), (dec!(5), dec!(0)));
+        assert_eq!(account.open_disputes, 0);
+    }
+
+    #[test]
+    fn test_deposit_hold_on_clock() {
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            deposit_hold: Some(HoldPeriod::Time(Duration::from_secs(3600))),
+            ..EngineConfig::default()
+        });
+        let clock = TestClock::default();
+        engine.set_clock(Arc::new(clock.clone()));
+
+        engine
+            .process(record(TransactionType::Deposit, 1, 1, Some(dec!(10))))
+            .expect("Deposit failed");
+        clock.advance(Duration::from_secs(3599));
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 2, Some(dec!(1)))),
+            Err(TxError::Rejected(RejectionReason::InsufficientFunds))
+        );
+
+        // Released before the first record once the hour is up
+        clock.advance(Duration::from_secs(1));
+        engine
+            .process(record(TransactionType::Withdrawal, 1, 3, Some(dec!(1))))
+            .expect("Withdrawal failed");
+        let account = engine.account(1).expect("Client 1 not found");
+        assert_eq!((account.available, account.held), (dec!(9), dec!(0)));
+    }
+
+    #[test]
+    fn test_deposit_hold_on_virtual_time() {
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            deposit_hold: Some(HoldPeriod::Time(Duration::from_secs(3600))),
+            virtual_time: true,
+            ..EngineConfig::default()
+        });
+        let stamped = |tx_type, tx, amount, at: &str| TransactionRecord {
+            timestamp: Some(at.parse().unwrap()),
+            ..record(tx_type, 1, tx, amount)
+        };
+
+        engine
+            .process(stamped(
+                TransactionType::Deposit,
+                1,
+                Some(dec!(10)),
+                "2020-01-01T00:00:00Z",
+            ))
+            .expect("Deposit failed");
+        assert_eq!(
+            engine.process(stamped(
+                TransactionType::Withdrawal,
+                2,
+                Some(dec!(1)),
+                "2020-01-01T00:59:59Z"
+            )),
+            Err(TxError::Rejected(RejectionReason::InsufficientFunds))
+        );
+        // A record without a timestamp, or stamped earlier, does not move the time
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 3, Some(dec!(1)))),
+            Err(TxError::Rejected(RejectionReason::InsufficientFunds))
+        );
+        assert_eq!(
+            engine.process(stamped(
+                TransactionType::Withdrawal,
+                4,
+                Some(dec!(1)),
+                "2019-12-31T00:00:00Z"
+            )),
+            Err(TxError::Rejected(RejectionReason::InsufficientFunds))
+        );
+
+        // Released once a record is stamped an hour after the deposit
+        engine
+            .process(stamped(
+                TransactionType::Withdrawal,
+                5,
+                Some(dec!(1)),
+                "2020-01-01T01:00:00Z",
+            ))
+            .expect("Withdrawal failed");
+        let account = engine.account(1).expect("Client 1 not found");
+        assert_eq!((account.available, account.held), (dec!(9), dec!(0)));
+    }
+
+    #[test]
+    fn test_recovery_on_release() {
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            deposit_hold: Some(HoldPeriod::Records(1)),
+            recovery_sweep: true,
+            ..EngineConfig::default()
+        });
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+            record(TransactionType::Withdrawal, 1, 2, Some(dec!(10))),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Deposit, 1, 3, Some(dec!(4))),
+        ] {
+            engine.process(r).expect("Record rejected");
+        }
+        assert!(engine.recoveries.is_empty());
+
+        // Deposit 3 reaches available, and the deficit, before the next record
+        engine
+            .process(record(TransactionType::Deposit, 2, 4, Some(dec!(1))))
+            .expect("Deposit failed");
+        let report = engine.into_report();
+        assert_eq!(report.recoveries.len(), 1);
+        assert_eq!(report.recoveries[0].position, 5);
+        assert_eq!(report.recoveries[0].tx, 3);
+        assert_eq!(report.recoveries[0].recovered, dec!(4));
+        assert_eq!(report.recoveries[0].remaining_deficit, dec!(6));
+    }
+
+    #[test]
+    fn test_fail_fast_validation() {
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            validation: ValidationConfig {
+                abort_on_violation: true,
+                ..ValidationConfig::strict()
+            },
+            ..EngineConfig::default()
+        });
+        engine
+            .process(record(TransactionType::Deposit, 1, 1, Some(dec!(10))))
+            .expect("Deposit failed");
+
+        // Ordinary rejections carry on
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 2, Some(dec!(20)))),
+            Err(TxError::Rejected(RejectionReason::InsufficientFunds))
+        );
+        let err = engine
+            .process(record(TransactionType::Deposit, 1, 3, Some(dec!(0.00001))))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            TxError::Violation(r) if r.reason == RejectionReason::ExcessPrecision && r.tx == Some(3)
+        ));
+        assert!(matches!(
+            engine.process(record(TransactionType::Deposit, 1, 1, Some(dec!(1)))),
+            Err(TxError::Violation(r)) if r.reason == RejectionReason::DuplicateTxId
+        ));
+        assert_eq!(engine.account(1).map(|a| a.total), Some(dec!(10)));
+        assert_eq!(engine.client_stats(1).map(|s| s.deposit.rejected), Some(2));
+    }
+
+    #[test]
+    fn test_risk_rules() {
+        /// Refuses withdrawals over half the available balance
+        #[derive(Debug)]
+        struct HalfBalance;
+
+        impl RiskRule for HalfBalance {
+            fn check(
+                &mut self,
+                record: &TransactionRecord,
+                account: Option<&Account>,
+            ) -> Result<(), RejectionReason> {
+                match (record.tx_type, record.amount, account) {
+                    (TransactionType::Withdrawal, Some(amount), Some(account))
+                        if amount * dec!(2) > account.available =>
+                    {
+                        Err(RejectionReason::RiskRule)
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        engine.add_risk_rule(Box::new(HalfBalance));
+        engine
+            .process(record(TransactionType::Deposit, 1, 1, Some(dec!(10))))
+            .expect("Deposit failed");
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 2, Some(dec!(6)))),
+            Err(TxError::Rejected(RejectionReason::RiskRule))
+        );
+        engine
+            .process(record(TransactionType::Withdrawal, 1, 3, Some(dec!(5))))
+            .expect("Withdrawal failed");
+        assert_eq!(engine.account(1).map(|a| a.available), Some(dec!(5)));
+    }
+
+    #[test]
+    fn test_transformers() {
+        /// Partner sends amounts in cents
+        #[derive(Debug)]
+        struct Cents;
+
+        impl RecordTransformer for Cents {
+            fn transform(
+                &mut self,
+                record: TransactionRecord,
+            ) -> Result<TransactionRecord, RejectionReason> {
+                if record.client == 0 {
+                    return Err(RejectionReason::RiskRule);
+                }
+                Ok(TransactionRecord {
+                    amount: record.amount.map(|a| a / dec!(100)),
+                    ..record
+                })
+            }
+        }
+
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        engine.add_transformer(Box::new(Cents));
+        engine
+            .process(record(TransactionType::Deposit, 1, 1, Some(dec!(250))))
+            .expect("Deposit failed");
+        assert_eq!(
+            engine.process(record(TransactionType::Deposit, 0, 2, Some(dec!(1)))),
+            Err(TxError::Rejected(RejectionReason::RiskRule))
+        );
+        assert_eq!(engine.account(1).map(|a| a.total), Some(dec!(2.5)));
+        assert_eq!(engine.client_stats(0).map(|s| s.deposit.rejected), Some(1));
+    }
+
+    #[test]
+    fn test_audit_events() {
+        #[derive(Debug, Default)]
+        struct Collect(Vec<AuditEvent>);
+
+        impl AuditSink for Collect {
+            fn record(&mut self, event: &AuditEvent) {
+                self.0.push(*event);
+            }
+        }
+
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Collect::default()));
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        engine.set_audit_sink(Box::new(events.clone()));
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+            record(TransactionType::Withdrawal, 1, 2, Some(dec!(15))),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 1, 9, None),
+            record(TransactionType::Chargeback, 1, 1, None),
+        ] {
+            let _ = engine.process(r);
+        }
+
+        let events = &events.lock().unwrap().0;
+        let kinds: Vec<AuditEventKind> = events.iter().map(|e| e.event).collect();
+        assert_eq!(
+            kinds,
+            [
+                AuditEventKind::DepositApplied,
+                AuditEventKind::WithdrawalDenied,
+                AuditEventKind::FundsHeld,
+                AuditEventKind::Chargeback,
+                AuditEventKind::AccountLocked,
+            ]
+        );
+        assert_eq!(events[1].reason, Some(RejectionReason::InsufficientFunds));
+        assert_eq!(events[1].before, events[1].after);
+        assert_eq!(events[2].amount, Some(dec!(10)));
+        assert_eq!(events[2].after.held, dec!(10));
+        assert_eq!(events[3].position, 5);
+        assert_eq!(events[3].before.held, dec!(10));
+        assert_eq!(events[3].after.total, dec!(0));
+        assert!(events[4].after.locked);
+    }
+
+    #[test]
+    fn test_close_account() {
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            close_dust: dec!(0.01),
+            ..EngineConfig::default()
+        });
+        let rejected = |engine: &mut PaymentsEngine, r| match engine.process(r) {
+            Err(TxError::Rejected(reason)) => reason,
+            other => panic!("Expected a rejection, got {:?}", other),
+        };
+
+        engine
+            .process(record(TransactionType::Deposit, 1, 1, Some(dec!(40))))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        assert_eq!(
+            rejected(&mut engine, record(TransactionType::Close, 1, 2, None)),
+            RejectionReason::FundsHeld
+        );
+        engine
+            .process(record(TransactionType::Resolve, 1, 1, None))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Close, 1, 2, None))
+            .unwrap();
+
+        let account = engine.account(1).unwrap();
+        assert!(account.is_closed() && account.is_locked());
+        assert_eq!((account.available, account.total), (dec!(0), dec!(0)));
+        for r in [
+            record(TransactionType::Deposit, 1, 3, Some(dec!(5))),
+            record(TransactionType::Close, 1, 4, None),
+        ] {
+            assert_eq!(rejected(&mut engine, r), RejectionReason::AccountClosed);
+        }
+
+        // Dust is written off, a larger debt blocks the close
+        engine
+            .process(record(TransactionType::Deposit, 2, 5, Some(dec!(0.01))))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Close, 2, 6, None))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Deposit, 3, 7, Some(dec!(5))))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Withdrawal, 3, 8, Some(dec!(5))))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Dispute, 3, 7, None))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Chargeback, 3, 7, None))
+            .unwrap();
+        assert_eq!(
+            rejected(&mut engine, record(TransactionType::Close, 3, 9, None)),
+            RejectionReason::AccountLocked
+        );
+
+        let report = engine.into_report();
+        assert_eq!(
+            report.closures,
+            [
+                Closure::new(5, 1, 2, dec!(40), dec!(0.01)),
+                Closure::new(9, 2, 6, dec!(0.01), dec!(0.01)),
+            ]
+        );
+        assert_eq!(report.closures[0].payout, dec!(40));
+        assert_eq!(report.closures[1].written_off, dec!(0.01));
+        assert_eq!(report.flows.payouts, dec!(40));
+        assert_eq!(
+            report.flows.expected_total(),
+            report.accounts.values().map(|a| a.total).sum::<Decimal>()
+        );
+        assert_eq!(report.rejections[&RejectionReason::AccountClosed], 2);
+    }
+
+    /// Two disputed deposits, the first charged back
+    fn locked_with_open_dispute(engine: &mut PaymentsEngine) {
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+            record(TransactionType::Deposit, 1, 2, Some(dec!(20))),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 1, 2, None),
+            record(TransactionType::Chargeback, 1, 1, None),
+        ] {
+            engine.process(r).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_locked_account_policy() {
+        // Spec behavior: the other dispute can never be resolved
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        locked_with_open_dispute(&mut engine);
+        assert_eq!(
+            engine.process(record(TransactionType::Resolve, 1, 2, None)),
+            Err(TxError::Rejected(RejectionReason::AccountLocked))
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Unlock, 1, 3, None)),
+            Err(TxError::Rejected(RejectionReason::UnlockDisabled))
+        );
+
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            account_policy: AccountPolicy {
+                locked_accepts: vec![TransactionType::Deposit, TransactionType::Resolve],
+                allow_unlock: false,
+            },
+            ..EngineConfig::default()
+        });
+        locked_with_open_dispute(&mut engine);
+        engine
+            .process(record(TransactionType::Resolve, 1, 2, None))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Deposit, 1, 3, Some(dec!(5))))
+            .unwrap();
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 4, Some(dec!(1)))),
+            Err(TxError::Rejected(RejectionReason::AccountLocked))
+        );
+        let account = engine.account(1).unwrap();
+        assert!(account.is_locked());
+        assert_eq!((account.available, account.held), (dec!(25), dec!(0)));
+    }
+
+    #[test]
+    fn test_unlock() {
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            account_policy: AccountPolicy {
+                locked_accepts: Vec::new(),
+                allow_unlock: true,
+            },
+            ..EngineConfig::default()
+        });
+        assert_eq!(
+            engine.process(record(TransactionType::Unlock, 2, 9, None)),
+            Err(TxError::Rejected(RejectionReason::NotLocked))
+        );
+        locked_with_open_dispute(&mut engine);
+        engine
+            .process(record(TransactionType::Unlock, 1, 3, None))
+            .unwrap();
+        let account = engine.account(1).unwrap();
+        assert!(!account.is_locked());
+        assert_eq!(account.lock_reason, None);
+
+        // The other dispute carries on, and a new chargeback locks again
+        engine
+            .process(record(TransactionType::Deposit, 1, 4, Some(dec!(5))))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Chargeback, 1, 2, None))
+            .unwrap();
+        assert!(engine.account(1).unwrap().is_locked());
+
+        // Closing is final
+        engine
+            .process(record(TransactionType::Unlock, 1, 5, None))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Close, 1, 6, None))
+            .unwrap();
+        assert_eq!(
+            engine.process(record(TransactionType::Unlock, 1, 7, None)),
+            Err(TxError::Rejected(RejectionReason::AccountClosed))
+        );
+    }
+
+    #[test]
+    fn test_charged_back_is_final() {
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            account_policy: AccountPolicy {
+                locked_accepts: Vec::new(),
+                allow_unlock: true,
+            },
+            ..EngineConfig::default()
+        });
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(100))),
+            record(TransactionType::Deposit, 1, 2, Some(dec!(50))),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Chargeback, 1, 1, None),
+            record(TransactionType::Unlock, 1, 3, None),
+        ] {
+            engine.process(r).unwrap();
+        }
+        // Resolving after the unlock would hand the charged back funds out again
+        assert_eq!(
+            engine.process(record(TransactionType::Resolve, 1, 1, None)),
+            Err(TxError::Rejected(RejectionReason::ChargedBack))
+        );
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (dec!(50), dec!(0), dec!(50))
+        );
+    }
+
+    #[test]
+    fn test_double_chargeback() {
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            account_policy: AccountPolicy {
+                locked_accepts: vec![TransactionType::Resolve, TransactionType::Chargeback],
+                allow_unlock: false,
+            },
+            check_invariants: true,
+            ..EngineConfig::default()
+        });
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(100))),
+            record(TransactionType::Deposit, 1, 2, Some(dec!(50))),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Chargeback, 1, 1, None),
+        ] {
+            engine.process(r).unwrap();
+        }
+        assert_eq!(
+            engine.process(record(TransactionType::Chargeback, 1, 1, None)),
+            Err(TxError::Rejected(RejectionReason::ChargedBack))
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Resolve, 1, 1, None)),
+            Err(TxError::Rejected(RejectionReason::ChargedBack))
+        );
+        let account = engine.account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (dec!(50), dec!(0), dec!(50))
+        );
+    }
+
+    #[test]
+    fn test_close_negative_balance() {
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+            record(TransactionType::Withdrawal, 1, 2, Some(dec!(10))),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Resolve, 1, 1, None),
+        ] {
+            engine.process(r).unwrap();
+        }
+        // Nothing left, nothing to pay out
+        engine
+            .process(record(TransactionType::Close, 1, 3, None))
+            .unwrap();
+        assert_eq!(engine.closures, [Closure::new(5, 1, 3, dec!(0), dec!(0))]);
+
+        // A debt, as carried over from an earlier run
+        let mut account = Account::new(2);
+        account.available = dec!(-5);
+        account.total = dec!(-5);
+        let snapshot = Snapshot {
+            accounts: HashMap::from([(2, account)]),
+            ..Snapshot::default()
+        };
+        let mut engine = PaymentsEngine::from_snapshot(EngineConfig::default(), snapshot);
+        assert_eq!(
+            engine.process(record(TransactionType::Close, 2, 3, None)),
+            Err(TxError::Rejected(RejectionReason::NegativeBalance))
+        );
+        assert!(!engine.account(2).unwrap().is_closed());
+    }
+
+    #[test]
+    fn test_diff_between_checkpoints() {
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(5))),
+            record(TransactionType::Deposit, 2, 2, Some(dec!(3))),
+        ] {
+            engine.process(r).expect("Deposit failed");
+        }
+        let checkpoint = engine.snapshot().unwrap();
+        let earlier = PaymentsEngine::from_snapshot(EngineConfig::default(), checkpoint.clone());
+
+        engine
+            .process(record(TransactionType::Dispute, 2, 2, None))
+            .expect("Dispute failed");
+        engine
+            .process(record(TransactionType::Deposit, 3, 3, Some(dec!(1))))
+            .expect("Deposit failed");
+
+        let deltas = engine.diff_since(&checkpoint);
+        assert_eq!(earlier.diff(&engine), deltas);
+        assert_eq!(
+            deltas.iter().map(|d| d.client).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(deltas[0].held_delta(), dec!(3));
+        assert_eq!(deltas[0].total_delta(), dec!(0));
+        assert_eq!(deltas[1].before, None);
+        assert!(engine.diff(&engine).is_empty());
+    }
+
+    #[test]
+    fn test_balances_as_of() {
+        let stamped = |r: TransactionRecord, at: &str| TransactionRecord {
+            timestamp: Some(at.parse().unwrap()),
+            ..r
+        };
+        let records = [
+            stamped(
+                record(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+                "2024-01-31T09:00:00Z",
+            ),
+            stamped(
+                record(TransactionType::Deposit, 2, 2, Some(dec!(4))),
+                "2024-01-31T17:30:00Z",
+            ),
+            stamped(
+                record(TransactionType::Dispute, 1, 1, None),
+                "2024-01-31T23:59:59Z",
+            ),
+            stamped(
+                record(TransactionType::Withdrawal, 2, 3, Some(dec!(1))),
+                "2024-02-01T00:00:00Z",
+            ),
+        ];
+
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        assert_eq!(
+            engine.balances_as_of("2024-02-01T00:00:00Z".parse().unwrap()),
+            None
+        );
+        engine.keep_balance_history();
+        for r in records {
+            engine.process(r).unwrap();
+        }
+        assert_eq!(
+            engine.transactions().get(1).unwrap().unwrap().timestamp,
+            records[0].timestamp
+        );
+
+        let end_of_day: Timestamp = "2024-01-31T23:59:59Z".parse().unwrap();
+        let mut replayed = PaymentsEngine::new(EngineConfig::default());
+        for r in records.iter().filter(|r| r.timestamp <= Some(end_of_day)) {
+            replayed.process(*r).unwrap();
+        }
+        assert_eq!(
+            engine.balances_as_of(end_of_day).as_ref(),
+            Some(replayed.accounts())
+        );
+        assert!(engine
+            .balances_as_of("2024-01-31T08:59:59Z".parse().unwrap())
+            .unwrap()
+            .is_empty());
+
+        engine
+            .process(record(TransactionType::Deposit, 3, 4, Some(dec!(1))))
+            .unwrap();
+        assert_eq!(engine.balances_as_of(end_of_day), None);
+    }
+
+    #[test]
+    fn test_duplicate_under_fail_policy() {
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            ref_dedup: DedupPolicy::Fail,
+            ..EngineConfig::default()
+        });
+        engine
+            .process(record(TransactionType::Deposit, 1, 1, Some(dec!(5))))
+            .expect("Deposit failed");
+        engine
+            .process(record(TransactionType::Dispute, 1, 1, None))
+            .expect("Dispute failed");
+
+        let err = engine
+            .process(record(TransactionType::Dispute, 1, 1, None))
+            .expect_err("Duplicate accepted");
+        assert!(matches!(err, TxError::Duplicate(d) if d.position == 3));
+    }
+
+    #[test]
+    fn test_dispute_before_deposit_not_duplicate() {
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            ref_dedup: DedupPolicy::Drop,
+            ..EngineConfig::default()
+        });
+        assert_eq!(
+            engine.process(record(TransactionType::Dispute, 1, 5, None)),
+            Err(TxError::Rejected(RejectionReason::UnknownTx))
+        );
+        engine
+            .process(record(TransactionType::Deposit, 1, 5, Some(dec!(10))))
+            .unwrap();
+        // The real dispute, e.g. re-fed from the dead letters of the first
+        engine
+            .process(record(TransactionType::Dispute, 1, 5, None))
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().held, dec!(10));
+
+        // Its retransmission is the duplicate
+        assert_eq!(
+            engine.process(record(TransactionType::Dispute, 1, 5, None)),
+            Err(TxError::Rejected(RejectionReason::DuplicateReference))
+        );
+    }
+
+    #[test]
+    fn test_run_until_cancelled() {
+        use crate::cancel::CancelReason;
+        use crate::input::{self, InputFormat, MalformedRecord};
+
+        /// Cancels its token once `after` records have been read
+        struct CancelAfter {
+            source: Box<dyn RecordSource>,
+            token: CancellationToken,
+            after: u64,
+        }
+
+        impl RecordSource for CancelAfter {
+            fn next_record(&mut self) -> Option<Result<TransactionRecord, MalformedRecord>> {
+                self.after = self.after.saturating_sub(1);
+                if self.after == 0 {
+                    self.token.cancel();
+                }
+                self.source.next_record()
+            }
+        }
+
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   withdrawal,1,2,50\n\
+                   bogus,1,3,1\n\
+                   deposit,2,4,5\n\
+                   deposit,2,5,5\n";
+        let token = CancellationToken::new();
+        let mut source = CancelAfter {
+            source: input::from_reader(csv.as_bytes(), InputFormat::Csv).unwrap(),
+            token: token.clone(),
+            after: 4,
+        };
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        let err = engine
+            .run(&mut source, &token)
+            .expect_err("Cancelled run finished");
+        let TxError::Cancelled(cancelled) = err else {
+            panic!("Expected cancellation, got {:?}", err);
+        };
+        assert_eq!(cancelled.reason, CancelReason::Cancelled);
+        assert_eq!(
+            cancelled.stats,
+            RunStats {
+                applied: 2,
+                rejected: 1,
+                malformed: 1,
+            }
+        );
+        assert_eq!(err.to_string(), "run cancelled after 4 records (2 applied)");
+        // Stopped between records: the state so far is whole and the run can go on
+        assert_eq!(engine.records_processed(), 3);
+        assert_eq!(engine.account(2).map(|a| a.total), Some(dec!(5)));
+        let stats = engine
+            .run(&mut source, &CancellationToken::new())
+            .expect("Resumed run failed");
+        assert_eq!(stats.applied, 1);
+        assert_eq!(engine.account(2).map(|a| a.total), Some(dec!(10)));
+
+        let expired = CancellationToken::with_timeout(Duration::ZERO);
+        let mut rest = input::from_reader(csv.as_bytes(), InputFormat::Csv).unwrap();
+        assert!(matches!(
+            PaymentsEngine::new(EngineConfig::default()).run(rest.as_mut(), &expired),
+            Err(TxError::Cancelled(Cancelled {
+                reason: CancelReason::DeadlineExceeded,
+                ..
+            }))
+        ));
+    }
+}
@@ -0,0 +1,28 @@
+//! Identity of the events sent to event sinks
+//!
+//! Each applied record is one event, identified by its type, client and tx id,
+//! which stay the same when the record is fed again. With
+//! `EngineConfig::dedup_events` the engine calls event sinks once per id and
+//! keeps the ids in snapshots, so re-running or resuming over input that was
+//! already processed does not notify consumers twice.
+
+use crate::types::{ClientId, TransactionId, TransactionRecord, TransactionType};
+
+/// What an event sink notification is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventId {
+    pub tx_type: TransactionType,
+    pub client: ClientId,
+    pub tx: TransactionId,
+}
+
+impl EventId {
+    /// The event of an applied record
+    pub fn of(record: &TransactionRecord) -> Self {
+        Self {
+            tx_type: record.tx_type,
+            client: record.client,
+            tx: record.tx,
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use crate::types::{Account, ClientId, StoredTransaction, TransactionId};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+/// Upper bounds (exclusive) of the dispute age buckets in records applied since the dispute
+const AGE_BUCKET_BOUNDS: [u64; 3] = [1_000, 10_000, 100_000];
+
+/// Upper bounds (exclusive) of the dispute age buckets in whole days on the engine's clock
+/// Under `--virtual-time-from-timestamps` these are days of the record timestamps
+const DAY_BUCKET_BOUNDS: [u64; 3] = [7, 30, 90];
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Open disputes falling into one age range
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AgeBucket {
+    /// Inclusive lower bound, in records or days
+    pub min_age: u64,
+    /// Exclusive upper bound, `None` for the last open-ended bucket
+    pub max_age: Option<u64>,
+    pub count: usize,
+    pub amount: Decimal,
+}
+
+/// When the open disputes were opened, on the engine's clock
+#[derive(Debug, Clone, Copy)]
+pub struct DisputeTimes<'a> {
+    /// Disputes opened in the run
+    pub opened: &'a HashMap<TransactionId, SystemTime>,
+    /// Clock time at the first record, for disputes opened before the run
+    pub started: SystemTime,
+    /// Clock time at the end of the run
+    pub now: SystemTime,
+}
+
+/// Empty buckets bounded by `bounds`, the last one open-ended
+fn buckets(bounds: [u64; 3]) -> Vec<AgeBucket> {
+    std::iter::once(0)
+        .chain(bounds)
+        .zip(bounds.map(Some).into_iter().chain([None]))
+        .map(|(min_age, max_age)| AgeBucket {
+            min_age,
+            max_age,
+            ..AgeBucket::default()
+        })
+        .collect()
+}
+
+/// Count a dispute of `amount` into the bucket for `age`
+fn add_to_bucket(buckets: &mut [AgeBucket], age: u64, amount: Decimal) {
+    let bucket = buckets
+        .iter_mut()
+        .find(|b| b.max_age.is_none_or(|max| age < max))
+        .expect("last bucket is open-ended");
+    bucket.count += 1;
+    bucket.amount += amount;
+}
+
+/// Potential chargeback liability of a single client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientExposure {
+    pub client: ClientId,
+    pub held: Decimal,
+    pub open_disputes: usize,
+    /// Amount that would be clawed back if every open dispute were charged back
+    pub liability: Decimal,
+}
+
+/// Run-level summary of held funds and open dispute exposure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExposureReport {
+    pub total_held: Decimal,
+    pub open_disputes: usize,
+    pub open_dispute_amount: Decimal,
+    /// Open disputes by records applied since the dispute
+    pub age_buckets: Vec<AgeBucket>,
+    /// Open disputes by days since the dispute
+    pub day_buckets: Vec<AgeBucket>,
+    /// Clients with open disputes, largest liability first
+    pub clients: Vec<ClientExposure>,
+}
+
+impl ExposureReport {
+    /// Compute the report from end-of-run state
+    /// `records_applied` is the position of the last record, used with `times`
+    /// to age disputes
+    pub fn compute(
+        accounts: &HashMap<ClientId, Account>,
+        transactions: &HashMap<TransactionId, StoredTransaction>,
+        records_applied: u64,
+        times: &DisputeTimes,
+    ) -> Self {
+        let mut age_buckets = buckets(AGE_BUCKET_BOUNDS);
+        let mut day_buckets = buckets(DAY_BUCKET_BOUNDS);
+
+        let mut clients: HashMap<ClientId, ClientExposure> = HashMap::new();
+        let mut open_disputes = 0;
+        let mut open_dispute_amount = Decimal::ZERO;
+
+        for (tx, stored_tx) in transactions.iter().filter(|(_, tx)| tx.is_open_dispute()) {
+            open_disputes += 1;
+            open_dispute_amount += stored_tx.amount;
+
+            let age = records_applied.saturating_sub(stored_tx.disputed_at.unwrap_or(0));
+            add_to_bucket(&mut age_buckets, age, stored_tx.amount);
+            let since = times.opened.get(tx).unwrap_or(&times.started);
+            let days = times
+                .now
+                .duration_since(*since)
+                .unwrap_or_default()
+                .as_secs()
+                / SECS_PER_DAY;
+            add_to_bucket(&mut day_buckets, days, stored_tx.amount);
+
+            let client = clients
+                .entry(stored_tx.client_id)
+                .or_insert_with(|| ClientExposure {
+                    client: stored_tx.client_id,
+                    held: accounts
+                        .get(&stored_tx.client_id)
+                        .map_or(Decimal::ZERO, |a| a.held),
+                    open_disputes: 0,
+                    liability: Decimal::ZERO,
+                });
+            client.open_disputes += 1;
+            client.liability += stored_tx.amount;
+        }
+
+        let mut clients: Vec<_> = clients.into_values().collect();
+        clients.sort_by(|a, b| b.liability.cmp(&a.liability).then(a.client.cmp(&b.client)));
+
+        Self {
+            total_held: accounts.values().map(|a| a.held).sum(),
+            open_disputes,
+            open_dispute_amount,
+            age_buckets,
+            day_buckets,
+            clients,
+        }
+    }
+
+    /// Write the report as plain text
+    pub fn write_text<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "Exposure report")?;
+        writeln!(out, "total held: {}", self.total_held.round_dp(4))?;
+        writeln!(
+            out,
+            "open disputes: {} ({})",
+            self.open_disputes,
+            self.open_dispute_amount.round_dp(4)
+        )?;
+
+        for (unit, buckets) in [("records", &self.age_buckets), ("days", &self.day_buckets)] {
+            writeln!(out, "open disputes by age ({} since dispute):", unit)?;
+            for bucket in buckets {
+                let range = match bucket.max_age {
+                    Some(max) => format!("{}-{}", bucket.min_age, max - 1),
+                    None => format!("{}+", bucket.min_age),
+                };
+                writeln!(
+                    out,
+                    "  {}: {} ({})",
+                    range,
+                    bucket.count,
+                    bucket.amount.round_dp(4)
+                )?;
+            }
+        }
+
+        writeln!(out, "potential chargeback liability by client:")?;
+        for client in &self.clients {
+            writeln!(
+                out,
+                "  client {}: {} across {} open disputes (held {})",
+                client.client,
+                client.liability.round_dp(4),
+                client.open_disputes,
+                client.held.round_dp(4)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TransactionType;
+    use rust_decimal_macros::dec;
+    use std::time::Duration;
+
+    fn days(n: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(n * SECS_PER_DAY)
+    }
+
+    fn disputed(client: ClientId, amount: Decimal, at: u64) -> StoredTransaction {
+        let mut tx = StoredTransaction::new(client, TransactionType::Deposit, amount);
+        tx.mark_disputed();
+        tx.disputed_at = Some(at);
+        tx
+    }
+
+    #[test]
+    fn test_exposure_report() {
+        let mut accounts = HashMap::new();
+        let mut transactions = HashMap::new();
+
+        let mut account = Account::new(1);
+        account.deposit(dec!(100));
+        account.hold_funds(dec!(100));
+        accounts.insert(1, account);
+        transactions.insert(1, disputed(1, dec!(100), 20_500));
+
+        let mut account = Account::new(2);
+        account.deposit(dec!(30));
+        account.deposit(dec!(20));
+        account.hold_funds(dec!(50));
+        accounts.insert(2, account);
+        transactions.insert(2, disputed(2, dec!(30), 1));
+        transactions.insert(3, disputed(2, dec!(20), 20_900));
+
+        // Charged back disputes are no longer exposure
+        let mut charged = disputed(2, dec!(999), 5);
+        charged.mark_charged_back();
+        transactions.insert(4, charged);
+
+        // Transaction 2 was disputed before the run
+        let opened = HashMap::from([(1, days(95)), (3, days(60)), (4, days(0))]);
+        let times = DisputeTimes {
+            opened: &opened,
+            started: days(10),
+            now: days(100),
+        };
+        let report = ExposureReport::compute(&accounts, &transactions, 21_000, &times);
+
+        assert_eq!(report.total_held, dec!(150));
+        assert_eq!(report.open_disputes, 3);
+        assert_eq!(report.open_dispute_amount, dec!(150));
+
+        let counts: Vec<_> = report.age_buckets.iter().map(|b| b.count).collect();
+        assert_eq!(counts, vec![2, 0, 1, 0]);
+        assert_eq!(report.age_buckets[0].amount, dec!(120));
+        assert_eq!(report.age_buckets[2].amount, dec!(30));
+        assert_eq!(report.age_buckets[3].max_age, None);
+
+        let counts: Vec<_> = report.day_buckets.iter().map(|b| b.count).collect();
+        assert_eq!(counts, vec![1, 0, 1, 1]);
+        assert_eq!(report.day_buckets[2].amount, dec!(20));
+        assert_eq!(report.day_buckets[3].amount, dec!(30));
+
+        assert_eq!(report.clients.len(), 2);
+        assert_eq!(report.clients[0].client, 1);
+        assert_eq!(report.clients[0].liability, dec!(100));
+        assert_eq!(report.clients[1].open_disputes, 2);
+        assert_eq!(report.clients[1].held, dec!(50));
+    }
+
+    #[test]
+    fn test_write_text() {
+        let times = DisputeTimes {
+            opened: &HashMap::new(),
+            started: SystemTime::UNIX_EPOCH,
+            now: SystemTime::UNIX_EPOCH,
+        };
+        let report = ExposureReport::compute(&HashMap::new(), &HashMap::new(), 0, &times);
+        let mut buf = Vec::new();
+        report.write_text(&mut buf).unwrap();
+
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.contains("total held: 0"));
+        assert!(text.contains("  0-999: 0 (0)"));
+        assert!(text.contains("  100000+: 0 (0)"));
+        assert!(text.contains("open disputes by age (days since dispute):\n  0-6: 0 (0)"));
+        assert!(text.contains("  90+: 0 (0)"));
+    }
+}
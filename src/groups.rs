@@ -0,0 +1,155 @@
+use crate::types::{serialize_decimal_str, Account, ClientId};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::{self, Read, Write};
+
+/// Client to group (program/merchant) assignment
+pub type Groups = HashMap<ClientId, String>;
+
+/// Group name used for clients missing from the grouping file
+pub const UNGROUPED: &str = "";
+
+#[derive(Deserialize)]
+struct GroupRow {
+    client: ClientId,
+    group: String,
+}
+
+/// Read a `client,group` CSV
+pub fn read_groups<R: Read>(reader: R) -> Result<Groups, Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+
+    let mut groups = Groups::new();
+    for row in reader.deserialize() {
+        let row: GroupRow = row?;
+        if let Some(previous) = groups.insert(row.client, row.group.clone()) {
+            if previous != row.group {
+                return Err(format!(
+                    "client {} assigned to both '{}' and '{}'",
+                    row.client, previous, row.group
+                )
+                .into());
+            }
+        }
+    }
+
+    Ok(groups)
+}
+
+/// Aggregated balances of one group
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GroupTotals {
+    pub group: String,
+    pub clients: u32,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub available: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub held: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub total: Decimal,
+    /// Number of locked clients in the group
+    pub locked: u32,
+}
+
+impl GroupTotals {
+    fn new(group: &str) -> Self {
+        Self {
+            group: group.to_string(),
+            clients: 0,
+            available: Decimal::ZERO,
+            held: Decimal::ZERO,
+            total: Decimal::ZERO,
+            locked: 0,
+        }
+    }
+}
+
+/// Sum account balances per group, ordered by group name
+/// Clients without a group are summed under `UNGROUPED`, so group totals add up to the book
+/// Sums are normalized so their text does not depend on the order accounts were added in
+pub fn roll_up(accounts: &HashMap<ClientId, Account>, groups: &Groups) -> Vec<GroupTotals> {
+    let mut totals: BTreeMap<&str, GroupTotals> = BTreeMap::new();
+
+    for account in accounts.values() {
+        let group = groups
+            .get(&account.client)
+            .map_or(UNGROUPED, String::as_str);
+        let entry = totals
+            .entry(group)
+            .or_insert_with(|| GroupTotals::new(group));
+        entry.clients += 1;
+        entry.available += account.available;
+        entry.held += account.held;
+        entry.total += account.total;
+        entry.locked += u32::from(account.locked);
+    }
+
+    totals
+        .into_values()
+        .map(|mut t| {
+            t.available = t.available.round_dp(4).normalize();
+            t.held = t.held.round_dp(4).normalize();
+            t.total = t.total.round_dp(4).normalize();
+            t
+        })
+        .collect()
+}
+
+/// Write group totals as CSV
+pub fn write_group_totals<W: Write>(totals: &[GroupTotals], writer: W) -> io::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for group in totals {
+        writer.serialize(group)?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_read_groups() {
+        let groups = read_groups("client,group\n1, gold\n2,silver\n1,gold\n".as_bytes())
+            .expect("Failed to parse");
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[&1], "gold");
+
+        assert!(read_groups("client,group\n1,gold\n1,silver\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_roll_up() {
+        let mut accounts = HashMap::new();
+        for (client, amount) in [(1, dec!(10.5)), (2, dec!(4.25)), (3, dec!(1))] {
+            let mut account = Account::new(client);
+            account.deposit(amount);
+            accounts.insert(client, account);
+        }
+        accounts.get_mut(&2).unwrap().hold_funds(dec!(4.25));
+        accounts.get_mut(&2).unwrap().chargeback(dec!(4.25));
+
+        let groups = Groups::from([(1, "gold".to_string()), (2, "gold".to_string())]);
+        let totals = roll_up(&accounts, &groups);
+
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals[0].group, UNGROUPED);
+        assert_eq!(totals[0].total, dec!(1));
+        assert_eq!(totals[1].group, "gold");
+        assert_eq!(totals[1].clients, 2);
+        assert_eq!(totals[1].total, dec!(10.5));
+        assert_eq!(totals[1].locked, 1);
+
+        let mut buf = Vec::new();
+        write_group_totals(&totals, &mut buf).expect("Failed to write");
+        assert_eq!(
+            String::from_utf8(buf).expect("Invalid UTF-8"),
+            "group,clients,available,held,total,locked\n,1,1,0,1,0\ngold,2,10.5,0,10.5,1\n"
+        );
+    }
+}
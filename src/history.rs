@@ -0,0 +1,122 @@
+//! Point-in-time balances from timestamped records
+//!
+//! A `BalanceHistory` keeps the state each applied record left its account in,
+//! keyed by the record's timestamp, so balances can be read back as of any
+//! instant without replaying the input. Answers are only exact while every
+//! applied record carries a timestamp and they arrive in time order; once either
+//! fails the history stops answering rather than guess.
+
+use crate::timestamp::Timestamp;
+use crate::types::{Account, ClientId};
+use std::collections::HashMap;
+
+/// Account states by the timestamp of the record that produced them
+#[derive(Debug, Clone)]
+pub struct BalanceHistory {
+    /// Accounts as they were before the first recorded change
+    opening: HashMap<ClientId, Account>,
+    /// Per client, in time order
+    states: HashMap<ClientId, Vec<(Timestamp, Account)>>,
+    /// Latest timestamp recorded
+    latest: Option<Timestamp>,
+    /// Cleared by a record without a timestamp or one earlier than `latest`
+    usable: bool,
+}
+
+impl BalanceHistory {
+    /// History starting from `opening`, e.g. accounts resumed from a snapshot
+    pub fn new(opening: HashMap<ClientId, Account>) -> Self {
+        Self {
+            opening,
+            states: HashMap::new(),
+            latest: None,
+            usable: true,
+        }
+    }
+
+    /// Keep the state `account` was left in by an applied record
+    pub fn record(&mut self, timestamp: Option<Timestamp>, account: &Account) {
+        if !self.usable {
+            return;
+        }
+        match timestamp {
+            Some(t) if self.latest.is_none_or(|latest| t >= latest) => {
+                self.latest = Some(t);
+                self.states
+                    .entry(account.client)
+                    .or_default()
+                    .push((t, account.clone()));
+            }
+            _ => {
+                self.usable = false;
+                self.states.clear();
+            }
+        }
+    }
+
+    /// Accounts as they stood at `at`, after every record stamped at or before it
+    /// Clients without an account by then are left out. `None` if a record came
+    /// without a timestamp or out of time order
+    pub fn as_of(&self, at: Timestamp) -> Option<HashMap<ClientId, Account>> {
+        if !self.usable {
+            return None;
+        }
+        let mut accounts = self.opening.clone();
+        for (client, states) in &self.states {
+            let applied = states.partition_point(|(t, _)| *t <= at);
+            if let Some((_, account)) = applied.checked_sub(1).map(|i| &states[i]) {
+                accounts.insert(*client, account.clone());
+            }
+        }
+        Some(accounts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn ts(s: &str) -> Timestamp {
+        s.parse().unwrap()
+    }
+
+    fn account(client: ClientId, available: rust_decimal::Decimal) -> Account {
+        let mut account = Account::new(client);
+        account.deposit(available);
+        account
+    }
+
+    #[test]
+    fn test_as_of() {
+        let mut history = BalanceHistory::new(HashMap::from([(9, account(9, dec!(1)))]));
+        history.record(Some(ts("2024-01-01T10:00:00Z")), &account(1, dec!(5)));
+        history.record(Some(ts("2024-01-01T12:00:00Z")), &account(2, dec!(3)));
+        history.record(Some(ts("2024-01-01T12:00:00Z")), &account(1, dec!(7)));
+        history.record(Some(ts("2024-01-02T00:00:00Z")), &account(1, dec!(2)));
+
+        let before = history.as_of(ts("2024-01-01T09:59:59Z")).unwrap();
+        assert_eq!(before.keys().collect::<Vec<_>>(), [&9]);
+
+        let noon = history.as_of(ts("2024-01-01T12:00:00Z")).unwrap();
+        assert_eq!(noon.len(), 3);
+        assert_eq!(noon[&1].available, dec!(7));
+        assert_eq!(noon[&2].available, dec!(3));
+
+        let later = history.as_of(ts("2030-01-01T00:00:00Z")).unwrap();
+        assert_eq!(later[&1].available, dec!(2));
+    }
+
+    #[test]
+    fn test_unusable_history() {
+        let mut unstamped = BalanceHistory::new(HashMap::new());
+        unstamped.record(Some(ts("2024-01-01T00:00:00Z")), &account(1, dec!(5)));
+        unstamped.record(None, &account(1, dec!(6)));
+        assert_eq!(unstamped.as_of(ts("2024-01-02T00:00:00Z")), None);
+
+        let mut unordered = BalanceHistory::new(HashMap::new());
+        unordered.record(Some(ts("2024-01-02T00:00:00Z")), &account(1, dec!(5)));
+        unordered.record(Some(ts("2024-01-01T00:00:00Z")), &account(2, dec!(6)));
+        assert_eq!(unordered.as_of(ts("2024-01-03T00:00:00Z")), None);
+    }
+}
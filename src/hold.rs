@@ -0,0 +1,253 @@
+//! Deposit hold period
+//!
+//! With a hold period configured, deposits are credited to `held` and only
+//! released to `available` once the period has passed, modelling settlement
+//! delays such as ACH. A dispute during the hold cancels the release: the funds
+//! are already held, and a resolve makes them available like any other.
+
+use crate::clock::Clock;
+use crate::types::{ClientId, TransactionId};
+use rust_decimal::Decimal;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// How long deposits stay held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HoldPeriod {
+    /// Released before the record this many positions after the deposit
+    Records(u64),
+    /// Released before the first record processed once this much time has passed
+    /// on the engine's clock
+    Time(Duration),
+}
+
+impl FromStr for HoldPeriod {
+    type Err = String;
+
+    /// `<n>` records, or a duration `<n>ms`, `<n>s`, `<n>m`, `<n>h`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid hold period: {} (expected a record count or a duration like 30s)",
+                s
+            )
+        };
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let n: u64 = number.parse().map_err(|_| invalid())?;
+        if n == 0 {
+            return Err(invalid());
+        }
+
+        let seconds = match unit {
+            "" => return Ok(HoldPeriod::Records(n)),
+            "ms" => return Ok(HoldPeriod::Time(Duration::from_millis(n))),
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            _ => return Err(invalid()),
+        };
+        let seconds = n.checked_mul(seconds).ok_or_else(invalid)?;
+        Ok(HoldPeriod::Time(Duration::from_secs(seconds)))
+    }
+}
+
+impl fmt::Display for HoldPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HoldPeriod::Records(n) => write!(f, "{} records", n),
+            HoldPeriod::Time(duration) => write!(f, "{:?}", duration),
+        }
+    }
+}
+
+/// When a held deposit is due for release
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Due {
+    Position(u64),
+    At(SystemTime),
+}
+
+/// A deposit waiting out its hold period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingRelease {
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub amount: Decimal,
+    due: Due,
+}
+
+/// Deposits on hold, in the order they fall due
+///
+/// Periods are the same for every deposit, so deposits fall due in the order
+/// they were made and a queue is enough. Cancelled releases stay queued and are
+/// skipped when they come up.
+#[derive(Debug, Default)]
+pub struct HoldQueue {
+    period: Option<HoldPeriod>,
+    queue: VecDeque<PendingRelease>,
+    pending: HashSet<TransactionId>,
+}
+
+impl HoldQueue {
+    /// Queue for the given period, `None` releases deposits immediately
+    pub fn new(period: Option<HoldPeriod>) -> Self {
+        Self {
+            period,
+            ..Self::default()
+        }
+    }
+
+    /// Whether deposits are held at all
+    pub fn is_enabled(&self) -> bool {
+        self.period.is_some()
+    }
+
+    /// Queue the release of a deposit made at `position`
+    pub fn hold(
+        &mut self,
+        position: u64,
+        clock: &dyn Clock,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Decimal,
+    ) {
+        let due = match self.period {
+            Some(HoldPeriod::Records(n)) => Due::Position(position.saturating_add(n)),
+            Some(HoldPeriod::Time(duration)) => Due::At(clock.now() + duration),
+            None => return,
+        };
+        self.queue.push_back(PendingRelease {
+            client,
+            tx,
+            amount,
+            due,
+        });
+        self.pending.insert(tx);
+    }
+
+    /// Whether the deposit is still waiting for its release
+    pub fn is_pending(&self, tx: TransactionId) -> bool {
+        self.pending.contains(&tx)
+    }
+
+    /// Cancel a pending release, returning whether there was one
+    pub fn cancel(&mut self, tx: TransactionId) -> bool {
+        self.pending.remove(&tx)
+    }
+
+    /// Deposits still on hold
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Deposits still on hold, in the order they fall due
+    pub fn into_pending(self) -> Vec<PendingRelease> {
+        let pending = self.pending;
+        self.queue
+            .into_iter()
+            .filter(|release| pending.contains(&release.tx))
+            .collect()
+    }
+
+    /// Take the releases due before the record at `position` is applied
+    pub fn take_due(&mut self, position: u64, clock: &dyn Clock) -> Vec<PendingRelease> {
+        let mut due = Vec::new();
+        while let Some(release) = self.queue.front() {
+            let is_due = match release.due {
+                Due::Position(p) => p <= position,
+                Due::At(at) => at <= clock.now(),
+            };
+            if !is_due {
+                break;
+            }
+            let release = self.queue.pop_front().expect("front exists");
+            if self.pending.remove(&release.tx) {
+                due.push(release);
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{SystemClock, TestClock};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_parse_hold_period() {
+        assert_eq!("3".parse(), Ok(HoldPeriod::Records(3)));
+        assert_eq!(
+            "250ms".parse(),
+            Ok(HoldPeriod::Time(Duration::from_millis(250)))
+        );
+        assert_eq!("2m".parse(), Ok(HoldPeriod::Time(Duration::from_secs(120))));
+        assert_eq!(
+            "1h".parse(),
+            Ok(HoldPeriod::Time(Duration::from_secs(3600)))
+        );
+        assert!("0".parse::<HoldPeriod>().is_err());
+        assert!("5d".parse::<HoldPeriod>().is_err());
+        assert!("s".parse::<HoldPeriod>().is_err());
+        assert!("-1".parse::<HoldPeriod>().is_err());
+    }
+
+    #[test]
+    fn test_release_by_records() {
+        let mut holds = HoldQueue::new(Some(HoldPeriod::Records(2)));
+        holds.hold(1, &SystemClock, 1, 10, dec!(5));
+        holds.hold(2, &SystemClock, 1, 11, dec!(6));
+        holds.hold(3, &SystemClock, 2, 12, dec!(7));
+        assert_eq!(holds.len(), 3);
+
+        assert!(holds.take_due(2, &SystemClock).is_empty());
+        assert!(holds.cancel(11));
+        assert!(!holds.cancel(11));
+
+        let due = holds.take_due(4, &SystemClock);
+        assert_eq!(due.len(), 1);
+        assert_eq!((due[0].tx, due[0].amount), (10, dec!(5)));
+        assert!(holds.is_pending(12));
+
+        let due = holds.take_due(5, &SystemClock);
+        assert_eq!(due.iter().map(|r| r.tx).collect::<Vec<_>>(), vec![12]);
+        assert!(holds.is_empty());
+    }
+
+    #[test]
+    fn test_release_by_time() {
+        let clock = TestClock::default();
+        let mut holds = HoldQueue::new(Some(HoldPeriod::Time(Duration::from_secs(60))));
+        holds.hold(1, &clock, 1, 10, dec!(5));
+        clock.advance(Duration::from_secs(30));
+        holds.hold(2, &clock, 1, 11, dec!(6));
+
+        clock.advance(Duration::from_secs(29));
+        assert!(holds.take_due(1000, &clock).is_empty());
+
+        clock.advance(Duration::from_secs(1));
+        let due = holds.take_due(1001, &clock);
+        assert_eq!(due.iter().map(|r| r.tx).collect::<Vec<_>>(), vec![10]);
+
+        clock.advance(Duration::from_secs(30));
+        let due = holds.take_due(1002, &clock);
+        assert_eq!(due.iter().map(|r| r.tx).collect::<Vec<_>>(), vec![11]);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut holds = HoldQueue::new(None);
+        holds.hold(1, &SystemClock, 1, 10, dec!(5));
+        assert!(!holds.is_enabled());
+        assert!(!holds.is_pending(10));
+        assert!(holds.take_due(100, &SystemClock).is_empty());
+    }
+}
@@ -0,0 +1,283 @@
+//! Transaction input formats
+//!
+//! Every format yields the same `TransactionRecord`s through `RecordSource`:
+//! CSV (`csv_parser`) and newline-delimited JSON, one object per line with the
+//! CSV column names as keys. The path `-` reads standard input.
+
+use crate::csv_parser::{TransactionReader, TransactionRecordIterator};
+use crate::types::TransactionRecord;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+/// A stream of transaction records
+pub trait RecordSource {
+    /// The next record, `None` at end of input
+    /// A malformed record is returned as an error and reading carries on after it
+    fn next_record(&mut self) -> Option<Result<TransactionRecord, MalformedRecord>>;
+}
+
+/// A record that could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MalformedRecord {
+    /// Line in the input, if known
+    pub line: Option<u64>,
+    pub message: String,
+}
+
+impl fmt::Display for MalformedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for MalformedRecord {}
+
+impl From<csv::Error> for MalformedRecord {
+    fn from(e: csv::Error) -> Self {
+        Self {
+            line: e.position().map(|p| p.line()),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Input file format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// From the file extension, else from the first non-blank byte
+    #[default]
+    Auto,
+    Csv,
+    Ndjson,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(InputFormat::Auto),
+            "csv" => Ok(InputFormat::Csv),
+            "ndjson" => Ok(InputFormat::Ndjson),
+            _ => Err(format!(
+                "Unknown input format: {} (expected csv, ndjson or auto)",
+                s
+            )),
+        }
+    }
+}
+
+/// Path standing for standard input
+pub const STDIN: &str = "-";
+
+/// Open a transactions file in the given format, `-` for standard input
+pub fn open<P: AsRef<Path>>(path: P, format: InputFormat) -> io::Result<Box<dyn RecordSource>> {
+    let path = path.as_ref();
+    if path == Path::new(STDIN) {
+        return from_reader(io::stdin().lock(), format);
+    }
+
+    let format = match format {
+        InputFormat::Auto => match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => InputFormat::Csv,
+            Some("ndjson" | "jsonl") => InputFormat::Ndjson,
+            _ => InputFormat::Auto,
+        },
+        format => format,
+    };
+    from_reader(BufReader::new(File::open(path)?), format)
+}
+
+/// Read transactions from any buffered source, sniffing the format under `Auto`
+pub fn from_reader<R: BufRead + 'static>(
+    mut reader: R,
+    format: InputFormat,
+) -> io::Result<Box<dyn RecordSource>> {
+    let format = match format {
+        InputFormat::Auto => sniff(&mut reader)?,
+        format => format,
+    };
+
+    Ok(match format {
+        InputFormat::Ndjson => Box::new(NdjsonReader::new(reader)),
+        _ => Box::new(TransactionReader::from_reader(reader).records()),
+    })
+}
+
+/// Guess the format from the first non-blank byte: `{` starts a JSON object
+/// Only peeks at the buffered start of the input, nothing is consumed
+fn sniff<R: BufRead>(reader: &mut R) -> io::Result<InputFormat> {
+    let buf = reader.fill_buf()?;
+    Ok(match buf.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') => InputFormat::Ndjson,
+        _ => InputFormat::Csv,
+    })
+}
+
+impl<R: Read> RecordSource for TransactionRecordIterator<R> {
+    fn next_record(&mut self) -> Option<Result<TransactionRecord, MalformedRecord>> {
+        self.next().map(|r| r.map_err(MalformedRecord::from))
+    }
+}
+
+/// Newline-delimited JSON reader, blank lines are skipped
+pub struct NdjsonReader<R: BufRead> {
+    reader: R,
+    line: u64,
+    buf: String,
+}
+
+impl<R: BufRead> NdjsonReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: 0,
+            buf: String::new(),
+        }
+    }
+}
+
+impl<R: BufRead> RecordSource for NdjsonReader<R> {
+    fn next_record(&mut self) -> Option<Result<TransactionRecord, MalformedRecord>> {
+        loop {
+            self.buf.clear();
+            self.line += 1;
+            let malformed = |line, message: String| MalformedRecord {
+                line: Some(line),
+                message,
+            };
+
+            match self.reader.read_line(&mut self.buf) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(malformed(self.line, e.to_string()))),
+            }
+            let text = self.buf.trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            return Some(parse_ndjson_line(text, self.line));
+        }
+    }
+}
+
+/// Parse one non-blank NDJSON line
+pub(crate) fn parse_ndjson_line(
+    text: &str,
+    line: u64,
+) -> Result<TransactionRecord, MalformedRecord> {
+    serde_json::from_str::<TransactionRecord>(text)
+        .map(|parsed| TransactionRecord {
+            line: Some(line),
+            ..parsed
+        })
+        .map_err(|e| MalformedRecord {
+            line: Some(line),
+            message: e.to_string(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TransactionType;
+    use rust_decimal_macros::dec;
+
+    fn collect(source: &mut dyn RecordSource) -> Vec<Result<TransactionRecord, MalformedRecord>> {
+        std::iter::from_fn(|| source.next_record()).collect()
+    }
+
+    #[test]
+    fn test_ndjson_records() {
+        let data = r#"{"type":"deposit","client":1,"tx":1,"amount":"1.2345"}
+{"type":"withdrawal","client":1,"tx":2,"amount":0.1234,"seq":3}
+
+{"type":"dispute","client":1,"tx":1}
+{"type":"resolve","client":1,"tx":1,"amount":null}
+{"type":"bogus","client":1,"tx":3}
+{"type":"chargeback","client":1,"tx":1,"amount":""}
+"#;
+        let records = collect(&mut NdjsonReader::new(data.as_bytes()));
+
+        assert_eq!(records.len(), 6);
+        let deposit = records[0].as_ref().unwrap();
+        assert_eq!(deposit.tx_type, TransactionType::Deposit);
+        assert_eq!(deposit.amount, Some(dec!(1.2345)));
+        assert_eq!(deposit.line, Some(1));
+
+        let withdrawal = records[1].as_ref().unwrap();
+        assert_eq!(withdrawal.amount, Some(dec!(0.1234)));
+        assert_eq!(withdrawal.seq, Some(3));
+
+        let dispute = records[2].as_ref().unwrap();
+        assert_eq!(dispute.amount, None);
+        assert_eq!(dispute.line, Some(4));
+        assert_eq!(records[3].as_ref().unwrap().amount, None);
+
+        assert_eq!(records[4].as_ref().unwrap_err().line, Some(6));
+        assert_eq!(records[5].as_ref().unwrap().amount, None);
+    }
+
+    #[test]
+    fn test_sniff() {
+        let mut reader = "\n  {\"type\":\"deposit\"}".as_bytes();
+        assert_eq!(sniff(&mut reader).unwrap(), InputFormat::Ndjson);
+
+        let mut reader = "type,client,tx,amount\n".as_bytes();
+        assert_eq!(sniff(&mut reader).unwrap(), InputFormat::Csv);
+        assert_eq!(reader, "type,client,tx,amount\n".as_bytes());
+
+        assert_eq!(sniff(&mut "".as_bytes()).unwrap(), InputFormat::Csv);
+    }
+
+    #[test]
+    fn test_open_by_extension() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("core-tx-runner-input-{}.jsonl", std::process::id()));
+        let line = "{\"type\":\"deposit\",\"client\":2,\"tx\":5,\"amount\":3}\n";
+        std::fs::write(&path, line.repeat(2)).unwrap();
+
+        let records = collect(open(&path, InputFormat::Auto).unwrap().as_mut());
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].as_ref().unwrap().client, 2);
+        // Forced to CSV, the first object is the header and the second does not fit it
+        let records = collect(open(&path, InputFormat::Csv).unwrap().as_mut());
+        assert_eq!(records.len(), 1);
+        assert!(records[0].is_err());
+        std::fs::remove_file(path).unwrap();
+
+        let records = collect(
+            open("test_data/simple.csv", InputFormat::Auto)
+                .unwrap()
+                .as_mut(),
+        );
+        assert!(records.iter().all(|r| r.is_ok()));
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let data = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":2}\n";
+        let records = collect(
+            from_reader(data.as_bytes(), InputFormat::Auto)
+                .unwrap()
+                .as_mut(),
+        );
+        assert_eq!(records.len(), 1);
+        assert!(records[0].is_ok());
+
+        let data = "type,client,tx,amount\ndeposit,1,1,2\n";
+        let records = collect(
+            from_reader(data.as_bytes(), InputFormat::Csv)
+                .unwrap()
+                .as_mut(),
+        );
+        assert_eq!(records[0].as_ref().unwrap().amount, Some(dec!(2)));
+    }
+}
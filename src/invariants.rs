@@ -0,0 +1,204 @@
+//! Account invariants, checked after every applied record under `--check`
+//!
+//! None of these can break unless the engine has a bug. Checking them costs a
+//! few comparisons per record, which long runs and regression runs over
+//! generated workloads (see `workload`) pay to trust their balances. The first
+//! record that breaks one stops the run.
+
+use crate::audit_log::Balances;
+use crate::config::AccountPolicy;
+use crate::types::{Account, ClientId, TransactionId, TransactionRecord, TransactionType};
+use rust_decimal::Decimal;
+use std::fmt;
+
+/// A property every account keeps between records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Invariant {
+    /// `total` is `available + held`
+    TotalIsSum,
+    /// `held` is only negative while a dispute is open
+    HeldNeedsDispute,
+    /// A locked account only changes through records the account policy lets through
+    LockedUnchanged,
+}
+
+impl Invariant {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Invariant::TotalIsSum => "total_is_sum",
+            Invariant::HeldNeedsDispute => "held_needs_dispute",
+            Invariant::LockedUnchanged => "locked_unchanged",
+        }
+    }
+}
+
+impl fmt::Display for Invariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The first record found breaking an invariant, with the account it left behind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvariantViolation {
+    /// Position of the record in application order
+    pub position: u64,
+    pub tx_type: TransactionType,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub invariant: Invariant,
+    pub before: Balances,
+    pub after: Balances,
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record {} ({} tx {} client {}) broke {}, leaving available {} held {} total {}",
+            self.position,
+            self.tx_type.as_str(),
+            self.tx,
+            self.client,
+            self.invariant,
+            self.after.available,
+            self.after.held,
+            self.after.total
+        )
+    }
+}
+
+/// Check the account `record` was applied to, given its balances before
+pub fn check(
+    position: u64,
+    record: &TransactionRecord,
+    before: Balances,
+    after: &Account,
+    policy: &AccountPolicy,
+) -> Result<(), InvariantViolation> {
+    let broken = if after.total != after.available + after.held {
+        Some(Invariant::TotalIsSum)
+    } else if after.held < Decimal::ZERO && after.open_disputes == 0 {
+        Some(Invariant::HeldNeedsDispute)
+    } else if before.locked && !permitted_when_locked(record.tx_type, policy) {
+        Some(Invariant::LockedUnchanged)
+    } else {
+        None
+    };
+
+    match broken {
+        Some(invariant) => Err(InvariantViolation {
+            position,
+            tx_type: record.tx_type,
+            client: record.client,
+            tx: record.tx,
+            invariant,
+            before,
+            after: Balances::of(Some(after)),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Whether a locked account may apply a record of type `tx_type` at all
+fn permitted_when_locked(tx_type: TransactionType, policy: &AccountPolicy) -> bool {
+    match tx_type {
+        TransactionType::Unlock => policy.allow_unlock,
+        tx_type => policy.accepts_when_locked(tx_type),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn record(tx_type: TransactionType) -> TransactionRecord {
+        TransactionRecord {
+            tx_type,
+            client: 1,
+            tx: 7,
+            amount: None,
+            seq: None,
+            timestamp: None,
+            line: None,
+        }
+    }
+
+    fn broken(
+        tx_type: TransactionType,
+        before: Balances,
+        after: &Account,
+        policy: &AccountPolicy,
+    ) -> Option<Invariant> {
+        check(3, &record(tx_type), before, after, policy)
+            .err()
+            .map(|v| v.invariant)
+    }
+
+    #[test]
+    fn test_invariants() {
+        let policy = AccountPolicy::default();
+        let mut account = Account::new(1);
+        account.deposit(dec!(10));
+        account.hold_funds(dec!(4));
+        let before = Balances::default();
+        assert_eq!(
+            broken(TransactionType::Dispute, before, &account, &policy),
+            None
+        );
+
+        let mut skewed = account.clone();
+        skewed.total += dec!(0.0001);
+        let violation = check(
+            3,
+            &record(TransactionType::Dispute),
+            before,
+            &skewed,
+            &policy,
+        )
+        .expect_err("Total skew passed");
+        assert_eq!(violation.invariant, Invariant::TotalIsSum);
+        assert!(violation
+            .to_string()
+            .starts_with("record 3 (dispute tx 7 client 1) broke total_is_sum, leaving"));
+
+        let mut negative = Account::new(1);
+        negative.held = dec!(-1);
+        negative.total = dec!(-1);
+        assert_eq!(
+            broken(TransactionType::Resolve, before, &negative, &policy),
+            Some(Invariant::HeldNeedsDispute)
+        );
+        negative.open_disputes = 1;
+        assert_eq!(
+            broken(TransactionType::Resolve, before, &negative, &policy),
+            None
+        );
+
+        let locked = Balances {
+            locked: true,
+            ..Balances::default()
+        };
+        assert_eq!(
+            broken(TransactionType::Deposit, locked, &account, &policy),
+            Some(Invariant::LockedUnchanged)
+        );
+        let lenient = AccountPolicy {
+            locked_accepts: vec![TransactionType::Deposit],
+            allow_unlock: true,
+        };
+        assert_eq!(
+            broken(TransactionType::Deposit, locked, &account, &lenient),
+            None
+        );
+        assert_eq!(
+            broken(TransactionType::Unlock, locked, &account, &lenient),
+            None
+        );
+        assert_eq!(
+            broken(TransactionType::Unlock, locked, &account, &policy),
+            Some(Invariant::LockedUnchanged)
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Kafka topic as a transaction source
+//!
+//! With the `kafka` feature, `consume` subscribes to a topic and applies each
+//! message, one JSON object or one headerless CSV line, as a transaction record.
+//! Kafka orders messages within a partition, so producers key messages by
+//! client: all of a client's records then land in one partition and are
+//! applied in offset order.
+//!
+//! Delivery is at-least-once. Offsets are committed only after a snapshot
+//! holding every record up to them was written, so a restarted consumer
+//! resumes from its last snapshot and may be sent messages it already applied.
+//! `MessageApplier` recognises those by their event id, which the snapshot
+//! keeps under `dedup_events`, and skips them. Rejected records leave no id and
+//! are evaluated again.
+
+use crate::csv_parser;
+use crate::engine::PaymentsEngine;
+use crate::events::EventId;
+use crate::input::{InputFormat, MalformedRecord};
+use crate::types::{RejectionReason, TransactionRecord, TxError};
+use csv::StringRecord;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer};
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
+use rdkafka::Message;
+use std::time::Duration;
+
+/// Columns of a CSV message, which carries no header row
+pub const CSV_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Parse one message payload; under `Auto` a payload starting with `{` is JSON
+pub fn decode_message(
+    payload: &[u8],
+    format: InputFormat,
+) -> Result<TransactionRecord, MalformedRecord> {
+    let malformed = |message: String| MalformedRecord {
+        line: None,
+        message,
+    };
+    let text = std::str::from_utf8(payload)
+        .map_err(|e| malformed(e.to_string()))?
+        .trim();
+    let json = match format {
+        InputFormat::Auto => text.starts_with('{'),
+        InputFormat::Ndjson => true,
+        InputFormat::Csv => false,
+    };
+    if json {
+        return serde_json::from_str(text).map_err(|e| malformed(e.to_string()));
+    }
+
+    let headers = StringRecord::from(CSV_COLUMNS.to_vec());
+    csv_parser::parse_line(text)
+        .and_then(|record| record.deserialize(Some(&headers)))
+        .map_err(|e| malformed(e.to_string()))
+}
+
+/// What became of one message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    Applied,
+    Rejected(RejectionReason),
+    /// Applied before, in this run or the one that wrote the snapshot
+    Redelivered,
+    /// Not a transaction record, skipped
+    Malformed(MalformedRecord),
+}
+
+/// Messages seen by a `MessageApplier`, by what became of them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConsumeStats {
+    pub messages: u64,
+    pub applied: u64,
+    pub rejected: u64,
+    pub redelivered: u64,
+    pub malformed: u64,
+}
+
+/// Applies message payloads to an engine, skipping redelivered ones
+/// The engine must run with `dedup_events`, or it remembers no applied records
+#[derive(Debug)]
+pub struct MessageApplier {
+    engine: PaymentsEngine,
+    format: InputFormat,
+    stats: ConsumeStats,
+}
+
+impl MessageApplier {
+    pub fn new(engine: PaymentsEngine, format: InputFormat) -> Self {
+        Self {
+            engine,
+            format,
+            stats: ConsumeStats::default(),
+        }
+    }
+
+    pub fn engine(&self) -> &PaymentsEngine {
+        &self.engine
+    }
+
+    pub fn stats(&self) -> ConsumeStats {
+        self.stats
+    }
+
+    pub fn into_inner(self) -> PaymentsEngine {
+        self.engine
+    }
+
+    /// Apply one message payload
+    /// Rejections are returned as a `Delivery`; any other error should stop consuming
+    pub fn apply(&mut self, payload: &[u8]) -> Result<Delivery, TxError> {
+        self.stats.messages += 1;
+        let record = match decode_message(payload, self.format) {
+            Ok(record) => record,
+            Err(e) => {
+                self.stats.malformed += 1;
+                return Ok(Delivery::Malformed(e));
+            }
+        };
+        if self.engine.has_emitted(&EventId::of(&record)) {
+            self.stats.redelivered += 1;
+            return Ok(Delivery::Redelivered);
+        }
+
+        match self.engine.process(record) {
+            Ok(()) => {
+                self.stats.applied += 1;
+                Ok(Delivery::Applied)
+            }
+            Err(TxError::Rejected(reason)) => {
+                self.stats.rejected += 1;
+                Ok(Delivery::Rejected(reason))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Consumer of one topic that commits offsets only when told to
+/// Run one per group: another member would take partitions into another engine
+pub struct KafkaSource {
+    consumer: BaseConsumer,
+}
+
+impl KafkaSource {
+    /// Subscribe to `topic`; a group without committed offsets starts at the beginning
+    pub fn subscribe(brokers: &str, group: &str, topic: &str) -> KafkaResult<Self> {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", group)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()?;
+        consumer.subscribe(&[topic])?;
+        Ok(Self { consumer })
+    }
+
+    /// Payload of the next message, `None` if none arrived within `timeout`
+    /// A message without a payload is returned empty
+    pub fn poll(&self, timeout: Duration) -> Option<KafkaResult<Vec<u8>>> {
+        self.consumer
+            .poll(timeout)
+            .map(|message| message.map(|message| message.payload().unwrap_or_default().to_vec()))
+    }
+
+    /// Commit the offsets of every message polled so far, waiting for the broker
+    pub fn commit(&self) -> KafkaResult<()> {
+        match self.consumer.commit_consumer_state(CommitMode::Sync) {
+            // Nothing polled since the last commit
+            Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::types::TransactionType;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_decode_message() {
+        let record = decode_message(
+            br#"{"type":"deposit","client":1,"tx":2,"amount":"1.5"}"#,
+            InputFormat::Auto,
+        )
+        .unwrap();
+        assert_eq!(record.tx_type, TransactionType::Deposit);
+        assert_eq!(record.amount, Some(dec!(1.5)));
+
+        let record = decode_message(b"withdrawal, 3, 4, 2.25\n", InputFormat::Auto).unwrap();
+        assert_eq!(
+            (record.tx_type, record.client, record.tx, record.amount),
+            (TransactionType::Withdrawal, 3, 4, Some(dec!(2.25)))
+        );
+        let record = decode_message(b"dispute,3,4", InputFormat::Csv).unwrap();
+        assert_eq!(record.tx_type, TransactionType::Dispute);
+        assert_eq!(record.amount, None);
+
+        assert!(decode_message(b"dispute,3,4", InputFormat::Ndjson).is_err());
+        assert!(decode_message(b"refund,1,1,1", InputFormat::Auto).is_err());
+        assert!(decode_message(&[0xff, 0xfe], InputFormat::Auto).is_err());
+    }
+
+    #[test]
+    fn test_redelivery_after_restart() {
+        let config = EngineConfig {
+            dedup_events: true,
+            ..EngineConfig::default()
+        };
+        let messages: [&[u8]; 4] = [
+            b"deposit,1,1,10",
+            b"withdrawal,1,2,4",
+            b"dispute,1,1",
+            b"withdrawal,1,3,100",
+        ];
+
+        let mut applier =
+            MessageApplier::new(PaymentsEngine::new(config.clone()), InputFormat::Csv);
+        for message in &messages[..2] {
+            assert_eq!(applier.apply(message), Ok(Delivery::Applied));
+        }
+        let snapshot = applier.engine().snapshot().unwrap();
+
+        // Restarted from the snapshot, the broker sends everything again
+        let engine = PaymentsEngine::from_snapshot(config, snapshot);
+        let mut applier = MessageApplier::new(engine, InputFormat::Csv);
+        let deliveries: Vec<Delivery> = messages
+            .iter()
+            .chain([&b"not a record"[..]].iter())
+            .map(|message| applier.apply(message).unwrap())
+            .collect();
+        assert_eq!(
+            deliveries[..4],
+            [
+                Delivery::Redelivered,
+                Delivery::Redelivered,
+                Delivery::Applied,
+                Delivery::Rejected(RejectionReason::InsufficientFunds),
+            ]
+        );
+        assert!(matches!(deliveries[4], Delivery::Malformed(_)));
+        assert_eq!(
+            applier.stats(),
+            ConsumeStats {
+                messages: 5,
+                applied: 1,
+                rejected: 1,
+                redelivered: 2,
+                malformed: 1,
+            }
+        );
+        let account = applier.engine().account(1).unwrap();
+        assert_eq!((account.available, account.held), (dec!(-4), dec!(10)));
+    }
+}
@@ -0,0 +1,244 @@
+//! Per-source latency of served transactions
+//!
+//! `serve` times every applied record from the moment it was read off its
+//! connection to the moment the engine applied it, and, when the record carries
+//! a producer `timestamp`, from that timestamp to the moment it was applied.
+//! Times go into log-linear histograms of microseconds: 16 buckets per power of
+//! two, so a percentile is reported as the upper bound of its bucket, at most
+//! about 6% above the true value and never below it. The end-to-end figure
+//! relies on the producer's clock; records stamped after they were applied are
+//! counted as skewed and left out of it.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// Distinct sources tracked; later ones are counted under `OTHER_SOURCE`
+pub const MAX_SOURCES: usize = 64;
+
+/// Source of records from sources beyond `MAX_SOURCES`
+pub const OTHER_SOURCE: &str = "other";
+
+/// Buckets per power of two
+const SUB_BUCKETS: u64 = 16;
+
+/// Enough buckets for any `u64` of microseconds
+const BUCKETS: usize = 61 * SUB_BUCKETS as usize;
+
+/// Bucket of a value, exact below `SUB_BUCKETS`
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exp = u64::from(63 - micros.leading_zeros());
+    let sub = (micros >> (exp - 4)) & (SUB_BUCKETS - 1);
+    ((exp - 3) * SUB_BUCKETS + sub) as usize
+}
+
+/// Largest value falling in bucket `index`
+fn upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let exp = index / SUB_BUCKETS + 3;
+    let sub = index % SUB_BUCKETS;
+    let next = u128::from(SUB_BUCKETS + sub + 1) << (exp - 4);
+    u64::try_from(next - 1).unwrap_or(u64::MAX)
+}
+
+/// Latencies recorded so far
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; BUCKETS],
+            count: 0,
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[bucket(micros)] += 1;
+        self.count += 1;
+        self.max = self.max.max(micros);
+    }
+
+    /// Smallest recorded bound that `quantile` (0 to 1) of the latencies are at or below
+    pub fn quantile(&self, quantile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((quantile * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (index, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return upper_bound(index).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count,
+            p50_us: self.quantile(0.5),
+            p90_us: self.quantile(0.9),
+            p99_us: self.quantile(0.99),
+            p999_us: self.quantile(0.999),
+            max_us: self.max,
+        }
+    }
+}
+
+/// Percentiles of one histogram, in microseconds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub p999_us: u64,
+    pub max_us: u64,
+}
+
+/// Latencies of the records of one source
+#[derive(Debug, Clone, Default)]
+struct SourceHistograms {
+    ingest_to_applied: Histogram,
+    end_to_end: Histogram,
+    skewed: u64,
+}
+
+/// What `GET /latency` reports for one source
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SourceLatency {
+    /// From reading the record to applying it
+    pub ingest_to_applied: LatencySummary,
+    /// From the producer `timestamp` to applying it, records with one only
+    pub end_to_end: LatencySummary,
+    /// Records stamped later than they were applied, left out of `end_to_end`
+    pub skewed: u64,
+}
+
+/// Latency histograms by source
+#[derive(Debug, Clone, Default)]
+pub struct LatencyTracker {
+    sources: HashMap<String, SourceHistograms>,
+}
+
+impl LatencyTracker {
+    /// Record an applied record of `source`
+    /// `end_to_end` is `None` for a record without a producer timestamp, and
+    /// `Some(Err(_))` for one stamped after it was applied
+    pub fn record(
+        &mut self,
+        source: &str,
+        ingest_to_applied: Duration,
+        end_to_end: Option<Result<Duration, Duration>>,
+    ) {
+        let source = if self.sources.contains_key(source) || self.sources.len() < MAX_SOURCES {
+            source
+        } else {
+            OTHER_SOURCE
+        };
+        let histograms = self.sources.entry(source.to_string()).or_default();
+        histograms.ingest_to_applied.record(ingest_to_applied);
+        match end_to_end {
+            Some(Ok(latency)) => histograms.end_to_end.record(latency),
+            Some(Err(_)) => histograms.skewed += 1,
+            None => {}
+        }
+    }
+
+    /// Percentiles by source name
+    pub fn report(&self) -> BTreeMap<String, SourceLatency> {
+        self.sources
+            .iter()
+            .map(|(source, histograms)| {
+                (
+                    source.clone(),
+                    SourceLatency {
+                        ingest_to_applied: histograms.ingest_to_applied.summary(),
+                        end_to_end: histograms.end_to_end.summary(),
+                        skewed: histograms.skewed,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        for micros in [0, 1, 15, 16, 17, 31, 32, 33, 1_000, 123_456_789, u64::MAX] {
+            let index = bucket(micros);
+            assert!(index < BUCKETS);
+            assert!(upper_bound(index) >= micros, "{} above its bucket", micros);
+            if index > 0 {
+                assert!(
+                    upper_bound(index - 1) < micros,
+                    "{} in too high a bucket",
+                    micros
+                );
+            }
+        }
+        assert_eq!(upper_bound(bucket(1_000)), 1_023);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.summary(), LatencySummary::default());
+        for micros in 1..=1_000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        let summary = histogram.summary();
+        assert_eq!(summary.count, 1_000);
+        assert_eq!(summary.max_us, 1_000);
+        for (reported, exact) in [
+            (summary.p50_us, 500),
+            (summary.p90_us, 900),
+            (summary.p99_us, 990),
+        ] {
+            assert!(reported >= exact && reported * 100 <= exact * 107);
+        }
+        assert_eq!(summary.p999_us, 1_000);
+    }
+
+    #[test]
+    fn test_sources() {
+        let mut tracker = LatencyTracker::default();
+        let ms = Duration::from_millis;
+        tracker.record("http", ms(1), Some(Ok(ms(40))));
+        tracker.record("http", ms(3), None);
+        tracker.record("tcp", ms(2), Some(Err(ms(5))));
+        for n in 0..MAX_SOURCES {
+            tracker.record(&format!("producer-{}", n), ms(1), None);
+        }
+
+        let report = tracker.report();
+        assert_eq!(report.len(), MAX_SOURCES + 1);
+        assert_eq!(report["http"].ingest_to_applied.count, 2);
+        assert_eq!(report["http"].ingest_to_applied.max_us, 3_000);
+        assert_eq!(report["http"].end_to_end.count, 1);
+        assert_eq!(report["http"].end_to_end.p50_us, 40_000);
+        assert_eq!(report["tcp"].end_to_end.count, 0);
+        assert_eq!(report["tcp"].skewed, 1);
+        assert_eq!(report[OTHER_SOURCE].ingest_to_applied.count, 2);
+    }
+}
@@ -0,0 +1,64 @@
+//! Payments engine: applies deposits, withdrawals, disputes, resolves and
+//! chargebacks to client accounts
+//!
+//! `PaymentsEngine` is the entry point for embedding; the remaining modules
+//! are the readers, reports and writers the `core-tx-runner` binary is built from.
+
+pub mod audit;
+pub mod audit_log;
+pub mod bench_gate;
+pub mod cancel;
+pub mod clock;
+pub mod closure;
+pub mod config;
+pub mod csv_parser;
+pub mod dead_letter;
+pub mod dedup;
+pub mod diff;
+pub mod dormancy;
+pub mod engine;
+pub mod events;
+pub mod exposure;
+pub mod groups;
+pub mod history;
+pub mod hold;
+pub mod input;
+pub mod invariants;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod latency;
+pub mod mismatch;
+pub mod output;
+pub mod plugin;
+pub mod proof;
+pub mod reconcile;
+pub mod recovery;
+pub mod rejects;
+pub mod repro;
+pub mod rules;
+pub mod schema;
+pub mod sequence;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod shadow;
+pub mod shard;
+pub mod snapshot;
+pub mod statement;
+pub mod stats;
+pub mod store;
+#[cfg(feature = "tokio")]
+pub mod stream;
+pub mod summary;
+pub mod timestamp;
+pub mod types;
+pub mod validation;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod what_if;
+pub mod workload;
+
+pub use cancel::CancellationToken;
+pub use config::EngineConfig;
+pub use diff::AccountDelta;
+pub use engine::{EngineReport, PaymentsEngine, RunStats};
+pub use types::{RejectionReason, TransactionRecord, TxError};
@@ -1,31 +1,413 @@
-pub mod csv_parser;
-pub mod types;
+mod cli;
 
-use csv_parser::TransactionReader;
+use cli::{
+    AuditOptions, Command, ConsumeOptions, GenOptions, ReconcileOptions, ReplayOptions,
+    ReproOptions, SchemaOptions, StatementOptions, StatementTarget,
+};
+use core_tx_runner::audit_log::JsonLinesAudit;
+use core_tx_runner::closure::{self, Closure};
+use core_tx_runner::config::{BlockedWithdrawal, EngineConfig};
+use core_tx_runner::dedup::DuplicateReference;
+use core_tx_runner::dormancy::{self, DormancyThreshold, DormantAccount};
+use core_tx_runner::exposure::{DisputeTimes, ExposureReport};
+use core_tx_runner::mismatch::ClientMismatch;
+use core_tx_runner::output::{self, AtomicFile};
+use core_tx_runner::plugin::EventSink;
+use core_tx_runner::proof::{self, InputHash, Proof};
+use core_tx_runner::recovery::{self, Recovery};
+use core_tx_runner::rejects::{Rejection, RejectsFormat, RejectsWriter};
+use core_tx_runner::repro::{self, ReproManifest};
+use core_tx_runner::schema::{SchemaDocument, SchemaFormat};
+use core_tx_runner::sequence::{SequenceReport, SequenceTracker};
+use core_tx_runner::shadow::{self, Divergence, ShadowEngine, ShadowReport};
+use core_tx_runner::shard::ShardedEngine;
+use core_tx_runner::snapshot::Snapshot;
+use core_tx_runner::statement::{BulkStatementBuilder, StatementBuilder};
+use core_tx_runner::store::StoreKind;
+use core_tx_runner::types::{Account, ClientId, RejectionReason, TransactionRecord, TxError};
+use core_tx_runner::workload;
+use core_tx_runner::{
+    audit, bench_gate, dead_letter, diff, groups, input, reconcile, what_if, EngineReport,
+    PaymentsEngine,
+};
+use rust_decimal::Decimal;
 use std::collections::HashMap;
 use std::env;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use std::process;
-use types::{Account, ClientId, StoredTransaction, TransactionId, TransactionType};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 fn main() {
     // Parse command line arguments
-    // Since we have 2 arguments only, no need for any fancy library
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <transactions.csv>", args[0]);
-        process::exit(1);
+    let mut args = env::args();
+    let program = args.next().unwrap_or_else(|| "core-tx-runner".to_string());
+    let command = match cli::parse_command(args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", cli::usage(&program));
+            process::exit(1);
+        }
+    };
+
+    match command {
+        Command::Run(options) => run(*options),
+        Command::Statement(options) => {
+            if let Err(e) = run_statement(&options) {
+                eprintln!("Error generating statement: {}", e);
+                process::exit(1);
+            }
+        }
+        Command::Replay(options) => {
+            if let Err(e) = run_replay(&options) {
+                eprintln!("Error replaying transactions: {}", e);
+                process::exit(1);
+            }
+        }
+        Command::Audit(options) => match run_audit(&options) {
+            Ok(0) => eprintln!("Audit passed"),
+            Ok(count) => {
+                eprintln!("Audit failed: {} discrepancies", count);
+                process::exit(2);
+            }
+            Err(e) => {
+                eprintln!("Error auditing accounts: {}", e);
+                process::exit(1);
+            }
+        },
+        Command::Reconcile(options) => match run_reconcile(&options) {
+            Ok(0) => eprintln!("Reconciled, no breaks"),
+            Ok(count) => {
+                eprintln!("{} clients differ from the external balances", count);
+                process::exit(2);
+            }
+            Err(e) => {
+                eprintln!("Error reconciling balances: {}", e);
+                process::exit(1);
+            }
+        },
+        #[cfg(feature = "serve")]
+        Command::Serve(options) => {
+            if let Err(e) = run_serve(&options) {
+                eprintln!("Error serving: {}", e);
+                process::exit(1);
+            }
+        }
+        Command::Consume(options) => {
+            if let Err(e) = run_consume(&options) {
+                eprintln!("Error consuming: {}", e);
+                process::exit(1);
+            }
+        }
+        Command::Gen(options) => match run_gen(&options) {
+            Ok(rows) => eprintln!(
+                "Generated {} records for {} clients",
+                rows, options.spec.clients
+            ),
+            Err(e) => {
+                eprintln!("Error generating transactions: {}", e);
+                process::exit(1);
+            }
+        },
+        Command::Schema(options) => {
+            if let Err(e) = run_schema(&options) {
+                eprintln!("Error writing schema: {}", e);
+                process::exit(1);
+            }
+        }
+        Command::Repro(options) => match run_repro(&options) {
+            Ok(None) => eprintln!("Did not reproduce"),
+            Ok(Some(failure)) => {
+                eprintln!("Reproduced: {}", failure);
+                process::exit(2);
+            }
+            Err(e) => {
+                eprintln!("Error replaying bundle: {}", e);
+                process::exit(1);
+            }
+        },
     }
+}
+
+/// Default command: process transactions and output final account states
+fn run(options: cli::Options) {
+    // Process transactions, then any dead letters re-fed from an earlier run
+    let mut inputs: Vec<&str> = options.inputs.iter().map(String::as_str).collect();
+    inputs.extend(options.refeed.as_deref());
+
+    // Rejected records are streamed out while processing
+    let mut rejects = match options.rejects.as_deref().map(open_rejects).transpose() {
+        Ok(rejects) => rejects,
+        Err(e) => {
+            eprintln!("Error opening rejects file: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // A bundle is only written for a failed run, check it has somewhere to go first
+    if let Some(dir) = &options.record_repro {
+        if dir.join(repro::MANIFEST).exists() {
+            eprintln!(
+                "Error: {} already holds a reproduction bundle",
+                dir.display()
+            );
+            process::exit(1);
+        }
+    }
+
+    // Shared with the engine, finished once processing is done
+    let audit = match options.audit.as_deref().map(AtomicFile::create).transpose() {
+        Ok(file) => file.map(|file| Arc::new(Mutex::new(JsonLinesAudit::new(file)))),
+        Err(e) => {
+            eprintln!("Error opening audit log: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let started = Instant::now();
+    let validation = options.config.validation;
+    let mut violations = 0;
+    let mut malformed = 0;
+    let on_rejected = |rejection: &Rejection| {
+        // Malformed rows never reach the engine, so its counts miss them
+        if rejection.reason == RejectionReason::Malformed {
+            malformed += 1;
+        }
+        if validation.is_violation(rejection.reason) {
+            eprintln!("Validation failed: {}", rejection);
+            violations += 1;
+        }
+        match &mut rejects {
+            Some(writer) => writer.write(rejection),
+            None => Ok(()),
+        }
+    };
+    let processed = match &options.record_repro {
+        Some(dir) => recording_repro(dir, &inputs, || {
+            process_inputs(&inputs, &options, audit.as_ref(), on_rejected)
+        }),
+        None => process_inputs(&inputs, &options, audit.as_ref(), on_rejected),
+    };
+    let elapsed = started.elapsed();
+
+    // Like the other outputs, rejects are only published for a completed run
+    if let (Some(writer), true) = (rejects, processed.is_ok()) {
+        if let Err(e) = writer.finish().and_then(AtomicFile::commit) {
+            eprintln!("Error writing rejects: {}", e);
+            process::exit(1);
+        }
+    }
+    if let (Some(audit), true) = (audit, processed.is_ok()) {
+        if let Err(e) = finish_audit(audit) {
+            eprintln!("Error writing audit log: {}", e);
+            process::exit(1);
+        }
+    }
+
+    match processed {
+        Ok(mut result) => {
+            let measurement = bench_gate::Measurement {
+                records: result.report.records_processed,
+                elapsed,
+                peak_memory_kb: bench_gate::peak_memory_kb(),
+            };
+
+            report_sequence_anomalies(&result.sequence);
+            report_duplicates(&result.report.duplicates);
+            report_mismatches(&result.report.mismatches);
+            report_blocked_withdrawals(&result.report.blocked_withdrawals);
+            report_recoveries(&result.report.recoveries);
+            report_closures(&result.report.closures);
+            if let Some(shadow) = &result.shadow {
+                eprintln!("Shadow: {}", shadow);
+                if let Some(path) = &options.shadow_log {
+                    if let Err(e) = write_divergences(&shadow.divergences, path) {
+                        eprintln!("Error writing shadow log: {}", e);
+                        process::exit(1);
+                    }
+                }
+            }
+            if result.report.suppressed_events > 0 {
+                eprintln!(
+                    "{} events already sent in an earlier run, not sent to event sinks again",
+                    result.report.suppressed_events
+                );
+            }
+
+            if let Some(path) = &options.recoveries {
+                if let Err(e) = write_recoveries(&result.report.recoveries, path) {
+                    eprintln!("Error writing recoveries: {}", e);
+                    process::exit(1);
+                }
+            }
+
+            if let Some(path) = &options.closures {
+                if let Err(e) = write_closures(&result.report.closures, path) {
+                    eprintln!("Error writing closures: {}", e);
+                    process::exit(1);
+                }
+            }
+
+            if let Some(threshold) = options.dormant_after {
+                let dormant = result.report.flag_dormant(threshold);
+                report_dormant(&dormant, threshold);
+                if let Some(path) = &options.dormant {
+                    if let Err(e) = write_dormant(&dormant, path) {
+                        eprintln!("Error writing dormant accounts: {}", e);
+                        process::exit(1);
+                    }
+                }
+            }
+
+            if let Some(path) = &options.dead_letter {
+                if let Err(e) = write_dead_letters(&result.report.dead_letters, path) {
+                    eprintln!("Error writing dead letters: {}", e);
+                    process::exit(1);
+                }
+            }
+            if !result.report.dead_letters.is_empty() {
+                eprintln!(
+                    "{} reference transactions to unknown tx ids{}",
+                    result.report.dead_letters.len(),
+                    if options.dead_letter.is_some() {
+                        " dead-lettered"
+                    } else {
+                        " ignored"
+                    }
+                );
+            }
+
+            // End-of-run reports only look at open disputes, not every stored deposit
+            let open_disputes = if options.exposure_report.is_some() || options.what_if.is_some() {
+                match result.report.transactions.open_disputes() {
+                    Ok(open_disputes) => open_disputes,
+                    Err(e) => {
+                        eprintln!("Error reading transactions: {}", e);
+                        process::exit(1);
+                    }
+                }
+            } else {
+                HashMap::new()
+            };
+
+            if let Some(path) = &options.exposure_report {
+                let times = DisputeTimes {
+                    opened: &result.report.disputed_since,
+                    started: result.report.started_at.unwrap_or(result.report.ended_at),
+                    now: result.report.ended_at,
+                };
+                let report = ExposureReport::compute(
+                    &result.report.accounts,
+                    &open_disputes,
+                    result.report.records_processed,
+                    &times,
+                );
+                if let Err(e) = write_exposure_report(&report, path) {
+                    eprintln!("Error writing exposure report: {}", e);
+                    process::exit(1);
+                }
+            }
+
+            if let Some(path) = &options.save_state {
+                if let Err(e) = save_state(&result.report, path) {
+                    eprintln!("Error saving state: {}", e);
+                    process::exit(1);
+                }
+            }
+
+            // Real balances, even under --what-if
+            let summary = options.summary.as_ref().map(|_| {
+                let mut summary = result.report.summary(options.summary_top);
+                summary.add_malformed(malformed);
+                summary
+            });
 
-    let filename = &args[1];
+            // Built before the accounts are moved out; never combined with --what-if
+            let proof = match &options.proof {
+                Some(_) => match build_proof(&result.report, &inputs, &options) {
+                    Ok(proof) => Some(proof),
+                    Err(e) => {
+                        eprintln!("Error building proof: {}", e);
+                        process::exit(1);
+                    }
+                },
+                None => None,
+            };
 
-    // Process transactions and get final account states
-    match process_file(filename) {
-        Ok(accounts) => {
-            // Output results to stdout
-            if let Err(e) = output_accounts(accounts) {
+            // Hypothetical balances go to their own report, the real outputs are untouched
+            if let Some((scenario, sink)) = &options.what_if {
+                let accounts =
+                    what_if::evaluate(*scenario, &result.report.accounts, &open_disputes);
+                let written = output::write_accounts_to_sinks(
+                    &accounts,
+                    options.schema,
+                    options.output_format,
+                    std::slice::from_ref(sink),
+                );
+                if let Err(e) = written {
+                    eprintln!("Error writing what-if report: {}", e);
+                    process::exit(1);
+                }
+            }
+            let accounts = result.report.accounts;
+
+            // Output results to stdout or the requested file
+            if let Err(e) = output_accounts(&accounts, &options) {
                 eprintln!("Error writing output: {}", e);
                 process::exit(1);
             }
+
+            if let Some((path, sink)) = &options.groups {
+                if let Err(e) = output_group_totals(&accounts, path, sink) {
+                    eprintln!("Error writing group totals: {}", e);
+                    process::exit(1);
+                }
+            }
+
+            if let (Some(summary), Some(sink)) = (&summary, &options.summary) {
+                let written = output::write_to_sink(sink, |out| Ok(summary.write_text(out)?));
+                if let Err(e) = written {
+                    eprintln!("Error writing summary: {}", e);
+                    process::exit(1);
+                }
+            }
+
+            // Published last, so a proof never vouches for outputs that failed to write
+            if let (Some(proof), Some(path)) = (&proof, &options.proof) {
+                if let Err(e) = write_proof(proof, path, options.proof_key.as_deref()) {
+                    eprintln!("Error writing proof: {}", e);
+                    process::exit(1);
+                }
+            }
+
+            if let Some(path) = &options.benchmark_gate {
+                match benchmark_gate(&measurement, path) {
+                    Ok(true) => {}
+                    Ok(false) => process::exit(2),
+                    Err(e) => {
+                        eprintln!("Error checking benchmark baseline: {}", e);
+                        process::exit(1);
+                    }
+                }
+            }
+
+            // Strict runs still write their outputs, but fail
+            if violations > 0 {
+                eprintln!("{} records failed strict validation", violations);
+                process::exit(2);
+            }
+            // So does a run whose accounts don't add up, its proof says so too
+            if let Some(proof) = proof.filter(|p| !p.balanced) {
+                eprintln!(
+                    "Conservation check failed: accounts total {}, expected {}",
+                    proof.accounts_total, proof.expected_total
+                );
+                process::exit(2);
+            }
         }
         Err(e) => {
             eprintln!("Error processing transactions: {}", e);
@@ -34,157 +416,906 @@ fn main() {
     }
 }
 
-/// Read CSV file and process all transactions, streaming one record at a time
-fn process_file(filename: &str) -> Result<HashMap<ClientId, Account>, Box<dyn std::error::Error>> {
-    // Account storage - created on demand
-    let mut accounts: HashMap<ClientId, Account> = HashMap::new();
-
-    // Transaction storage - only deposits stored for dispute tracking
-    // Note: Withdrawals are not stored since they cannot be disputed
-    let mut transactions: HashMap<TransactionId, StoredTransaction> = HashMap::new();
-
-    // Open CSV file and stream records
-    let reader = TransactionReader::from_file(filename)?;
-
-    // Process each transaction record one at a time
-    for result in reader.records() {
-        let record = match result {
-            Ok(r) => r,
-            Err(_) => continue, // Skip malformed records silently
+/// Serve one engine over HTTP and/or line-delimited TCP until a listener fails
+#[cfg(feature = "serve")]
+fn run_serve(options: &cli::ServeOptions) -> Result<(), Box<dyn std::error::Error>> {
+    use core_tx_runner::serve;
+    use std::fs::OpenOptions;
+    use std::io::BufWriter;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let mut engine = PaymentsEngine::new(options.config.clone());
+    if !options.wasm.modules.is_empty() {
+        load_wasm(&options.wasm, &mut engine)?;
+    }
+    let mut server = serve::Server::new(engine, options.shed);
+    if let Some(path) = &options.ledger {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        server.set_ledger(Box::new(BufWriter::new(file)));
+    }
+    let server = Arc::new(server);
+
+    let mut listeners = Vec::new();
+    if let Some(addr) = &options.http {
+        let listener = TcpListener::bind(addr)?;
+        eprintln!("Serving HTTP on {}", listener.local_addr()?);
+        let server = Arc::clone(&server);
+        listeners.push(thread::spawn(move || serve::serve_http(listener, server)));
+    }
+    if let Some(addr) = &options.tcp {
+        let listener = TcpListener::bind(addr)?;
+        eprintln!("Serving line-delimited TCP on {}", listener.local_addr()?);
+        let server = Arc::clone(&server);
+        listeners.push(thread::spawn(move || serve::serve_tcp(listener, server)));
+    }
+
+    for listener in listeners {
+        listener.join().expect("listener thread panicked")?;
+    }
+    Ok(())
+}
+
+/// Apply messages from a Kafka topic until `--max-messages` or `--idle-exit`
+/// Offsets are committed after each snapshot, so a restart resumes where the snapshot left off
+#[cfg(feature = "kafka")]
+fn run_consume(options: &ConsumeOptions) -> Result<(), Box<dyn std::error::Error>> {
+    use core_tx_runner::kafka::{Delivery, KafkaSource, MessageApplier};
+    use std::time::Duration;
+
+    let engine = match &options.load_state {
+        Some(path) => resume_engine(&options.config, path)?,
+        None => PaymentsEngine::new(options.config.clone()),
+    };
+    let source = KafkaSource::subscribe(&options.brokers, &options.group, &options.topic)?;
+    eprintln!(
+        "Consuming {} from {} as group {}",
+        options.topic, options.brokers, options.group
+    );
+
+    let mut applier = MessageApplier::new(engine, options.config.input_format);
+    let mut since_snapshot = 0;
+    let mut last_message = Instant::now();
+    while options
+        .max_messages
+        .is_none_or(|max| applier.stats().messages < max)
+    {
+        let Some(payload) = source.poll(Duration::from_millis(500)) else {
+            if options
+                .idle_exit
+                .is_some_and(|secs| last_message.elapsed() >= Duration::from_secs(secs))
+            {
+                break;
+            }
+            continue;
         };
+        last_message = Instant::now();
+        if let Delivery::Malformed(e) = applier.apply(&payload?)? {
+            eprintln!("Skipping malformed message: {}", e);
+        }
+        since_snapshot += 1;
+        if since_snapshot == options.snapshot_every {
+            checkpoint(applier.engine(), &source, &options.save_state)?;
+            since_snapshot = 0;
+        }
+    }
+    checkpoint(applier.engine(), &source, &options.save_state)?;
+
+    let stats = applier.stats();
+    eprintln!(
+        "Consumed {} messages: {} applied, {} rejected, {} redelivered, {} malformed",
+        stats.messages, stats.applied, stats.rejected, stats.redelivered, stats.malformed
+    );
+    output::write_accounts_to_sinks(
+        applier.engine().accounts(),
+        output::Schema::V1,
+        options.output_format,
+        &options.outputs,
+    )
+}
+
+/// Write the engine state, then commit the offsets of the messages it holds
+#[cfg(feature = "kafka")]
+fn checkpoint(
+    engine: &PaymentsEngine,
+    source: &core_tx_runner::kafka::KafkaSource,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = AtomicFile::create(path)?;
+    engine.snapshot()?.write(&mut file)?;
+    file.commit()?;
+    source.commit()?;
+    Ok(())
+}
 
-        // Process this single transaction
-        process_transaction(record, &mut accounts, &mut transactions);
+#[cfg(not(feature = "kafka"))]
+fn run_consume(_options: &ConsumeOptions) -> Result<(), Box<dyn std::error::Error>> {
+    Err("built without Kafka support, rebuild with --features kafka".into())
+}
+
+fn run_gen(options: &GenOptions) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut rows = 0;
+    output::write_to_sink(&options.output, |out| {
+        rows = workload::write_workload(&options.spec, out)?;
+        Ok(())
+    })?;
+    Ok(rows)
+}
+
+fn run_schema(options: &SchemaOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let document = SchemaDocument::current();
+    output::write_to_sink(&options.output, |out| match options.format {
+        SchemaFormat::Json => document.write_json(out),
+    })
+}
+
+/// Feed `inputs` to the engine or shards `options` ask for
+fn process_inputs<R>(
+    inputs: &[&str],
+    options: &cli::Options,
+    audit: Option<&Arc<Mutex<JsonLinesAudit<AtomicFile>>>>,
+    on_rejected: R,
+) -> Result<RunResult, Box<dyn std::error::Error>>
+where
+    R: FnMut(&Rejection) -> std::io::Result<()>,
+{
+    if options.threads > 1 {
+        return process_files_sharded(inputs, &options.config, options.threads, on_rejected);
     }
+    let mut engine = open_engine(options)?;
+    if let Some(audit) = audit {
+        engine.set_audit_sink(Box::new(Arc::clone(audit)));
+    }
+    if !options.wasm.modules.is_empty() {
+        load_wasm(&options.wasm, &mut engine)?;
+    }
+    let mut sinks = match &options.plugins {
+        Some(dir) => load_plugins(dir, &mut engine)?,
+        None => Vec::new(),
+    };
+    let shadow = options
+        .shadow
+        .as_ref()
+        .map(|config| open_shadow(config, options))
+        .transpose()?;
+    let on_applied = |position: u64, record: &TransactionRecord, account: &Account| {
+        for sink in &mut sinks {
+            sink.applied(position, record, account);
+        }
+    };
+    process_files_with(
+        inputs,
+        &options.config,
+        engine,
+        shadow,
+        on_applied,
+        on_rejected,
+    )
+}
 
-    Ok(accounts)
+/// The invariant violation or panic that ended `outcome`, if one did
+fn repro_failure<T>(
+    outcome: &std::thread::Result<Result<T, Box<dyn std::error::Error>>>,
+) -> Option<String> {
+    match outcome {
+        Ok(Err(e)) if matches!(e.downcast_ref(), Some(TxError::Invariant(_))) => {
+            Some(e.to_string())
+        }
+        Ok(_) => None,
+        Err(payload) => Some(format!("panic: {}", repro::panic_message(payload.as_ref()))),
+    }
 }
 
-/// Process a single transaction record
-fn process_transaction(
-    record: types::TransactionRecord,
-    accounts: &mut HashMap<ClientId, Account>,
-    transactions: &mut HashMap<TransactionId, StoredTransaction>,
-) {
-    // Get or create account for this client
-    let account = accounts
-        .entry(record.client)
-        .or_insert_with(|| Account::new(record.client));
+/// Run `process`, bundling the run into `dir` if it breaks an invariant or panics
+/// The run then fails as it would have without the bundle
+fn recording_repro<T>(
+    dir: &Path,
+    inputs: &[&str],
+    process: impl FnOnce() -> Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let outcome = panic::catch_unwind(AssertUnwindSafe(process));
+    if let Some(failure) = repro_failure(&outcome) {
+        let args: Vec<String> = env::args().skip(1).collect();
+        match ReproManifest::record(dir, &args, inputs, &failure) {
+            Ok(_) => eprintln!("Reproduction bundle written to {}", dir.display()),
+            Err(e) => eprintln!("Error writing reproduction bundle: {}", e),
+        }
+    }
+    outcome.unwrap_or_else(|payload| panic::resume_unwind(payload))
+}
 
-    // Skip all operations if account is locked
-    if account.is_locked() {
-        return;
+/// Run a `--record-repro` bundle again, returning its failure if it came back
+/// Nothing the recorded run wrote is written, a disk store goes in the bundle
+fn run_repro(options: &ReproOptions) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let manifest = ReproManifest::read(&options.dir)?;
+    if manifest.version != env!("CARGO_PKG_VERSION") {
+        eprintln!(
+            "Warning: bundle recorded by version {}, replaying with {}",
+            manifest.version,
+            env!("CARGO_PKG_VERSION")
+        );
     }
+    eprintln!("Recorded failure: {}", manifest.failure);
 
-    // Process transaction based on type
-    match record.tx_type {
-        TransactionType::Deposit => {
-            if let Some(amount) = record.amount {
-                // Credit account
-                account.deposit(amount);
+    let mut run = cli::parse_args(manifest.replay_args(&options.dir))?;
+    if let StoreKind::Disk(_) = run.store {
+        run.store = StoreKind::Disk(options.dir.join("replay.store"));
+    }
+    let mut inputs: Vec<&str> = run.inputs.iter().map(String::as_str).collect();
+    inputs.extend(run.refeed.as_deref());
 
-                // Store transaction for potential disputes
-                transactions.insert(
-                    record.tx,
-                    StoredTransaction::new(record.client, TransactionType::Deposit, amount),
-                );
-            }
-            // Skip if amount is missing (malformed)
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        process_inputs(&inputs, &run, None, |_| Ok(()))
+    }));
+    if let Some(failure) = repro_failure(&outcome) {
+        return Ok(Some(failure));
+    }
+    outcome
+        .unwrap_or_else(|payload| panic::resume_unwind(payload))
+        .map(|_| None)
+}
+
+/// Shadow engine for a run, starting where the primary starts
+/// It keeps deposits in memory and gets the primary's wasm modules, but no plugins or audit log
+fn open_shadow(
+    config: &EngineConfig,
+    options: &cli::Options,
+) -> Result<ShadowEngine, Box<dyn std::error::Error>> {
+    let mut engine = match &options.load_state {
+        Some(path) => resume_engine(config, path)?,
+        None => PaymentsEngine::new(config.clone()),
+    };
+    if !options.wasm.modules.is_empty() {
+        load_wasm(&options.wasm, &mut engine)?;
+    }
+    Ok(ShadowEngine::new(engine))
+}
+
+/// Engine for a run: resumed from `--load-state`, or empty with the chosen store
+fn open_engine(options: &cli::Options) -> Result<PaymentsEngine, Box<dyn std::error::Error>> {
+    match &options.load_state {
+        Some(path) => resume_engine(&options.config, path),
+        None => Ok(PaymentsEngine::with_store(
+            options.config.clone(),
+            options.store.open()?,
+        )),
+    }
+}
+
+/// Engine resumed from the snapshot at `path`
+fn resume_engine(
+    config: &EngineConfig,
+    path: &Path,
+) -> Result<PaymentsEngine, Box<dyn std::error::Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let snapshot = Snapshot::read(reader).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(PaymentsEngine::from_snapshot(config.clone(), snapshot))
+}
+
+/// Load the plugins in `dir`: risk rules go into `engine`, sinks are returned
+#[cfg(feature = "plugins")]
+fn load_plugins(
+    dir: &Path,
+    engine: &mut PaymentsEngine,
+) -> Result<Vec<Box<dyn EventSink>>, Box<dyn std::error::Error>> {
+    // SAFETY: the plugins directory is trusted like the binary itself
+    let plugins = unsafe { core_tx_runner::plugin::load_dir(dir)? };
+    let mut sinks: Vec<Box<dyn EventSink>> = Vec::new();
+    for plugin in plugins {
+        eprintln!("Loaded plugin {}", plugin.name());
+        if plugin.is_risk_rule() {
+            engine.add_risk_rule(Box::new(plugin.clone()));
         }
+        if plugin.is_sink() {
+            sinks.push(Box::new(plugin));
+        }
+    }
+    Ok(sinks)
+}
 
-        TransactionType::Withdrawal => {
-            if let Some(amount) = record.amount {
-                // Attempt to debit account (fails silently if insufficient funds)
-                account.withdraw(amount);
-                // Note: Don't store withdrawals - only deposits can be disputed
-            }
-            // Skip if amount is missing (malformed)
+#[cfg(not(feature = "plugins"))]
+fn load_plugins(
+    _dir: &Path,
+    _engine: &mut PaymentsEngine,
+) -> Result<Vec<Box<dyn EventSink>>, Box<dyn std::error::Error>> {
+    Err("built without plugin support, rebuild with --features plugins".into())
+}
+
+/// Load sandboxed modules into `engine` as risk rules and transformers
+#[cfg(feature = "wasm")]
+fn load_wasm(
+    options: &cli::WasmOptions,
+    engine: &mut PaymentsEngine,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use core_tx_runner::wasm::{WasmLimits, WasmModule};
+
+    let defaults = WasmLimits::default();
+    let limits = WasmLimits {
+        fuel: options.fuel.unwrap_or(defaults.fuel),
+        memory: options.memory.unwrap_or(defaults.memory),
+    };
+    for path in &options.modules {
+        let module = WasmModule::load(path, limits)?;
+        eprintln!("Loaded wasm module {}", module.name());
+        if module.is_transformer() {
+            engine.add_transformer(Box::new(module.clone()));
         }
+        if module.is_risk_rule() {
+            engine.add_risk_rule(Box::new(module));
+        }
+    }
+    Ok(())
+}
 
-        TransactionType::Dispute => {
-            // Look up the referenced transaction
-            if let Some(stored_tx) = transactions.get_mut(&record.tx) {
-                // Verify client matches
-                if stored_tx.client_id != record.client {
-                    return; // Wrong client, ignore
-                }
+#[cfg(not(feature = "wasm"))]
+fn load_wasm(
+    _options: &cli::WasmOptions,
+    _engine: &mut PaymentsEngine,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("built without WebAssembly support, rebuild with --features wasm".into())
+}
 
-                // Only deposits can be disputed, and only if not already disputed
-                if stored_tx.can_dispute() {
-                    // Hold the funds
-                    account.hold_funds(stored_tx.amount);
+/// Write the final engine state to `path` for a later `--load-state`
+fn save_state(report: &EngineReport, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = AtomicFile::create(path)?;
+    report.snapshot()?.write(&mut file)?;
+    file.commit()?;
+    Ok(())
+}
 
-                    // Mark transaction as disputed
-                    stored_tx.mark_disputed();
-                }
-            }
-            // If tx doesn't exist or can't be disputed, ignore silently
+/// Compare the run against the baseline at `path`, recording it if there is none yet
+/// Returns false if throughput or memory regressed beyond the baseline's tolerance
+fn benchmark_gate(
+    measurement: &bench_gate::Measurement,
+    path: &std::path::Path,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        measurement.to_baseline().save(path)?;
+        eprintln!(
+            "Benchmark baseline recorded: {:.0} records/sec",
+            measurement.records_per_sec()
+        );
+        return Ok(true);
+    }
+
+    let baseline = bench_gate::Baseline::load(path)?;
+    let regressions = bench_gate::check(&baseline, measurement);
+    for regression in &regressions {
+        eprintln!("Benchmark gate: {}", regression);
+    }
+    Ok(regressions.is_empty())
+}
+
+/// Final state of a processing run
+struct RunResult {
+    report: EngineReport,
+    sequence: SequenceReport,
+    /// How the shadow engine compared, if there was one
+    shadow: Option<ShadowReport>,
+}
+
+/// Replay the input and write one client's statement, or every client's
+fn run_statement(options: &StatementOptions) -> Result<(), Box<dyn std::error::Error>> {
+    match &options.target {
+        StatementTarget::Client { client, output } => {
+            let mut builder = StatementBuilder::new(*client, options.from, options.to);
+            process_file_with(
+                &options.input,
+                &options.config,
+                |position, record, account| builder.observe(position, record, account),
+            )?;
+            let statement = builder.finish();
+
+            output::write_to_sink(output, |w| statement.write(options.format, w))
         }
+        StatementTarget::AllClients { out_dir } => {
+            // Single pass over the input, files are then written in parallel
+            let mut builder = BulkStatementBuilder::new(options.from, options.to, out_dir);
+            process_file_with(
+                &options.input,
+                &options.config,
+                |position, record, account| builder.observe(position, record, account),
+            )?;
 
-        TransactionType::Resolve => {
-            // Look up the referenced transaction
-            if let Some(stored_tx) = transactions.get_mut(&record.tx) {
-                // Verify client matches
-                if stored_tx.client_id != record.client {
-                    return; // Wrong client, ignore
-                }
+            builder.write_all(options.format)
+        }
+    }
+}
 
-                // Only resolve if transaction is currently disputed
-                if stored_tx.is_disputed() {
-                    // Release the held funds
-                    account.release_funds(stored_tx.amount);
+/// Process the input under default and overridden settings and write the differences
+fn run_replay(options: &ReplayOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let original = process_file(&options.input, &options.base)?;
+    let replayed = process_file(&options.input, &options.config)?;
+
+    let deltas = diff::diff_accounts(&original.report.accounts, &replayed.report.accounts);
+    output::write_to_sink(&options.output, |w| diff::write_deltas_csv(&deltas, w))
+}
+
+/// Recompute accounts from the input and check them against the accounts file
+/// Returns the number of discrepancies written to the report
+fn run_audit(options: &AuditOptions) -> Result<usize, Box<dyn std::error::Error>> {
+    let reported = audit::read_accounts(&options.accounts)?;
+    let recomputed = process_file(&options.input, &options.config)?;
+
+    let discrepancies = audit::audit(
+        &reported,
+        &recomputed.report.accounts,
+        &recomputed.report.transactions.open_disputes()?,
+        &recomputed.report.pending_holds,
+    );
+    output::write_to_sink(&options.output, |w| {
+        Ok(audit::write_report(&discrepancies, w)?)
+    })?;
+    Ok(discrepancies.len())
+}
 
-                    // Mark transaction as resolved (no longer disputed)
-                    stored_tx.mark_resolved();
+/// Compute balances from the input and compare them to the external balance file
+/// Returns the number of breaks written to the report
+fn run_reconcile(options: &ReconcileOptions) -> Result<usize, Box<dyn std::error::Error>> {
+    let external = reconcile::read_balances(std::fs::File::open(&options.external)?)?;
+    let computed = process_file(&options.input, &options.config)?;
+
+    let breaks = reconcile::reconcile(&computed.report.accounts, &external);
+    output::write_to_sink(&options.output, |w| {
+        Ok(reconcile::write_breaks(&breaks, w)?)
+    })?;
+    Ok(breaks.len())
+}
+
+/// Read CSV file and process all transactions, streaming one record at a time
+fn process_file(
+    filename: &str,
+    config: &EngineConfig,
+) -> Result<RunResult, Box<dyn std::error::Error>> {
+    process_files(&[filename], config)
+}
+
+/// Process several CSV files one after the other into a single state
+fn process_files(
+    filenames: &[&str],
+    config: &EngineConfig,
+) -> Result<RunResult, Box<dyn std::error::Error>> {
+    process_files_with(
+        filenames,
+        config,
+        PaymentsEngine::new(config.clone()),
+        None,
+        |_, _, _| {},
+        |_| Ok(()),
+    )
+}
+
+/// Like `process_file`, calling `on_applied` with the record position, the record
+/// and the resulting account state after every successfully applied record
+fn process_file_with<F>(
+    filename: &str,
+    config: &EngineConfig,
+    on_applied: F,
+) -> Result<RunResult, Box<dyn std::error::Error>>
+where
+    F: FnMut(u64, &TransactionRecord, &Account),
+{
+    process_files_with(
+        &[filename],
+        config,
+        PaymentsEngine::new(config.clone()),
+        None,
+        on_applied,
+        |_| Ok(()),
+    )
+}
+
+/// Multi-file form of `process_file_with` feeding `engine`, also calling
+/// `on_rejected` for every record that was not applied, malformed rows included
+/// A `shadow` engine is fed every record too, its results only compared
+fn process_files_with<F, R>(
+    filenames: &[&str],
+    config: &EngineConfig,
+    mut engine: PaymentsEngine,
+    mut shadow: Option<ShadowEngine>,
+    mut on_applied: F,
+    mut on_rejected: R,
+) -> Result<RunResult, Box<dyn std::error::Error>>
+where
+    F: FnMut(u64, &TransactionRecord, &Account),
+    R: FnMut(&Rejection) -> std::io::Result<()>,
+{
+    let sequence = feed_files(filenames, config, |next| {
+        let rejection = match next {
+            Ok(record) => {
+                let result = engine.process_with(record, &mut on_applied);
+                if let Some(shadow) = &mut shadow {
+                    shadow.observe(record, &result);
+                }
+                match result {
+                    Ok(()) => None,
+                    Err(TxError::Rejected(reason)) => Some(Rejection::new(&record, reason)),
+                    Err(e) => return Err(e.into()),
                 }
             }
-            // If tx doesn't exist or isn't disputed, ignore silently
+            Err(malformed) => Some(malformed),
+        };
+        if let Some(rejection) = rejection {
+            on_rejected(&rejection)?;
         }
+        Ok(())
+    })?;
 
-        TransactionType::Chargeback => {
-            // Look up the referenced transaction
-            if let Some(stored_tx) = transactions.get_mut(&record.tx) {
-                // Verify client matches
-                if stored_tx.client_id != record.client {
-                    return; // Wrong client, ignore
-                }
+    let shadow = shadow.map(|shadow| shadow.finish(engine.accounts()));
+    Ok(RunResult {
+        report: engine.into_report(),
+        sequence,
+        shadow,
+    })
+}
+
+/// Like `process_files_with` without `on_applied`, spreading the records over
+/// `threads` engines by client
+fn process_files_sharded<R>(
+    filenames: &[&str],
+    config: &EngineConfig,
+    threads: usize,
+    mut on_rejected: R,
+) -> Result<RunResult, Box<dyn std::error::Error>>
+where
+    R: FnMut(&Rejection) -> std::io::Result<()>,
+{
+    let mut engine = ShardedEngine::new(config, threads);
+
+    let fed = feed_files(filenames, config, |next| {
+        match next {
+            Ok(record) => engine.process(record)?,
+            Err(malformed) => on_rejected(&malformed)?,
+        }
+        for rejection in engine.rejections() {
+            on_rejected(&rejection)?;
+        }
+        Ok(())
+    });
 
-                // Only chargeback if transaction is currently disputed
-                if stored_tx.is_disputed() {
-                    // Remove held funds and lock account
-                    account.chargeback(stored_tx.amount);
+    let mut written = Ok(());
+    let finished = engine.finish(|rejection| {
+        if written.is_ok() {
+            written = on_rejected(&rejection);
+        }
+    });
+
+    // A shard that stopped early fails `fed`, the reason comes from `finish`
+    let report = finished?;
+    let sequence = fed?;
+    written?;
+    Ok(RunResult {
+        report,
+        sequence,
+        shadow: None,
+    })
+}
 
-                    // Transaction remains disputed (terminal state)
-                    // Note: We don't remove the transaction from storage
+/// Stream the files' records to `apply` in per-client sequence order
+/// Malformed rows are passed as their rejection
+fn feed_files<A>(
+    filenames: &[&str],
+    config: &EngineConfig,
+    mut apply: A,
+) -> Result<SequenceReport, Box<dyn std::error::Error>>
+where
+    A: FnMut(Result<TransactionRecord, Rejection>) -> Result<(), Box<dyn std::error::Error>>,
+{
+    // Puts records carrying a `seq` column back in per-client order
+    let mut sequencer = SequenceTracker::new(config.seq_window);
+
+    for filename in filenames {
+        // Open the file and stream records
+        let mut source = input::open(filename, config.input_format)?;
+
+        // Process each transaction record one at a time
+        while let Some(result) = source.next_record() {
+            let record = match result {
+                Ok(r) => r,
+                Err(e) => {
+                    let rejection = Rejection::malformed(e.line);
+                    if config.validation.aborts_on(rejection.reason) {
+                        return Err(TxError::Violation(rejection).into());
+                    }
+                    // Skip malformed records, they only show up in rejects
+                    apply(Err(rejection))?;
+                    continue;
+                }
+            };
+
+            // Replaying up to a point in time, later records are left out entirely
+            if let Some(as_of) = config.as_of {
+                match record.timestamp {
+                    Some(timestamp) if timestamp > as_of => continue,
+                    Some(_) => {}
+                    None => {
+                        return Err(input::MalformedRecord {
+                            line: record.line,
+                            message: "no timestamp, --as-of needs one on every record".to_string(),
+                        }
+                        .into());
+                    }
                 }
             }
-            // If tx doesn't exist or isn't disputed, ignore silently
+
+            // Process the records that are ready in sequence order
+            for record in sequencer.push(record) {
+                apply(Ok(record))?;
+            }
         }
     }
+
+    // Apply anything still buffered behind a sequence gap
+    let (remaining, sequence) = sequencer.finish();
+    for record in remaining {
+        apply(Ok(record))?;
+    }
+    Ok(sequence)
+}
+
+/// Warn on stderr about missing or duplicated sequence numbers
+fn report_sequence_anomalies(report: &SequenceReport) {
+    for gap in &report.gaps {
+        eprintln!("Warning: sequence gap, {}", gap);
+    }
+    for (client, seq) in &report.duplicates {
+        eprintln!(
+            "Warning: duplicate or late seq {} for client {}, record dropped",
+            seq, client
+        );
+    }
+    for (client, seq) in &report.overflows {
+        eprintln!(
+            "Warning: seq {} for client {} after its sequence reached {}, record dropped",
+            seq,
+            client,
+            u64::MAX
+        );
+    }
+}
+
+/// Report duplicated reference transactions on stderr
+fn report_duplicates(duplicates: &[DuplicateReference]) {
+    for duplicate in duplicates {
+        eprintln!("Warning: {}", duplicate);
+    }
+}
+
+/// Report references addressed to the wrong client on stderr
+fn report_mismatches(mismatches: &[ClientMismatch]) {
+    for mismatch in mismatches {
+        eprintln!("Warning: {}", mismatch);
+    }
+}
+
+/// Report withdrawals blocked by open disputes on stderr
+fn report_blocked_withdrawals(blocked: &[BlockedWithdrawal]) {
+    for withdrawal in blocked {
+        eprintln!("Warning: {}", withdrawal);
+    }
+}
+
+/// Summarize deficits recovered by the recovery sweep on stderr
+fn report_recoveries(recoveries: &[Recovery]) {
+    if recoveries.is_empty() {
+        return;
+    }
+    let recovered: Decimal = recoveries.iter().map(|r| r.recovered).sum();
+    let cleared = recoveries
+        .iter()
+        .filter(|r| r.remaining_deficit.is_zero())
+        .count();
+    eprintln!(
+        "{} deposits recovered {} of negative balances, {} deficits cleared",
+        recoveries.len(),
+        recovered,
+        cleared
+    );
+}
+
+fn report_closures(closures: &[Closure]) {
+    if closures.is_empty() {
+        return;
+    }
+    let payouts: Decimal = closures.iter().map(|c| c.payout).sum();
+    let written_off: Decimal = closures.iter().map(|c| c.written_off).sum();
+    eprintln!(
+        "{} accounts closed, {} to pay out, {} written off",
+        closures.len(),
+        payouts,
+        written_off
+    );
+}
+
+/// Write closures to `path` as CSV
+fn write_closures(closures: &[Closure], path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = AtomicFile::create(path)?;
+    closure::write_closures(closures, &mut file)?;
+    file.commit()?;
+    Ok(())
+}
+
+fn report_dormant(dormant: &[DormantAccount], threshold: DormancyThreshold) {
+    if dormant.is_empty() {
+        return;
+    }
+    let total: Decimal = dormant.iter().map(|d| d.total).sum();
+    eprintln!(
+        "{} accounts dormant (no activity in the last {}), {} in funds",
+        dormant.len(),
+        threshold,
+        total
+    );
+}
+
+/// Write shadow divergences to `path` as CSV
+fn write_divergences(
+    divergences: &[Divergence],
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = AtomicFile::create(path)?;
+    shadow::write_divergences(divergences, &mut file)?;
+    file.commit()?;
+    Ok(())
+}
+
+/// Write dormant accounts to `path` as CSV
+fn write_dormant(
+    dormant: &[DormantAccount],
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = AtomicFile::create(path)?;
+    dormancy::write_dormant(dormant, &mut file)?;
+    file.commit()?;
+    Ok(())
+}
+
+/// Write recoveries to `path` as CSV
+fn write_recoveries(
+    recoveries: &[Recovery],
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = AtomicFile::create(path)?;
+    recovery::write_recoveries(recoveries, &mut file)?;
+    file.commit()?;
+    Ok(())
 }
 
-/// Output account states to stdout as CSV
-fn output_accounts(accounts: HashMap<ClientId, Account>) -> Result<(), Box<dyn std::error::Error>> {
-    let mut writer = csv::Writer::from_writer(std::io::stdout());
+/// Proof of a finished run, hashing the accounts as `output_accounts` writes them
+/// and every input file; standard input cannot be read again and gets no hash
+fn build_proof(
+    report: &EngineReport,
+    inputs: &[&str],
+    options: &cli::Options,
+) -> Result<Proof, Box<dyn std::error::Error>> {
+    let mut accounts_file = Vec::new();
+    output::write_accounts(
+        &report.accounts,
+        options.schema,
+        options.output_format,
+        &mut accounts_file,
+    )?;
+
+    let inputs = inputs
+        .iter()
+        .map(|&path| {
+            let sha256 = match path {
+                input::STDIN => None,
+                path => Some(proof::sha256_hex(&std::fs::read(path)?)),
+            };
+            Ok(InputHash {
+                path: path.to_string(),
+                sha256,
+            })
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    Ok(Proof::new(report, &accounts_file, inputs))
+}
 
-    // Write all accounts (order doesn't matter per spec)
-    for account in accounts.values() {
-        writer.serialize(account)?;
+/// Write the proof to `path`, and its signature to `<path>.sig` if there is a key
+/// The key file's trailing line break is not part of the key
+fn write_proof(
+    proof: &Proof,
+    path: &Path,
+    key: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bytes = proof.to_bytes();
+    let signature = match key {
+        Some(key) => {
+            let key = std::fs::read(key).map_err(|e| format!("{}: {}", key.display(), e))?;
+            let key = key.strip_suffix(b"\n").unwrap_or(&key);
+            let key = key.strip_suffix(b"\r").unwrap_or(key);
+            Some(proof::sign(&bytes, key))
+        }
+        None => None,
+    };
+
+    let mut file = AtomicFile::create(path)?;
+    file.write_all(&bytes)?;
+    file.commit()?;
+    if let Some(signature) = signature {
+        let mut sig_path = path.as_os_str().to_owned();
+        sig_path.push(".sig");
+        let mut file = AtomicFile::create(sig_path)?;
+        writeln!(file, "{}", signature)?;
+        file.commit()?;
     }
+    Ok(())
+}
+
+/// Publish the audit log once the engine holding it is gone
+fn finish_audit(audit: Arc<Mutex<JsonLinesAudit<AtomicFile>>>) -> std::io::Result<()> {
+    let audit = Arc::try_unwrap(audit)
+        .expect("engine still holds the audit log")
+        .into_inner()
+        .expect("audit log lock poisoned");
+    audit.finish()?.commit()
+}
+
+/// Start the rejects stream, in a format picked from the file extension
+fn open_rejects(path: &std::path::Path) -> std::io::Result<RejectsWriter<AtomicFile>> {
+    RejectsWriter::new(RejectsFormat::from_path(path), AtomicFile::create(path)?)
+}
+
+/// Write the exposure report to a file, atomically
+fn write_exposure_report(
+    report: &ExposureReport,
+    path: &std::path::Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = AtomicFile::create(path)?;
+    report.write_text(&mut file)?;
+    file.commit()?;
+    Ok(())
+}
 
-    writer.flush()?;
+/// Write dead-lettered reference records to a file, atomically
+fn write_dead_letters(
+    records: &[TransactionRecord],
+    path: &std::path::Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = AtomicFile::create(path)?;
+    dead_letter::write_dead_letters(records, &mut file)?;
+    file.commit()?;
     Ok(())
 }
 
+/// Output account states as CSV to every configured sink
+fn output_accounts(
+    accounts: &HashMap<ClientId, Account>,
+    options: &cli::Options,
+) -> Result<(), Box<dyn std::error::Error>> {
+    output::write_accounts_to_sinks(
+        accounts,
+        options.schema,
+        options.output_format,
+        &options.outputs,
+    )
+}
+
+/// Roll accounts up by the groups in `path` and write the totals to `sink`
+fn output_group_totals(
+    accounts: &HashMap<ClientId, Account>,
+    path: &std::path::Path,
+    sink: &output::Sink,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let groups = groups::read_groups(std::fs::File::open(path)?)?;
+    let totals = groups::roll_up(accounts, &groups);
+    output::write_to_sink(sink, |w| Ok(groups::write_group_totals(&totals, w)?))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use core_tx_runner::types::TransactionType;
 
     #[test]
     fn test_process_simple_transactions() {
         use rust_decimal_macros::dec;
 
-        let accounts = process_file("test_data/simple.csv").expect("Failed to process");
+        let accounts = process_file("test_data/simple.csv", &EngineConfig::default())
+            .expect("Failed to process")
+            .report
+            .accounts;
 
         // Client 1: deposit 100 + deposit 50 - withdraw 25 = 125
         let client1 = accounts.get(&1).expect("Client 1 not found");
@@ -205,7 +1336,10 @@ mod tests {
     fn test_process_disputes() {
         use rust_decimal_macros::dec;
 
-        let accounts = process_file("test_data/disputes.csv").expect("Failed to process");
+        let accounts = process_file("test_data/disputes.csv", &EngineConfig::default())
+            .expect("Failed to process")
+            .report
+            .accounts;
 
         // Client 1: Should have resolved dispute
         let client1 = accounts.get(&1).expect("Client 1 not found");
@@ -226,7 +1360,10 @@ mod tests {
     fn test_process_edge_cases() {
         use rust_decimal_macros::dec;
 
-        let accounts = process_file("test_data/edge_cases.csv").expect("Failed to process");
+        let accounts = process_file("test_data/edge_cases.csv", &EngineConfig::default())
+            .expect("Failed to process")
+            .report
+            .accounts;
 
         // Client 1: 1000.5678 - 100.0 = 900.5678
         let client1 = accounts.get(&1).expect("Client 1 not found");
@@ -254,7 +1391,10 @@ mod tests {
     fn test_invalid_references() {
         use rust_decimal_macros::dec;
 
-        let accounts = process_file("test_data/invalid_references.csv").expect("Failed to process");
+        let accounts = process_file("test_data/invalid_references.csv", &EngineConfig::default())
+            .expect("Failed to process")
+            .report
+            .accounts;
 
         // Client 1: Only deposit, all invalid dispute/resolve/chargeback ignored
         let client1 = accounts.get(&1).expect("Client 1 not found");
@@ -277,4 +1417,229 @@ mod tests {
         assert_eq!(client3.total, dec!(300));
         assert!(!client3.locked); // Not locked because chargeback referenced non-existent tx
     }
+
+    #[test]
+    fn test_unknown_references_dead_lettered() {
+        let result = process_file("test_data/invalid_references.csv", &EngineConfig::default())
+            .expect("Failed to process");
+
+        let unknown: Vec<_> = result
+            .report
+            .dead_letters
+            .iter()
+            .map(|r| (r.tx_type, r.client, r.tx))
+            .collect();
+        assert_eq!(
+            unknown,
+            vec![
+                (TransactionType::Dispute, 1, 999),
+                (TransactionType::Resolve, 1, 888),
+                (TransactionType::Chargeback, 1, 777),
+                (TransactionType::Chargeback, 3, 999),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_refeed_applies_late_references() {
+        use rust_decimal_macros::dec;
+
+        // The dispute arrives before its deposit, re-feeding it afterwards applies it
+        let result = process_file("test_data/late_deposit.csv", &EngineConfig::default())
+            .expect("Failed to process");
+        assert_eq!(result.report.dead_letters.len(), 1);
+        assert_eq!(result.report.accounts[&1].held, dec!(0));
+
+        let result = process_files(
+            &["test_data/late_deposit.csv", "test_data/dead_letters.csv"],
+            &EngineConfig::default(),
+        )
+        .expect("Failed to process");
+        assert_eq!(result.report.accounts[&1].available, dec!(10));
+        assert_eq!(result.report.accounts[&1].held, dec!(40));
+    }
+
+    #[test]
+    fn test_sequenced_input() {
+        use rust_decimal_macros::dec;
+
+        let result = process_file("test_data/sequenced.csv", &EngineConfig::default())
+            .expect("Failed to process");
+
+        // Client 1: withdrawal seq 3 applied after deposit seq 2, duplicate seq 4 dropped
+        let client1 = result.report.accounts.get(&1).expect("Client 1 not found");
+        assert_eq!(client1.available, dec!(60));
+        assert_eq!(client1.total, dec!(60));
+
+        // Client 2: seq 2 never arrived, seq 3 still applied at end of input
+        let client2 = result.report.accounts.get(&2).expect("Client 2 not found");
+        assert_eq!(client2.total, dec!(75));
+
+        assert_eq!(result.sequence.duplicates, vec![(1, 4)]);
+        assert_eq!(result.sequence.gaps.len(), 1);
+        assert_eq!(result.sequence.gaps[0].client, 2);
+    }
+
+    #[test]
+    fn test_implausible_amounts_ignored() {
+        use rust_decimal_macros::dec;
+
+        let result = process_file("test_data/implausible.csv", &EngineConfig::default())
+            .expect("Failed to process");
+
+        // Client 1: 19-digit deposit and oversized withdrawal ignored
+        let client1 = result.report.accounts.get(&1).expect("Client 1 not found");
+        assert_eq!(client1.total, dec!(100));
+
+        // Client 2 only ever sent an implausible amount, so no account exists
+        assert!(!result.report.accounts.contains_key(&2));
+
+        // Without a bound the 19-digit deposit goes through and funds the withdrawal
+        let unbounded = EngineConfig {
+            max_amount: None,
+            ..EngineConfig::default()
+        };
+        let result =
+            process_file("test_data/implausible.csv", &unbounded).expect("Failed to process");
+        let client1 = result.report.accounts.get(&1).expect("Client 1 not found");
+        assert_eq!(
+            client1.total,
+            dec!(9999999999999999999) + dec!(100) - dec!(5000000000000)
+        );
+    }
+
+    #[test]
+    fn test_client_mismatch_policies() {
+        use core_tx_runner::mismatch::MismatchPolicy;
+        use rust_decimal_macros::dec;
+
+        // Default: the dispute from client 9 for client 1's deposit is ignored
+        let result = process_file("test_data/client_mismatch.csv", &EngineConfig::default())
+            .expect("Failed to process");
+        assert_eq!(result.report.accounts[&1].held, dec!(0));
+        assert!(result.report.accounts.contains_key(&9));
+        assert!(result.report.mismatches.is_empty());
+
+        let config = EngineConfig {
+            client_mismatch: MismatchPolicy::Report,
+            ..EngineConfig::default()
+        };
+        let result =
+            process_file("test_data/client_mismatch.csv", &config).expect("Failed to process");
+        assert_eq!(result.report.accounts[&1].held, dec!(0));
+        assert!(!result.report.accounts.contains_key(&9));
+        assert_eq!(result.report.mismatches.len(), 2);
+
+        // Trusting the stored client, the dispute and chargeback hit client 1
+        let config = EngineConfig {
+            client_mismatch: MismatchPolicy::TrustStored,
+            ..EngineConfig::default()
+        };
+        let result =
+            process_file("test_data/client_mismatch.csv", &config).expect("Failed to process");
+        let client1 = &result.report.accounts[&1];
+        assert_eq!(client1.total, dec!(20));
+        assert!(client1.locked);
+        assert!(!result.report.accounts.contains_key(&9));
+        assert!(result.report.mismatches.iter().all(|m| m.redirected));
+    }
+
+    #[test]
+    fn test_withdrawal_disputes() {
+        use rust_decimal_macros::dec;
+
+        // Per spec the withdrawals are not stored, so the disputes are unknown
+        let result = process_file(
+            "test_data/withdrawal_disputes.csv",
+            &EngineConfig::default(),
+        )
+        .expect("Failed to process");
+        assert_eq!(result.report.accounts[&2].available, dec!(30));
+        assert!(!result.report.accounts[&2].locked);
+        assert_eq!(result.report.dead_letters.len(), 4);
+
+        let config = EngineConfig {
+            allow_withdrawal_disputes: true,
+            ..EngineConfig::default()
+        };
+        let result =
+            process_file("test_data/withdrawal_disputes.csv", &config).expect("Failed to process");
+        // Disputed then resolved: the withdrawal stands
+        let client1 = &result.report.accounts[&1];
+        assert_eq!(client1.available, dec!(60));
+        assert_eq!(client1.held, dec!(0));
+        assert_eq!(client1.total, dec!(60));
+        // Charged back: the withdrawal is credited back and the account locked
+        let client2 = &result.report.accounts[&2];
+        assert_eq!(client2.available, dec!(50));
+        assert_eq!(client2.total, dec!(50));
+        assert!(client2.locked);
+        assert!(result.report.dead_letters.is_empty());
+    }
+
+    #[test]
+    fn test_withdrawals_blocked_by_open_disputes() {
+        use core_tx_runner::config::WithdrawalBlock;
+        use rust_decimal_macros::dec;
+
+        // Without limits the withdrawal during the dispute goes through
+        let result = process_file("test_data/dispute_withdrawal.csv", &EngineConfig::default())
+            .expect("Failed to process");
+        assert_eq!(result.report.accounts[&1].available, dec!(50));
+        assert!(result.report.blocked_withdrawals.is_empty());
+
+        let config = EngineConfig {
+            withdrawal_block: WithdrawalBlock {
+                max_held: Some(dec!(50)),
+                max_open_disputes: None,
+            },
+            ..EngineConfig::default()
+        };
+        let result =
+            process_file("test_data/dispute_withdrawal.csv", &config).expect("Failed to process");
+
+        // Blocked while 60 is held, allowed again after the resolve
+        assert_eq!(result.report.accounts[&1].available, dec!(130));
+        let blocked: Vec<_> = result
+            .report
+            .blocked_withdrawals
+            .iter()
+            .map(|b| b.tx)
+            .collect();
+        assert_eq!(blocked, vec![3]);
+        assert_eq!(result.report.blocked_withdrawals[0].held, dec!(60));
+    }
+
+    #[test]
+    fn test_duplicate_references() {
+        use core_tx_runner::dedup::DedupPolicy;
+        use rust_decimal_macros::dec;
+
+        let config = EngineConfig {
+            ref_dedup: DedupPolicy::Drop,
+            ..EngineConfig::default()
+        };
+        let result =
+            process_file("test_data/duplicate_references.csv", &config).expect("Failed to process");
+
+        // Re-dispute after resolve still applies, so the chargeback goes through
+        let client1 = result.report.accounts.get(&1).expect("Client 1 not found");
+        assert_eq!(client1.total, dec!(0));
+        assert!(client1.locked);
+
+        let positions: Vec<_> = result
+            .report
+            .duplicates
+            .iter()
+            .map(|d| d.position)
+            .collect();
+        assert_eq!(positions, vec![3, 7]);
+
+        // Failing policy aborts the run
+        let config = EngineConfig {
+            ref_dedup: DedupPolicy::Fail,
+            ..EngineConfig::default()
+        };
+        assert!(process_file("test_data/duplicate_references.csv", &config).is_err());
+    }
 }
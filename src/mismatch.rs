@@ -0,0 +1,180 @@
+use crate::types::{ClientId, TransactionId, TransactionRecord, TransactionType};
+use std::fmt;
+use std::str::FromStr;
+
+/// What to do with a dispute/resolve/chargeback whose client differs from
+/// the client of the deposit it refers to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MismatchPolicy {
+    /// Pass the record through, the state machine ignores it (spec behavior)
+    #[default]
+    Ignore,
+    /// Skip the record and report it
+    Report,
+    /// Apply the record to the deposit's owner and report it
+    /// For acquirers that put the merchant id in the client column
+    TrustStored,
+}
+
+impl FromStr for MismatchPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(MismatchPolicy::Ignore),
+            "report" => Ok(MismatchPolicy::Report),
+            "trust-stored" => Ok(MismatchPolicy::TrustStored),
+            _ => Err(format!("Unknown client mismatch policy: {}", s)),
+        }
+    }
+}
+
+/// A reference record naming a different client than the referenced deposit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientMismatch {
+    /// Position of the record in application order
+    pub position: u64,
+    pub tx_type: TransactionType,
+    /// Client on the record
+    pub client: ClientId,
+    /// Client owning the referenced deposit
+    pub stored_client: ClientId,
+    pub tx: TransactionId,
+    /// True if the record was applied to `stored_client`, false if it was skipped
+    pub redirected: bool,
+}
+
+impl fmt::Display for ClientMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record {}: {} from client {} for tx {} of client {} ({})",
+            self.position,
+            self.tx_type.as_str(),
+            self.client,
+            self.tx,
+            self.stored_client,
+            if self.redirected {
+                "applied to stored client"
+            } else {
+                "rejected"
+            }
+        )
+    }
+}
+
+/// Applies the client mismatch policy to reference records
+#[derive(Debug)]
+pub struct MismatchHandler {
+    policy: MismatchPolicy,
+    mismatches: Vec<ClientMismatch>,
+}
+
+impl MismatchHandler {
+    pub fn new(policy: MismatchPolicy) -> Self {
+        Self {
+            policy,
+            mismatches: Vec::new(),
+        }
+    }
+
+    /// Check a record against the client of the tx it refers to
+    /// Returns the record to process, possibly re-addressed, or `None` to skip it
+    pub fn check(
+        &mut self,
+        position: u64,
+        record: TransactionRecord,
+        stored_client: Option<ClientId>,
+    ) -> Option<TransactionRecord> {
+        let stored_client = match stored_client {
+            Some(client) if record.tx_type.is_reference() && client != record.client => client,
+            _ => return Some(record),
+        };
+        if self.policy == MismatchPolicy::Ignore {
+            return Some(record);
+        }
+
+        let redirected = self.policy == MismatchPolicy::TrustStored;
+        self.mismatches.push(ClientMismatch {
+            position,
+            tx_type: record.tx_type,
+            client: record.client,
+            stored_client,
+            tx: record.tx,
+            redirected,
+        });
+
+        redirected.then_some(TransactionRecord {
+            client: stored_client,
+            ..record
+        })
+    }
+
+    /// Mismatches seen so far
+    pub fn into_mismatches(self) -> Vec<ClientMismatch> {
+        self.mismatches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(tx_type: TransactionType, client: ClientId, tx: TransactionId) -> TransactionRecord {
+        TransactionRecord {
+            tx_type,
+            client,
+            tx,
+            amount: None,
+            seq: None,
+            timestamp: None,
+            line: None,
+        }
+    }
+
+    #[test]
+    fn test_ignore_passes_through() {
+        let mut handler = MismatchHandler::new(MismatchPolicy::Ignore);
+        let checked = handler.check(1, record(TransactionType::Dispute, 9, 1), Some(1));
+        assert_eq!(checked.map(|r| r.client), Some(9));
+        assert!(handler.into_mismatches().is_empty());
+    }
+
+    #[test]
+    fn test_report_rejects() {
+        let mut handler = MismatchHandler::new(MismatchPolicy::Report);
+        assert!(handler
+            .check(3, record(TransactionType::Chargeback, 9, 1), Some(1))
+            .is_none());
+
+        // Matching client and unknown tx are not mismatches
+        assert!(handler
+            .check(4, record(TransactionType::Dispute, 1, 1), Some(1))
+            .is_some());
+        assert!(handler
+            .check(5, record(TransactionType::Dispute, 9, 2), None)
+            .is_some());
+
+        let mismatches = handler.into_mismatches();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(
+            mismatches[0].to_string(),
+            "record 3: chargeback from client 9 for tx 1 of client 1 (rejected)"
+        );
+    }
+
+    #[test]
+    fn test_trust_stored_redirects() {
+        let mut handler = MismatchHandler::new(MismatchPolicy::TrustStored);
+        let checked = handler.check(2, record(TransactionType::Dispute, 9, 1), Some(1));
+        assert_eq!(checked.map(|r| r.client), Some(1));
+
+        // Deposits reusing a tx id are left to the state machine
+        let checked = handler.check(3, record(TransactionType::Deposit, 9, 1), Some(1));
+        assert_eq!(checked.map(|r| r.client), Some(9));
+
+        let mismatches = handler.into_mismatches();
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].redirected);
+    }
+}
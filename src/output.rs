@@ -0,0 +1,632 @@
+use crate::types::{serialize_decimal_str, Account, ClientId, LockReason, TransactionId};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Layout of the accounts CSV
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Schema {
+    /// The original 5 columns: client,available,held,total,locked
+    #[default]
+    V1,
+    /// V1 plus lock_reason, open_disputes, last_tx and dormant
+    V2,
+}
+
+impl FromStr for Schema {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(Schema::V1),
+            "v2" => Ok(Schema::V2),
+            _ => Err(format!("Unknown output schema: {}", s)),
+        }
+    }
+}
+
+/// How the accounts are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// One JSON array of row objects, keyed like the CSV columns
+    Json,
+    /// Aligned columns for reading in a terminal
+    Table,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+/// Account row in the v2 schema
+/// Amounts are exact strings with exactly 4 decimal places, whatever the
+/// account's history, rather than going through f64
+#[derive(Serialize)]
+struct AccountRowV2 {
+    client: ClientId,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    available: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    held: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    total: Decimal,
+    locked: bool,
+    lock_reason: Option<LockReason>,
+    open_disputes: u32,
+    last_tx: Option<TransactionId>,
+    dormant: bool,
+}
+
+impl From<&Account> for AccountRowV2 {
+    fn from(account: &Account) -> Self {
+        Self {
+            client: account.client,
+            available: fixed_4dp(account.available),
+            held: fixed_4dp(account.held),
+            total: fixed_4dp(account.total),
+            locked: account.locked,
+            lock_reason: account.lock_reason,
+            open_disputes: account.open_disputes,
+            last_tx: account.last_tx,
+            dormant: account.dormant,
+        }
+    }
+}
+
+/// `amount` rounded to 4 decimal places and padded to exactly 4
+fn fixed_4dp(amount: Decimal) -> Decimal {
+    let mut amount = amount.round_dp(4);
+    amount.rescale(4);
+    amount
+}
+
+/// Accounts ordered by client id, so runs over the same input diff cleanly
+fn sorted_accounts(accounts: &HashMap<ClientId, Account>) -> Vec<&Account> {
+    let mut sorted: Vec<&Account> = accounts.values().collect();
+    sorted.sort_unstable_by_key(|a| a.client);
+    sorted
+}
+
+fn write_csv<W: Write>(
+    accounts: &[&Account],
+    schema: Schema,
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for account in accounts {
+        match schema {
+            Schema::V1 => writer.serialize(account)?,
+            Schema::V2 => writer.serialize(AccountRowV2::from(*account))?,
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_json<W: Write>(
+    accounts: &[&Account],
+    schema: Schema,
+    mut writer: W,
+) -> Result<(), Box<dyn Error>> {
+    match schema {
+        Schema::V1 => serde_json::to_writer_pretty(&mut writer, accounts)?,
+        Schema::V2 => {
+            let rows: Vec<AccountRowV2> = accounts.iter().map(|a| AccountRowV2::from(*a)).collect();
+            serde_json::to_writer_pretty(&mut writer, &rows)?;
+        }
+    }
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Lay the CSV cells out in columns: text left-aligned, numbers right-aligned
+fn write_table<W: Write>(
+    accounts: &[&Account],
+    schema: Schema,
+    mut writer: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut csv = Vec::new();
+    write_csv(accounts, schema, &mut csv)?;
+    let rows = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(csv.as_slice())
+        .into_records()
+        .collect::<csv::Result<Vec<_>>>()?;
+
+    let mut widths = Vec::new();
+    for row in &rows {
+        widths.resize(widths.len().max(row.len()), 0);
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    for (i, row) in rows.iter().enumerate() {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| {
+                if i > 0 && Decimal::from_str(cell).is_ok() {
+                    format!("{:>width$}", cell)
+                } else {
+                    format!("{:<width$}", cell)
+                }
+            })
+            .collect();
+        writeln!(writer, "{}", cells.join("  ").trim_end())?;
+        if i == 0 {
+            let rule: Vec<String> = widths.iter().map(|&width| "-".repeat(width)).collect();
+            writeln!(writer, "{}", rule.join("  "))?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Destination for the final account states
+/// Only stdout and files: there are no Parquet or Kafka sinks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sink {
+    /// Standard output
+    Stdout,
+    /// File, written atomically
+    File(PathBuf),
+}
+
+impl Sink {
+    /// Parse a sink argument, `-` meaning stdout
+    pub fn parse(arg: &str) -> Self {
+        match arg {
+            "-" => Sink::Stdout,
+            path => Sink::File(PathBuf::from(path)),
+        }
+    }
+
+    fn open(&self) -> io::Result<OpenSink> {
+        match self {
+            Sink::Stdout => Ok(OpenSink::Stdout(io::stdout().lock())),
+            Sink::File(path) => AtomicFile::create(path).map(OpenSink::File),
+        }
+    }
+}
+
+/// A sink opened for writing
+enum OpenSink {
+    Stdout(io::StdoutLock<'static>),
+    File(AtomicFile),
+}
+
+impl OpenSink {
+    /// Get a file to disk without publishing it yet; stdout is left to `finish`
+    fn sync(&mut self) -> io::Result<()> {
+        match self {
+            OpenSink::Stdout(_) => Ok(()),
+            OpenSink::File(file) => file.sync(),
+        }
+    }
+
+    /// Flush and publish the written data
+    fn finish(self) -> io::Result<()> {
+        match self {
+            OpenSink::Stdout(mut out) => out.flush(),
+            OpenSink::File(file) => file.commit(),
+        }
+    }
+}
+
+impl Write for OpenSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OpenSink::Stdout(out) => out.write(buf),
+            OpenSink::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OpenSink::Stdout(out) => out.flush(),
+            OpenSink::File(file) => file.flush(),
+        }
+    }
+}
+
+/// Write account states to any writer, ordered by client id
+pub fn write_accounts<W: Write>(
+    accounts: &HashMap<ClientId, Account>,
+    schema: Schema,
+    format: OutputFormat,
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    let accounts = sorted_accounts(accounts);
+    match format {
+        OutputFormat::Csv => write_csv(&accounts, schema, writer),
+        OutputFormat::Json => write_json(&accounts, schema, writer),
+        OutputFormat::Table => write_table(&accounts, schema, writer),
+    }
+}
+
+/// Write account states to several sinks at once
+/// The accounts are rendered a single time and copied to every sink, so all
+/// consumers see the same bytes. Every file sink is written and synced before the
+/// first is renamed into place, so a failed write publishes none of them
+pub fn write_accounts_to_sinks(
+    accounts: &HashMap<ClientId, Account>,
+    schema: Schema,
+    format: OutputFormat,
+    sinks: &[Sink],
+) -> Result<(), Box<dyn Error>> {
+    let mut rendered = Vec::new();
+    write_accounts(accounts, schema, format, &mut rendered)?;
+
+    let mut outs = sinks
+        .iter()
+        .map(Sink::open)
+        .collect::<io::Result<Vec<_>>>()?;
+    for out in outs.iter_mut() {
+        out.write_all(&rendered)?;
+    }
+    for out in outs.iter_mut() {
+        out.sync()?;
+    }
+    for out in outs {
+        out.finish()?;
+    }
+
+    Ok(())
+}
+
+/// Write to a single sink, publishing it only if `write` succeeds
+pub fn write_to_sink<F>(sink: &Sink, write: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), Box<dyn Error>>,
+{
+    let mut out = sink.open()?;
+    write(&mut out)?;
+    out.finish()?;
+    Ok(())
+}
+
+/// Temp path used while writing `path`: `<path>.tmp`
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+/// File written transactionally
+/// Output goes to `<path>.tmp` and is renamed over `path` only on `commit`,
+/// so a failed run never leaves a plausible-looking but incomplete file behind.
+/// Dropping without committing removes the temp file.
+pub struct AtomicFile {
+    path: PathBuf,
+    tmp: PathBuf,
+    writer: Option<BufWriter<File>>,
+}
+
+impl AtomicFile {
+    /// Create `<path>.tmp` for writing
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let tmp = temp_path(&path);
+        let file = File::create(&tmp)?;
+
+        Ok(Self {
+            path,
+            tmp,
+            writer: Some(BufWriter::new(file)),
+        })
+    }
+
+    /// Flush and sync the temp file, without publishing it yet
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Flush, sync and rename the temp file over the target path
+    /// On failure the temp file is removed, as when dropped
+    pub fn commit(mut self) -> io::Result<()> {
+        // Make sure everything hit the disk before publishing the file
+        self.sync()?;
+        if self.writer.is_some() {
+            fs::rename(&self.tmp, &self.path)?;
+            self.writer = None;
+        }
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.write(buf),
+            None => Err(io::Error::other("file already committed")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        // Not committed: best effort cleanup of the partial temp file
+        if self.writer.take().is_some() {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("core-tx-runner-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).expect("Failed to create scratch dir");
+        dir
+    }
+
+    #[test]
+    fn test_temp_path() {
+        assert_eq!(
+            temp_path(Path::new("out/accounts.csv")),
+            PathBuf::from("out/accounts.csv.tmp")
+        );
+    }
+
+    #[test]
+    fn test_parse_sink() {
+        assert_eq!(Sink::parse("-"), Sink::Stdout);
+        assert_eq!(Sink::parse("out.csv"), Sink::File(PathBuf::from("out.csv")));
+    }
+
+    #[test]
+    fn test_write_accounts_csv() {
+        let mut accounts = HashMap::new();
+        let mut account = Account::new(1);
+        account.deposit(dec!(1.5));
+        accounts.insert(1, account);
+
+        let mut buf = Vec::new();
+        write_accounts(&accounts, Schema::V1, OutputFormat::Csv, &mut buf)
+            .expect("Failed to write");
+
+        let text = String::from_utf8(buf).expect("Invalid UTF-8");
+        assert_eq!(
+            text,
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+        );
+    }
+
+    #[test]
+    fn test_write_accounts_v2() {
+        let mut account = Account::new(7);
+        account.deposit(dec!(10.12345));
+        account.hold_funds(dec!(10.12345));
+        account.chargeback(dec!(10.12345));
+        account.last_tx = Some(3);
+        let accounts = HashMap::from([(7, account)]);
+
+        let mut buf = Vec::new();
+        write_accounts(&accounts, Schema::V2, OutputFormat::Csv, &mut buf)
+            .expect("Failed to write");
+
+        let text = String::from_utf8(buf).expect("Invalid UTF-8");
+        assert_eq!(
+            text,
+            "client,available,held,total,locked,lock_reason,open_disputes,last_tx,dormant\n\
+             7,0.0000,0.0000,0.0000,true,chargeback,0,3,false\n"
+        );
+
+        let mut buf = Vec::new();
+        write_accounts(
+            &HashMap::from([(1, Account::new(1))]),
+            Schema::V2,
+            OutputFormat::Csv,
+            &mut buf,
+        )
+        .unwrap();
+        let text = String::from_utf8(buf).expect("Invalid UTF-8");
+        assert!(text.ends_with("\n1,0.0000,0.0000,0.0000,false,,0,,false\n"));
+
+        let mut account = Account::new(2);
+        account.deposit(dec!(0.1));
+        let mut buf = Vec::new();
+        write_accounts(
+            &HashMap::from([(2, account)]),
+            Schema::V2,
+            OutputFormat::Csv,
+            &mut buf,
+        )
+        .unwrap();
+        let text = String::from_utf8(buf).expect("Invalid UTF-8");
+        assert!(text.ends_with("\n2,0.1000,0.0000,0.1000,false,,0,,false\n"));
+    }
+
+    fn accounts() -> HashMap<ClientId, Account> {
+        [(10, dec!(2.5)), (2, dec!(1000.125)), (7, dec!(0))]
+            .into_iter()
+            .map(|(client, amount)| {
+                let mut account = Account::new(client);
+                account.deposit(amount);
+                (client, account)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_accounts_ordered_by_client() {
+        let mut buf = Vec::new();
+        write_accounts(&accounts(), Schema::V1, OutputFormat::Csv, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,available,held,total,locked\n\
+             2,1000.125,0,1000.125,false\n\
+             7,0,0,0,false\n\
+             10,2.5,0,2.5,false\n"
+        );
+    }
+
+    #[test]
+    fn test_v1_amounts_exact() {
+        let mut account = Account::new(1);
+        account.deposit(dec!(12345678901234567.12345));
+        let mut buf = Vec::new();
+        write_accounts(
+            &HashMap::from([(1, account)]),
+            Schema::V1,
+            OutputFormat::Csv,
+            &mut buf,
+        )
+        .unwrap();
+        assert!(String::from_utf8(buf)
+            .unwrap()
+            .ends_with("\n1,12345678901234567.1234,0,12345678901234567.1234,false\n"));
+    }
+
+    #[test]
+    fn test_write_accounts_json() {
+        let mut buf = Vec::new();
+        write_accounts(&accounts(), Schema::V1, OutputFormat::Json, &mut buf).unwrap();
+        let rows: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(rows[0]["client"], 2);
+        assert_eq!(rows[0]["available"], "1000.125");
+        assert_eq!(rows[2]["locked"], false);
+        assert!(buf.ends_with(b"]\n"));
+
+        let mut buf = Vec::new();
+        write_accounts(&accounts(), Schema::V2, OutputFormat::Json, &mut buf).unwrap();
+        let rows: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(rows[1]["total"], "0.0000");
+        assert_eq!(rows[1]["lock_reason"], serde_json::Value::Null);
+
+        let mut buf = Vec::new();
+        write_accounts(&HashMap::new(), Schema::V1, OutputFormat::Json, &mut buf).unwrap();
+        assert_eq!(buf, b"[]\n");
+    }
+
+    #[test]
+    fn test_write_accounts_table() {
+        let mut buf = Vec::new();
+        write_accounts(&accounts(), Schema::V1, OutputFormat::Table, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client  available  held  total     locked\n\
+             ------  ---------  ----  --------  ------\n\
+             \x20    2   1000.125     0  1000.125  false\n\
+             \x20    7          0     0         0  false\n\
+             \x20   10        2.5     0       2.5  false\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_output_format() {
+        assert_eq!("table".parse(), Ok(OutputFormat::Table));
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_parse_schema() {
+        assert_eq!("v2".parse(), Ok(Schema::V2));
+        assert!("v3".parse::<Schema>().is_err());
+    }
+
+    #[test]
+    fn test_atomic_file_commit() {
+        let dir = scratch_dir("atomic-ok");
+        let path = dir.join("accounts.csv");
+
+        let mut file = AtomicFile::create(&path).expect("Failed to create");
+        file.write_all(b"hello").unwrap();
+        assert!(!path.exists());
+        file.commit().expect("Failed to commit");
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello");
+        assert!(!temp_path(&path).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_atomic_file_dropped_keeps_previous_file() {
+        let dir = scratch_dir("atomic-fail");
+        let path = dir.join("accounts.csv");
+        fs::write(&path, "previous").unwrap();
+
+        {
+            let mut file = AtomicFile::create(&path).expect("Failed to create");
+            file.write_all(b"partial").unwrap();
+            // Dropped without commit, e.g. due to an error
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "previous");
+        assert!(!temp_path(&path).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_commit_removes_temp_file() {
+        let dir = scratch_dir("failed-commit");
+        // A non-empty directory cannot be renamed over
+        let path = dir.join("accounts.csv");
+        fs::create_dir_all(path.join("taken")).unwrap();
+
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"client\n").unwrap();
+        assert!(temp_path(&path).exists());
+        assert!(file.commit().is_err());
+        assert!(!temp_path(&path).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_accounts_to_multiple_sinks() {
+        let dir = scratch_dir("fanout");
+        let first = dir.join("a.csv");
+        let second = dir.join("b.csv");
+
+        let mut accounts = HashMap::new();
+        for client in 1..=3 {
+            let mut account = Account::new(client);
+            account.deposit(dec!(10));
+            accounts.insert(client, account);
+        }
+
+        let sinks = vec![Sink::File(first.clone()), Sink::File(second.clone())];
+        write_accounts_to_sinks(&accounts, Schema::V1, OutputFormat::Csv, &sinks)
+            .expect("Failed to write");
+
+        let a = fs::read_to_string(&first).unwrap();
+        let b = fs::read_to_string(&second).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.lines().count(), 4);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+//! Risk rules and event sinks from outside the crate
+//!
+//! `RiskRule`, `RecordTransformer` and `EventSink` are the extension points: a
+//! transformer may rewrite a record before the engine looks at it, a risk rule
+//! may refuse a record before it is applied, a sink sees every applied record
+//! with the account state after it. Embedders implement them directly, and the
+//! `wasm` feature runs them from sandboxed modules (see `crate::wasm`).
+//!
+//! With the `plugins` feature they can also come from shared libraries loaded
+//! at startup, so rules ship without rebuilding the runner. The ABI is plain C
+//! and data crosses it as JSON, so plugins can be written in any language and
+//! do not depend on this crate's Rust types:
+//! - the library exports `core_tx_runner_plugin_v1`, returning a `PluginVtable`
+//! - `check` gets the record (NDJSON input form, amount as a string) and the
+//!   account (as served by `GET /accounts/{client}`, `null` for a new client);
+//!   0 lets the record through, anything else rejects it with `risk_rule`
+//! - `applied` gets `{"position":n,"record":{..},"account":{..}}`
+//! - `destroy` is called once when the runner is done with the plugin
+//!
+//! Strings passed to a plugin are only valid for the duration of the call.
+//! Calls into one plugin never overlap, but may come from different threads.
+
+use crate::types::{Account, RejectionReason, TransactionRecord};
+use std::ffi::{c_char, c_void};
+use std::fmt;
+
+/// Version of `PluginVtable`, checked at load
+pub const ABI_VERSION: u32 = 1;
+
+/// Symbol a plugin library exports, of type `PluginEntry`
+pub const ENTRY_POINT: &str = "core_tx_runner_plugin_v1";
+
+/// Refuses records before they are applied
+pub trait RiskRule: Send + fmt::Debug {
+    /// Check a record against the client's account, `None` if it has none yet
+    fn check(
+        &mut self,
+        record: &TransactionRecord,
+        account: Option<&Account>,
+    ) -> Result<(), RejectionReason>;
+}
+
+/// Rewrites records before any check, e.g. to map a partner's conventions
+pub trait RecordTransformer: Send + fmt::Debug {
+    /// The record to process instead, or why to reject it
+    fn transform(
+        &mut self,
+        record: TransactionRecord,
+    ) -> Result<TransactionRecord, RejectionReason>;
+}
+
+/// Receives every applied record
+pub trait EventSink {
+    /// Called with the record position, the record as applied and the resulting account
+    fn applied(&mut self, position: u64, record: &TransactionRecord, account: &Account);
+}
+
+/// Functions and state a plugin hands to the runner, any function may be null
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PluginVtable {
+    /// Must be `ABI_VERSION`
+    pub abi_version: u32,
+    /// NUL-terminated name, used in messages
+    pub name: *const c_char,
+    /// Passed back to every call
+    pub state: *mut c_void,
+    /// Risk rule: record and account JSON, 0 to let the record through
+    pub check: Option<
+        unsafe extern "C" fn(
+            state: *mut c_void,
+            record: *const c_char,
+            account: *const c_char,
+        ) -> i32,
+    >,
+    /// Sink: applied event JSON
+    pub applied: Option<unsafe extern "C" fn(state: *mut c_void, event: *const c_char)>,
+    /// Release the state
+    pub destroy: Option<unsafe extern "C" fn(state: *mut c_void)>,
+}
+
+/// Type of the `ENTRY_POINT` symbol
+pub type PluginEntry = unsafe extern "C" fn() -> PluginVtable;
+
+/// Record as plugins get it: the NDJSON input form, amount as a string
+pub fn record_json(record: &TransactionRecord) -> serde_json::Value {
+    let mut json = serde_json::json!({
+        "type": record.tx_type.as_str(),
+        "client": record.client,
+        "tx": record.tx,
+    });
+    if let Some(amount) = record.amount {
+        json["amount"] = amount.to_string().into();
+    }
+    if let Some(timestamp) = record.timestamp {
+        json["timestamp"] = timestamp.to_string().into();
+    }
+    json
+}
+
+#[cfg(feature = "plugins")]
+pub use dylib::{load_dir, Plugin};
+
+#[cfg(feature = "plugins")]
+mod dylib {
+    use super::{
+        record_json, EventSink, PluginEntry, PluginVtable, RiskRule, ABI_VERSION, ENTRY_POINT,
+    };
+    use crate::types::{Account, RejectionReason, TransactionRecord};
+    use libloading::Library;
+    use std::error::Error;
+    use std::ffi::{CStr, CString};
+    use std::fmt;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    /// A loaded plugin; clones share it, so one plugin can be both rule and sink
+    #[derive(Clone)]
+    pub struct Plugin {
+        name: String,
+        loaded: Arc<Mutex<Loaded>>,
+    }
+
+    struct Loaded {
+        vtable: PluginVtable,
+        // Keeps the code behind the vtable mapped, dropped after `destroy`
+        _library: Option<Library>,
+    }
+
+    // The ABI contract: plugin state may move between threads, and calls are
+    // serialized by the mutex
+    unsafe impl Send for Loaded {}
+
+    impl Drop for Loaded {
+        fn drop(&mut self) {
+            if let Some(destroy) = self.vtable.destroy {
+                unsafe { destroy(self.vtable.state) };
+            }
+        }
+    }
+
+    impl Plugin {
+        /// Load a plugin library
+        ///
+        /// # Safety
+        /// Loading runs the library's initializers and trusts its vtable: the
+        /// library must be a plugin built against this ABI.
+        pub unsafe fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+            let library = Library::new(path)?;
+            let entry = library.get::<PluginEntry>(ENTRY_POINT.as_bytes())?;
+            let vtable = entry();
+            Self::from_vtable(vtable, Some(library))
+                .map_err(|e| format!("{}: {}", path.display(), e).into())
+        }
+
+        /// Wrap a vtable, checking its version
+        ///
+        /// # Safety
+        /// `vtable` must follow the ABI, its functions valid while `library` is loaded.
+        pub unsafe fn from_vtable(
+            vtable: PluginVtable,
+            library: Option<Library>,
+        ) -> Result<Self, String> {
+            // Nothing else in a vtable of another version can be trusted, not even `destroy`
+            if vtable.abi_version != ABI_VERSION {
+                return Err(format!(
+                    "plugin ABI version {}, expected {}",
+                    vtable.abi_version, ABI_VERSION
+                ));
+            }
+            let name = if vtable.name.is_null() {
+                "unnamed".to_string()
+            } else {
+                CStr::from_ptr(vtable.name).to_string_lossy().into_owned()
+            };
+            let loaded = Loaded {
+                vtable,
+                _library: library,
+            };
+            Ok(Self {
+                name,
+                loaded: Arc::new(Mutex::new(loaded)),
+            })
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        /// Whether the plugin checks records
+        pub fn is_risk_rule(&self) -> bool {
+            self.vtable().check.is_some()
+        }
+
+        /// Whether the plugin wants applied records
+        pub fn is_sink(&self) -> bool {
+            self.vtable().applied.is_some()
+        }
+
+        fn vtable(&self) -> PluginVtable {
+            self.loaded.lock().expect("plugin lock poisoned").vtable
+        }
+    }
+
+    impl fmt::Debug for Plugin {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Plugin").field("name", &self.name).finish()
+        }
+    }
+
+    impl RiskRule for Plugin {
+        fn check(
+            &mut self,
+            record: &TransactionRecord,
+            account: Option<&Account>,
+        ) -> Result<(), RejectionReason> {
+            let loaded = self.loaded.lock().expect("plugin lock poisoned");
+            let Some(check) = loaded.vtable.check else {
+                return Ok(());
+            };
+            let record = to_c_json(&record_json(record));
+            let account = to_c_json(&serde_json::json!(account));
+            match unsafe { check(loaded.vtable.state, record.as_ptr(), account.as_ptr()) } {
+                0 => Ok(()),
+                _ => Err(RejectionReason::RiskRule),
+            }
+        }
+    }
+
+    impl EventSink for Plugin {
+        fn applied(&mut self, position: u64, record: &TransactionRecord, account: &Account) {
+            let loaded = self.loaded.lock().expect("plugin lock poisoned");
+            let Some(applied) = loaded.vtable.applied else {
+                return;
+            };
+            let event = to_c_json(&serde_json::json!({
+                "position": position,
+                "record": record_json(record),
+                "account": account,
+            }));
+            unsafe { applied(loaded.vtable.state, event.as_ptr()) };
+        }
+    }
+
+    /// Load every library in `dir`, in file name order
+    ///
+    /// # Safety
+    /// See `Plugin::load`: every library in the directory is trusted.
+    pub unsafe fn load_dir(dir: &Path) -> Result<Vec<Plugin>, Box<dyn Error>> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some(std::env::consts::DLL_EXTENSION) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        paths.iter().map(|path| Plugin::load(path)).collect()
+    }
+
+    fn to_c_json(value: &serde_json::Value) -> CString {
+        // Serialized JSON escapes control characters, so it has no NUL
+        CString::new(value.to_string()).expect("JSON contains no NUL")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::types::TransactionType;
+        use rust_decimal_macros::dec;
+        use std::ffi::{c_char, c_void};
+        use std::ptr;
+
+        #[derive(Default)]
+        struct State {
+            checked: Vec<String>,
+            events: Vec<String>,
+        }
+
+        unsafe extern "C" fn check(
+            state: *mut c_void,
+            record: *const c_char,
+            account: *const c_char,
+        ) -> i32 {
+            let state = &mut *(state as *mut State);
+            let record = CStr::from_ptr(record).to_str().unwrap();
+            let account = CStr::from_ptr(account).to_str().unwrap();
+            state.checked.push(format!("{} {}", record, account));
+            // Refuse withdrawals of client 2
+            i32::from(record.contains("\"withdrawal\"") && record.contains("\"client\":2"))
+        }
+
+        unsafe extern "C" fn applied(state: *mut c_void, event: *const c_char) {
+            let state = &mut *(state as *mut State);
+            state
+                .events
+                .push(CStr::from_ptr(event).to_str().unwrap().to_string());
+        }
+
+        fn vtable(state: &mut State) -> PluginVtable {
+            PluginVtable {
+                abi_version: ABI_VERSION,
+                name: c"test".as_ptr(),
+                state: state as *mut State as *mut c_void,
+                check: Some(check),
+                applied: Some(applied),
+                destroy: None,
+            }
+        }
+
+        fn record(tx_type: TransactionType, client: u16) -> TransactionRecord {
+            TransactionRecord {
+                tx_type,
+                client,
+                tx: 7,
+                amount: Some(dec!(1.5)),
+                seq: None,
+                timestamp: None,
+                line: None,
+            }
+        }
+
+        #[test]
+        fn test_plugin_calls() {
+            let mut state = State::default();
+            let mut plugin = unsafe { Plugin::from_vtable(vtable(&mut state), None) }.unwrap();
+            assert_eq!(plugin.name(), "test");
+            assert!(plugin.is_risk_rule() && plugin.is_sink());
+
+            let mut account = Account::new(2);
+            account.deposit(dec!(3));
+            assert_eq!(
+                plugin.check(&record(TransactionType::Deposit, 2), None),
+                Ok(())
+            );
+            assert_eq!(
+                plugin.check(&record(TransactionType::Withdrawal, 2), Some(&account)),
+                Err(RejectionReason::RiskRule)
+            );
+            plugin.applied(4, &record(TransactionType::Deposit, 2), &account);
+            drop(plugin);
+
+            assert_eq!(
+                state.checked[0],
+                r#"{"amount":"1.5","client":2,"tx":7,"type":"deposit"} null"#
+            );
+            let (_, account) = state.checked[1].split_once(' ').unwrap();
+            let account: serde_json::Value = serde_json::from_str(account).unwrap();
+            assert_eq!(account["available"], "3");
+            let event: serde_json::Value = serde_json::from_str(&state.events[0]).unwrap();
+            assert_eq!(event["position"], 4);
+            assert_eq!(event["record"]["type"], "deposit");
+            assert_eq!(event["account"]["client"], 2);
+        }
+
+        #[test]
+        fn test_version_mismatch() {
+            let mut state = State::default();
+            let mut vtable = vtable(&mut state);
+            vtable.abi_version = ABI_VERSION + 1;
+            vtable.name = ptr::null();
+            assert!(unsafe { Plugin::from_vtable(vtable, None) }.is_err());
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! End-of-run proof that the accounts add up
+//!
+//! Downstream systems check the proof before accepting an account file: the
+//! account totals must equal what flowed in and out (conservation), and the
+//! account file must hash to `accounts_sha256`. With a key configured the proof
+//! is signed with HMAC-SHA256 over its exact bytes, the signature kept next to it
+//! as hex, so the proof file is verified as written and never re-serialized:
+//!
+//! openssl dgst -sha256 -hmac "$(cat proof.key)" proof.json
+
+use crate::closure::Closure;
+use crate::engine::EngineReport;
+use crate::stats::ClientStats;
+use crate::types::{serialize_decimal_str, StoredTransaction, TransactionRecord, TransactionType};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+/// Version of the proof layout
+pub const PROOF_VERSION: u32 = 1;
+
+/// Money that moved through an engine, what the account totals must add up to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Flows {
+    /// Sum of the account totals the engine started from, non-zero when resumed
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub opening: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub deposits: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub withdrawals: Decimal,
+    /// Disputed deposits charged back
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub chargebacks: Decimal,
+    /// Disputed withdrawals, held or charged back, whose funds came back
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub reversed_withdrawals: Decimal,
+    /// Balances of closed accounts paid out
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub payouts: Decimal,
+    /// Balances of closed accounts written off
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub written_off: Decimal,
+}
+
+impl Flows {
+    /// Account for an applied record; references need the transaction they name
+    pub fn record(&mut self, record: &TransactionRecord, referenced: Option<&StoredTransaction>) {
+        let amount = record.amount.unwrap_or_default();
+        match record.tx_type {
+            TransactionType::Deposit => self.deposits += amount,
+            TransactionType::Withdrawal => self.withdrawals += amount,
+            // Moves the account's own balance, see `record_closure`
+            TransactionType::Close => {}
+            TransactionType::Unlock => {}
+            _ => {
+                let Some(stored) = referenced else { return };
+                match (record.tx_type, stored.tx_type) {
+                    (TransactionType::Chargeback, TransactionType::Deposit) => {
+                        self.chargebacks += stored.amount;
+                    }
+                    (TransactionType::Dispute, TransactionType::Withdrawal) => {
+                        self.reversed_withdrawals += stored.amount;
+                    }
+                    (TransactionType::Resolve, TransactionType::Withdrawal) => {
+                        self.reversed_withdrawals -= stored.amount;
+                    }
+                    // Other references only move funds between available and held
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Account for the balance a closed account disposed of
+    pub fn record_closure(&mut self, closure: &Closure) {
+        self.payouts += closure.payout;
+        self.written_off += closure.written_off;
+    }
+
+    /// What the account totals should sum to
+    pub fn expected_total(&self) -> Decimal {
+        self.opening + self.deposits - self.withdrawals - self.chargebacks
+            + self.reversed_withdrawals
+            - self.payouts
+            - self.written_off
+    }
+
+    /// Add another engine's flows, e.g. from another shard
+    pub fn merge(&mut self, other: &Flows) {
+        self.opening += other.opening;
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.chargebacks += other.chargebacks;
+        self.reversed_withdrawals += other.reversed_withdrawals;
+        self.payouts += other.payouts;
+        self.written_off += other.written_off;
+    }
+}
+
+/// Applied and rejected records of one operation type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct OpCounts {
+    pub applied: u64,
+    pub rejected: u64,
+}
+
+/// Hash of one input file, `None` for standard input which cannot be read again
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InputHash {
+    pub path: String,
+    pub sha256: Option<String>,
+}
+
+/// The proof file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Proof {
+    pub version: u32,
+    /// Records given to the engine, applied or not; malformed rows are not
+    pub records_processed: u64,
+    /// By operation type, keyed like the `type` column
+    pub counts: BTreeMap<&'static str, OpCounts>,
+    pub accounts: usize,
+    pub flows: Flows,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub expected_total: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub accounts_total: Decimal,
+    /// Whether the account totals match the flows
+    pub balanced: bool,
+    /// SHA-256 of the account file as written
+    pub accounts_sha256: String,
+    pub inputs: Vec<InputHash>,
+}
+
+impl Proof {
+    /// Build the proof of a finished run whose accounts were written as `accounts_file`
+    pub fn new(report: &EngineReport, accounts_file: &[u8], inputs: Vec<InputHash>) -> Self {
+        let mut stats = ClientStats::default();
+        for client_stats in report.client_stats.values() {
+            stats.merge(client_stats);
+        }
+        let counts = [
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+            TransactionType::Close,
+            TransactionType::Unlock,
+        ]
+        .into_iter()
+        .map(|tx_type| {
+            let op = stats.get(tx_type);
+            let counts = OpCounts {
+                applied: op.applied,
+                rejected: op.rejected,
+            };
+            (tx_type.as_str(), counts)
+        })
+        .collect();
+
+        let accounts_total = report.accounts.values().map(|a| a.total).sum();
+        let expected_total = report.flows.expected_total();
+        Self {
+            version: PROOF_VERSION,
+            records_processed: report.records_processed,
+            counts,
+            accounts: report.accounts.len(),
+            flows: report.flows,
+            expected_total,
+            accounts_total,
+            balanced: accounts_total == expected_total,
+            accounts_sha256: sha256_hex(accounts_file),
+            inputs,
+        }
+    }
+
+    /// The proof as written to the proof file, the bytes a signature covers
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = serde_json::to_vec_pretty(self).expect("proof serializes");
+        bytes.push(b'\n');
+        bytes
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())?;
+        writer.flush()
+    }
+}
+
+/// Hex SHA-256 of `bytes`
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex(&hmac_sha256::Hash::hash(bytes))
+}
+
+/// Hex HMAC-SHA256 of a proof file
+pub fn sign(proof: &[u8], key: &[u8]) -> String {
+    hex(&hmac_sha256::HMAC::mac(proof, key))
+}
+
+/// Whether `signature` is the hex HMAC-SHA256 of `proof` under `key`
+pub fn verify(proof: &[u8], key: &[u8], signature: &str) -> bool {
+    let expected = sign(proof, key);
+    let signature = signature.trim().to_ascii_lowercase();
+    // Compare every byte, so timing does not give away the matching prefix
+    expected.len() == signature.len()
+        && expected
+            .bytes()
+            .zip(signature.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::engine::PaymentsEngine;
+    use rust_decimal_macros::dec;
+
+    fn record(
+        tx_type: TransactionType,
+        client: u16,
+        tx: u32,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tx_type,
+            client,
+            tx,
+            amount,
+            seq: None,
+            timestamp: None,
+            line: None,
+        }
+    }
+
+    fn run(config: EngineConfig, records: &[TransactionRecord]) -> EngineReport {
+        let mut engine = PaymentsEngine::new(config);
+        for record in records {
+            let _ = engine.process(*record);
+        }
+        engine.into_report()
+    }
+
+    #[test]
+    fn test_conservation() {
+        let config = EngineConfig {
+            allow_withdrawal_disputes: true,
+            ..EngineConfig::default()
+        };
+        let report = run(
+            config,
+            &[
+                record(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+                record(TransactionType::Deposit, 1, 2, Some(dec!(5))),
+                record(TransactionType::Withdrawal, 1, 3, Some(dec!(3))),
+                record(TransactionType::Dispute, 1, 2, None),
+                record(TransactionType::Chargeback, 1, 2, None),
+                record(TransactionType::Deposit, 2, 4, Some(dec!(8))),
+                record(TransactionType::Withdrawal, 2, 5, Some(dec!(2))),
+                record(TransactionType::Dispute, 2, 5, None),
+                // Rejected, the account is locked
+                record(TransactionType::Withdrawal, 1, 6, Some(dec!(1))),
+            ],
+        );
+        assert_eq!(
+            report.flows,
+            Flows {
+                opening: dec!(0),
+                deposits: dec!(23),
+                withdrawals: dec!(5),
+                chargebacks: dec!(5),
+                reversed_withdrawals: dec!(2),
+                payouts: dec!(0),
+                written_off: dec!(0),
+            }
+        );
+
+        let proof = Proof::new(&report, b"accounts", Vec::new());
+        assert!(proof.balanced);
+        assert_eq!(proof.accounts_total, dec!(15));
+        assert_eq!(proof.accounts, 2);
+        assert_eq!(proof.records_processed, 9);
+        assert_eq!(proof.counts["withdrawal"].applied, 2);
+        assert_eq!(proof.counts["withdrawal"].rejected, 1);
+        assert_eq!(proof.accounts_sha256, sha256_hex(b"accounts"));
+    }
+
+    #[test]
+    fn test_unbalanced() {
+        let mut report = run(
+            EngineConfig::default(),
+            &[record(TransactionType::Deposit, 1, 1, Some(dec!(10)))],
+        );
+        report.accounts.get_mut(&1).unwrap().total += dec!(1);
+        let proof = Proof::new(&report, b"", Vec::new());
+        assert!(!proof.balanced);
+        assert_eq!(proof.expected_total, dec!(10));
+    }
+
+    #[test]
+    fn test_hashes_and_signature() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let signature = sign(b"proof", b"key");
+        assert!(verify(b"proof", b"key", &signature));
+        assert!(verify(
+            b"proof",
+            b"key",
+            &format!("{}\n", signature.to_uppercase())
+        ));
+        assert!(!verify(b"proof!", b"key", &signature));
+        assert!(!verify(b"proof", b"other", &signature));
+        assert!(!verify(b"proof", b"key", ""));
+    }
+}
@@ -0,0 +1,156 @@
+use crate::types::{Account, ClientId, TransactionType};
+use rust_decimal::Decimal;
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::io::{self, Read, Write};
+
+/// Read externally reported balances, a CSV with `client` and `total` columns
+/// Other columns are ignored so bank exports can be used as they are
+pub fn read_balances<R: Read>(reader: R) -> Result<HashMap<ClientId, Decimal>, Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h == name)
+            .ok_or_else(|| format!("balances file has no '{}' column", name))
+    };
+    let (client, total) = (column("client")?, column("total")?);
+
+    let mut balances = HashMap::new();
+    for row in reader.records() {
+        let row = row?;
+        let field = |index: usize| row.get(index).unwrap_or_default();
+
+        let id: ClientId = field(client).parse()?;
+        if balances.insert(id, field(total).parse()?).is_some() {
+            return Err(format!("client {} listed twice in balances file", id).into());
+        }
+    }
+
+    Ok(balances)
+}
+
+/// A client whose engine total differs from the external balance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Break {
+    pub client: ClientId,
+    /// Engine-computed total, zero if the engine has no such account
+    pub engine: Decimal,
+    /// Externally reported total, zero if the client is not listed
+    pub external: Decimal,
+}
+
+impl Break {
+    /// External minus engine total
+    pub fn difference(&self) -> Decimal {
+        self.external - self.engine
+    }
+
+    /// Transaction that would bring the engine in line with the external balance
+    /// A suggestion only: a withdrawal may still fail on available funds or a lock
+    pub fn adjustment(&self) -> (TransactionType, Decimal) {
+        let difference = self.difference();
+        if difference.is_sign_negative() {
+            (TransactionType::Withdrawal, -difference)
+        } else {
+            (TransactionType::Deposit, difference)
+        }
+    }
+}
+
+/// Compare engine totals (at output precision) against external balances, ordered by client
+pub fn reconcile(
+    accounts: &HashMap<ClientId, Account>,
+    external: &HashMap<ClientId, Decimal>,
+) -> Vec<Break> {
+    let clients: BTreeSet<ClientId> = accounts.keys().chain(external.keys()).copied().collect();
+
+    clients
+        .into_iter()
+        .map(|client| Break {
+            client,
+            engine: accounts
+                .get(&client)
+                .map(|a| a.total.round_dp(4))
+                .unwrap_or_default(),
+            external: external.get(&client).copied().unwrap_or_default(),
+        })
+        .filter(|b| b.engine != b.external)
+        .collect()
+}
+
+/// Write the break report as CSV
+pub fn write_breaks<W: Write>(breaks: &[Break], writer: W) -> io::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record([
+        "client",
+        "engine_total",
+        "external_total",
+        "difference",
+        "adjustment_type",
+        "adjustment_amount",
+    ])?;
+    for b in breaks {
+        let (tx_type, amount) = b.adjustment();
+        writer.write_record([
+            b.client.to_string().as_str(),
+            &b.engine.to_string(),
+            &b.external.to_string(),
+            &b.difference().to_string(),
+            tx_type.as_str(),
+            &amount.to_string(),
+        ])?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_read_balances() {
+        let balances = read_balances("bank_ref,client,total\nA,1, 10.5\nB,2,0\n".as_bytes())
+            .expect("Failed to parse");
+        assert_eq!(balances[&1], dec!(10.5));
+        assert_eq!(balances.len(), 2);
+
+        assert!(read_balances("client,total\n1,1\n1,2\n".as_bytes()).is_err());
+        assert!(read_balances("client,balance\n1,1\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let mut accounts = HashMap::new();
+        for (client, amount) in [(1, dec!(100)), (2, dec!(50.12345)), (3, dec!(7))] {
+            let mut account = Account::new(client);
+            account.deposit(amount);
+            accounts.insert(client, account);
+        }
+        let external = HashMap::from([(1, dec!(100.0)), (2, dec!(40.1234)), (4, dec!(3))]);
+
+        let breaks = reconcile(&accounts, &external);
+        let clients: Vec<_> = breaks.iter().map(|b| b.client).collect();
+        assert_eq!(clients, vec![2, 3, 4]);
+
+        assert_eq!(breaks[0].difference(), dec!(-10));
+        assert_eq!(
+            breaks[0].adjustment(),
+            (TransactionType::Withdrawal, dec!(10))
+        );
+        assert_eq!(breaks[1].external, dec!(0));
+        assert_eq!(breaks[2].adjustment(), (TransactionType::Deposit, dec!(3)));
+
+        let mut buf = Vec::new();
+        write_breaks(&breaks[2..], &mut buf).expect("Failed to write");
+        assert_eq!(
+            String::from_utf8(buf).expect("Invalid UTF-8"),
+            "client,engine_total,external_total,difference,adjustment_type,adjustment_amount\n\
+             4,0,3,3,deposit,3\n"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use crate::types::{serialize_decimal_str, ClientId, TransactionId};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+
+/// Part of a deposit that went to cover a negative available balance
+///
+/// Available funds go negative when a dispute holds more than is left (the
+/// deposit was already spent). Under `EngineConfig::recovery_sweep` deposits
+/// reaching such an account are counted against the deficit first, and each
+/// one that recovers something is recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Recovery {
+    /// Position of the record the funds became available at: the deposit's,
+    /// or the first after its hold period
+    pub position: u64,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// Amount of the deposit applied to the deficit
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub recovered: Decimal,
+    /// Deficit still open afterwards, zero once recovered in full
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub remaining_deficit: Decimal,
+}
+
+impl Recovery {
+    /// Recovery from crediting `amount` to an account that had `available`
+    /// `None` unless available was negative
+    pub fn from_credit(
+        position: u64,
+        client: ClientId,
+        tx: TransactionId,
+        available: Decimal,
+        amount: Decimal,
+    ) -> Option<Self> {
+        if available >= Decimal::ZERO || amount <= Decimal::ZERO {
+            return None;
+        }
+        let deficit = -available;
+        let recovered = amount.min(deficit);
+        Some(Self {
+            position,
+            client,
+            tx,
+            recovered,
+            remaining_deficit: deficit - recovered,
+        })
+    }
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record {}: deposit tx {} recovered {} for client {} ({} deficit left)",
+            self.position, self.tx, self.recovered, self.client, self.remaining_deficit
+        )
+    }
+}
+
+/// Write recoveries as CSV, in the order they happened
+pub fn write_recoveries<W: Write>(
+    recoveries: &[Recovery],
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for recovery in recoveries {
+        writer.serialize(recovery)?;
+    }
+    if recoveries.is_empty() {
+        writer.write_record(["position", "client", "tx", "recovered", "remaining_deficit"])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_from_credit() {
+        assert_eq!(Recovery::from_credit(1, 1, 1, dec!(0), dec!(5)), None);
+        assert_eq!(Recovery::from_credit(1, 1, 1, dec!(3), dec!(5)), None);
+
+        let partial = Recovery::from_credit(4, 2, 9, dec!(-10), dec!(4)).unwrap();
+        assert_eq!(partial.recovered, dec!(4));
+        assert_eq!(partial.remaining_deficit, dec!(6));
+
+        let full = Recovery::from_credit(5, 2, 10, dec!(-6), dec!(8)).unwrap();
+        assert_eq!(full.recovered, dec!(6));
+        assert_eq!(full.remaining_deficit, dec!(0));
+    }
+
+    #[test]
+    fn test_write_recoveries() {
+        let mut buf = Vec::new();
+        write_recoveries(
+            &[Recovery::from_credit(4, 2, 9, dec!(-10), dec!(4)).unwrap()],
+            &mut buf,
+        )
+        .expect("Failed to write");
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "position,client,tx,recovered,remaining_deficit\n4,2,9,4,6\n"
+        );
+
+        let mut buf = Vec::new();
+        write_recoveries(&[], &mut buf).expect("Failed to write");
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "position,client,tx,recovered,remaining_deficit\n"
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use crate::types::{ClientId, RejectionReason, TransactionId, TransactionRecord, TransactionType};
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// A record that was not applied, and why
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Rejection {
+    /// Line in the input file
+    pub line: Option<u64>,
+    /// Record fields, missing for rows that could not be parsed
+    #[serde(rename = "type")]
+    pub tx_type: Option<TransactionType>,
+    pub client: Option<ClientId>,
+    pub tx: Option<TransactionId>,
+    pub reason: RejectionReason,
+}
+
+impl Rejection {
+    pub fn new(record: &TransactionRecord, reason: RejectionReason) -> Self {
+        Self {
+            line: record.line,
+            tx_type: Some(record.tx_type),
+            client: Some(record.client),
+            tx: Some(record.tx),
+            reason,
+        }
+    }
+
+    /// Rejection of a row that failed to parse
+    pub fn malformed(line: Option<u64>) -> Self {
+        Self {
+            line,
+            tx_type: None,
+            client: None,
+            tx: None,
+            reason: RejectionReason::Malformed,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        match (self.tx_type, self.client, self.tx) {
+            (Some(tx_type), Some(client), Some(tx)) => write!(
+                f,
+                "{} tx {} for client {} {}",
+                tx_type.as_str(),
+                tx,
+                client,
+                self.reason
+            ),
+            _ => write!(f, "{}", self.reason),
+        }
+    }
+}
+
+/// Encoding of the rejects stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectsFormat {
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+}
+
+impl RejectsFormat {
+    /// NDJSON for `.ndjson`/`.jsonl` paths, CSV otherwise
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("ndjson" | "jsonl") => RejectsFormat::Ndjson,
+            _ => RejectsFormat::Csv,
+        }
+    }
+}
+
+/// Streams rejections as they happen
+pub enum RejectsWriter<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Ndjson(W),
+}
+
+impl<W: Write> RejectsWriter<W> {
+    /// Start a stream, writing the CSV header right away so an empty stream is still valid
+    pub fn new(format: RejectsFormat, writer: W) -> io::Result<Self> {
+        Ok(match format {
+            RejectsFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(writer);
+                writer.write_record(["line", "type", "client", "tx", "reason"])?;
+                RejectsWriter::Csv(Box::new(writer))
+            }
+            RejectsFormat::Ndjson => RejectsWriter::Ndjson(writer),
+        })
+    }
+
+    pub fn write(&mut self, rejection: &Rejection) -> io::Result<()> {
+        match self {
+            RejectsWriter::Csv(writer) => Ok(writer.serialize(rejection)?),
+            RejectsWriter::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, rejection)?;
+                writer.write_all(b"\n")
+            }
+        }
+    }
+
+    /// Flush and return the underlying writer
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            RejectsWriter::Csv(writer) => writer.into_inner().map_err(|e| e.into_error()),
+            RejectsWriter::Ndjson(mut writer) => {
+                writer.flush()?;
+                Ok(writer)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejections() -> [Rejection; 2] {
+        let record = TransactionRecord {
+            tx_type: TransactionType::Withdrawal,
+            client: 3,
+            tx: 12,
+            amount: None,
+            seq: None,
+            timestamp: None,
+            line: Some(5),
+        };
+        [
+            Rejection::new(&record, RejectionReason::InsufficientFunds),
+            Rejection::malformed(Some(7)),
+        ]
+    }
+
+    #[test]
+    fn test_csv_rejects() {
+        let mut writer = RejectsWriter::new(RejectsFormat::Csv, Vec::new()).unwrap();
+        for rejection in &rejections() {
+            writer.write(rejection).expect("Failed to write");
+        }
+        let text = String::from_utf8(writer.finish().unwrap()).expect("Invalid UTF-8");
+        assert_eq!(
+            text,
+            "line,type,client,tx,reason\n5,withdrawal,3,12,insufficient_funds\n7,,,,malformed\n"
+        );
+    }
+
+    #[test]
+    fn test_ndjson_rejects() {
+        let mut writer = RejectsWriter::new(RejectsFormat::Ndjson, Vec::new()).unwrap();
+        for rejection in &rejections() {
+            writer.write(rejection).expect("Failed to write");
+        }
+        let text = String::from_utf8(writer.finish().unwrap()).expect("Invalid UTF-8");
+        assert_eq!(
+            text,
+            "{\"line\":5,\"type\":\"withdrawal\",\"client\":3,\"tx\":12,\"reason\":\"insufficient_funds\"}\n\
+             {\"line\":7,\"type\":null,\"client\":null,\"tx\":null,\"reason\":\"malformed\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(
+            RejectsFormat::from_path(Path::new("rejects.ndjson")),
+            RejectsFormat::Ndjson
+        );
+        assert_eq!(
+            RejectsFormat::from_path(Path::new("rejects.csv")),
+            RejectsFormat::Csv
+        );
+    }
+}
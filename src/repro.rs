@@ -0,0 +1,193 @@
+//! Reproduction bundles for bug reports
+//!
+//! Under `--record-repro <dir>`, a run that breaks an invariant or panics copies
+//! what it takes to run it again into `<dir>`: the exact bytes of every file the
+//! run read (inputs, `--refeed`, `--load-state`, `--tier-rules`, `--tiers` and
+//! `--wasm` modules) under `files/`, and a `manifest.json` with the command
+//! line, the version and features of the binary and the failure. `repro run
+//! <dir>` parses the recorded command line again with those paths pointing into
+//! the bundle and processes the inputs the way the original run did, without
+//! writing any of its outputs.
+
+use crate::output::AtomicFile;
+use crate::schema;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Bundle description, written last so a bundle without one is incomplete
+pub const MANIFEST: &str = "manifest.json";
+
+/// Subdirectory the read files are copied to
+const FILES_DIR: &str = "files";
+
+/// Flags whose value is a file the run reads, besides its inputs
+pub const FILE_FLAGS: [&str; 4] = ["--load-state", "--tier-rules", "--tiers", "--wasm"];
+
+/// A file the recorded run read, and its copy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundledFile {
+    /// Path as given on the recorded command line
+    pub original: String,
+    /// Copy, relative to the bundle directory
+    pub path: String,
+}
+
+/// What `manifest.json` records
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReproManifest {
+    pub program: String,
+    pub version: String,
+    /// Cargo features of the binary that recorded the bundle
+    pub features: Vec<String>,
+    /// The invariant violation or panic that triggered the recording
+    pub failure: String,
+    /// Command line of the run, after the program name
+    pub args: Vec<String>,
+    pub files: Vec<BundledFile>,
+}
+
+impl ReproManifest {
+    /// Bundle the run of `args` into `dir`: copy `inputs` and the files named by
+    /// `FILE_FLAGS`, then write the manifest
+    pub fn record(dir: &Path, args: &[String], inputs: &[&str], failure: &str) -> io::Result<Self> {
+        let flagged = args
+            .windows(2)
+            .filter(|pair| FILE_FLAGS.contains(&pair[0].as_str()))
+            .map(|pair| pair[1].as_str());
+        let mut seen = HashSet::new();
+        let read: Vec<&str> = inputs
+            .iter()
+            .copied()
+            .chain(flagged)
+            .filter(|path| seen.insert(*path))
+            .collect();
+
+        fs::create_dir_all(dir.join(FILES_DIR))?;
+        let mut files = Vec::new();
+        for (i, original) in read.into_iter().enumerate() {
+            let name = Path::new(original)
+                .file_name()
+                .map_or_else(|| "input".into(), |name| name.to_string_lossy());
+            let path = format!("{}/{}-{}", FILES_DIR, i + 1, name);
+            fs::copy(original, dir.join(&path))?;
+            files.push(BundledFile {
+                original: original.to_string(),
+                path,
+            });
+        }
+
+        let manifest = Self {
+            program: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features: schema::features().into_iter().map(String::from).collect(),
+            failure: failure.to_string(),
+            args: args.to_vec(),
+            files,
+        };
+        let mut file = AtomicFile::create(dir.join(MANIFEST))?;
+        serde_json::to_writer_pretty(&mut file, &manifest)?;
+        writeln!(file)?;
+        file.commit()?;
+        Ok(manifest)
+    }
+
+    /// The manifest of the bundle in `dir`
+    pub fn read(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(dir.join(MANIFEST))
+            .map_err(|e| format!("{}: {}", dir.join(MANIFEST).display(), e))?;
+        Ok(serde_json::from_reader(io::BufReader::new(file))?)
+    }
+
+    /// The recorded command line, with every bundled path pointing into `dir`
+    pub fn replay_args(&self, dir: &Path) -> Vec<String> {
+        self.args
+            .iter()
+            .map(
+                |arg| match self.files.iter().find(|file| file.original == *arg) {
+                    Some(file) => dir.join(&file.path).to_string_lossy().into_owned(),
+                    None => arg.clone(),
+                },
+            )
+            .collect()
+    }
+}
+
+/// Message of a caught panic
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match (
+        payload.downcast_ref::<&str>(),
+        payload.downcast_ref::<String>(),
+    ) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic payload".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("core-tx-runner-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Failed to create scratch dir");
+        dir
+    }
+
+    #[test]
+    fn test_record_and_replay_args() {
+        let dir = scratch_dir("repro");
+        let input = dir.join("tx.csv");
+        fs::write(&input, "type,client,tx,amount\ndeposit,1,1,1\n").unwrap();
+        let rules = dir.join("rules.csv");
+        fs::write(&rules, "tier,min_balance,increment\n").unwrap();
+        let input = input.to_string_lossy().into_owned();
+        let rules = rules.to_string_lossy().into_owned();
+        let args: Vec<String> = [&input, "--tier-rules", &rules, "-o", "out.csv", &input]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        let bundle = dir.join("bundle");
+        let recorded = ReproManifest::record(
+            &bundle,
+            &args,
+            &[&input, &input],
+            "invariant check failed: x",
+        )
+        .unwrap();
+        assert_eq!(recorded.files.len(), 2);
+        assert_eq!(recorded.files[0].path, "files/1-tx.csv");
+        assert_eq!(recorded.files[1].path, "files/2-rules.csv");
+        assert_eq!(
+            fs::read(bundle.join("files/1-tx.csv")).unwrap(),
+            fs::read(&input).unwrap()
+        );
+
+        let manifest = ReproManifest::read(&bundle).unwrap();
+        assert_eq!(manifest, recorded);
+        assert_eq!(manifest.version, env!("CARGO_PKG_VERSION"));
+        let copy = bundle.join("files/1-tx.csv").to_string_lossy().into_owned();
+        let replayed = manifest.replay_args(&bundle);
+        assert_eq!(replayed[0], copy);
+        assert_eq!(replayed[5], copy);
+        assert_eq!(
+            replayed[2],
+            bundle.join("files/2-rules.csv").to_string_lossy()
+        );
+        assert_eq!(replayed[4], "out.csv");
+
+        assert!(ReproManifest::read(&dir).is_err());
+        assert_eq!(panic_message(&"boom"), "boom");
+        assert_eq!(panic_message(&"boom".to_string()), "boom");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+//! Per-tier withdrawal rules
+//!
+//! Clients are assigned to tiers by a `client,tier` CSV; each tier may require a
+//! minimum available balance left after a withdrawal and withdrawal amounts in
+//! multiples of an increment. The tier `*` applies to clients without a tier, or
+//! whose tier has no rules row.
+
+use crate::types::{ClientId, RejectionReason};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Read;
+
+/// Tier whose rules apply to everyone else
+pub const DEFAULT_TIER: &str = "*";
+
+/// Limits on withdrawals of one tier, `None` leaves a limit off
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WithdrawalRule {
+    /// Available funds that must remain after a withdrawal
+    pub min_balance: Option<Decimal>,
+    /// Withdrawal amounts must be multiples of this
+    pub increment: Option<Decimal>,
+}
+
+impl WithdrawalRule {
+    /// Check a withdrawal amount against the increment
+    pub fn check_increment(&self, amount: Decimal) -> Result<(), RejectionReason> {
+        match self.increment {
+            Some(increment) if !(amount % increment).is_zero() => {
+                Err(RejectionReason::AmountIncrement)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the balance left after withdrawing `amount` from `available`
+    pub fn check_min_balance(
+        &self,
+        available: Decimal,
+        amount: Decimal,
+    ) -> Result<(), RejectionReason> {
+        match self.min_balance {
+            Some(min) if available - amount < min => Err(RejectionReason::MinimumBalance),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Withdrawal rules by tier and the tier of each client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TierRules {
+    pub rules: HashMap<String, WithdrawalRule>,
+    pub tiers: HashMap<ClientId, String>,
+}
+
+impl TierRules {
+    /// Rule applying to a client, `None` if neither its tier nor the default has one
+    pub fn rule_for(&self, client: ClientId) -> Option<&WithdrawalRule> {
+        self.tiers
+            .get(&client)
+            .and_then(|tier| self.rules.get(tier))
+            .or_else(|| self.rules.get(DEFAULT_TIER))
+    }
+}
+
+#[derive(Deserialize)]
+struct RuleRow {
+    tier: String,
+    min_balance: Option<Decimal>,
+    increment: Option<Decimal>,
+}
+
+#[derive(Deserialize)]
+struct TierRow {
+    client: ClientId,
+    tier: String,
+}
+
+/// Read a `tier,min_balance,increment` CSV, empty cells leave a rule off
+pub fn read_rules<R: Read>(reader: R) -> Result<HashMap<String, WithdrawalRule>, Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+
+    let mut rules = HashMap::new();
+    for row in reader.deserialize() {
+        let row: RuleRow = row?;
+        if row.min_balance.is_some_and(|min| min.is_sign_negative()) {
+            return Err(format!("tier '{}': min_balance must not be negative", row.tier).into());
+        }
+        if row.increment.is_some_and(|inc| inc <= Decimal::ZERO) {
+            return Err(format!("tier '{}': increment must be positive", row.tier).into());
+        }
+        let rule = WithdrawalRule {
+            min_balance: row.min_balance,
+            increment: row.increment,
+        };
+        if rules.insert(row.tier.clone(), rule).is_some() {
+            return Err(format!("tier '{}' listed twice", row.tier).into());
+        }
+    }
+
+    Ok(rules)
+}
+
+/// Read a `client,tier` CSV
+pub fn read_tiers<R: Read>(reader: R) -> Result<HashMap<ClientId, String>, Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+
+    let mut tiers = HashMap::new();
+    for row in reader.deserialize() {
+        let row: TierRow = row?;
+        if let Some(previous) = tiers.insert(row.client, row.tier.clone()) {
+            if previous != row.tier {
+                return Err(format!(
+                    "client {} assigned to both '{}' and '{}'",
+                    row.client, previous, row.tier
+                )
+                .into());
+            }
+        }
+    }
+
+    Ok(tiers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_rule_checks() {
+        let rule = WithdrawalRule {
+            min_balance: Some(dec!(5)),
+            increment: Some(dec!(0.01)),
+        };
+        assert_eq!(rule.check_increment(dec!(1.25)), Ok(()));
+        assert_eq!(
+            rule.check_increment(dec!(1.255)),
+            Err(RejectionReason::AmountIncrement)
+        );
+        assert_eq!(rule.check_min_balance(dec!(10), dec!(5)), Ok(()));
+        assert_eq!(
+            rule.check_min_balance(dec!(10), dec!(5.01)),
+            Err(RejectionReason::MinimumBalance)
+        );
+
+        let none = WithdrawalRule::default();
+        assert_eq!(none.check_increment(dec!(0.0001)), Ok(()));
+        assert_eq!(none.check_min_balance(dec!(0), dec!(0)), Ok(()));
+    }
+
+    #[test]
+    fn test_read_rules_and_tiers() {
+        let rules = "tier,min_balance,increment\nprepaid, 10 ,0.01\n*,,1\n";
+        let tiers = "client,tier\n1,prepaid\n2,unknown\n";
+        let rules = TierRules {
+            rules: read_rules(rules.as_bytes()).expect("Failed to read rules"),
+            tiers: read_tiers(tiers.as_bytes()).expect("Failed to read tiers"),
+        };
+
+        assert_eq!(
+            rules.rule_for(1),
+            Some(&WithdrawalRule {
+                min_balance: Some(dec!(10)),
+                increment: Some(dec!(0.01)),
+            })
+        );
+        // Unknown tiers and unassigned clients fall back to the default tier
+        assert_eq!(rules.rule_for(2).and_then(|r| r.increment), Some(dec!(1)));
+        assert_eq!(rules.rule_for(3).and_then(|r| r.min_balance), None);
+        assert_eq!(TierRules::default().rule_for(1), None);
+    }
+
+    #[test]
+    fn test_read_rules_errors() {
+        assert!(read_rules("tier,min_balance,increment\na,-1,\n".as_bytes()).is_err());
+        assert!(read_rules("tier,min_balance,increment\na,,0\n".as_bytes()).is_err());
+        assert!(read_rules("tier,min_balance,increment\na,1,\na,2,\n".as_bytes()).is_err());
+        assert!(read_tiers("client,tier\n1,a\n1,b\n".as_bytes()).is_err());
+    }
+}
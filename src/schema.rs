@@ -0,0 +1,390 @@
+//! Machine-readable description of the input and output layouts
+//!
+//! `schema --format json` prints what this build reads and writes: the input
+//! record fields and each output layout, with types, allowed values and which
+//! fields may be empty. Enum values are taken from the types the parser and
+//! writers use, so producers and consumers generated from it match the binary
+//! that printed it.
+
+use crate::snapshot;
+use crate::types::{ClientId, LockReason, RejectionReason, TransactionId, TransactionType};
+use serde::Serialize;
+use std::error::Error;
+use std::io::Write;
+use std::str::FromStr;
+
+/// How `schema` prints the description
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SchemaFormat {
+    #[default]
+    Json,
+}
+
+impl FromStr for SchemaFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(SchemaFormat::Json),
+            _ => Err(format!("Unknown schema format: {} (expected json)", s)),
+        }
+    }
+}
+
+/// Kind of value a field holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Integer,
+    /// Exact decimal, written as a string
+    Decimal,
+    Boolean,
+    /// One of the field's `values`
+    Enum,
+    /// RFC 3339 date and time
+    Timestamp,
+}
+
+/// One column of a CSV layout, or key of its JSON form
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Field {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    /// May be empty in CSV, or absent or null in JSON
+    pub optional: bool,
+    /// Bounds of an integer field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minimum: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maximum: Option<u64>,
+    /// Allowed values of an enum field
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<&'static str>,
+    pub description: &'static str,
+}
+
+impl Field {
+    fn new(name: &'static str, field_type: FieldType, description: &'static str) -> Self {
+        Self {
+            name,
+            field_type,
+            optional: false,
+            minimum: None,
+            maximum: None,
+            values: Vec::new(),
+            description,
+        }
+    }
+
+    fn integer(name: &'static str, maximum: u64, description: &'static str) -> Self {
+        Self {
+            minimum: Some(0),
+            maximum: Some(maximum),
+            ..Self::new(name, FieldType::Integer, description)
+        }
+    }
+
+    fn enumeration(
+        name: &'static str,
+        values: impl IntoIterator<Item = &'static str>,
+        description: &'static str,
+    ) -> Self {
+        Self {
+            values: values.into_iter().collect(),
+            ..Self::new(name, FieldType::Enum, description)
+        }
+    }
+
+    fn optional(self) -> Self {
+        Self {
+            optional: true,
+            ..self
+        }
+    }
+}
+
+/// Fields of one record layout, in column order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Layout {
+    /// Encodings the layout is read or written in
+    pub formats: Vec<&'static str>,
+    pub fields: Vec<Field>,
+}
+
+/// Layouts the binary writes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Outputs {
+    /// Accounts under `--schema v1`, the default
+    pub accounts_v1: Layout,
+    /// Accounts under `--schema v2`
+    pub accounts_v2: Layout,
+    /// `--rejects` stream
+    pub rejects: Layout,
+}
+
+/// Everything `schema` describes about this build
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SchemaDocument {
+    pub program: &'static str,
+    pub version: &'static str,
+    /// Cargo features the binary was built with
+    pub features: Vec<&'static str>,
+    /// Version of the `--save-state` snapshots written
+    pub snapshot_version: u16,
+    /// Transaction records, per line of CSV or NDJSON
+    pub input: Layout,
+    pub outputs: Outputs,
+}
+
+impl SchemaDocument {
+    /// Describe the layouts of this build
+    pub fn current() -> Self {
+        Self {
+            program: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            features: features(),
+            snapshot_version: snapshot::VERSION,
+            input: input_layout(),
+            outputs: Outputs {
+                accounts_v1: Layout {
+                    formats: vec!["csv", "json", "table"],
+                    fields: account_fields("Rounded to 4 decimal places, trailing zeros dropped"),
+                },
+                accounts_v2: Layout {
+                    formats: vec!["csv", "json", "table"],
+                    fields: account_fields("Rounded to 4 decimal places")
+                        .into_iter()
+                        .chain([
+                            Field::enumeration(
+                                "lock_reason",
+                                LockReason::ALL.iter().map(|r| r.as_str()),
+                                "Why the account is locked, empty while it is not",
+                            )
+                            .optional(),
+                            Field::integer(
+                                "open_disputes",
+                                u32::MAX.into(),
+                                "Disputes currently holding funds",
+                            ),
+                            Field::integer(
+                                "last_tx",
+                                TransactionId::MAX.into(),
+                                "Last transaction applied to the account",
+                            )
+                            .optional(),
+                            Field::new(
+                                "dormant",
+                                FieldType::Boolean,
+                                "Idle past --dormant-after at the end of the run",
+                            ),
+                        ])
+                        .collect(),
+                },
+                rejects: rejects_layout(),
+            },
+        }
+    }
+
+    /// Write the description as pretty-printed JSON
+    pub fn write_json<W: Write>(&self, mut writer: W) -> Result<(), Box<dyn Error>> {
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Cargo features compiled in, in `Cargo.toml` order
+pub fn features() -> Vec<&'static str> {
+    [
+        ("serve", cfg!(feature = "serve")),
+        ("zstd", cfg!(feature = "zstd")),
+        ("plugins", cfg!(feature = "plugins")),
+        ("wasm", cfg!(feature = "wasm")),
+        ("tokio", cfg!(feature = "tokio")),
+        ("kafka", cfg!(feature = "kafka")),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| name)
+    .collect()
+}
+
+fn type_field() -> Field {
+    Field::enumeration(
+        "type",
+        TransactionType::ALL.iter().map(|t| t.as_str()),
+        "Transaction type",
+    )
+}
+
+fn input_layout() -> Layout {
+    Layout {
+        formats: vec!["csv", "ndjson"],
+        fields: vec![
+            type_field(),
+            Field::integer("client", ClientId::MAX.into(), "Client id"),
+            Field::integer(
+                "tx",
+                TransactionId::MAX.into(),
+                "Transaction id; dispute, resolve and chargeback name the deposit's",
+            ),
+            Field::new(
+                "amount",
+                FieldType::Decimal,
+                "Required by deposit and withdrawal; NDJSON also accepts a number",
+            )
+            .optional(),
+            Field::integer(
+                "seq",
+                u64::MAX,
+                "Per-client sequence number, for --seq-window reordering",
+            )
+            .optional(),
+            Field::new(
+                "timestamp",
+                FieldType::Timestamp,
+                "When the transaction happened; required on every record by --as-of",
+            )
+            .optional(),
+        ],
+    }
+}
+
+/// The spec's 5 columns, shared by both account schemas
+fn account_fields(amounts: &'static str) -> Vec<Field> {
+    vec![
+        Field::integer("client", ClientId::MAX.into(), "Client id"),
+        Field::new("available", FieldType::Decimal, amounts),
+        Field::new("held", FieldType::Decimal, amounts),
+        Field::new("total", FieldType::Decimal, amounts),
+        Field::new(
+            "locked",
+            FieldType::Boolean,
+            "Locked by a chargeback or closure",
+        ),
+    ]
+}
+
+fn rejects_layout() -> Layout {
+    Layout {
+        formats: vec!["csv", "ndjson"],
+        fields: vec![
+            Field::integer(
+                "line",
+                u64::MAX,
+                "Line in the input file, when read from one",
+            )
+            .optional(),
+            Field {
+                description: "Transaction type, empty for rows that could not be parsed",
+                ..type_field().optional()
+            },
+            Field::integer("client", ClientId::MAX.into(), "Client id").optional(),
+            Field::integer("tx", TransactionId::MAX.into(), "Transaction id").optional(),
+            Field::enumeration(
+                "reason",
+                RejectionReason::ALL.iter().map(|r| r.as_str()),
+                "Why the record was not applied",
+            ),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{self, OutputFormat, Schema};
+    use crate::rejects::{RejectsFormat, RejectsWriter};
+    use crate::types::Account;
+    use std::collections::HashMap;
+
+    fn names(layout: &Layout) -> String {
+        let names: Vec<&str> = layout.fields.iter().map(|f| f.name).collect();
+        names.join(",")
+    }
+
+    fn header(text: Vec<u8>) -> String {
+        let text = String::from_utf8(text).unwrap();
+        text.lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_layouts_match_writers() {
+        let document = SchemaDocument::current();
+        let accounts = HashMap::from([(1, Account::new(1))]);
+        for (schema, layout) in [
+            (Schema::V1, &document.outputs.accounts_v1),
+            (Schema::V2, &document.outputs.accounts_v2),
+        ] {
+            let mut out = Vec::new();
+            output::write_accounts(&accounts, schema, OutputFormat::Csv, &mut out).unwrap();
+            assert_eq!(header(out), names(layout));
+        }
+
+        let writer = RejectsWriter::new(RejectsFormat::Csv, Vec::new()).unwrap();
+        assert_eq!(
+            header(writer.finish().unwrap()),
+            names(&document.outputs.rejects)
+        );
+        assert_eq!(
+            names(&document.input),
+            "type,client,tx,amount,seq,timestamp"
+        );
+
+        // Enum values are the strings the writers emit
+        for reason in RejectionReason::ALL {
+            assert_eq!(
+                serde_json::to_value(reason).unwrap(),
+                serde_json::Value::from(reason.as_str())
+            );
+        }
+        for tx_type in TransactionType::ALL {
+            assert_eq!(tx_type.as_str().parse::<TransactionType>(), Ok(tx_type));
+        }
+        for lock_reason in LockReason::ALL {
+            assert_eq!(
+                serde_json::to_value(lock_reason).unwrap(),
+                serde_json::Value::from(lock_reason.as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let mut out = Vec::new();
+        SchemaDocument::current().write_json(&mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["program"], "core-tx-runner");
+        assert_eq!(json["snapshot_version"], snapshot::VERSION);
+        assert_eq!(
+            json["features"]
+                .as_array()
+                .unwrap()
+                .contains(&"serve".into()),
+            cfg!(feature = "serve")
+        );
+
+        let client = &json["input"]["fields"][1];
+        assert_eq!(client["type"], "integer");
+        assert_eq!(client["maximum"], 65535);
+        assert_eq!(client["optional"], false);
+        assert!(client.get("values").is_none());
+        let amount = &json["input"]["fields"][3];
+        assert_eq!(amount["type"], "decimal");
+        assert_eq!(amount["optional"], true);
+        assert!(amount.get("maximum").is_none());
+
+        let reasons = json["outputs"]["rejects"]["fields"][4]["values"]
+            .as_array()
+            .unwrap();
+        assert_eq!(reasons.len(), RejectionReason::ALL.len());
+        assert_eq!(reasons[0], "malformed");
+
+        assert_eq!(
+            "xml".parse::<SchemaFormat>(),
+            Err("Unknown schema format: xml (expected json)".to_string())
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use crate::types::{ClientId, TransactionRecord};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Default number of out-of-order records buffered per client before a gap is declared
+pub const DEFAULT_REORDER_WINDOW: usize = 16;
+
+/// Range of sequence numbers that never arrived for a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceGap {
+    pub client: ClientId,
+    /// First missing sequence number
+    pub from: u64,
+    /// Last missing sequence number (inclusive)
+    pub to: u64,
+}
+
+impl fmt::Display for SequenceGap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.from == self.to {
+            write!(f, "client {}: missing seq {}", self.client, self.from)
+        } else {
+            write!(
+                f,
+                "client {}: missing seq {}..={}",
+                self.client, self.from, self.to
+            )
+        }
+    }
+}
+
+/// Sequence anomalies detected during a run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SequenceReport {
+    /// Missing sequence ranges, in detection order
+    pub gaps: Vec<SequenceGap>,
+    /// (client, seq) pairs seen more than once, or arriving after a gap was declared
+    pub duplicates: Vec<(ClientId, u64)>,
+    /// Number of records that arrived out of order but were put back in sequence
+    pub reordered: usize,
+    /// (client, seq) pairs arriving after the client's sequence reached `u64::MAX`,
+    /// which no sequence number can follow
+    pub overflows: Vec<(ClientId, u64)>,
+}
+
+impl SequenceReport {
+    /// True if nothing was dropped or missing
+    pub fn is_clean(&self) -> bool {
+        self.gaps.is_empty() && self.duplicates.is_empty() && self.overflows.is_empty()
+    }
+}
+
+/// Per-client sequencing state
+#[derive(Debug, Default)]
+struct ClientSequence {
+    /// Next sequence number expected, `None` once `u64::MAX` was taken
+    next: Option<u64>,
+    /// Records that arrived ahead of `next`
+    pending: BTreeMap<u64, TransactionRecord>,
+}
+
+/// Orders records by their optional per-client `seq` column
+///
+/// The first sequence number seen for a client is taken as its starting point.
+/// Records arriving early are buffered (up to `window` per client) until the
+/// missing ones show up; once the buffer is full the missing range is reported
+/// as a gap and processing moves on. Records without `seq` pass straight through.
+#[derive(Debug)]
+pub struct SequenceTracker {
+    window: usize,
+    clients: HashMap<ClientId, ClientSequence>,
+    report: SequenceReport,
+}
+
+impl SequenceTracker {
+    /// Create a tracker buffering at most `window` early records per client
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            clients: HashMap::new(),
+            report: SequenceReport::default(),
+        }
+    }
+
+    /// Feed one record, returning the records now ready to apply (in order)
+    pub fn push(&mut self, record: TransactionRecord) -> Vec<TransactionRecord> {
+        let seq = match record.seq {
+            Some(seq) => seq,
+            None => return vec![record],
+        };
+
+        let client = record.client;
+        let state = self
+            .clients
+            .entry(client)
+            .or_insert_with(|| ClientSequence {
+                next: Some(seq),
+                pending: BTreeMap::new(),
+            });
+
+        let Some(next) = state.next else {
+            self.report.overflows.push((client, seq));
+            return Vec::new();
+        };
+        if seq < next || state.pending.contains_key(&seq) {
+            self.report.duplicates.push((client, seq));
+            return Vec::new();
+        }
+
+        if seq > next {
+            state.pending.insert(seq, record);
+            self.report.reordered += 1;
+
+            if state.pending.len() <= self.window {
+                return Vec::new();
+            }
+
+            // Buffer full: give up on the missing range
+            let first_pending = *state.pending.keys().next().expect("pending is not empty");
+            self.report.gaps.push(SequenceGap {
+                client,
+                from: next,
+                to: first_pending - 1,
+            });
+            state.next = Some(first_pending);
+            return Self::drain_ready(state);
+        }
+
+        state.next = seq.checked_add(1);
+        let mut ready = vec![record];
+        ready.extend(Self::drain_ready(state));
+        ready
+    }
+
+    /// Flush all buffered records at end of input, reporting remaining gaps
+    pub fn finish(mut self) -> (Vec<TransactionRecord>, SequenceReport) {
+        let mut ready = Vec::new();
+
+        // Deterministic order across clients
+        let mut clients: Vec<_> = self.clients.into_iter().collect();
+        clients.sort_by_key(|(client, _)| *client);
+
+        for (client, mut state) in clients {
+            // Pending records come after `next`, so it has not run out
+            while let (Some(&first_pending), Some(next)) = (state.pending.keys().next(), state.next)
+            {
+                self.report.gaps.push(SequenceGap {
+                    client,
+                    from: next,
+                    to: first_pending - 1,
+                });
+                state.next = Some(first_pending);
+                ready.extend(Self::drain_ready(&mut state));
+            }
+        }
+
+        (ready, self.report)
+    }
+
+    /// Release buffered records that are now contiguous with `next`
+    fn drain_ready(state: &mut ClientSequence) -> Vec<TransactionRecord> {
+        let mut ready = Vec::new();
+        while let Some(next) = state.next {
+            let Some(record) = state.pending.remove(&next) else {
+                break;
+            };
+            state.next = next.checked_add(1);
+            ready.push(record);
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TransactionType;
+
+    fn record(client: ClientId, tx: u32, seq: Option<u64>) -> TransactionRecord {
+        TransactionRecord {
+            tx_type: TransactionType::Deposit,
+            client,
+            tx,
+            amount: None,
+            seq,
+            timestamp: None,
+            line: None,
+        }
+    }
+
+    fn txs(records: &[TransactionRecord]) -> Vec<u32> {
+        records.iter().map(|r| r.tx).collect()
+    }
+
+    #[test]
+    fn test_in_order_passes_through() {
+        let mut tracker = SequenceTracker::new(4);
+        assert_eq!(txs(&tracker.push(record(1, 1, Some(1)))), vec![1]);
+        assert_eq!(txs(&tracker.push(record(1, 2, Some(2)))), vec![2]);
+        assert_eq!(txs(&tracker.push(record(2, 3, None))), vec![3]);
+
+        let (rest, report) = tracker.finish();
+        assert!(rest.is_empty());
+        assert!(report.is_clean());
+        assert_eq!(report.reordered, 0);
+    }
+
+    #[test]
+    fn test_small_reordering_is_buffered() {
+        let mut tracker = SequenceTracker::new(4);
+        assert_eq!(txs(&tracker.push(record(1, 1, Some(1)))), vec![1]);
+        assert!(tracker.push(record(1, 3, Some(3))).is_empty());
+        assert_eq!(txs(&tracker.push(record(1, 2, Some(2)))), vec![2, 3]);
+
+        let (_, report) = tracker.finish();
+        assert!(report.is_clean());
+        assert_eq!(report.reordered, 1);
+    }
+
+    #[test]
+    fn test_gap_declared_when_window_full() {
+        let mut tracker = SequenceTracker::new(1);
+        tracker.push(record(1, 1, Some(1)));
+        assert!(tracker.push(record(1, 4, Some(4))).is_empty());
+        assert_eq!(txs(&tracker.push(record(1, 5, Some(5)))), vec![4, 5]);
+
+        // Late arrival after the gap was declared
+        assert!(tracker.push(record(1, 2, Some(2))).is_empty());
+
+        let (_, report) = tracker.finish();
+        assert_eq!(
+            report.gaps,
+            vec![SequenceGap {
+                client: 1,
+                from: 2,
+                to: 3
+            }]
+        );
+        assert_eq!(report.duplicates, vec![(1, 2)]);
+    }
+
+    #[test]
+    fn test_duplicates_dropped() {
+        let mut tracker = SequenceTracker::new(4);
+        tracker.push(record(1, 1, Some(1)));
+        assert!(tracker.push(record(1, 1, Some(1))).is_empty());
+        tracker.push(record(1, 3, Some(3)));
+        assert!(tracker.push(record(1, 3, Some(3))).is_empty());
+
+        let (_, report) = tracker.finish();
+        assert_eq!(report.duplicates, vec![(1, 1), (1, 3)]);
+    }
+
+    #[test]
+    fn test_finish_flushes_pending_with_gap() {
+        let mut tracker = SequenceTracker::new(8);
+        tracker.push(record(7, 1, Some(10)));
+        tracker.push(record(7, 2, Some(12)));
+
+        let (rest, report) = tracker.finish();
+        assert_eq!(txs(&rest), vec![2]);
+        assert_eq!(
+            report.gaps,
+            vec![SequenceGap {
+                client: 7,
+                from: 11,
+                to: 11
+            }]
+        );
+        assert_eq!(report.gaps[0].to_string(), "client 7: missing seq 11");
+    }
+
+    #[test]
+    fn test_sequence_end_overflow() {
+        let mut tracker = SequenceTracker::new(4);
+        tracker.push(record(1, 1, Some(u64::MAX - 2)));
+        assert!(tracker.push(record(1, 3, Some(u64::MAX))).is_empty());
+        assert_eq!(
+            txs(&tracker.push(record(1, 2, Some(u64::MAX - 1)))),
+            vec![2, 3]
+        );
+
+        // Nothing follows u64::MAX
+        assert!(tracker.push(record(1, 4, Some(u64::MAX))).is_empty());
+        assert!(tracker.push(record(1, 5, Some(0))).is_empty());
+        assert_eq!(txs(&tracker.push(record(2, 6, Some(u64::MAX)))), vec![6]);
+
+        let (rest, report) = tracker.finish();
+        assert!(rest.is_empty());
+        assert!(!report.is_clean());
+        assert_eq!(report.overflows, vec![(1, u64::MAX), (1, 0)]);
+        assert!(report.duplicates.is_empty());
+    }
+
+    #[test]
+    fn test_clients_are_independent() {
+        let mut tracker = SequenceTracker::new(4);
+        tracker.push(record(1, 1, Some(1)));
+        tracker.push(record(2, 2, Some(100)));
+        assert_eq!(txs(&tracker.push(record(1, 3, Some(2)))), vec![3]);
+        assert_eq!(txs(&tracker.push(record(2, 4, Some(101)))), vec![4]);
+
+        let (_, report) = tracker.finish();
+        assert!(report.is_clean());
+    }
+}
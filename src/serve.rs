@@ -0,0 +1,745 @@
+//! Long-running server mode
+//!
+//! One `PaymentsEngine` is shared by every connection behind a mutex, so records
+//! are applied one at a time, in the order they arrive. Two front ends:
+//! - HTTP/1.1, one request per connection: `POST /transactions` with a
+//!   transaction JSON body, `GET /accounts` and `GET /accounts/{client}`.
+//! - TCP: newline-delimited transaction JSON, answered with one JSON line per record.
+//!
+//! Transactions are the objects of the NDJSON input format. There is no `seq`
+//! reordering: records are applied as they come.
+//!
+//! Under overload a `ShedPolicy` turns requests away with 503 instead of
+//! queueing them on the engine lock: account reads first, then HTTP
+//! transactions. A shed transaction is never applied, so the records that are
+//! applied keep their arrival order. TCP lines are never shed: skipping one
+//! would apply the connection's later lines ahead of it, so TCP clients are
+//! slowed down by the lock instead. `GET /metrics` reports the queue depth and
+//! shed counts without taking the lock.
+//!
+//! Applied records are timed per source (see `latency`): HTTP requests are
+//! `http` unless they name their producer in an `X-Source` header, TCP lines
+//! are `tcp`. `GET /latency` reports the percentiles, also without the lock.
+//! With a ledger set, every applied record is written to it as a JSON line
+//! stamped with when it was read and applied, in the order it was applied.
+
+use crate::clock::{Clock, SystemClock};
+use crate::engine::PaymentsEngine;
+use crate::latency::{LatencyTracker, SourceLatency};
+use crate::timestamp::Timestamp;
+use crate::types::{Account, ClientId, TransactionId, TransactionRecord, TransactionType, TxError};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::SystemTime;
+
+/// Server shared between connections
+pub type SharedServer = Arc<Server>;
+
+/// Largest HTTP request body accepted, a transaction is well below this
+pub const MAX_BODY: usize = 64 * 1024;
+
+/// Source of HTTP transactions without an `X-Source` header
+pub const HTTP_SOURCE: &str = "http";
+
+/// Source of TCP transactions
+pub const TCP_SOURCE: &str = "tcp";
+
+/// Longest `X-Source` name accepted
+pub const MAX_SOURCE_LEN: usize = 64;
+
+/// Queue depths at which requests are answered 503 instead of waiting for the engine
+/// The depth counts requests waiting for or holding the engine lock
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShedPolicy {
+    /// Shed HTTP transactions once this many requests are queued
+    pub max_queue: Option<usize>,
+    /// Shed account reads once this many requests are queued, normally below `max_queue`
+    pub max_read_queue: Option<usize>,
+}
+
+/// Load counters, as `GET /metrics` reports them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Metrics {
+    /// Requests waiting for or holding the engine right now
+    pub queue_depth: usize,
+    /// Transactions given to the engine, applied or not
+    pub submitted: u64,
+    /// Transactions answered 503 and not applied
+    pub shed_writes: u64,
+    /// Account reads answered 503
+    pub shed_reads: u64,
+    /// Applied records that could not be written to the ledger
+    pub ledger_errors: u64,
+}
+
+/// Line of the ledger: an applied record, and when it was read and applied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LedgerEntry<'a> {
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<String>,
+    /// Producer timestamp, as the record carried it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    pub source: &'a str,
+    pub ingested_at: Timestamp,
+    pub applied_at: Timestamp,
+}
+
+/// Where applied records are written
+struct Ledger(Box<dyn Write + Send>);
+
+impl fmt::Debug for Ledger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Ledger")
+    }
+}
+
+/// One engine behind a lock, with the load it is under
+#[derive(Debug)]
+pub struct Server {
+    engine: Mutex<PaymentsEngine>,
+    policy: ShedPolicy,
+    queue: AtomicUsize,
+    submitted: AtomicU64,
+    shed_writes: AtomicU64,
+    shed_reads: AtomicU64,
+    clock: Arc<dyn Clock>,
+    latency: Mutex<LatencyTracker>,
+    /// Written while holding the engine, so lines come in application order
+    ledger: Option<Mutex<Ledger>>,
+    ledger_errors: AtomicU64,
+}
+
+/// A request's place in the engine queue, given up on drop
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Server {
+    pub fn new(engine: PaymentsEngine, policy: ShedPolicy) -> Self {
+        Self {
+            engine: Mutex::new(engine),
+            policy,
+            queue: AtomicUsize::new(0),
+            submitted: AtomicU64::new(0),
+            shed_writes: AtomicU64::new(0),
+            shed_reads: AtomicU64::new(0),
+            clock: Arc::new(SystemClock),
+            latency: Mutex::new(LatencyTracker::default()),
+            ledger: None,
+            ledger_errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Time latencies and stamp ledger lines on `clock` instead of the system clock
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Write every applied record to `writer`, one JSON line each, flushed as it goes
+    pub fn set_ledger(&mut self, writer: Box<dyn Write + Send>) {
+        self.ledger = Some(Mutex::new(Ledger(writer)));
+    }
+
+    /// The engine, once requests queued ahead of the caller are done with it
+    pub fn engine(&self) -> MutexGuard<'_, PaymentsEngine> {
+        self.engine.lock().expect("engine lock poisoned")
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            queue_depth: self.queue.load(Ordering::SeqCst),
+            submitted: self.submitted.load(Ordering::SeqCst),
+            shed_writes: self.shed_writes.load(Ordering::SeqCst),
+            shed_reads: self.shed_reads.load(Ordering::SeqCst),
+            ledger_errors: self.ledger_errors.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Latency percentiles of applied records, by source
+    pub fn latency(&self) -> BTreeMap<String, SourceLatency> {
+        self.latency.lock().expect("latency lock poisoned").report()
+    }
+
+    /// Join the engine queue, or `None` if `limit` requests are already in it
+    fn enter(&self, limit: Option<usize>) -> Option<QueueSlot<'_>> {
+        let ahead = self.queue.fetch_add(1, Ordering::SeqCst);
+        let slot = QueueSlot(&self.queue);
+        match limit {
+            Some(limit) if ahead >= limit => None,
+            _ => Some(slot),
+        }
+    }
+
+    /// Parse one transaction JSON object from `source` and apply it, never shedding it
+    pub fn submit(&self, json: &str, source: &str) -> Outcome {
+        let ingested_at = self.clock.now();
+        let _slot = self.enter(None);
+        self.apply(json, source, ingested_at)
+    }
+
+    /// Like `submit`, but answer `shed` if the queue is at `ShedPolicy::max_queue`
+    pub fn try_submit(&self, json: &str, source: &str) -> Outcome {
+        let ingested_at = self.clock.now();
+        let Some(_slot) = self.enter(self.policy.max_queue) else {
+            self.shed_writes.fetch_add(1, Ordering::SeqCst);
+            return Outcome {
+                status: "shed",
+                tx: None,
+                reason: Some("server overloaded, retry later".to_string()),
+            };
+        };
+        self.apply(json, source, ingested_at)
+    }
+
+    fn apply(&self, json: &str, source: &str, ingested_at: SystemTime) -> Outcome {
+        let record: TransactionRecord = match serde_json::from_str(json) {
+            Ok(record) => record,
+            Err(e) => {
+                return Outcome {
+                    status: "malformed",
+                    tx: None,
+                    reason: Some(e.to_string()),
+                }
+            }
+        };
+
+        let mut engine = self.engine();
+        let result = engine.process(record);
+        let applied_at = self.clock.now();
+        if result.is_ok() {
+            self.write_ledger(&record, source, ingested_at, applied_at);
+        }
+        drop(engine);
+        self.submitted.fetch_add(1, Ordering::SeqCst);
+        if result.is_ok() {
+            // A clock stepping back between ingest and apply counts as no wait
+            let waited = applied_at.duration_since(ingested_at).unwrap_or_default();
+            let end_to_end = record.timestamp.map(|produced| {
+                applied_at
+                    .duration_since(produced.into())
+                    .map_err(|e| e.duration())
+            });
+            self.latency
+                .lock()
+                .expect("latency lock poisoned")
+                .record(source, waited, end_to_end);
+        }
+        let (status, reason) = match result {
+            Ok(()) => ("applied", None),
+            Err(TxError::Rejected(reason)) => ("rejected", Some(reason.as_str().to_string())),
+            // The engine stays up, the record is simply not applied
+            Err(e) => ("error", Some(e.to_string())),
+        };
+        Outcome {
+            status,
+            tx: Some(record.tx),
+            reason,
+        }
+    }
+
+    /// Append an applied record to the ledger, if there is one
+    /// A failed write is counted in the metrics; the record stays applied
+    fn write_ledger(
+        &self,
+        record: &TransactionRecord,
+        source: &str,
+        ingested_at: SystemTime,
+        applied_at: SystemTime,
+    ) {
+        let Some(ledger) = &self.ledger else {
+            return;
+        };
+        let entry = LedgerEntry {
+            tx_type: record.tx_type,
+            client: record.client,
+            tx: record.tx,
+            amount: record.amount.map(|amount| amount.to_string()),
+            timestamp: record.timestamp,
+            source,
+            ingested_at: ingested_at.into(),
+            applied_at: applied_at.into(),
+        };
+        let mut ledger = ledger.lock().expect("ledger lock poisoned");
+        let written = serde_json::to_writer(&mut ledger.0, &entry)
+            .map_err(io::Error::from)
+            .and_then(|()| ledger.0.write_all(b"\n"))
+            .and_then(|()| ledger.0.flush());
+        if written.is_err() {
+            self.ledger_errors.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Answer an account read, or 503 if the queue is at `ShedPolicy::max_read_queue`
+    fn read<F>(&self, read: F) -> (u16, String)
+    where
+        F: FnOnce(&PaymentsEngine) -> (u16, String),
+    {
+        let Some(_slot) = self.enter(self.policy.max_read_queue) else {
+            self.shed_reads.fetch_add(1, Ordering::SeqCst);
+            return (503, error_body("server overloaded, retry later"));
+        };
+        read(&self.engine())
+    }
+}
+
+/// What became of one submitted record
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Outcome {
+    /// `applied`, `rejected`, `malformed`, `shed` or `error`
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx: Option<TransactionId>,
+    /// Rejection reason code or error message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Outcome {
+    /// HTTP status answering a `POST /transactions`
+    fn http_status(&self) -> u16 {
+        match self.status {
+            "applied" => 200,
+            "rejected" => 422,
+            "malformed" => 400,
+            "shed" => 503,
+            _ => 500,
+        }
+    }
+}
+
+/// Accept HTTP connections until the listener fails, a thread per connection
+pub fn serve_http(listener: TcpListener, server: SharedServer) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            // A client hanging up mid-request only ends its own connection
+            let _ = handle_http(BufReader::new(&stream), &stream, &server);
+        });
+    }
+    Ok(())
+}
+
+/// Accept line-delimited TCP connections until the listener fails
+pub fn serve_tcp(listener: TcpListener, server: SharedServer) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            let _ = handle_lines(BufReader::new(&stream), &stream, &server);
+        });
+    }
+    Ok(())
+}
+
+/// Answer every transaction line with its outcome, until the client closes
+/// Blank lines are skipped
+pub fn handle_lines<R: BufRead, W: Write>(
+    reader: R,
+    mut writer: W,
+    server: &Server,
+) -> io::Result<()> {
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let outcome = server.submit(&line, TCP_SOURCE);
+        serde_json::to_writer(&mut writer, &outcome)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Read one HTTP request, answer it and close
+pub fn handle_http<R: BufRead, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    server: &Server,
+) -> io::Result<()> {
+    let (status, body) = match read_request(&mut reader)? {
+        Ok(request) => route(&request, server),
+        Err(status) => (status, error_body(reason_phrase(status))),
+    };
+
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+        status,
+        reason_phrase(status),
+        body.len()
+    )?;
+    if status == 503 {
+        writer.write_all(b"Retry-After: 1\r\n")?;
+    }
+    writer.write_all(b"Connection: close\r\n\r\n")?;
+    writer.write_all(body.as_bytes())?;
+    writer.flush()
+}
+
+/// The parts of an HTTP request the routes look at
+struct Request {
+    method: String,
+    path: String,
+    /// `X-Source` header
+    source: Option<String>,
+    body: String,
+}
+
+/// Whether `name` can label a source: short, and letters, digits, `-`, `_`, `.` or `:`
+fn valid_source(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_SOURCE_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+/// The request, or the status refusing it
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Result<Request, u16>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Ok(Err(400));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut content_length = 0;
+    let mut source = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(Err(400));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                match value.trim().parse() {
+                    Ok(length) => content_length = length,
+                    Err(_) => return Ok(Err(400)),
+                }
+            } else if name.trim().eq_ignore_ascii_case("x-source") {
+                let name = value.trim();
+                if !valid_source(name) {
+                    return Ok(Err(400));
+                }
+                source = Some(name.to_string());
+            }
+        }
+    }
+
+    if content_length > MAX_BODY {
+        return Ok(Err(413));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    match String::from_utf8(body) {
+        Ok(body) => Ok(Ok(Request {
+            method,
+            path,
+            source,
+            body,
+        })),
+        Err(_) => Ok(Err(400)),
+    }
+}
+
+/// Dispatch a request, returning the status and JSON body
+fn route(request: &Request, server: &Server) -> (u16, String) {
+    let path = request.path.split('?').next().unwrap_or(&request.path);
+    match (request.method.as_str(), path.trim_end_matches('/')) {
+        ("POST", "/transactions") => {
+            let source = request.source.as_deref().unwrap_or(HTTP_SOURCE);
+            let outcome = server.try_submit(&request.body, source);
+            (outcome.http_status(), to_json(&outcome))
+        }
+        ("GET", "/accounts") => server.read(|engine| {
+            let mut accounts: Vec<&Account> = engine.accounts().values().collect();
+            accounts.sort_by_key(|a| a.client);
+            (200, to_json(&accounts))
+        }),
+        ("GET", path) if path.starts_with("/accounts/") => {
+            let Ok(client) = path["/accounts/".len()..].parse::<ClientId>() else {
+                return (400, error_body("invalid client id"));
+            };
+            server.read(|engine| match engine.account(client) {
+                Some(account) => (200, to_json(account)),
+                None => (404, error_body("unknown client")),
+            })
+        }
+        ("GET", "/metrics") => (200, to_json(&server.metrics())),
+        ("GET", "/latency") => (200, to_json(&server.latency())),
+        (_, "/transactions" | "/accounts" | "/metrics" | "/latency") => {
+            (405, error_body(reason_phrase(405)))
+        }
+        _ => (404, error_body(reason_phrase(404))),
+    }
+}
+
+fn to_json<T: Serialize + ?Sized>(value: &T) -> String {
+    serde_json::to_string(value).expect("serializing to a string cannot fail")
+}
+
+fn error_body(message: &str) -> String {
+    to_json(&serde_json::json!({ "error": message }))
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+
+    fn engine() -> Server {
+        Server::new(
+            PaymentsEngine::new(EngineConfig::default()),
+            ShedPolicy::default(),
+        )
+    }
+
+    fn http(engine: &Server, request: &str) -> String {
+        let mut response = Vec::new();
+        handle_http(request.as_bytes(), &mut response, engine).expect("Request failed");
+        String::from_utf8(response).unwrap()
+    }
+
+    fn post(engine: &Server, body: &str) -> String {
+        http(
+            engine,
+            &format!(
+                "POST /transactions HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+        )
+    }
+
+    fn body(response: &str) -> &str {
+        response.split("\r\n\r\n").nth(1).unwrap()
+    }
+
+    #[test]
+    fn test_http_routes() {
+        let engine = engine();
+
+        let response = post(
+            &engine,
+            r#"{"type":"deposit","client":7,"tx":1,"amount":"2.5"}"#,
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(body(&response), r#"{"status":"applied","tx":1}"#);
+
+        let response = post(
+            &engine,
+            r#"{"type":"withdrawal","client":7,"tx":2,"amount":"5"}"#,
+        );
+        assert!(response.starts_with("HTTP/1.1 422 "));
+        assert_eq!(
+            body(&response),
+            r#"{"status":"rejected","tx":2,"reason":"insufficient_funds"}"#
+        );
+        assert!(post(&engine, "{").starts_with("HTTP/1.1 400 "));
+
+        let response = http(&engine, "GET /accounts/7 HTTP/1.1\r\n\r\n");
+        let account: serde_json::Value = serde_json::from_str(body(&response)).unwrap();
+        assert_eq!(account["client"], 7);
+        assert_eq!(account["available"], "2.5");
+
+        let response = http(&engine, "GET /accounts HTTP/1.1\r\n\r\n");
+        let accounts: serde_json::Value = serde_json::from_str(body(&response)).unwrap();
+        assert_eq!(accounts.as_array().map(Vec::len), Some(1));
+
+        assert!(http(&engine, "GET /accounts/8 HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 "));
+        assert!(http(&engine, "GET /accounts/x HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 400 "));
+        assert!(http(&engine, "DELETE /accounts HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 "));
+        assert!(http(&engine, "GET /nope HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 "));
+        assert!(http(&engine, "garbage\r\n\r\n").starts_with("HTTP/1.1 400 "));
+        assert!(http(
+            &engine,
+            &format!(
+                "POST /transactions HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+                MAX_BODY + 1
+            )
+        )
+        .starts_with("HTTP/1.1 413 "));
+    }
+
+    #[test]
+    fn test_line_protocol() {
+        let engine = engine();
+        let input = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":3}\n\n\
+                     {\"type\":\"dispute\",\"client\":1,\"tx\":9}\n\
+                     not json\n";
+        let mut output = Vec::new();
+        handle_lines(input.as_bytes(), &mut output, &engine).expect("Stream failed");
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["status"], "applied");
+        assert_eq!(lines[1]["reason"], "unknown_tx");
+        assert_eq!(lines[2]["status"], "malformed");
+        assert_eq!(
+            engine.engine().account(1).map(|a| a.total),
+            Some(rust_decimal_macros::dec!(3))
+        );
+    }
+
+    /// Ledger the test can read back while the server holds it
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_latency_and_ledger() {
+        use crate::clock::TestClock;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let mut server = engine();
+        let clock = TestClock::new(UNIX_EPOCH + Duration::from_secs(60));
+        server.set_clock(Arc::new(clock.clone()));
+        let ledger = SharedBuf::default();
+        server.set_ledger(Box::new(ledger.clone()));
+
+        let stamped = r#"{"type":"deposit","client":1,"tx":1,"amount":"5","timestamp":"1970-01-01T00:00:58Z"}"#;
+        let response = http(
+            &server,
+            &format!(
+                "POST /transactions HTTP/1.1\r\nX-Source: till-7\r\nContent-Length: {}\r\n\r\n{}",
+                stamped.len(),
+                stamped
+            ),
+        );
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        assert!(post(
+            &server,
+            r#"{"type":"deposit","client":1,"tx":2,"amount":1,"timestamp":"1970-01-01T00:01:30Z"}"#
+        )
+        .starts_with("HTTP/1.1 200 "));
+        clock.advance(Duration::from_millis(250));
+        let input = "{\"type\":\"deposit\",\"client\":2,\"tx\":3,\"amount\":2}\n\
+                     {\"type\":\"withdrawal\",\"client\":2,\"tx\":4,\"amount\":9}\n";
+        handle_lines(input.as_bytes(), Vec::new(), &server).expect("Stream failed");
+        assert!(http(
+            &server,
+            "POST /transactions HTTP/1.1\r\nX-Source: a b\r\n\r\n"
+        )
+        .starts_with("HTTP/1.1 400 "));
+
+        let response = http(&server, "GET /latency HTTP/1.1\r\n\r\n");
+        let latency: serde_json::Value = serde_json::from_str(body(&response)).unwrap();
+        assert_eq!(latency["till-7"]["end_to_end"]["count"], 1);
+        assert_eq!(latency["till-7"]["end_to_end"]["max_us"], 2_000_000);
+        assert_eq!(latency["till-7"]["ingest_to_applied"]["count"], 1);
+        assert_eq!(latency["http"]["end_to_end"]["count"], 0);
+        assert_eq!(latency["http"]["skewed"], 1);
+        // Only the applied TCP line is timed
+        assert_eq!(latency["tcp"]["ingest_to_applied"]["count"], 1);
+        assert_eq!(latency["tcp"]["end_to_end"]["count"], 0);
+
+        let ledger = String::from_utf8(ledger.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = ledger.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"5","timestamp":"1970-01-01T00:00:58Z","source":"till-7","ingested_at":"1970-01-01T00:01:00Z","applied_at":"1970-01-01T00:01:00Z"}"#
+        );
+        assert_eq!(
+            lines[2],
+            r#"{"type":"deposit","client":2,"tx":3,"amount":"2","source":"tcp","ingested_at":"1970-01-01T00:01:00.25Z","applied_at":"1970-01-01T00:01:00.25Z"}"#
+        );
+        assert_eq!(server.metrics().ledger_errors, 0);
+    }
+
+    #[test]
+    fn test_load_shedding() {
+        let server = Server::new(
+            PaymentsEngine::new(EngineConfig::default()),
+            ShedPolicy {
+                max_queue: Some(2),
+                max_read_queue: Some(1),
+            },
+        );
+        let deposit =
+            |tx: u32| format!(r#"{{"type":"deposit","client":1,"tx":{},"amount":1}}"#, tx);
+
+        // Another request holds the engine: reads are shed, transactions still queue
+        let busy = server.enter(None);
+        let response = http(&server, "GET /accounts HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("\r\nRetry-After: 1\r\n"));
+        assert!(post(&server, &deposit(1)).starts_with("HTTP/1.1 200 "));
+
+        let busier = server.enter(None);
+        let response = post(&server, &deposit(2));
+        assert!(response.starts_with("HTTP/1.1 503 "));
+        assert_eq!(
+            body(&response),
+            r#"{"status":"shed","reason":"server overloaded, retry later"}"#
+        );
+        // TCP lines wait their turn instead
+        let mut output = Vec::new();
+        handle_lines(deposit(3).as_bytes(), &mut output, &server).expect("Stream failed");
+        assert!(String::from_utf8(output).unwrap().contains("applied"));
+
+        let response = http(&server, "GET /metrics HTTP/1.1\r\n\r\n");
+        assert_eq!(
+            body(&response),
+            r#"{"queue_depth":2,"submitted":2,"shed_writes":1,"shed_reads":1,"ledger_errors":0}"#
+        );
+
+        drop((busy, busier));
+        let response = http(&server, "GET /accounts/1 HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        assert_eq!(
+            server.engine().account(1).map(|a| a.total),
+            Some(rust_decimal_macros::dec!(2))
+        );
+        assert_eq!(server.metrics().queue_depth, 0);
+    }
+}
@@ -0,0 +1,214 @@
+//! Shadow processing, for rolling out engine changes safely
+//!
+//! A `ShadowEngine` is given every record the primary engine was given, runs
+//! it under its own configuration and compares the outcomes record by record.
+//! Nothing it does is emitted: its accounts, rejections and events stay inside
+//! it, and only the divergences come out. Once the stream ends, `finish` also
+//! compares its accounts with the primary's.
+
+use crate::diff::{self, AccountDelta};
+use crate::engine::PaymentsEngine;
+use crate::types::{Account, ClientId, TransactionId, TransactionRecord, TransactionType, TxError};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+
+/// A record the shadow engine treated differently from the primary
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Divergence {
+    /// Position of the record, 1-based, as in statements
+    pub position: u64,
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    /// `applied`, the rejection reason or the error, per engine
+    pub primary: String,
+    pub shadow: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record {} ({} tx {} client {}): primary {}, shadow {}",
+            self.position,
+            self.tx_type.as_str(),
+            self.tx,
+            self.client,
+            self.primary,
+            self.shadow
+        )
+    }
+}
+
+/// How a shadow run compared with the primary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShadowReport {
+    /// Records given to both engines
+    pub records: u64,
+    /// In record order
+    pub divergences: Vec<Divergence>,
+    /// Accounts that ended up different, primary as `before` and shadow as `after`
+    pub accounts: Vec<AccountDelta>,
+}
+
+impl fmt::Display for ShadowReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} records and {} accounts diverged",
+            self.divergences.len(),
+            self.records,
+            self.accounts.len()
+        )
+    }
+}
+
+/// Second engine fed the primary's records, recording where it disagrees
+#[derive(Debug)]
+pub struct ShadowEngine {
+    engine: PaymentsEngine,
+    records: u64,
+    divergences: Vec<Divergence>,
+}
+
+impl ShadowEngine {
+    /// Shadow running `engine`, which should start from the same state as the primary
+    pub fn new(engine: PaymentsEngine) -> Self {
+        Self {
+            engine,
+            records: 0,
+            divergences: Vec::new(),
+        }
+    }
+
+    pub fn engine(&self) -> &PaymentsEngine {
+        &self.engine
+    }
+
+    /// Apply `record` to the shadow and compare with what the primary made of it
+    pub fn observe(&mut self, record: TransactionRecord, primary: &Result<(), TxError>) {
+        self.records += 1;
+        let shadow = self.engine.process(record);
+        if shadow != *primary {
+            self.divergences.push(Divergence {
+                position: self.records,
+                tx_type: record.tx_type,
+                client: record.client,
+                tx: record.tx,
+                primary: outcome(primary),
+                shadow: outcome(&shadow),
+            });
+        }
+    }
+
+    /// Finish the comparison against the primary's final accounts
+    pub fn finish(self, primary: &HashMap<ClientId, Account>) -> ShadowReport {
+        ShadowReport {
+            records: self.records,
+            divergences: self.divergences,
+            accounts: diff::diff_accounts(primary, self.engine.accounts()),
+        }
+    }
+}
+
+fn outcome(result: &Result<(), TxError>) -> String {
+    match result {
+        Ok(()) => "applied".to_string(),
+        Err(TxError::Rejected(reason)) => reason.as_str().to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Write divergences as CSV, in record order
+pub fn write_divergences<W: Write>(
+    divergences: &[Divergence],
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for divergence in divergences {
+        writer.serialize(divergence)?;
+    }
+    if divergences.is_empty() {
+        writer.write_record(["position", "type", "client", "tx", "primary", "shadow"])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    fn record(
+        tx_type: TransactionType,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tx_type,
+            client,
+            tx,
+            amount,
+            seq: None,
+            timestamp: None,
+            line: None,
+        }
+    }
+
+    #[test]
+    fn test_shadow_divergences() {
+        let mut primary = PaymentsEngine::new(EngineConfig::default());
+        let mut shadow = ShadowEngine::new(PaymentsEngine::new(EngineConfig {
+            max_amount: Some(dec!(100)),
+            ..EngineConfig::default()
+        }));
+        for record in [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(50))),
+            record(TransactionType::Deposit, 1, 2, Some(dec!(500))),
+            record(TransactionType::Withdrawal, 1, 3, Some(dec!(60))),
+            record(TransactionType::Deposit, 2, 4, Some(dec!(5))),
+        ] {
+            let result = primary.process(record);
+            shadow.observe(record, &result);
+        }
+
+        let report = shadow.finish(primary.accounts());
+        assert_eq!(report.records, 4);
+        assert_eq!(
+            report
+                .divergences
+                .iter()
+                .map(|d| (d.position, d.primary.as_str(), d.shadow.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                (2, "applied", "implausible_amount"),
+                (3, "applied", "insufficient_funds")
+            ]
+        );
+        assert_eq!(report.accounts.len(), 1);
+        assert_eq!(report.accounts[0].client, 1);
+        assert_eq!(report.accounts[0].total_delta(), dec!(-440));
+        assert_eq!(report.to_string(), "2 of 4 records and 1 accounts diverged");
+
+        let mut out = Vec::new();
+        write_divergences(&report.divergences[..1], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "position,type,client,tx,primary,shadow\n2,deposit,1,2,applied,implausible_amount\n"
+        );
+        let mut out = Vec::new();
+        write_divergences(&[], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "position,type,client,tx,primary,shadow\n"
+        );
+    }
+}
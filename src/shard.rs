@@ -0,0 +1,305 @@
+use crate::config::EngineConfig;
+use crate::engine::{EngineReport, PaymentsEngine};
+use crate::rejects::Rejection;
+use crate::store::MemoryStore;
+use crate::types::{TransactionRecord, TxError};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+
+/// Records sent to a worker at a time
+const BATCH_SIZE: usize = 1024;
+
+/// Batches in flight per worker before the reader blocks
+const QUEUE_DEPTH: usize = 4;
+
+type Batch = Vec<(u64, TransactionRecord)>;
+
+/// Engine spread over worker threads, one `PaymentsEngine` per shard
+///
+/// Records are routed by `client % threads`, so each client's records are applied
+/// in the order given, by the one worker owning that client. This relies on tx ids
+/// being unique across clients. A reference naming another client than the
+/// deposit's lands on a shard that doesn't know the tx and is rejected as unknown.
+pub struct ShardedEngine {
+    batches: Vec<Batch>,
+    senders: Vec<SyncSender<Batch>>,
+    workers: Vec<JoinHandle<Result<EngineReport, TxError>>>,
+    rejections: Receiver<Rejection>,
+    position: u64,
+}
+
+impl ShardedEngine {
+    /// Start `threads` workers (at least one)
+    pub fn new(config: &EngineConfig, threads: usize) -> Self {
+        let threads = threads.max(1);
+        let (rejection_tx, rejections) = mpsc::channel();
+
+        let (senders, workers) = (0..threads)
+            .map(|_| {
+                let (sender, receiver) = mpsc::sync_channel(QUEUE_DEPTH);
+                let engine = PaymentsEngine::new(config.clone());
+                let rejection_tx = rejection_tx.clone();
+                let worker = thread::spawn(move || run_shard(engine, receiver, rejection_tx));
+                (sender, worker)
+            })
+            .unzip();
+
+        Self {
+            batches: vec![Vec::with_capacity(BATCH_SIZE); threads],
+            senders,
+            workers,
+            rejections,
+            position: 0,
+        }
+    }
+
+    /// Queue a record on its client's shard
+    /// Fails if the shard stopped, `finish` then returns the reason
+    pub fn process(&mut self, record: TransactionRecord) -> Result<(), ShardStopped> {
+        self.position += 1;
+        let shard = usize::from(record.client) % self.senders.len();
+        self.batches[shard].push((self.position, record));
+        if self.batches[shard].len() >= BATCH_SIZE {
+            self.flush(shard)?;
+        }
+        Ok(())
+    }
+
+    /// Rejections reported by the workers so far
+    pub fn rejections(&self) -> impl Iterator<Item = Rejection> + '_ {
+        self.rejections.try_iter()
+    }
+
+    /// Wait for the workers and merge their state
+    /// Rejections not yet taken with `rejections` are passed to `on_rejected`
+    pub fn finish<R>(mut self, mut on_rejected: R) -> Result<EngineReport, TxError>
+    where
+        R: FnMut(Rejection),
+    {
+        for shard in 0..self.senders.len() {
+            // A stopped worker is reported by its join below
+            let _ = self.flush(shard);
+        }
+        self.senders.clear();
+
+        let mut reports = Vec::with_capacity(self.workers.len());
+        let mut failure = None;
+        for worker in self.workers {
+            match worker.join().expect("shard worker panicked") {
+                Ok(report) => reports.push(report),
+                Err(e) => failure = failure.or(Some(e)),
+            }
+        }
+        self.rejections.try_iter().for_each(&mut on_rejected);
+
+        match failure {
+            Some(e) => Err(e),
+            None => merge(reports, self.position),
+        }
+    }
+
+    fn flush(&mut self, shard: usize) -> Result<(), ShardStopped> {
+        if self.batches[shard].is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::replace(&mut self.batches[shard], Vec::with_capacity(BATCH_SIZE));
+        self.senders[shard].send(batch).map_err(|_| ShardStopped)
+    }
+}
+
+/// A worker stopped early, on an error `ShardedEngine::finish` returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardStopped;
+
+impl fmt::Display for ShardStopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "shard worker stopped")
+    }
+}
+
+impl std::error::Error for ShardStopped {}
+
+/// Worker loop: apply batches at their global positions until the reader is done
+fn run_shard(
+    mut engine: PaymentsEngine,
+    batches: Receiver<Batch>,
+    rejections: Sender<Rejection>,
+) -> Result<EngineReport, TxError> {
+    for batch in batches {
+        for (position, record) in batch {
+            // Keep dispute ages in whole-input positions
+            engine.set_records_processed(position - 1);
+            match engine.process(record) {
+                Ok(()) => {}
+                Err(TxError::Rejected(reason)) => {
+                    let _ = rejections.send(Rejection::new(&record, reason));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(engine.into_report())
+}
+
+/// Combine shard reports; findings are put back in input order
+/// Dead letters stay grouped by shard, which keeps each client's in order
+fn merge(reports: Vec<EngineReport>, records_processed: u64) -> Result<EngineReport, TxError> {
+    let mut merged = PaymentsEngine::new(EngineConfig::default()).into_report();
+    // The shards' clocks, not the time of the merge
+    if let Some(ended_at) = reports.iter().map(|r| r.ended_at).max() {
+        merged.ended_at = ended_at;
+    }
+    let mut transactions = HashMap::new();
+    for report in reports {
+        merged.accounts.extend(report.accounts);
+        report.transactions.for_each(&mut |tx, t| {
+            transactions.insert(tx, *t);
+        })?;
+        merged.duplicates.extend(report.duplicates);
+        merged.dead_letters.extend(report.dead_letters);
+        merged.mismatches.extend(report.mismatches);
+        merged
+            .blocked_withdrawals
+            .extend(report.blocked_withdrawals);
+        // Each client's records all go to one shard
+        merged.client_stats.extend(report.client_stats);
+        merged.recoveries.extend(report.recoveries);
+        merged.flows.merge(&report.flows);
+        merged.closures.extend(report.closures);
+        merged.last_active.extend(report.last_active);
+        merged.last_active_at.extend(report.last_active_at);
+        merged.disputed_since.extend(report.disputed_since);
+        merged.started_at = merged.started_at.into_iter().chain(report.started_at).min();
+        merged.emitted_events.extend(report.emitted_events);
+        merged.suppressed_events += report.suppressed_events;
+        merged.pending_holds.extend(report.pending_holds);
+        for (reason, count) in report.rejections {
+            *merged.rejections.entry(reason).or_default() += count;
+        }
+    }
+
+    merged.transactions = Box::new(MemoryStore::from(transactions));
+    merged.records_processed = records_processed;
+    merged.duplicates.sort_by_key(|d| d.position);
+    merged.mismatches.sort_by_key(|m| m.position);
+    merged.blocked_withdrawals.sort_by_key(|b| b.position);
+    merged.recoveries.sort_by_key(|r| r.position);
+    merged.closures.sort_by_key(|c| c.position);
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ClientId, RejectionReason, TransactionType};
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    fn record(
+        tx_type: TransactionType,
+        client: ClientId,
+        tx: u32,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tx_type,
+            client,
+            tx,
+            amount,
+            seq: None,
+            timestamp: None,
+            line: Some(u64::from(tx)),
+        }
+    }
+
+    fn workload() -> Vec<TransactionRecord> {
+        let mut records = Vec::new();
+        let mut tx = 0;
+        let mut next = || {
+            tx += 1;
+            tx
+        };
+        for round in 0..50u32 {
+            for client in 0..10u16 {
+                let amount = Decimal::from(round + u32::from(client));
+                let deposit = next();
+                records.push(record(
+                    TransactionType::Deposit,
+                    client,
+                    deposit,
+                    Some(amount),
+                ));
+                records.push(record(
+                    TransactionType::Withdrawal,
+                    client,
+                    next(),
+                    Some(dec!(3)),
+                ));
+                if round % 7 == 0 {
+                    records.push(record(TransactionType::Dispute, client, deposit, None));
+                }
+                if round % 14 == 0 && client % 3 == 0 {
+                    records.push(record(TransactionType::Chargeback, client, deposit, None));
+                }
+            }
+        }
+        records
+    }
+
+    #[test]
+    fn test_matches_single_engine() {
+        let mut single = PaymentsEngine::new(EngineConfig::default());
+        let mut single_rejections = 0;
+        for r in workload() {
+            if let Err(TxError::Rejected(_)) = single.process(r) {
+                single_rejections += 1;
+            }
+        }
+        let expected = single.into_report();
+
+        for threads in [1, 3, 4] {
+            let mut sharded = ShardedEngine::new(&EngineConfig::default(), threads);
+            for r in workload() {
+                sharded.process(r).expect("Shard stopped");
+            }
+            let mut rejections = 0;
+            let report = sharded.finish(|_| rejections += 1).expect("Shard failed");
+
+            assert_eq!(report.accounts, expected.accounts);
+            assert_eq!(report.transactions.to_map(), expected.transactions.to_map());
+            assert_eq!(report.records_processed, expected.records_processed);
+            assert_eq!(rejections, single_rejections);
+        }
+    }
+
+    #[test]
+    fn test_rejections_and_failure() {
+        use crate::dedup::DedupPolicy;
+
+        let config = EngineConfig {
+            ref_dedup: DedupPolicy::Fail,
+            ..EngineConfig::default()
+        };
+        let mut sharded = ShardedEngine::new(&config, 2);
+        sharded
+            .process(record(TransactionType::Withdrawal, 1, 1, Some(dec!(1))))
+            .unwrap();
+        sharded
+            .process(record(TransactionType::Deposit, 2, 2, Some(dec!(1))))
+            .unwrap();
+        sharded
+            .process(record(TransactionType::Dispute, 2, 2, None))
+            .unwrap();
+        sharded
+            .process(record(TransactionType::Dispute, 2, 2, None))
+            .unwrap();
+
+        let mut rejections = Vec::new();
+        let result = sharded.finish(|r| rejections.push(r));
+        assert!(matches!(result, Err(TxError::Duplicate(d)) if d.position == 4));
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].reason, RejectionReason::InsufficientFunds);
+    }
+}
@@ -0,0 +1,739 @@
+//! Versioned binary snapshot of engine state
+//!
+//! Layout (integers little endian):
+//!
+//! | bytes | field                                        |
+//! |-------|----------------------------------------------|
+//! | 8     | magic `CTXSNAP\0`                            |
+//! | 2     | format version                               |
+//! | 2     | flags, bit 0 set: payload is zstd compressed |
+//! | 8     | payload length                               |
+//! | 4     | CRC-32 of the payload                        |
+//! | n     | payload, postcard encoding of `SnapshotV4`   |
+//!
+//! Length and checksum are those of the payload as stored, so corruption is
+//! caught before decompressing. Other flag bits are reserved and refused.
+//!
+//! The payload structs are frozen per version and separate from `Account` and
+//! `StoredTransaction`, so those can gain fields without changing what is on
+//! disk. To change the layout:
+//! - add the next `SnapshotVn` and bump `VERSION`;
+//! - keep the older payloads and their decoders;
+//! - migrate them into the current types in `decode`, deriving or defaulting
+//!   the new fields.
+//!
+//! Amounts are stored as `Decimal::serialize` bytes, which round-trip exactly.
+//!
+//! Versions:
+//! - 1: accounts, stored transactions and the record count
+//! - 2: adds the event ids already sent to event sinks; v1 loads with none
+//! - 3: adds the timestamp of stored transactions; older versions load without
+//! - 4: the version 3 layout, whose lock reasons and event types may also name
+//!   closed accounts and `close`/`unlock` records; older versions refuse them
+
+use crate::events::EventId;
+use crate::timestamp::Timestamp;
+use crate::types::{
+    Account, ClientId, LockReason, StoredTransaction, TransactionId, TransactionType,
+};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// File magic
+pub const MAGIC: [u8; 8] = *b"CTXSNAP\0";
+
+/// Format version written by this build
+pub const VERSION: u16 = 4;
+
+/// Flag bit set when the payload is zstd compressed
+pub const FLAG_ZSTD: u16 = 1;
+
+const HEADER_LEN: usize = 8 + 2 + 2 + 8 + 4;
+
+/// How the snapshot payload is stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// zstd at the given level (1-22, 0 picks zstd's default), needs the `zstd` feature
+    Zstd(i32),
+}
+
+/// Engine state captured between runs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub accounts: HashMap<ClientId, Account>,
+    pub transactions: HashMap<TransactionId, StoredTransaction>,
+    /// Records processed so far, so dispute ages carry on across runs
+    pub records_processed: u64,
+    /// Events already sent to event sinks, under `EngineConfig::dedup_events`
+    pub emitted_events: HashSet<EventId>,
+}
+
+/// Why a snapshot could not be read
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// Not a snapshot file
+    BadMagic,
+    /// Written by a newer build
+    UnsupportedVersion(u16),
+    /// Flag bits this build does not know
+    UnsupportedFlags(u16),
+    /// Payload does not match its checksum
+    ChecksumMismatch,
+    /// Payload could not be decoded or encoded
+    Encoding(postcard::Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(
+                    f,
+                    "snapshot version {} is newer than supported ({})",
+                    v, VERSION
+                )
+            }
+            SnapshotError::UnsupportedFlags(flags) => {
+                write!(f, "unsupported snapshot flags {:#06x}", flags)
+            }
+            SnapshotError::ChecksumMismatch => write!(f, "snapshot checksum mismatch"),
+            SnapshotError::Encoding(e) => write!(f, "invalid snapshot payload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<postcard::Error> for SnapshotError {
+    fn from(e: postcard::Error) -> Self {
+        SnapshotError::Encoding(e)
+    }
+}
+
+/// Version 1 payload
+#[derive(Serialize, Deserialize)]
+struct SnapshotV1 {
+    records_processed: u64,
+    accounts: Vec<AccountV1>,
+    transactions: Vec<StoredTransactionV1>,
+}
+
+/// Version 2 payload
+#[derive(Serialize, Deserialize)]
+struct SnapshotV2 {
+    records_processed: u64,
+    accounts: Vec<AccountV1>,
+    transactions: Vec<StoredTransactionV1>,
+    emitted_events: Vec<EventIdV2>,
+}
+
+/// Version 3 payload
+#[derive(Serialize, Deserialize)]
+struct SnapshotV3 {
+    records_processed: u64,
+    accounts: Vec<AccountV1>,
+    transactions: Vec<StoredTransactionV3>,
+    emitted_events: Vec<EventIdV2>,
+}
+
+/// Version 4 payload, laid out like version 3
+type SnapshotV4 = SnapshotV3;
+
+/// Highest lock reason and event type code before version 4
+const PRE_V4_LOCK_REASON: u8 = 1;
+const PRE_V4_TX_TYPE: u8 = 4;
+
+#[derive(Serialize, Deserialize)]
+struct EventIdV2 {
+    tx_type: u8,
+    client: ClientId,
+    tx: TransactionId,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AccountV1 {
+    client: ClientId,
+    available: [u8; 16],
+    held: [u8; 16],
+    total: [u8; 16],
+    locked: bool,
+    /// 0 = none, 1 = chargeback, 2 = closed (version 4 on)
+    lock_reason: u8,
+    open_disputes: u32,
+    last_tx: Option<TransactionId>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredTransactionV1 {
+    tx: TransactionId,
+    client: ClientId,
+    /// Deposits, and withdrawals when they may be disputed
+    tx_type: u8,
+    amount: [u8; 16],
+    disputed: bool,
+    charged_back: bool,
+    disputed_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredTransactionV3 {
+    tx: TransactionId,
+    client: ClientId,
+    tx_type: u8,
+    amount: [u8; 16],
+    disputed: bool,
+    charged_back: bool,
+    disputed_at: Option<u64>,
+    /// Seconds and nanoseconds since the Unix epoch
+    timestamp: Option<(i64, u32)>,
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_encode(payload: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    zstd::encode_all(payload, level)
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decode(payload: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::decode_all(payload)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_encode(_payload: &[u8], _level: i32) -> io::Result<Vec<u8>> {
+    Err(without_zstd())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decode(_payload: &[u8]) -> io::Result<Vec<u8>> {
+    Err(without_zstd())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn without_zstd() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "built without zstd support, rebuild with --features zstd",
+    )
+}
+
+/// Code of a transaction type; close (5) and unlock (6) from version 4 on
+pub(crate) fn tx_type_code(tx_type: TransactionType) -> u8 {
+    match tx_type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Close => 5,
+        TransactionType::Unlock => 6,
+    }
+}
+
+pub(crate) fn tx_type_from_code(code: u8) -> Option<TransactionType> {
+    Some(match code {
+        0 => TransactionType::Deposit,
+        1 => TransactionType::Withdrawal,
+        2 => TransactionType::Dispute,
+        3 => TransactionType::Resolve,
+        4 => TransactionType::Chargeback,
+        5 => TransactionType::Close,
+        6 => TransactionType::Unlock,
+        _ => return None,
+    })
+}
+
+impl Snapshot {
+    /// Write the snapshot in the current format version, uncompressed
+    pub fn write<W: Write>(&self, writer: W) -> Result<(), SnapshotError> {
+        self.write_with(writer, Compression::None)
+    }
+
+    /// Write the snapshot in the current format version
+    pub fn write_with<W: Write>(
+        &self,
+        mut writer: W,
+        compression: Compression,
+    ) -> Result<(), SnapshotError> {
+        let encoded = postcard::to_stdvec(&self.to_v4())?;
+        let (flags, payload) = match compression {
+            Compression::None => (0, encoded),
+            Compression::Zstd(level) => (FLAG_ZSTD, zstd_encode(&encoded, level)?),
+        };
+
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&flags.to_le_bytes())?;
+        writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+        writer.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        writer.write_all(&payload)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read a snapshot written by this or an older version
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, SnapshotError> {
+        let mut header = [0u8; HEADER_LEN];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => SnapshotError::BadMagic,
+            _ => SnapshotError::Io(e),
+        })?;
+        if header[..8] != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        let flags = u16::from_le_bytes([header[10], header[11]]);
+        if flags & !FLAG_ZSTD != 0 {
+            return Err(SnapshotError::UnsupportedFlags(flags));
+        }
+        let len = u64::from_le_bytes(header[12..20].try_into().expect("8 bytes"));
+        let checksum = u32::from_le_bytes(header[20..24].try_into().expect("4 bytes"));
+
+        let mut payload = Vec::new();
+        reader.take(len).read_to_end(&mut payload)?;
+        if payload.len() as u64 != len || crc32fast::hash(&payload) != checksum {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+        if flags & FLAG_ZSTD != 0 {
+            payload = zstd_decode(&payload)?;
+        }
+
+        Self::decode(version, &payload)
+    }
+
+    /// Decode a payload of the given version into the current types
+    fn decode(version: u16, payload: &[u8]) -> Result<Self, SnapshotError> {
+        match version {
+            1 => {
+                let v1: SnapshotV1 = postcard::from_bytes(payload)?;
+                check_pre_v4_codes(&v1.accounts, &[])?;
+                Self::from_v1(v1)
+            }
+            2 => {
+                let v2: SnapshotV2 = postcard::from_bytes(payload)?;
+                check_pre_v4_codes(&v2.accounts, &v2.emitted_events)?;
+                Self::from_v2(v2)
+            }
+            3 => {
+                let v3: SnapshotV3 = postcard::from_bytes(payload)?;
+                check_pre_v4_codes(&v3.accounts, &v3.emitted_events)?;
+                Self::from_v3(v3)
+            }
+            4 => Self::from_v3(postcard::from_bytes(payload)?),
+            _ => Err(SnapshotError::UnsupportedVersion(version)),
+        }
+    }
+
+    fn to_v4(&self) -> SnapshotV4 {
+        // Sorted so the same state always gives the same bytes
+        let mut accounts: Vec<_> = self.accounts.values().collect();
+        accounts.sort_by_key(|a| a.client);
+        let mut transactions: Vec<_> = self.transactions.iter().collect();
+        transactions.sort_by_key(|(tx, _)| **tx);
+        let mut emitted_events: Vec<_> = self
+            .emitted_events
+            .iter()
+            .map(|id| EventIdV2 {
+                tx_type: tx_type_code(id.tx_type),
+                client: id.client,
+                tx: id.tx,
+            })
+            .collect();
+        emitted_events.sort_by_key(|id| (id.tx, id.client, id.tx_type));
+
+        SnapshotV4 {
+            records_processed: self.records_processed,
+            emitted_events,
+            accounts: accounts
+                .into_iter()
+                .map(|a| AccountV1 {
+                    client: a.client,
+                    available: a.available.serialize(),
+                    held: a.held.serialize(),
+                    total: a.total.serialize(),
+                    locked: a.locked,
+                    lock_reason: match a.lock_reason {
+                        None => 0,
+                        Some(LockReason::Chargeback) => 1,
+                        Some(LockReason::Closed) => 2,
+                    },
+                    open_disputes: a.open_disputes,
+                    last_tx: a.last_tx,
+                })
+                .collect(),
+            transactions: transactions
+                .into_iter()
+                .map(|(tx, t)| StoredTransactionV3 {
+                    tx: *tx,
+                    client: t.client_id,
+                    tx_type: tx_type_code(t.tx_type),
+                    amount: t.amount.serialize(),
+                    disputed: t.disputed,
+                    charged_back: t.charged_back,
+                    disputed_at: t.disputed_at,
+                    timestamp: t.timestamp.map(|ts| (ts.unix_secs(), ts.subsec_nanos())),
+                })
+                .collect(),
+        }
+    }
+
+    fn from_v1(v1: SnapshotV1) -> Result<Self, SnapshotError> {
+        let invalid = |what: &str| {
+            SnapshotError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid {} in snapshot", what),
+            ))
+        };
+
+        let mut accounts = HashMap::with_capacity(v1.accounts.len());
+        for a in v1.accounts {
+            let mut account = Account::new(a.client);
+            account.available = Decimal::deserialize(a.available);
+            account.held = Decimal::deserialize(a.held);
+            account.total = Decimal::deserialize(a.total);
+            account.locked = a.locked;
+            account.lock_reason = match a.lock_reason {
+                0 => None,
+                1 => Some(LockReason::Chargeback),
+                2 => Some(LockReason::Closed),
+                _ => return Err(invalid("lock reason")),
+            };
+            account.open_disputes = a.open_disputes;
+            account.last_tx = a.last_tx;
+            accounts.insert(account.client, account);
+        }
+
+        let mut transactions = HashMap::with_capacity(v1.transactions.len());
+        for t in v1.transactions {
+            let tx_type = tx_type_from_code(t.tx_type).ok_or_else(|| invalid("tx type"))?;
+            let mut stored =
+                StoredTransaction::new(t.client, tx_type, Decimal::deserialize(t.amount));
+            stored.disputed = t.disputed;
+            stored.charged_back = t.charged_back;
+            stored.disputed_at = t.disputed_at;
+            transactions.insert(t.tx, stored);
+        }
+
+        Ok(Self {
+            accounts,
+            transactions,
+            records_processed: v1.records_processed,
+            emitted_events: HashSet::new(),
+        })
+    }
+
+    fn from_v2(v2: SnapshotV2) -> Result<Self, SnapshotError> {
+        let mut snapshot = Self::from_v1(SnapshotV1 {
+            records_processed: v2.records_processed,
+            accounts: v2.accounts,
+            transactions: v2.transactions,
+        })?;
+        for id in v2.emitted_events {
+            let tx_type = tx_type_from_code(id.tx_type).ok_or_else(|| {
+                SnapshotError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid event type in snapshot",
+                ))
+            })?;
+            snapshot.emitted_events.insert(EventId {
+                tx_type,
+                client: id.client,
+                tx: id.tx,
+            });
+        }
+        Ok(snapshot)
+    }
+
+    fn from_v3(v3: SnapshotV3) -> Result<Self, SnapshotError> {
+        let mut timestamps = Vec::new();
+        let transactions = v3
+            .transactions
+            .into_iter()
+            .map(|t| {
+                if let Some(timestamp) = t.timestamp {
+                    timestamps.push((t.tx, timestamp));
+                }
+                StoredTransactionV1 {
+                    tx: t.tx,
+                    client: t.client,
+                    tx_type: t.tx_type,
+                    amount: t.amount,
+                    disputed: t.disputed,
+                    charged_back: t.charged_back,
+                    disputed_at: t.disputed_at,
+                }
+            })
+            .collect();
+        let mut snapshot = Self::from_v2(SnapshotV2 {
+            records_processed: v3.records_processed,
+            accounts: v3.accounts,
+            transactions,
+            emitted_events: v3.emitted_events,
+        })?;
+        for (tx, (secs, nanos)) in timestamps {
+            if nanos >= 1_000_000_000 {
+                return Err(SnapshotError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid timestamp in snapshot",
+                )));
+            }
+            if let Some(stored) = snapshot.transactions.get_mut(&tx) {
+                stored.timestamp = Some(Timestamp::from_unix(secs, nanos));
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Refuse codes no build writing versions 1 to 3 could have written
+fn check_pre_v4_codes(accounts: &[AccountV1], events: &[EventIdV2]) -> Result<(), SnapshotError> {
+    let invalid = |what: &str| {
+        SnapshotError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid {} in snapshot before version 4", what),
+        ))
+    };
+    if accounts.iter().any(|a| a.lock_reason > PRE_V4_LOCK_REASON) {
+        return Err(invalid("lock reason"));
+    }
+    if events.iter().any(|id| id.tx_type > PRE_V4_TX_TYPE) {
+        return Err(invalid("event type"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn sample() -> Snapshot {
+        let mut account = Account::new(7);
+        account.deposit(dec!(0.1234));
+        account.deposit(dec!(99999999999.9999));
+        account.hold_funds(dec!(0.1234));
+        account.last_tx = Some(2);
+
+        let mut locked = Account::new(8);
+        locked.deposit(dec!(5));
+        locked.hold_funds(dec!(5));
+        locked.chargeback(dec!(5));
+
+        let mut disputed = StoredTransaction::new(7, TransactionType::Deposit, dec!(0.1234));
+        disputed.mark_disputed();
+        disputed.disputed_at = Some(3);
+        disputed.timestamp = Some(Timestamp::from_unix(1_706_745_599, 500_000_000));
+        let mut charged_back = StoredTransaction::new(8, TransactionType::Deposit, dec!(5));
+        charged_back.mark_disputed();
+        charged_back.mark_charged_back();
+
+        Snapshot {
+            accounts: HashMap::from([(7, account), (8, locked)]),
+            transactions: HashMap::from([(1, disputed), (4, charged_back)]),
+            records_processed: 6,
+            emitted_events: HashSet::from([
+                EventId {
+                    tx_type: TransactionType::Deposit,
+                    client: 7,
+                    tx: 1,
+                },
+                EventId {
+                    tx_type: TransactionType::Dispute,
+                    client: 7,
+                    tx: 1,
+                },
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let snapshot = sample();
+        let mut buf = Vec::new();
+        snapshot.write(&mut buf).expect("Failed to write");
+
+        assert_eq!(buf[..8], MAGIC);
+        assert_eq!(u16::from_le_bytes([buf[8], buf[9]]), VERSION);
+
+        let read = Snapshot::read(buf.as_slice()).expect("Failed to read");
+        assert_eq!(read, snapshot);
+        assert_eq!(read.accounts[&7].available.to_string(), "99999999999.9999");
+
+        // Deterministic encoding
+        let mut again = Vec::new();
+        read.write(&mut again).expect("Failed to write");
+        assert_eq!(again, buf);
+    }
+
+    fn framed(version: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&version.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn test_reads_older_versions() {
+        fn to_v1(t: StoredTransactionV3) -> StoredTransactionV1 {
+            StoredTransactionV1 {
+                tx: t.tx,
+                client: t.client,
+                tx_type: t.tx_type,
+                amount: t.amount,
+                disputed: t.disputed,
+                charged_back: t.charged_back,
+                disputed_at: t.disputed_at,
+            }
+        }
+        let snapshot = sample();
+        let mut without_timestamps = snapshot.clone();
+        for stored in without_timestamps.transactions.values_mut() {
+            stored.timestamp = None;
+        }
+
+        let v3 = snapshot.to_v4();
+        let v2 = postcard::to_stdvec(&SnapshotV2 {
+            records_processed: v3.records_processed,
+            accounts: v3.accounts,
+            transactions: v3.transactions.into_iter().map(to_v1).collect(),
+            emitted_events: v3.emitted_events,
+        })
+        .unwrap();
+        let read = Snapshot::read(framed(2, &v2).as_slice()).expect("Failed to read v2");
+        assert_eq!(read, without_timestamps);
+
+        let v3 = snapshot.to_v4();
+        let v1 = postcard::to_stdvec(&SnapshotV1 {
+            records_processed: v3.records_processed,
+            accounts: v3.accounts,
+            transactions: v3.transactions.into_iter().map(to_v1).collect(),
+        })
+        .unwrap();
+        let read = Snapshot::read(framed(1, &v1).as_slice()).expect("Failed to read v1");
+        assert!(read.emitted_events.is_empty());
+        assert_eq!(
+            read,
+            Snapshot {
+                emitted_events: HashSet::new(),
+                ..without_timestamps
+            }
+        );
+    }
+
+    #[test]
+    fn test_closed_accounts_need_version_4() {
+        let mut snapshot = sample();
+        let v3 = postcard::to_stdvec(&snapshot.to_v4()).unwrap();
+        let read = Snapshot::read(framed(3, &v3).as_slice()).expect("Failed to read v3");
+        assert_eq!(read, snapshot);
+
+        let mut closed = Account::new(9);
+        closed.close();
+        snapshot.accounts.insert(9, closed);
+        snapshot.emitted_events.insert(EventId {
+            tx_type: TransactionType::Unlock,
+            client: 8,
+            tx: 5,
+        });
+        let mut buf = Vec::new();
+        snapshot.write(&mut buf).expect("Failed to write");
+        let read = Snapshot::read(buf.as_slice()).expect("Failed to read");
+        assert_eq!(read.accounts[&9].lock_reason, Some(LockReason::Closed));
+        assert_eq!(read, snapshot);
+
+        // The same payload claiming to be version 3 is refused
+        let v4 = postcard::to_stdvec(&snapshot.to_v4()).unwrap();
+        let err = Snapshot::read(framed(3, &v4).as_slice()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid lock reason in snapshot before version 4"
+        );
+        snapshot.accounts.remove(&9);
+        let v4 = postcard::to_stdvec(&snapshot.to_v4()).unwrap();
+        let err = Snapshot::read(framed(3, &v4).as_slice()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid event type in snapshot before version 4"
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_round_trip() {
+        let mut snapshot = sample();
+        for tx in 10..1000 {
+            snapshot.transactions.insert(
+                tx,
+                StoredTransaction::new(7, TransactionType::Deposit, dec!(12.5)),
+            );
+        }
+        let mut plain = Vec::new();
+        snapshot.write(&mut plain).expect("Failed to write");
+        let mut compressed = Vec::new();
+        snapshot
+            .write_with(&mut compressed, Compression::Zstd(19))
+            .expect("Failed to write");
+
+        assert_eq!(
+            u16::from_le_bytes([compressed[10], compressed[11]]),
+            FLAG_ZSTD
+        );
+        assert!(compressed.len() < plain.len() / 4);
+        let read = Snapshot::read(compressed.as_slice()).expect("Failed to read");
+        assert_eq!(read, snapshot);
+
+        let mut unknown_flag = plain.clone();
+        unknown_flag[10] |= 2;
+        assert!(matches!(
+            Snapshot::read(unknown_flag.as_slice()),
+            Err(SnapshotError::UnsupportedFlags(2))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_corrupt_input() {
+        let mut buf = Vec::new();
+        sample().write(&mut buf).expect("Failed to write");
+
+        let mut flipped = buf.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            Snapshot::read(flipped.as_slice()),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+
+        assert!(matches!(
+            Snapshot::read(&buf[..buf.len() - 1]),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+
+        let mut newer = buf.clone();
+        newer[8..10].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Snapshot::read(newer.as_slice()),
+            Err(SnapshotError::UnsupportedVersion(v)) if v == VERSION + 1
+        ));
+
+        assert!(matches!(
+            Snapshot::read("type,client,tx,amount\n".as_bytes()),
+            Err(SnapshotError::BadMagic)
+        ));
+    }
+}
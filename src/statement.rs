@@ -0,0 +1,710 @@
+use crate::output::AtomicFile;
+use crate::types::{serialize_decimal_str, Account, ClientId, TransactionRecord, TransactionType};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::thread;
+
+/// Entries kept in memory across all clients of a bulk export before they are
+/// spilled to per-client files
+const SPILL_THRESHOLD: usize = 65_536;
+
+/// Statement output format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatementFormat {
+    Csv,
+    /// Structured document, ready to be rendered (e.g. to PDF) by a template
+    Json,
+}
+
+impl StatementFormat {
+    /// File extension used for bulk exports
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StatementFormat::Csv => "csv",
+            StatementFormat::Json => "json",
+        }
+    }
+}
+
+impl FromStr for StatementFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(StatementFormat::Csv),
+            "json" => Ok(StatementFormat::Json),
+            _ => Err(format!("Unknown statement format: {}", s)),
+        }
+    }
+}
+
+/// Account balances at a point of the statement
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Balance {
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub available: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub held: Decimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl Balance {
+    fn zero() -> Self {
+        Self::from(&Account::new(0))
+    }
+}
+
+impl From<&Account> for Balance {
+    fn from(account: &Account) -> Self {
+        Self {
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+        }
+    }
+}
+
+/// One applied transaction and the balances right after it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StatementEntry {
+    /// Position of the record in application order (1-based)
+    pub position: u64,
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub tx: u32,
+    /// Amount of the record, absent for dispute/resolve/chargeback
+    #[serde(serialize_with = "serialize_optional_decimal_str")]
+    pub amount: Option<Decimal>,
+    pub balance: Balance,
+}
+
+/// Per-client statement over a range of record positions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Statement {
+    pub client: ClientId,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub opening: Balance,
+    pub entries: Vec<StatementEntry>,
+    pub closing: Balance,
+}
+
+impl Statement {
+    /// Write the statement as CSV: an opening row, one row per entry, a closing row
+    pub fn write_csv<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record([
+            "position",
+            "type",
+            "tx",
+            "amount",
+            "available",
+            "held",
+            "total",
+            "locked",
+        ])?;
+
+        let row = |position: String,
+                   kind: &str,
+                   tx: String,
+                   amount: Option<Decimal>,
+                   balance: &Balance| {
+            [
+                position,
+                kind.to_string(),
+                tx,
+                amount.map(|a| a.to_string()).unwrap_or_default(),
+                balance.available.to_string(),
+                balance.held.to_string(),
+                balance.total.to_string(),
+                balance.locked.to_string(),
+            ]
+        };
+
+        writer.write_record(row(
+            String::new(),
+            "opening",
+            String::new(),
+            None,
+            &self.opening,
+        ))?;
+        for entry in &self.entries {
+            writer.write_record(row(
+                entry.position.to_string(),
+                entry.tx_type.as_str(),
+                entry.tx.to_string(),
+                entry.amount,
+                &entry.balance,
+            ))?;
+        }
+        writer.write_record(row(
+            String::new(),
+            "closing",
+            String::new(),
+            None,
+            &self.closing,
+        ))?;
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write the statement as a pretty-printed JSON document
+    pub fn write_json<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        let mut writer = writer;
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)?;
+        Ok(())
+    }
+
+    /// Write the statement in the given format
+    pub fn write<W: Write>(
+        &self,
+        format: StatementFormat,
+        writer: W,
+    ) -> Result<(), Box<dyn Error>> {
+        match format {
+            StatementFormat::Csv => self.write_csv(writer),
+            StatementFormat::Json => self.write_json(writer),
+        }
+    }
+}
+
+/// Collects a client's statement while transactions are being applied
+#[derive(Debug)]
+pub struct StatementBuilder {
+    statement: Statement,
+}
+
+impl StatementBuilder {
+    /// Start a statement for `client` over positions `from..=to`
+    pub fn new(client: ClientId, from: Option<u64>, to: Option<u64>) -> Self {
+        Self {
+            statement: Statement {
+                client,
+                from,
+                to,
+                opening: Balance::zero(),
+                entries: Vec::new(),
+                closing: Balance::zero(),
+            },
+        }
+    }
+
+    /// Record an applied transaction with the account state right after it
+    pub fn observe(&mut self, position: u64, record: &TransactionRecord, account: &Account) {
+        let statement = &mut self.statement;
+        if record.client != statement.client {
+            return;
+        }
+
+        if statement.from.is_some_and(|from| position < from) {
+            // Still before the range: this becomes the opening balance
+            statement.opening = Balance::from(account);
+        } else if statement.to.is_none_or(|to| position <= to) {
+            statement.entries.push(StatementEntry {
+                position,
+                tx_type: record.tx_type,
+                tx: record.tx,
+                amount: record.amount,
+                balance: Balance::from(account),
+            });
+        }
+    }
+
+    /// Finish the statement, closing balance is the last entry's (or the opening)
+    pub fn finish(mut self) -> Statement {
+        self.statement.closing = match self.statement.entries.last() {
+            Some(entry) => entry.balance.clone(),
+            None => self.statement.opening.clone(),
+        };
+        self.statement
+    }
+}
+
+/// Collects statements for every client in a single pass over the input
+///
+/// Only the opening balance of each client stays in memory for the whole run.
+/// Once `SPILL_THRESHOLD` entries are held, they are appended to a file per
+/// client in a hidden directory of the export, and read back one statement at a
+/// time when the statements are written.
+#[derive(Debug)]
+pub struct BulkStatementBuilder {
+    from: Option<u64>,
+    to: Option<u64>,
+    builders: HashMap<ClientId, StatementBuilder>,
+    out_dir: PathBuf,
+    spill_dir: PathBuf,
+    /// Whether `spill_dir` may exist, removed by `write_all` if so
+    spill_dir_created: bool,
+    /// Clients with entries in `spill_dir`
+    spilled: HashSet<ClientId>,
+    /// Entries held in memory
+    buffered: usize,
+    spill_after: usize,
+    /// First failed spill; `observe` cannot fail, so `write_all` reports it
+    spill_error: Option<io::Error>,
+}
+
+impl BulkStatementBuilder {
+    /// Start statements over positions `from..=to` for all clients, to be written into `out_dir`
+    pub fn new(from: Option<u64>, to: Option<u64>, out_dir: &Path) -> Self {
+        Self {
+            from,
+            to,
+            builders: HashMap::new(),
+            out_dir: out_dir.to_path_buf(),
+            spill_dir: out_dir.join(format!(".spill-{}", process::id())),
+            spill_dir_created: false,
+            spilled: HashSet::new(),
+            buffered: 0,
+            spill_after: SPILL_THRESHOLD,
+            spill_error: None,
+        }
+    }
+
+    /// Record an applied transaction for the client it belongs to
+    pub fn observe(&mut self, position: u64, record: &TransactionRecord, account: &Account) {
+        let (from, to) = (self.from, self.to);
+        let builder = self
+            .builders
+            .entry(record.client)
+            .or_insert_with(|| StatementBuilder::new(record.client, from, to));
+        let before = builder.statement.entries.len();
+        builder.observe(position, record, account);
+        self.buffered += builder.statement.entries.len() - before;
+
+        if self.buffered >= self.spill_after && self.spill_error.is_none() {
+            if let Err(e) = self.spill() {
+                self.spill_error = Some(e);
+            }
+        }
+    }
+
+    /// Append the entries in memory to their clients' spill files
+    fn spill(&mut self) -> io::Result<()> {
+        self.spill_dir_created = true;
+        fs::create_dir_all(&self.spill_dir)?;
+        for (client, builder) in &mut self.builders {
+            if builder.statement.entries.is_empty() {
+                continue;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(spill_path(&self.spill_dir, *client))?;
+            let mut writer = csv::Writer::from_writer(BufWriter::new(file));
+            for entry in builder.statement.entries.drain(..) {
+                writer.write_record(entry_row(&entry))?;
+            }
+            writer.flush()?;
+            self.spilled.insert(*client);
+        }
+        self.buffered = 0;
+        Ok(())
+    }
+
+    /// A client's finished statement, with its spilled entries read back
+    fn assemble(&self, builder: &StatementBuilder) -> Result<Statement, Box<dyn Error>> {
+        let statement = &builder.statement;
+        let mut entries = if self.spilled.contains(&statement.client) {
+            read_spilled(&spill_path(&self.spill_dir, statement.client))?
+        } else {
+            Vec::new()
+        };
+        entries.extend(statement.entries.iter().cloned());
+        let builder = StatementBuilder {
+            statement: Statement {
+                client: statement.client,
+                from: statement.from,
+                to: statement.to,
+                opening: statement.opening.clone(),
+                entries,
+                closing: Balance::zero(),
+            },
+        };
+        Ok(builder.finish())
+    }
+
+    /// Finish all statements and write one file per client into the export
+    /// directory, spreading the clients across threads that each hold one statement
+    /// at a time. Each file is written atomically; the first error encountered is
+    /// returned. The spill directory is removed either way
+    pub fn write_all(self, format: StatementFormat) -> Result<(), Box<dyn Error>> {
+        let written = self.write_statements(format);
+        if self.spill_dir_created {
+            match fs::remove_dir_all(&self.spill_dir) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        written
+    }
+
+    fn write_statements(&self, format: StatementFormat) -> Result<(), Box<dyn Error>> {
+        if let Some(e) = &self.spill_error {
+            return Err(format!("{}: {}", self.spill_dir.display(), e).into());
+        }
+        fs::create_dir_all(&self.out_dir)?;
+        if self.builders.is_empty() {
+            return Ok(());
+        }
+
+        let mut builders: Vec<_> = self.builders.values().collect();
+        builders.sort_by_key(|builder| builder.statement.client);
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = builders.len().div_ceil(threads);
+
+        thread::scope(|scope| {
+            let workers: Vec<_> = builders
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || -> Result<(), String> {
+                        for builder in chunk {
+                            let statement = self
+                                .assemble(builder)
+                                .map_err(|e| format!("{}: {}", self.spill_dir.display(), e))?;
+                            let path = statement_path(&self.out_dir, statement.client, format);
+                            write_file(&statement, &path, format)
+                                .map_err(|e| format!("{}: {}", path.display(), e))?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("statement writer panicked"))
+        })?;
+
+        Ok(())
+    }
+}
+
+/// Spill file of a client's entries
+fn spill_path(spill_dir: &Path, client: ClientId) -> PathBuf {
+    spill_dir.join(format!("client_{}.csv", client))
+}
+
+/// A statement entry as a spill file row
+fn entry_row(entry: &StatementEntry) -> [String; 8] {
+    [
+        entry.position.to_string(),
+        entry.tx_type.as_str().to_string(),
+        entry.tx.to_string(),
+        entry.amount.map(|a| a.to_string()).unwrap_or_default(),
+        entry.balance.available.to_string(),
+        entry.balance.held.to_string(),
+        entry.balance.total.to_string(),
+        entry.balance.locked.to_string(),
+    ]
+}
+
+/// Entries of a spill file, in the order they were spilled
+fn read_spilled(path: &Path) -> Result<Vec<StatementEntry>, Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(path)?;
+    let mut entries = Vec::new();
+    for row in reader.records() {
+        let row = row?;
+        let amount = match &row[3] {
+            "" => None,
+            amount => Some(amount.parse()?),
+        };
+        entries.push(StatementEntry {
+            position: row[0].parse()?,
+            tx_type: row[1].parse()?,
+            tx: row[2].parse()?,
+            amount,
+            balance: Balance {
+                available: row[4].parse()?,
+                held: row[5].parse()?,
+                total: row[6].parse()?,
+                locked: row[7].parse()?,
+            },
+        });
+    }
+    Ok(entries)
+}
+
+/// Path of a client's statement inside a bulk export directory
+pub fn statement_path(out_dir: &Path, client: ClientId, format: StatementFormat) -> PathBuf {
+    out_dir.join(format!("client_{}.{}", client, format.extension()))
+}
+
+/// Write a single statement file atomically
+fn write_file(
+    statement: &Statement,
+    path: &Path,
+    format: StatementFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = AtomicFile::create(path)?;
+    statement.write(format, &mut file)?;
+    file.commit()?;
+    Ok(())
+}
+
+/// Serialize an optional Decimal as a string, empty when absent
+fn serialize_optional_decimal_str<S>(
+    value: &Option<Decimal>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match value {
+        Some(value) => serialize_decimal_str(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn record(
+        tx_type: TransactionType,
+        client: ClientId,
+        tx: u32,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tx_type,
+            client,
+            tx,
+            amount,
+            seq: None,
+            timestamp: None,
+            line: None,
+        }
+    }
+
+    fn build(from: Option<u64>, to: Option<u64>) -> Statement {
+        let mut builder = StatementBuilder::new(1, from, to);
+        let mut account = Account::new(1);
+
+        account.deposit(dec!(100));
+        builder.observe(
+            1,
+            &record(TransactionType::Deposit, 1, 1, Some(dec!(100))),
+            &account,
+        );
+
+        // Other clients are not part of the statement
+        builder.observe(
+            2,
+            &record(TransactionType::Deposit, 2, 2, Some(dec!(5))),
+            &Account::new(2),
+        );
+
+        account.withdraw(dec!(30));
+        builder.observe(
+            3,
+            &record(TransactionType::Withdrawal, 1, 3, Some(dec!(30))),
+            &account,
+        );
+
+        account.hold_funds(dec!(100));
+        builder.observe(4, &record(TransactionType::Dispute, 1, 1, None), &account);
+
+        builder.finish()
+    }
+
+    #[test]
+    fn test_full_statement() {
+        let statement = build(None, None);
+
+        assert_eq!(statement.opening, Balance::zero());
+        assert_eq!(statement.entries.len(), 3);
+        assert_eq!(statement.closing.available, dec!(-30));
+        assert_eq!(statement.closing.held, dec!(100));
+        assert_eq!(statement.closing.total, dec!(70));
+    }
+
+    #[test]
+    fn test_statement_range() {
+        let statement = build(Some(3), Some(3));
+
+        assert_eq!(statement.opening.total, dec!(100));
+        assert_eq!(statement.entries.len(), 1);
+        assert_eq!(statement.entries[0].tx, 3);
+        assert_eq!(statement.closing.total, dec!(70));
+        assert_eq!(statement.closing.held, dec!(0));
+    }
+
+    #[test]
+    fn test_empty_range_closes_at_opening() {
+        let statement = build(Some(10), None);
+
+        assert!(statement.entries.is_empty());
+        assert_eq!(statement.opening.held, dec!(100));
+        assert_eq!(statement.closing, statement.opening);
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let statement = build(Some(3), Some(4));
+        let mut buf = Vec::new();
+        statement.write_csv(&mut buf).expect("Failed to write");
+
+        let text = String::from_utf8(buf).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "position,type,tx,amount,available,held,total,locked"
+        );
+        assert_eq!(lines[1], ",opening,,,100,0,100,false");
+        assert_eq!(lines[2], "3,withdrawal,3,30,70,0,70,false");
+        assert_eq!(lines[3], "4,dispute,1,,-30,100,70,false");
+        assert_eq!(lines[4], ",closing,,,-30,100,70,false");
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("core-tx-runner-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Statement files written by a bulk builder spilling after `spill_after` entries
+    fn bulk_statements(name: &str, spill_after: usize) -> Vec<String> {
+        let dir = scratch_dir(name);
+        let mut bulk = BulkStatementBuilder::new(Some(2), None, &dir);
+        bulk.spill_after = spill_after;
+        let mut first = Account::new(1);
+        let mut second = Account::new(2);
+
+        second.deposit(dec!(5));
+        bulk.observe(
+            1,
+            &record(TransactionType::Deposit, 2, 1, Some(dec!(5))),
+            &second,
+        );
+        first.deposit(dec!(10));
+        bulk.observe(
+            2,
+            &record(TransactionType::Deposit, 1, 2, Some(dec!(10))),
+            &first,
+        );
+        second.deposit(dec!(1.25));
+        bulk.observe(
+            3,
+            &record(TransactionType::Deposit, 2, 3, Some(dec!(1.25))),
+            &second,
+        );
+        second.hold_funds(dec!(1.25));
+        bulk.observe(4, &record(TransactionType::Dispute, 2, 3, None), &second);
+        bulk.write_all(StatementFormat::Json)
+            .expect("Failed to write");
+
+        let mut files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["client_1.json", "client_2.json"]);
+        let statements = (1..=2)
+            .map(|client| {
+                fs::read_to_string(statement_path(&dir, client, StatementFormat::Json)).unwrap()
+            })
+            .collect();
+        fs::remove_dir_all(dir).unwrap();
+        statements
+    }
+
+    #[test]
+    fn test_bulk_statements() {
+        let statements = bulk_statements("bulk", SPILL_THRESHOLD);
+        let first: serde_json::Value = serde_json::from_str(&statements[0]).unwrap();
+        assert_eq!(first["client"], 1);
+        assert_eq!(first["entries"].as_array().unwrap().len(), 1);
+
+        let second: serde_json::Value = serde_json::from_str(&statements[1]).unwrap();
+        assert_eq!(second["opening"]["total"], "5");
+        assert_eq!(second["entries"][0]["amount"], "1.25");
+        assert_eq!(second["entries"][1]["type"], "dispute");
+        assert_eq!(second["closing"]["total"], "6.25");
+        assert_eq!(second["closing"]["held"], "1.25");
+    }
+
+    #[test]
+    fn test_spilled_statements_match() {
+        // Every entry goes through a spill file, which is removed afterwards
+        let spilled = bulk_statements("bulk-spilled", 1);
+        assert_eq!(spilled, bulk_statements("bulk-in-memory", SPILL_THRESHOLD));
+    }
+
+    #[test]
+    fn test_write_all() {
+        let dir = scratch_dir("statements");
+        let mut bulk = BulkStatementBuilder::new(None, None, &dir);
+        for client in 1..=5 {
+            bulk.observe(
+                client.into(),
+                &record(
+                    TransactionType::Deposit,
+                    client,
+                    client.into(),
+                    Some(dec!(1)),
+                ),
+                &Account::new(client),
+            );
+        }
+        bulk.write_all(StatementFormat::Json)
+            .expect("Failed to write");
+
+        for client in 1..=5 {
+            let path = statement_path(&dir, client, StatementFormat::Json);
+            let value: serde_json::Value =
+                serde_json::from_slice(&fs::read(&path).expect("Missing statement")).unwrap();
+            assert_eq!(value["client"], client);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_spill_removes_spill_dir() {
+        let dir = scratch_dir("statements-failed-spill");
+        let mut bulk = BulkStatementBuilder::new(None, None, &dir);
+        bulk.spill_after = 1;
+        // A directory where the spill file should go makes the first open fail
+        fs::create_dir_all(spill_path(&bulk.spill_dir, 1)).unwrap();
+        let spill_dir = bulk.spill_dir.clone();
+
+        bulk.observe(
+            1,
+            &record(TransactionType::Deposit, 1, 1, Some(dec!(1))),
+            &Account::new(1),
+        );
+        assert!(bulk.write_all(StatementFormat::Csv).is_err());
+        assert!(!spill_dir.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_json() {
+        let statement = build(Some(4), None);
+        let mut buf = Vec::new();
+        statement.write_json(&mut buf).expect("Failed to write");
+
+        let value: serde_json::Value = serde_json::from_slice(&buf).expect("Invalid JSON");
+        assert_eq!(value["client"], 1);
+        assert_eq!(value["opening"]["total"], "70");
+        assert_eq!(value["entries"][0]["type"], "dispute");
+        assert_eq!(value["entries"][0]["amount"], serde_json::Value::Null);
+        assert_eq!(value["closing"]["held"], "100");
+    }
+}
@@ -0,0 +1,126 @@
+use crate::types::{TransactionRecord, TransactionType};
+use rust_decimal::Decimal;
+
+/// Applied and rejected counts of one operation type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpStats {
+    pub applied: u64,
+    pub rejected: u64,
+    /// Sum of the amounts on applied records
+    pub applied_amount: Decimal,
+    /// Sum of the amounts on rejected records
+    pub rejected_amount: Decimal,
+}
+
+/// Per-type operation counts of one client, kept up to date by the engine
+/// Disputes, resolves, chargebacks, closes and unlocks carry no amount, so only their counts move
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    pub deposit: OpStats,
+    pub withdrawal: OpStats,
+    pub dispute: OpStats,
+    pub resolve: OpStats,
+    pub chargeback: OpStats,
+    pub close: OpStats,
+    pub unlock: OpStats,
+}
+
+impl ClientStats {
+    /// Counts for one operation type
+    pub fn get(&self, tx_type: TransactionType) -> &OpStats {
+        match tx_type {
+            TransactionType::Deposit => &self.deposit,
+            TransactionType::Withdrawal => &self.withdrawal,
+            TransactionType::Dispute => &self.dispute,
+            TransactionType::Resolve => &self.resolve,
+            TransactionType::Chargeback => &self.chargeback,
+            TransactionType::Close => &self.close,
+            TransactionType::Unlock => &self.unlock,
+        }
+    }
+
+    fn get_mut(&mut self, tx_type: TransactionType) -> &mut OpStats {
+        match tx_type {
+            TransactionType::Deposit => &mut self.deposit,
+            TransactionType::Withdrawal => &mut self.withdrawal,
+            TransactionType::Dispute => &mut self.dispute,
+            TransactionType::Resolve => &mut self.resolve,
+            TransactionType::Chargeback => &mut self.chargeback,
+            TransactionType::Close => &mut self.close,
+            TransactionType::Unlock => &mut self.unlock,
+        }
+    }
+
+    /// Count a record the engine applied
+    pub fn record_applied(&mut self, record: &TransactionRecord) {
+        let stats = self.get_mut(record.tx_type);
+        stats.applied += 1;
+        stats.applied_amount += record.amount.unwrap_or_default();
+    }
+
+    /// Count a record the engine rejected
+    pub fn record_rejected(&mut self, record: &TransactionRecord) {
+        let stats = self.get_mut(record.tx_type);
+        stats.rejected += 1;
+        stats.rejected_amount += record.amount.unwrap_or_default();
+    }
+
+    /// Add another set of counts, e.g. from another shard
+    pub fn merge(&mut self, other: &ClientStats) {
+        for tx_type in [
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+            TransactionType::Close,
+            TransactionType::Unlock,
+        ] {
+            let theirs = *other.get(tx_type);
+            let ours = self.get_mut(tx_type);
+            ours.applied += theirs.applied;
+            ours.rejected += theirs.rejected;
+            ours.applied_amount += theirs.applied_amount;
+            ours.rejected_amount += theirs.rejected_amount;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn record(tx_type: TransactionType, amount: Option<Decimal>) -> TransactionRecord {
+        TransactionRecord {
+            tx_type,
+            client: 1,
+            tx: 1,
+            amount,
+            seq: None,
+            timestamp: None,
+            line: None,
+        }
+    }
+
+    #[test]
+    fn test_counts_and_sums() {
+        let mut stats = ClientStats::default();
+        stats.record_applied(&record(TransactionType::Deposit, Some(dec!(10))));
+        stats.record_applied(&record(TransactionType::Deposit, Some(dec!(2.5))));
+        stats.record_rejected(&record(TransactionType::Withdrawal, Some(dec!(20))));
+        stats.record_applied(&record(TransactionType::Dispute, None));
+
+        assert_eq!(stats.deposit.applied, 2);
+        assert_eq!(stats.deposit.applied_amount, dec!(12.5));
+        assert_eq!(stats.withdrawal.rejected, 1);
+        assert_eq!(stats.withdrawal.rejected_amount, dec!(20));
+        assert_eq!(stats.get(TransactionType::Dispute).applied, 1);
+        assert_eq!(stats.resolve, OpStats::default());
+
+        let mut merged = stats;
+        merged.merge(&stats);
+        assert_eq!(merged.deposit.applied, 4);
+        assert_eq!(merged.deposit.applied_amount, dec!(25));
+    }
+}
@@ -0,0 +1,357 @@
+//! Storage of deposits kept for dispute tracking
+//!
+//! `MemoryStore` keeps them in a map and is the default. `DiskStore` puts them
+//! in a file addressed by tx id, so memory use does not grow with the input.
+
+use crate::snapshot::{tx_type_code, tx_type_from_code};
+use crate::timestamp::Timestamp;
+use crate::types::{StoredTransaction, TransactionId};
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Where the engine keeps the transactions it may need to look up again
+pub trait TransactionStore: fmt::Debug + Send {
+    /// The transaction stored under `tx`, if any
+    fn get(&self, tx: TransactionId) -> Result<Option<StoredTransaction>, StoreError>;
+
+    /// Store a transaction, replacing any stored under the same id
+    fn put(&mut self, tx: TransactionId, transaction: StoredTransaction) -> Result<(), StoreError>;
+
+    /// Visit every stored transaction, in no particular order
+    fn for_each(
+        &self,
+        f: &mut dyn FnMut(TransactionId, &StoredTransaction),
+    ) -> Result<(), StoreError>;
+
+    /// Copy of everything stored
+    fn to_map(&self) -> Result<HashMap<TransactionId, StoredTransaction>, StoreError> {
+        let mut map = HashMap::new();
+        self.for_each(&mut |tx, t| {
+            map.insert(tx, *t);
+        })?;
+        Ok(map)
+    }
+
+    /// Disputed transactions not charged back, all end-of-run reports need
+    fn open_disputes(&self) -> Result<HashMap<TransactionId, StoredTransaction>, StoreError> {
+        let mut map = HashMap::new();
+        self.for_each(&mut |tx, t| {
+            if t.is_open_dispute() {
+                map.insert(tx, *t);
+            }
+        })?;
+        Ok(map)
+    }
+}
+
+/// A store could not be read or written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreError {
+    message: String,
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transaction store: {}", self.message)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        Self {
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Which store to use, as given on the command line
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum StoreKind {
+    /// `memory`
+    #[default]
+    Memory,
+    /// `disk:<path>`
+    Disk(PathBuf),
+}
+
+impl StoreKind {
+    /// Create an empty store of this kind
+    pub fn open(&self) -> Result<Box<dyn TransactionStore>, StoreError> {
+        Ok(match self {
+            StoreKind::Memory => Box::new(MemoryStore::default()),
+            StoreKind::Disk(path) => Box::new(DiskStore::create(path)?),
+        })
+    }
+}
+
+impl FromStr for StoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            _ if s == "memory" => Ok(StoreKind::Memory),
+            Some(("disk", path)) if !path.is_empty() => Ok(StoreKind::Disk(PathBuf::from(path))),
+            _ => Err(format!(
+                "Invalid store: {} (expected memory or disk:<path>)",
+                s
+            )),
+        }
+    }
+}
+
+/// In-memory store, a map from tx id
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryStore {
+    transactions: HashMap<TransactionId, StoredTransaction>,
+}
+
+impl From<HashMap<TransactionId, StoredTransaction>> for MemoryStore {
+    fn from(transactions: HashMap<TransactionId, StoredTransaction>) -> Self {
+        Self { transactions }
+    }
+}
+
+impl TransactionStore for MemoryStore {
+    fn get(&self, tx: TransactionId) -> Result<Option<StoredTransaction>, StoreError> {
+        Ok(self.transactions.get(&tx).copied())
+    }
+
+    fn put(&mut self, tx: TransactionId, transaction: StoredTransaction) -> Result<(), StoreError> {
+        self.transactions.insert(tx, transaction);
+        Ok(())
+    }
+
+    fn for_each(
+        &self,
+        f: &mut dyn FnMut(TransactionId, &StoredTransaction),
+    ) -> Result<(), StoreError> {
+        for (tx, t) in &self.transactions {
+            f(*tx, t);
+        }
+        Ok(())
+    }
+
+    fn to_map(&self) -> Result<HashMap<TransactionId, StoredTransaction>, StoreError> {
+        Ok(self.transactions.clone())
+    }
+}
+
+/// Bytes per transaction in a `DiskStore` file
+const SLOT_LEN: u64 = 48;
+
+/// File-backed store with one fixed-size slot per tx id
+///
+/// Slot `tx` lives at offset `tx * 48`, so lookups are a single read. Only the
+/// ids of open disputes are kept in memory, so `open_disputes` needs no scan;
+/// `for_each` reads the file up to the highest tx id. Unused slots are holes:
+/// on filesystems with sparse files the file only takes space for the ids
+/// actually stored, although its apparent size reaches 48 bytes times the
+/// highest tx id. The file is truncated on open; it is scratch space for one run.
+///
+/// Slot layout: present flag, client (u16 LE), tx type code, amount
+/// (`Decimal::serialize`), disputed, charged back, disputed-at flag and
+/// position (u64 LE), timestamp flag, seconds (i64 LE) and nanoseconds (u32 LE),
+/// four bytes padding.
+#[derive(Debug)]
+pub struct DiskStore {
+    file: File,
+    /// One past the highest slot written
+    slots: u64,
+    open_disputes: HashSet<TransactionId>,
+}
+
+impl DiskStore {
+    /// Create (or truncate) the store file at `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            file,
+            slots: 0,
+            open_disputes: HashSet::new(),
+        })
+    }
+
+    fn encode(t: &StoredTransaction) -> [u8; SLOT_LEN as usize] {
+        let mut slot = [0u8; SLOT_LEN as usize];
+        slot[0] = 1;
+        slot[1..3].copy_from_slice(&t.client_id.to_le_bytes());
+        slot[3] = tx_type_code(t.tx_type);
+        slot[4..20].copy_from_slice(&t.amount.serialize());
+        slot[20] = u8::from(t.disputed);
+        slot[21] = u8::from(t.charged_back);
+        if let Some(position) = t.disputed_at {
+            slot[22] = 1;
+            slot[23..31].copy_from_slice(&position.to_le_bytes());
+        }
+        if let Some(timestamp) = t.timestamp {
+            slot[31] = 1;
+            slot[32..40].copy_from_slice(&timestamp.unix_secs().to_le_bytes());
+            slot[40..44].copy_from_slice(&timestamp.subsec_nanos().to_le_bytes());
+        }
+        slot
+    }
+
+    fn decode(slot: &[u8; SLOT_LEN as usize]) -> io::Result<Option<StoredTransaction>> {
+        if slot[0] == 0 {
+            return Ok(None);
+        }
+        let tx_type = tx_type_from_code(slot[3]).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "invalid tx type in store")
+        })?;
+        let client = u16::from_le_bytes([slot[1], slot[2]]);
+        let amount = Decimal::deserialize(slot[4..20].try_into().expect("16 bytes"));
+
+        let mut t = StoredTransaction::new(client, tx_type, amount);
+        t.disputed = slot[20] != 0;
+        t.charged_back = slot[21] != 0;
+        t.disputed_at =
+            (slot[22] != 0).then(|| u64::from_le_bytes(slot[23..31].try_into().expect("8 bytes")));
+        if slot[31] != 0 {
+            let secs = i64::from_le_bytes(slot[32..40].try_into().expect("8 bytes"));
+            let nanos = u32::from_le_bytes(slot[40..44].try_into().expect("4 bytes"));
+            if nanos >= 1_000_000_000 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid timestamp in store",
+                ));
+            }
+            t.timestamp = Some(Timestamp::from_unix(secs, nanos));
+        }
+        Ok(Some(t))
+    }
+}
+
+impl TransactionStore for DiskStore {
+    fn get(&self, tx: TransactionId) -> Result<Option<StoredTransaction>, StoreError> {
+        if u64::from(tx) >= self.slots {
+            return Ok(None);
+        }
+        let mut slot = [0u8; SLOT_LEN as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(u64::from(tx) * SLOT_LEN))?;
+        file.read_exact(&mut slot)?;
+        Ok(Self::decode(&slot)?)
+    }
+
+    fn put(&mut self, tx: TransactionId, transaction: StoredTransaction) -> Result<(), StoreError> {
+        self.file.seek(SeekFrom::Start(u64::from(tx) * SLOT_LEN))?;
+        self.file.write_all(&Self::encode(&transaction))?;
+        self.slots = self.slots.max(u64::from(tx) + 1);
+        if transaction.is_open_dispute() {
+            self.open_disputes.insert(tx);
+        } else {
+            self.open_disputes.remove(&tx);
+        }
+        Ok(())
+    }
+
+    fn for_each(
+        &self,
+        f: &mut dyn FnMut(TransactionId, &StoredTransaction),
+    ) -> Result<(), StoreError> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file);
+        let mut slot = [0u8; SLOT_LEN as usize];
+        for tx in 0..self.slots {
+            reader.read_exact(&mut slot)?;
+            if let Some(t) = Self::decode(&slot)? {
+                // Slots only exist up to the highest tx id, a u32
+                f(tx as TransactionId, &t);
+            }
+        }
+        Ok(())
+    }
+
+    fn open_disputes(&self) -> Result<HashMap<TransactionId, StoredTransaction>, StoreError> {
+        let mut map = HashMap::with_capacity(self.open_disputes.len());
+        for &tx in &self.open_disputes {
+            if let Some(t) = self.get(tx)? {
+                map.insert(tx, t);
+            }
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TransactionType;
+    use rust_decimal_macros::dec;
+
+    fn exercise(store: &mut dyn TransactionStore) {
+        let mut disputed = StoredTransaction::new(3, TransactionType::Deposit, dec!(12.3456));
+        disputed.mark_disputed();
+        disputed.disputed_at = Some(u64::MAX);
+        disputed.timestamp = Some(Timestamp::from_unix(-1, 999_999_999));
+        let plain = StoredTransaction::new(65535, TransactionType::Deposit, dec!(0.0001));
+
+        assert_eq!(store.get(7), Ok(None));
+        store.put(7, disputed).unwrap();
+        store.put(100_000, plain).unwrap();
+        assert_eq!(store.get(7), Ok(Some(disputed)));
+        assert_eq!(store.get(100_000), Ok(Some(plain)));
+        assert_eq!(store.get(6), Ok(None));
+
+        let mut resolved = disputed;
+        resolved.mark_resolved();
+        store.put(7, resolved).unwrap();
+        assert_eq!(store.get(7), Ok(Some(resolved)));
+
+        assert_eq!(store.to_map().unwrap().len(), 2);
+        assert!(store.open_disputes().unwrap().is_empty());
+        store.put(7, disputed).unwrap();
+        assert_eq!(
+            store.open_disputes().unwrap(),
+            HashMap::from([(7, disputed)])
+        );
+    }
+
+    #[test]
+    fn test_memory_store() {
+        exercise(&mut MemoryStore::default());
+    }
+
+    #[test]
+    fn test_disk_store() {
+        let path =
+            std::env::temp_dir().join(format!("core-tx-runner-store-{}", std::process::id()));
+        let mut store = DiskStore::create(&path).expect("Failed to create store");
+        exercise(&mut store);
+
+        // The highest tx id is a slot like any other (the file is sparse)
+        let top = StoredTransaction::new(1, TransactionType::Deposit, dec!(1));
+        store.put(u32::MAX, top).unwrap();
+        assert_eq!(store.get(u32::MAX), Ok(Some(top)));
+        assert_eq!(store.open_disputes().unwrap().len(), 1);
+
+        // Reopening starts empty
+        let store = DiskStore::create(&path).expect("Failed to create store");
+        assert_eq!(store.get(7), Ok(None));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_store_kind() {
+        assert_eq!("memory".parse(), Ok(StoreKind::Memory));
+        assert_eq!(
+            "disk:/tmp/tx.store".parse(),
+            Ok(StoreKind::Disk(PathBuf::from("/tmp/tx.store")))
+        );
+        assert!("disk:".parse::<StoreKind>().is_err());
+        assert!("redis".parse::<StoreKind>().is_err());
+    }
+}
@@ -0,0 +1,313 @@
+//! Async front-end for streaming sources
+//!
+//! With the `tokio` feature the engine can be fed from async sources, such as
+//! object store streams, gRPC or websockets, without blocking an executor
+//! thread: `AsyncRecordReader` parses CSV or NDJSON from any `AsyncBufRead`,
+//! and `AsyncPaymentsEngine` applies a reader or any `Stream` of records,
+//! yielding to the executor every `YIELD_EVERY` records. Records are applied in
+//! stream order, like `PaymentsEngine::process`.
+
+use crate::csv_parser;
+use crate::engine::{PaymentsEngine, RunStats};
+use crate::input::{self, InputFormat, MalformedRecord};
+use crate::types::{TransactionRecord, TxError};
+use csv::StringRecord;
+use futures_core::Stream;
+use std::future::poll_fn;
+use std::pin::Pin;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// Records applied between yields to the executor
+pub const YIELD_EVERY: u64 = 1024;
+
+/// Transaction records read from an async source, a line at a time
+/// Blank lines are skipped; CSV rows must not span lines
+pub struct AsyncRecordReader<R> {
+    reader: R,
+    /// `Auto` until the first non-blank line gives the format away
+    format: InputFormat,
+    /// CSV header row, once read
+    headers: Option<StringRecord>,
+    line: u64,
+    buf: String,
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRecordReader<R> {
+    /// Reader in the given format; under `Auto` a first line starting with `{` is NDJSON
+    pub fn new(reader: R, format: InputFormat) -> Self {
+        Self {
+            reader,
+            format,
+            headers: None,
+            line: 0,
+            buf: String::new(),
+        }
+    }
+
+    /// The next record, `None` at end of input
+    /// A malformed record is returned as an error and reading carries on after it
+    pub async fn next_record(&mut self) -> Option<Result<TransactionRecord, MalformedRecord>> {
+        loop {
+            self.buf.clear();
+            self.line += 1;
+            let line = self.line;
+            let malformed = |message: String| MalformedRecord {
+                line: Some(line),
+                message,
+            };
+
+            match self.reader.read_line(&mut self.buf).await {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(malformed(e.to_string()))),
+            }
+            let text = self.buf.trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            if self.format == InputFormat::Auto {
+                self.format = match text.starts_with('{') {
+                    true => InputFormat::Ndjson,
+                    false => InputFormat::Csv,
+                };
+            }
+            if self.format == InputFormat::Ndjson {
+                return Some(input::parse_ndjson_line(text, line));
+            }
+
+            let record = match csv_parser::parse_line(text) {
+                Ok(record) => record,
+                Err(e) => return Some(Err(malformed(e.to_string()))),
+            };
+            // Skip whitespace-only rows (all fields empty after trimming)
+            if record.iter().all(|field| field.is_empty()) {
+                continue;
+            }
+            let Some(headers) = &self.headers else {
+                self.headers = Some(record);
+                continue;
+            };
+            return Some(
+                record
+                    .deserialize::<TransactionRecord>(Some(headers))
+                    .map(|parsed| TransactionRecord {
+                        line: Some(line),
+                        ..parsed
+                    })
+                    .map_err(|e| malformed(e.to_string())),
+            );
+        }
+    }
+}
+
+/// What applying a stream did, counted like `PaymentsEngine::run`
+pub type StreamStats = RunStats;
+
+/// `PaymentsEngine` driven from async sources
+#[derive(Debug)]
+pub struct AsyncPaymentsEngine {
+    engine: PaymentsEngine,
+}
+
+impl AsyncPaymentsEngine {
+    pub fn new(engine: PaymentsEngine) -> Self {
+        Self { engine }
+    }
+
+    pub fn engine(&self) -> &PaymentsEngine {
+        &self.engine
+    }
+
+    pub fn engine_mut(&mut self) -> &mut PaymentsEngine {
+        &mut self.engine
+    }
+
+    pub fn into_inner(self) -> PaymentsEngine {
+        self.engine
+    }
+
+    /// Apply every record of `records`
+    /// Rejections are counted; any other error stops at the record that caused it
+    pub async fn apply_stream<S>(&mut self, mut records: S) -> Result<StreamStats, TxError>
+    where
+        S: Stream<Item = TransactionRecord> + Unpin,
+    {
+        let mut stats = StreamStats::default();
+        while let Some(record) = poll_fn(|cx| Pin::new(&mut records).poll_next(cx)).await {
+            self.apply(record, &mut stats).await?;
+        }
+        Ok(stats)
+    }
+
+    /// Apply every record `reader` yields, skipping malformed ones
+    pub async fn apply_reader<R>(
+        &mut self,
+        mut reader: AsyncRecordReader<R>,
+    ) -> Result<StreamStats, TxError>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        let mut stats = StreamStats::default();
+        while let Some(record) = reader.next_record().await {
+            match record {
+                Ok(record) => self.apply(record, &mut stats).await?,
+                Err(_) => stats.malformed += 1,
+            }
+        }
+        Ok(stats)
+    }
+
+    async fn apply(
+        &mut self,
+        record: TransactionRecord,
+        stats: &mut StreamStats,
+    ) -> Result<(), TxError> {
+        match self.engine.process(record) {
+            Ok(()) => stats.applied += 1,
+            Err(TxError::Rejected(_)) => stats.rejected += 1,
+            Err(e) => return Err(e),
+        }
+        // Applying is synchronous, so give other tasks a turn now and then
+        if (stats.applied + stats.rejected).is_multiple_of(YIELD_EVERY) {
+            tokio::task::yield_now().await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::types::TransactionType;
+    use rust_decimal_macros::dec;
+    use std::collections::VecDeque;
+    use std::future::Future;
+    use std::task::{Context, Poll};
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("Failed to build runtime")
+            .block_on(future)
+    }
+
+    async fn collect<R: AsyncBufRead + Unpin>(
+        mut reader: AsyncRecordReader<R>,
+    ) -> Vec<Result<TransactionRecord, MalformedRecord>> {
+        let mut records = Vec::new();
+        while let Some(record) = reader.next_record().await {
+            records.push(record);
+        }
+        records
+    }
+
+    /// Records handed out one per poll, pending in between
+    struct Trickle {
+        records: VecDeque<TransactionRecord>,
+        ready: bool,
+    }
+
+    impl Stream for Trickle {
+        type Item = TransactionRecord;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(self.records.pop_front())
+        }
+    }
+
+    #[test]
+    fn test_read_csv() {
+        let data = "type, client, tx, amount\n\ndeposit, 1, 1, 1.5\n , , ,\nbogus,1,2,\nwithdrawal,1,3,0.5\n";
+        let records = block_on(collect(AsyncRecordReader::new(
+            data.as_bytes(),
+            InputFormat::Auto,
+        )));
+
+        assert_eq!(records.len(), 3);
+        let deposit = records[0].as_ref().unwrap();
+        assert_eq!(deposit.tx_type, TransactionType::Deposit);
+        assert_eq!(deposit.amount, Some(dec!(1.5)));
+        assert_eq!(deposit.line, Some(3));
+        assert_eq!(records[1].as_ref().unwrap_err().line, Some(5));
+        assert_eq!(records[2].as_ref().unwrap().line, Some(6));
+    }
+
+    #[test]
+    fn test_read_ndjson() {
+        let data = "\n{\"type\":\"deposit\",\"client\":2,\"tx\":1,\"amount\":3}\n{\"type\":\"dispute\",\"client\":2,\"tx\":1}\n";
+        let records = block_on(collect(AsyncRecordReader::new(
+            data.as_bytes(),
+            InputFormat::Auto,
+        )));
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].as_ref().unwrap().line, Some(2));
+        assert_eq!(
+            records[1].as_ref().unwrap().tx_type,
+            TransactionType::Dispute
+        );
+
+        // An explicit format is not sniffed
+        let records = block_on(collect(AsyncRecordReader::new(
+            data.as_bytes(),
+            InputFormat::Csv,
+        )));
+        assert!(records.iter().all(|r| r.is_err()));
+    }
+
+    #[test]
+    fn test_apply_reader() {
+        let file = tokio::io::BufReader::new(
+            std::fs::read("test_data/simple.csv")
+                .expect("Failed to read test file")
+                .leak() as &[u8],
+        );
+        let mut engine = AsyncPaymentsEngine::new(PaymentsEngine::new(EngineConfig::default()));
+        let stats = block_on(engine.apply_reader(AsyncRecordReader::new(file, InputFormat::Csv)))
+            .expect("Failed to apply");
+
+        assert_eq!(stats.applied, 5);
+        let engine = engine.into_inner();
+        assert_eq!(engine.account(1).unwrap().total, dec!(125));
+        assert_eq!(engine.account(2).unwrap().total, dec!(100));
+    }
+
+    #[test]
+    fn test_apply_stream() {
+        let record = |tx_type, tx, amount| TransactionRecord {
+            tx_type,
+            client: 1,
+            tx,
+            amount,
+            seq: None,
+            timestamp: None,
+            line: None,
+        };
+        let records = Trickle {
+            records: VecDeque::from([
+                record(TransactionType::Deposit, 1, Some(dec!(10))),
+                record(TransactionType::Withdrawal, 2, Some(dec!(20))),
+                record(TransactionType::Withdrawal, 3, Some(dec!(4))),
+            ]),
+            ready: false,
+        };
+
+        let mut engine = AsyncPaymentsEngine::new(PaymentsEngine::new(EngineConfig::default()));
+        let stats = block_on(engine.apply_stream(records)).expect("Failed to apply");
+        assert_eq!(
+            stats,
+            StreamStats {
+                applied: 2,
+                rejected: 1,
+                malformed: 0
+            }
+        );
+        assert_eq!(engine.engine().account(1).unwrap().available, dec!(6));
+    }
+}
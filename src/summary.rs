@@ -0,0 +1,254 @@
+use crate::stats::ClientStats;
+use crate::types::{Account, ClientId, RejectionReason, TransactionType};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Clients listed by `--summary` unless `--summary-top` says otherwise
+pub const DEFAULT_TOP_CLIENTS: usize = 10;
+
+/// One of the clients with the largest totals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopClient {
+    pub client: ClientId,
+    pub total: Decimal,
+}
+
+/// Aggregate statistics of a run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    /// Records given to the engine, plus malformed rows once added
+    pub records_processed: u64,
+    /// Applied/rejected counts and amounts by operation type, over all clients
+    pub operations: ClientStats,
+    /// Rejected records by reason, most frequent first
+    pub rejections: Vec<(RejectionReason, u64)>,
+    pub accounts: usize,
+    /// Locked by a chargeback
+    pub locked_accounts: usize,
+    pub closed_accounts: usize,
+    pub total_available: Decimal,
+    pub total_held: Decimal,
+    pub total_funds: Decimal,
+    /// Largest totals first, ties by client id
+    pub top_clients: Vec<TopClient>,
+}
+
+impl RunSummary {
+    /// Summarize end-of-run state, listing the `top` clients with the largest totals
+    pub fn compute(
+        accounts: &HashMap<ClientId, Account>,
+        client_stats: &HashMap<ClientId, ClientStats>,
+        rejections: &HashMap<RejectionReason, u64>,
+        records_processed: u64,
+        top: usize,
+    ) -> Self {
+        let mut operations = ClientStats::default();
+        for stats in client_stats.values() {
+            operations.merge(stats);
+        }
+
+        let mut top_clients: Vec<TopClient> = accounts
+            .values()
+            .map(|a| TopClient {
+                client: a.client,
+                total: a.total,
+            })
+            .collect();
+        top_clients.sort_by(|a, b| b.total.cmp(&a.total).then(a.client.cmp(&b.client)));
+        top_clients.truncate(top);
+
+        let mut summary = Self {
+            records_processed,
+            operations,
+            rejections: rejections.iter().map(|(r, n)| (*r, *n)).collect(),
+            accounts: accounts.len(),
+            locked_accounts: accounts
+                .values()
+                .filter(|a| a.is_locked() && !a.is_closed())
+                .count(),
+            closed_accounts: accounts.values().filter(|a| a.is_closed()).count(),
+            total_available: accounts.values().map(|a| a.available).sum(),
+            total_held: accounts.values().map(|a| a.held).sum(),
+            total_funds: accounts.values().map(|a| a.total).sum(),
+            top_clients,
+        };
+        summary.sort_rejections();
+        summary
+    }
+
+    /// Count malformed rows, which never reach the engine
+    pub fn add_malformed(&mut self, rows: u64) {
+        if rows == 0 {
+            return;
+        }
+        self.records_processed += rows;
+        match self
+            .rejections
+            .iter_mut()
+            .find(|(reason, _)| *reason == RejectionReason::Malformed)
+        {
+            Some((_, count)) => *count += rows,
+            None => self.rejections.push((RejectionReason::Malformed, rows)),
+        }
+        self.sort_rejections();
+    }
+
+    /// Records rejected for any reason
+    pub fn rejected(&self) -> u64 {
+        self.rejections.iter().map(|(_, n)| n).sum()
+    }
+
+    fn sort_rejections(&mut self) {
+        self.rejections
+            .sort_by(|a, b| b.1.cmp(&a.1).then(a.0.as_str().cmp(b.0.as_str())));
+    }
+
+    /// Write the summary as plain text
+    pub fn write_text<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "Run summary")?;
+        writeln!(
+            out,
+            "records processed: {} ({} rejected)",
+            self.records_processed,
+            self.rejected()
+        )?;
+
+        writeln!(out, "by type (applied/rejected):")?;
+        for tx_type in TransactionType::ALL {
+            let stats = self.operations.get(tx_type);
+            writeln!(
+                out,
+                "  {}: {}/{}",
+                tx_type.as_str(),
+                stats.applied,
+                stats.rejected
+            )?;
+        }
+
+        writeln!(out, "rejected by reason:")?;
+        for (reason, count) in &self.rejections {
+            writeln!(out, "  {}: {}", reason, count)?;
+        }
+
+        writeln!(
+            out,
+            "accounts: {} ({} locked, {} closed)",
+            self.accounts, self.locked_accounts, self.closed_accounts
+        )?;
+        writeln!(out, "total available: {}", self.total_available.round_dp(4))?;
+        writeln!(out, "total held: {}", self.total_held.round_dp(4))?;
+        writeln!(out, "total funds: {}", self.total_funds.round_dp(4))?;
+
+        writeln!(out, "top {} clients by total:", self.top_clients.len())?;
+        for top in &self.top_clients {
+            writeln!(out, "  client {}: {}", top.client, top.total.round_dp(4))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::engine::PaymentsEngine;
+    use crate::types::TransactionRecord;
+    use rust_decimal_macros::dec;
+
+    fn record(
+        tx_type: TransactionType,
+        client: ClientId,
+        tx: u32,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            tx_type,
+            client,
+            tx,
+            amount,
+            seq: None,
+            timestamp: None,
+            line: None,
+        }
+    }
+
+    fn engine() -> PaymentsEngine {
+        let mut engine = PaymentsEngine::new(EngineConfig::default());
+        for record in [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+            record(TransactionType::Deposit, 2, 2, Some(dec!(30))),
+            record(TransactionType::Deposit, 3, 3, Some(dec!(20))),
+            record(TransactionType::Withdrawal, 1, 4, Some(dec!(50))),
+            record(TransactionType::Dispute, 3, 3, None),
+            record(TransactionType::Dispute, 2, 2, None),
+            record(TransactionType::Chargeback, 2, 2, None),
+            record(TransactionType::Deposit, 2, 5, Some(dec!(1))),
+            record(TransactionType::Resolve, 1, 99, None),
+            record(TransactionType::Deposit, 4, 6, Some(dec!(3))),
+            record(TransactionType::Close, 4, 7, None),
+        ] {
+            let _ = engine.process(record);
+        }
+        engine
+    }
+
+    #[test]
+    fn test_summary() {
+        let summary = engine().summary(2);
+
+        assert_eq!(summary.records_processed, 11);
+        assert_eq!(summary.operations.deposit.applied, 4);
+        assert_eq!(summary.operations.deposit.rejected, 1);
+        assert_eq!(summary.operations.withdrawal.rejected, 1);
+        assert_eq!(summary.rejected(), 3);
+        assert_eq!(
+            summary.rejections,
+            vec![
+                (RejectionReason::AccountLocked, 1),
+                (RejectionReason::InsufficientFunds, 1),
+                (RejectionReason::UnknownTx, 1),
+            ]
+        );
+        assert_eq!(summary.accounts, 4);
+        assert_eq!(summary.locked_accounts, 1);
+        assert_eq!(summary.closed_accounts, 1);
+        assert_eq!(summary.operations.close.applied, 1);
+        assert_eq!(summary.total_available, dec!(10));
+        assert_eq!(summary.total_held, dec!(20));
+        assert_eq!(summary.total_funds, dec!(30));
+        assert_eq!(
+            summary.top_clients,
+            vec![
+                TopClient {
+                    client: 3,
+                    total: dec!(20)
+                },
+                TopClient {
+                    client: 1,
+                    total: dec!(10)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_malformed_and_text() {
+        let mut summary = engine().into_report().summary(1);
+        summary.add_malformed(2);
+        summary.add_malformed(0);
+        assert_eq!(summary.records_processed, 13);
+        assert_eq!(summary.rejections[0], (RejectionReason::Malformed, 2));
+
+        let mut out = Vec::new();
+        summary.write_text(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("Run summary\nrecords processed: 13 (5 rejected)\n"));
+        assert!(text.contains("  deposit: 4/1\n"));
+        assert!(text.contains("  malformed: 2\n"));
+        assert!(text.contains("  close: 1/0\n"));
+        assert!(text.contains("accounts: 4 (1 locked, 1 closed)\n"));
+        assert!(text.ends_with("top 1 clients by total:\n  client 3: 20\n"));
+    }
+}
@@ -0,0 +1,312 @@
+//! Record timestamps
+//!
+//! The optional `timestamp` column holds an RFC 3339 instant, such as
+//! `2024-01-31T23:59:59Z` or `2024-02-01T00:59:59.5+01:00`. Instants are kept
+//! in UTC with nanosecond precision, so any two compare exactly whatever
+//! offset they were written with.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// An instant, as seconds and nanoseconds since the Unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    secs: i64,
+    nanos: u32,
+}
+
+impl Timestamp {
+    /// `nanos` must be below one second
+    pub fn from_unix(secs: i64, nanos: u32) -> Self {
+        assert!(nanos < 1_000_000_000, "nanos out of range");
+        Self { secs, nanos }
+    }
+
+    /// Seconds since the Unix epoch, negative before it
+    pub fn unix_secs(&self) -> i64 {
+        self.secs
+    }
+
+    /// Nanoseconds past `unix_secs`
+    pub fn subsec_nanos(&self) -> u32 {
+        self.nanos
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> Self {
+        let nanos = Duration::from_nanos(u64::from(timestamp.nanos));
+        match u64::try_from(timestamp.secs) {
+            Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs) + nanos,
+            Err(_) => UNIX_EPOCH - Duration::from_secs(timestamp.secs.unsigned_abs()) + nanos,
+        }
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => Self {
+                secs: since.as_secs() as i64,
+                nanos: since.subsec_nanos(),
+            },
+            Err(before) => {
+                let before = before.duration();
+                let secs = -(before.as_secs() as i64);
+                match before.subsec_nanos() {
+                    0 => Self { secs, nanos: 0 },
+                    nanos => Self {
+                        secs: secs - 1,
+                        nanos: 1_000_000_000 - nanos,
+                    },
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    /// `YYYY-MM-DDTHH:MM:SS[.fraction](Z|+HH:MM|-HH:MM)`, `T` and `Z` in either case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid timestamp: {} (expected RFC 3339, like 2024-01-31T23:59:59Z)",
+                s
+            )
+        };
+        let b = s.as_bytes();
+        let digits = |range: std::ops::Range<usize>| -> Result<i64, String> {
+            let field = b.get(range).ok_or_else(invalid)?;
+            if !field.iter().all(u8::is_ascii_digit) {
+                return Err(invalid());
+            }
+            Ok(field.iter().fold(0, |n, d| n * 10 + i64::from(d - b'0')))
+        };
+        let separator = |at: usize, allowed: &[u8]| match b.get(at) {
+            Some(c) if allowed.contains(c) => Ok(()),
+            _ => Err(invalid()),
+        };
+
+        let year = digits(0..4)?;
+        separator(4, b"-")?;
+        let month = digits(5..7)?;
+        separator(7, b"-")?;
+        let day = digits(8..10)?;
+        separator(10, b"Tt ")?;
+        let hour = digits(11..13)?;
+        separator(13, b":")?;
+        let minute = digits(14..16)?;
+        separator(16, b":")?;
+        let second = digits(17..19)?;
+
+        let mut at = 19;
+        let mut nanos = 0u32;
+        if b.get(at) == Some(&b'.') {
+            at += 1;
+            let start = at;
+            while b.get(at).is_some_and(u8::is_ascii_digit) {
+                // Digits past nanoseconds are dropped
+                if at - start < 9 {
+                    nanos = nanos * 10 + u32::from(b[at] - b'0');
+                }
+                at += 1;
+            }
+            if at == start {
+                return Err(invalid());
+            }
+            nanos *= 10u32.pow(9u32.saturating_sub((at - start) as u32));
+        }
+
+        let offset = match b.get(at) {
+            Some(b'Z' | b'z') if at + 1 == b.len() => 0,
+            Some(sign @ (b'+' | b'-')) if at + 6 == b.len() => {
+                separator(at + 3, b":")?;
+                let hours = digits(at + 1..at + 3)?;
+                let minutes = digits(at + 4..at + 6)?;
+                if hours > 23 || minutes > 59 {
+                    return Err(invalid());
+                }
+                let offset = hours * 3600 + minutes * 60;
+                if *sign == b'-' {
+                    -offset
+                } else {
+                    offset
+                }
+            }
+            _ => return Err(invalid()),
+        };
+
+        // Leap seconds (:60) are accepted and land on the next second
+        if !(1..=12).contains(&month)
+            || day < 1
+            || day > days_in_month(year, month)
+            || hour > 23
+            || minute > 59
+            || second > 60
+        {
+            return Err(invalid());
+        }
+
+        let secs = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second
+            - offset;
+        Ok(Self { secs, nanos })
+    }
+}
+
+impl fmt::Display for Timestamp {
+    /// RFC 3339 in UTC, with as many fraction digits as needed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = self.secs.div_euclid(86_400);
+        let secs = self.secs.rem_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )?;
+        if self.nanos > 0 {
+            let fraction = format!("{:09}", self.nanos);
+            write!(f, ".{}", fraction.trim_end_matches('0'))?;
+        }
+        write!(f, "Z")
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.trim().parse().map_err(serde::de::Error::custom)
+    }
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Years start in March, so the leap day is the last day of the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(s: &str) -> Timestamp {
+        s.parse().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(ts("1970-01-01T00:00:00Z"), Timestamp::from_unix(0, 0));
+        assert_eq!(
+            ts("2024-02-29T23:59:59Z"),
+            Timestamp::from_unix(1_709_251_199, 0)
+        );
+        assert_eq!(
+            ts("2024-03-01t00:59:59.25+01:00"),
+            Timestamp::from_unix(1_709_251_199, 250_000_000)
+        );
+        assert_eq!(ts("2024-02-29 18:59:59-05:00"), ts("2024-02-29T23:59:59Z"));
+        assert_eq!(
+            ts("1969-12-31T23:59:59.1234567891z"),
+            Timestamp::from_unix(-1, 123_456_789)
+        );
+
+        for invalid in [
+            "",
+            "2024-02-30T00:00:00Z",
+            "2023-02-29T00:00:00Z",
+            "2024-13-01T00:00:00Z",
+            "2024-01-01T24:00:00Z",
+            "2024-01-01T00:00:00",
+            "2024-01-01T00:00:00.Z",
+            "2024-01-01T00:00:00+0100",
+            "2024-01-01T00:00:00Zjunk",
+            "2024-1-01T00:00:00Z",
+            "1700000000",
+        ] {
+            assert!(invalid.parse::<Timestamp>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for (input, output) in [
+            ("2024-01-31T23:59:59Z", "2024-01-31T23:59:59Z"),
+            ("2024-02-01T00:59:59.500+01:00", "2024-01-31T23:59:59.5Z"),
+            ("1969-07-20T20:17:40Z", "1969-07-20T20:17:40Z"),
+            (
+                "2000-02-29T12:00:00.000000001Z",
+                "2000-02-29T12:00:00.000000001Z",
+            ),
+        ] {
+            assert_eq!(ts(input).to_string(), output);
+            assert_eq!(ts(output), ts(input));
+        }
+        assert_eq!(
+            SystemTime::from(ts("1970-01-01T00:00:01.5Z")),
+            UNIX_EPOCH + Duration::from_millis(1500)
+        );
+        assert_eq!(
+            SystemTime::from(ts("1969-12-31T23:59:59.5Z")),
+            UNIX_EPOCH - Duration::from_millis(500)
+        );
+        for time in [
+            "1970-01-01T00:00:01.5Z",
+            "1969-12-31T23:59:59.5Z",
+            "1969-12-31T23:59:58Z",
+        ] {
+            assert_eq!(Timestamp::from(SystemTime::from(ts(time))), ts(time));
+        }
+    }
+}
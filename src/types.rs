@@ -1,5 +1,13 @@
+use crate::cancel::Cancelled;
+use crate::dedup::DuplicateReference;
+use crate::invariants::InvariantViolation;
+use crate::rejects::Rejection;
+use crate::store::StoreError;
+use crate::timestamp::Timestamp;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
 /// Client ID type (u16 as defined on the spec)
 pub type ClientId = u16;
@@ -8,7 +16,7 @@ pub type ClientId = u16;
 pub type TransactionId = u32;
 
 /// Type of transaction
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -16,18 +24,84 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    /// Admin action closing the client's account, see `Closure`
+    Close,
+    /// Admin action unfreezing a locked account, see `AccountPolicy`
+    Unlock,
 }
 
-/// Input transaction record from CSV
+impl TransactionType {
+    /// Every type, in declaration order
+    pub const ALL: [TransactionType; 7] = [
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::Chargeback,
+        TransactionType::Close,
+        TransactionType::Unlock,
+    ];
+
+    /// Name as it appears in the CSV `type` column
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Close => "close",
+            TransactionType::Unlock => "unlock",
+        }
+    }
+
+    /// True for dispute/resolve/chargeback, which refer to an earlier deposit
+    pub fn is_reference(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        )
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposit" => Ok(TransactionType::Deposit),
+            "withdrawal" => Ok(TransactionType::Withdrawal),
+            "dispute" => Ok(TransactionType::Dispute),
+            "resolve" => Ok(TransactionType::Resolve),
+            "chargeback" => Ok(TransactionType::Chargeback),
+            "close" => Ok(TransactionType::Close),
+            "unlock" => Ok(TransactionType::Unlock),
+            _ => Err(format!("Unknown transaction type: {}", s)),
+        }
+    }
+}
+
+/// Input transaction record, from CSV or NDJSON
 /// Handles all transaction types with optional amount field
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, Deserialize)]
 pub struct TransactionRecord {
     #[serde(rename = "type")]
     pub tx_type: TransactionType,
     pub client: ClientId,
     pub tx: TransactionId,
-    #[serde(deserialize_with = "deserialize_optional_decimal")]
+    #[serde(default, deserialize_with = "deserialize_optional_decimal")]
     pub amount: Option<Decimal>,
+    /// Optional per-client sequence number (`seq` column)
+    #[serde(default)]
+    pub seq: Option<u64>,
+    /// When the transaction happened (optional RFC 3339 `timestamp` column)
+    #[serde(default, deserialize_with = "deserialize_optional_timestamp")]
+    pub timestamp: Option<Timestamp>,
+    /// Line in the input file, when read from one
+    /// This is the line the csv reader reports the record starting on. The
+    /// reader attributes an empty line before a record to that record
+    #[serde(skip)]
+    pub line: Option<u64>,
 }
 
 /// Custom deserializer for optional decimal fields
@@ -57,14 +131,196 @@ where
     }
 }
 
+/// Optional timestamp, an empty CSV field counting as none
+fn deserialize_optional_timestamp<'de, D>(deserializer: D) -> Result<Option<Timestamp>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) if s.trim().is_empty() => Ok(None),
+        Some(s) => s.trim().parse().map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Why a record was rejected instead of applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionReason {
+    /// The row could not be parsed
+    Malformed,
+    /// Deposit/withdrawal without an amount
+    MissingAmount,
+    /// Amount beyond the `--max-amount` plausibility bound
+    ImplausibleAmount,
+    /// Deposit (or stored withdrawal) reusing the tx id of a stored transaction
+    DuplicateTxId,
+    /// Withdrawal above the available funds
+    InsufficientFunds,
+    /// Client account is locked after a chargeback
+    AccountLocked,
+    /// Dispute/resolve/chargeback for a tx that was never seen
+    UnknownTx,
+    /// Dispute/resolve/chargeback from another client than the deposit's
+    ClientMismatch,
+    /// Dispute of a tx that cannot be disputed or is already disputed
+    NotDisputable,
+    /// Resolve/chargeback of a tx that is not under dispute
+    NotDisputed,
+    /// Resolve/chargeback of a tx that was already charged back
+    ChargedBack,
+    /// Retransmitted dispute/resolve/chargeback dropped by `--ref-dedup`
+    DuplicateReference,
+    /// Withdrawal refused while open disputes exceed the configured limits
+    WithdrawalBlocked,
+    /// Withdrawal amount not a multiple of the client tier's increment
+    AmountIncrement,
+    /// Withdrawal leaving less available than the client tier's minimum balance
+    MinimumBalance,
+    /// Zero or negative amount, under strict validation
+    NonPositiveAmount,
+    /// Amount with more than 4 decimal places, under strict validation
+    ExcessPrecision,
+    /// Refused by a risk rule
+    RiskRule,
+    /// A sandboxed rule or transformer failed: trapped, ran out of fuel or returned garbage
+    SandboxFault,
+    /// Any record for a client whose account was closed
+    AccountClosed,
+    /// Close of an account with held funds or open disputes
+    FundsHeld,
+    /// Close of an account owing more than the write-off limit
+    NegativeBalance,
+    /// Unlock record while unlocking is not allowed
+    UnlockDisabled,
+    /// Unlock of an account that is not locked
+    NotLocked,
+}
+
+impl RejectionReason {
+    /// Every reason, in declaration order
+    pub const ALL: [RejectionReason; 24] = [
+        RejectionReason::Malformed,
+        RejectionReason::MissingAmount,
+        RejectionReason::ImplausibleAmount,
+        RejectionReason::DuplicateTxId,
+        RejectionReason::InsufficientFunds,
+        RejectionReason::AccountLocked,
+        RejectionReason::UnknownTx,
+        RejectionReason::ClientMismatch,
+        RejectionReason::NotDisputable,
+        RejectionReason::NotDisputed,
+        RejectionReason::ChargedBack,
+        RejectionReason::DuplicateReference,
+        RejectionReason::WithdrawalBlocked,
+        RejectionReason::AmountIncrement,
+        RejectionReason::MinimumBalance,
+        RejectionReason::NonPositiveAmount,
+        RejectionReason::ExcessPrecision,
+        RejectionReason::RiskRule,
+        RejectionReason::SandboxFault,
+        RejectionReason::AccountClosed,
+        RejectionReason::FundsHeld,
+        RejectionReason::NegativeBalance,
+        RejectionReason::UnlockDisabled,
+        RejectionReason::NotLocked,
+    ];
+
+    /// Machine readable code used in rejects output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectionReason::Malformed => "malformed",
+            RejectionReason::MissingAmount => "missing_amount",
+            RejectionReason::ImplausibleAmount => "implausible_amount",
+            RejectionReason::DuplicateTxId => "duplicate_tx_id",
+            RejectionReason::InsufficientFunds => "insufficient_funds",
+            RejectionReason::AccountLocked => "account_locked",
+            RejectionReason::UnknownTx => "unknown_tx",
+            RejectionReason::ClientMismatch => "client_mismatch",
+            RejectionReason::NotDisputable => "not_disputable",
+            RejectionReason::NotDisputed => "not_disputed",
+            RejectionReason::ChargedBack => "charged_back",
+            RejectionReason::DuplicateReference => "duplicate_reference",
+            RejectionReason::WithdrawalBlocked => "withdrawal_blocked",
+            RejectionReason::AmountIncrement => "amount_increment",
+            RejectionReason::MinimumBalance => "minimum_balance",
+            RejectionReason::NonPositiveAmount => "non_positive_amount",
+            RejectionReason::ExcessPrecision => "excess_precision",
+            RejectionReason::RiskRule => "risk_rule",
+            RejectionReason::SandboxFault => "sandbox_fault",
+            RejectionReason::AccountClosed => "account_closed",
+            RejectionReason::FundsHeld => "funds_held",
+            RejectionReason::NegativeBalance => "negative_balance",
+            RejectionReason::UnlockDisabled => "unlock_disabled",
+            RejectionReason::NotLocked => "not_locked",
+        }
+    }
+}
+
+impl fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why the engine did not apply a record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    /// Not valid in the current state, ignored per spec
+    Rejected(RejectionReason),
+    /// Retransmitted reference under `DedupPolicy::Fail`, processing should stop
+    Duplicate(DuplicateReference),
+    /// The transaction store failed, processing should stop
+    Store(StoreError),
+    /// Strict validation violation under `abort_on_violation`, processing should stop
+    Violation(Rejection),
+    /// An applied record broke an account invariant under `check_invariants`,
+    /// processing should stop
+    Invariant(InvariantViolation),
+    /// `PaymentsEngine::run` stopped by its `CancellationToken`, between records
+    Cancelled(Cancelled),
+}
+
+impl From<RejectionReason> for TxError {
+    fn from(reason: RejectionReason) -> Self {
+        TxError::Rejected(reason)
+    }
+}
+
+impl From<StoreError> for TxError {
+    fn from(e: StoreError) -> Self {
+        TxError::Store(e)
+    }
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxError::Rejected(reason) => write!(f, "record rejected: {}", reason),
+            TxError::Duplicate(duplicate) => write!(f, "{}", duplicate),
+            TxError::Store(e) => write!(f, "{}", e),
+            TxError::Violation(rejection) => write!(f, "validation failed: {}", rejection),
+            TxError::Invariant(violation) => write!(f, "invariant check failed: {}", violation),
+            TxError::Cancelled(cancelled) => write!(f, "{}", cancelled),
+        }
+    }
+}
+
+impl std::error::Error for TxError {}
+
 /// Stored transaction for dispute tracking
-/// Only deposits can be disputed, so we store them
-#[derive(Debug, Clone)]
+/// Deposits are stored, withdrawals too when withdrawal disputes are allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct StoredTransaction {
     pub client_id: ClientId,
     pub tx_type: TransactionType,
     pub amount: Decimal,
     pub disputed: bool,
+    pub charged_back: bool,
+    /// Position of the record that opened the current dispute
+    pub disputed_at: Option<u64>,
+    /// Timestamp of the record that created it, if it had one
+    pub timestamp: Option<Timestamp>,
 }
 
 impl StoredTransaction {
@@ -75,13 +331,20 @@ impl StoredTransaction {
             tx_type,
             amount,
             disputed: false,
+            charged_back: false,
+            disputed_at: None,
+            timestamp: None,
         }
     }
 
     /// Check if this transaction can be disputed
-    /// Only deposits can be disputed and only if not already disputed
+    /// Only deposits and withdrawals can be disputed and only if not already disputed.
+    /// Withdrawals are only stored under `EngineConfig::allow_withdrawal_disputes`
     pub fn can_dispute(&self) -> bool {
-        self.tx_type == TransactionType::Deposit && !self.disputed
+        matches!(
+            self.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) && !self.disputed
     }
 
     /// Mark transaction as disputed
@@ -92,16 +355,55 @@ impl StoredTransaction {
     /// Mark transaction as resolved (no longer disputed)
     pub fn mark_resolved(&mut self) {
         self.disputed = false;
+        self.disputed_at = None;
     }
 
     /// Check if transaction is currently disputed
     pub fn is_disputed(&self) -> bool {
         self.disputed
     }
+
+    /// Mark transaction as charged back (stays disputed, terminal state)
+    pub fn mark_charged_back(&mut self) {
+        self.charged_back = true;
+    }
+
+    /// Check if transaction was charged back
+    pub fn is_charged_back(&self) -> bool {
+        self.charged_back
+    }
+
+    /// Check if transaction is disputed and still awaiting resolve/chargeback
+    pub fn is_open_dispute(&self) -> bool {
+        self.disputed && !self.charged_back
+    }
+}
+
+/// Why an account got locked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockReason {
+    Chargeback,
+    /// Closed by a `close` record, for good
+    Closed,
+}
+
+impl LockReason {
+    /// Every reason, in declaration order
+    pub const ALL: [LockReason; 2] = [LockReason::Chargeback, LockReason::Closed];
+
+    /// Name as it appears in the v2 `lock_reason` column
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockReason::Chargeback => "chargeback",
+            LockReason::Closed => "closed",
+        }
+    }
 }
 
 /// Client account state
-#[derive(Debug, Clone, Serialize)]
+/// Serializes as the spec's 5-column row; bookkeeping fields are skipped
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct Account {
     pub client: ClientId,
     #[serde(serialize_with = "serialize_decimal_4dp")]
@@ -111,6 +413,18 @@ pub struct Account {
     #[serde(serialize_with = "serialize_decimal_4dp")]
     pub total: Decimal,
     pub locked: bool,
+    /// Why the account is locked, if it is
+    #[serde(skip)]
+    pub lock_reason: Option<LockReason>,
+    /// Number of disputes currently holding funds
+    #[serde(skip)]
+    pub open_disputes: u32,
+    /// Last transaction applied to the account
+    #[serde(skip)]
+    pub last_tx: Option<TransactionId>,
+    /// Idle past `--dormant-after` at the end of the run; not kept in snapshots
+    #[serde(skip)]
+    pub dormant: bool,
 }
 
 impl Account {
@@ -122,6 +436,10 @@ impl Account {
             held: Decimal::ZERO,
             total: Decimal::ZERO,
             locked: false,
+            lock_reason: None,
+            open_disputes: 0,
+            last_tx: None,
+            dormant: false,
         }
     }
 
@@ -131,10 +449,27 @@ impl Account {
         self.total += amount;
     }
 
+    /// Deposit funds on hold (increases held and total)
+    pub fn deposit_held(&mut self, amount: Decimal) {
+        self.held += amount;
+        self.total += amount;
+    }
+
+    /// Make a held deposit available once its hold period is over
+    pub fn release_deposit(&mut self, amount: Decimal) {
+        self.held -= amount;
+        self.available += amount;
+    }
+
+    /// Dispute a deposit still on hold, its funds are held already
+    pub fn dispute_held_deposit(&mut self) {
+        self.open_disputes += 1;
+    }
+
     /// Withdraw funds (decreases available and total)
     /// Returns true if successful, false if insufficient funds
     pub fn withdraw(&mut self, amount: Decimal) -> bool {
-        if self.available >= amount {
+        if self.can_withdraw(amount) {
             self.available -= amount;
             self.total -= amount;
             true
@@ -143,11 +478,17 @@ impl Account {
         }
     }
 
+    /// Check if available funds cover a withdrawal
+    pub fn can_withdraw(&self, amount: Decimal) -> bool {
+        self.available >= amount
+    }
+
     /// Move funds from available to held (dispute)
     /// Total remains unchanged
     pub fn hold_funds(&mut self, amount: Decimal) {
         self.available -= amount;
         self.held += amount;
+        self.open_disputes += 1;
     }
 
     /// Move funds from held to available (resolve)
@@ -155,6 +496,7 @@ impl Account {
     pub fn release_funds(&mut self, amount: Decimal) {
         self.held -= amount;
         self.available += amount;
+        self.open_disputes = self.open_disputes.saturating_sub(1);
     }
 
     /// Remove held funds and decrease total (chargeback)
@@ -162,25 +504,83 @@ impl Account {
     pub fn chargeback(&mut self, amount: Decimal) {
         self.held -= amount;
         self.total -= amount;
+        self.open_disputes = self.open_disputes.saturating_sub(1);
+        self.lock(LockReason::Chargeback);
+    }
+
+    /// Hold the amount of a disputed withdrawal
+    /// The withdrawn funds come back as held, so total grows
+    pub fn hold_withdrawn(&mut self, amount: Decimal) {
+        self.held += amount;
+        self.total += amount;
+        self.open_disputes += 1;
+    }
+
+    /// Drop the hold of a disputed withdrawal (resolve), the withdrawal stands
+    pub fn release_withdrawn(&mut self, amount: Decimal) {
+        self.held -= amount;
+        self.total -= amount;
+        self.open_disputes = self.open_disputes.saturating_sub(1);
+    }
+
+    /// Credit a disputed withdrawal back to the client (chargeback)
+    /// Locks the account permanently
+    pub fn chargeback_withdrawn(&mut self, amount: Decimal) {
+        self.held -= amount;
+        self.available += amount;
+        self.open_disputes = self.open_disputes.saturating_sub(1);
+        self.lock(LockReason::Chargeback);
+    }
+
+    /// Lock the account, keeping the first reason
+    pub fn lock(&mut self, reason: LockReason) {
         self.locked = true;
+        self.lock_reason.get_or_insert(reason);
     }
 
     /// Check if account is locked
     pub fn is_locked(&self) -> bool {
         self.locked
     }
+
+    /// Zero the available balance, paid out or written off, and close the account
+    /// Held funds stay: the engine only closes accounts that have none
+    pub fn close(&mut self) {
+        self.total -= self.available;
+        self.available = Decimal::ZERO;
+        // Closed wins over an earlier lock reason, closing is final
+        self.locked = true;
+        self.lock_reason = Some(LockReason::Closed);
+    }
+
+    /// Unfreeze the account, forgetting why it was locked
+    pub fn unlock(&mut self) {
+        self.locked = false;
+        self.lock_reason = None;
+    }
+
+    /// Check if the account was closed
+    pub fn is_closed(&self) -> bool {
+        self.lock_reason == Some(LockReason::Closed)
+    }
 }
 
-/// Custom serializer for Decimal with 4 decimal places
+/// Custom serializer for Decimal rounded to 4 decimal places
+/// Written as an exact string, never through f64, so large balances stay exact.
+/// Trailing zeros are dropped, so `100.0` and `100` in the input print the same
 fn serialize_decimal_4dp<S>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
-    use rust_decimal::prelude::ToPrimitive;
+    serializer.collect_str(&value.round_dp(4).normalize())
+}
 
-    // Round to 4 decimal places
-    let rounded = value.round_dp(4);
-    serializer.serialize_f64(rounded.to_f64().unwrap_or(0.0))
+/// Serializer for Decimal as an exact string, for formats where floats would lose precision
+pub fn serialize_decimal_str<S>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_str(value)
 }
 
 #[cfg(test)]
@@ -232,12 +632,14 @@ mod tests {
         assert_eq!(account.available, dec!(0));
         assert_eq!(account.held, dec!(100.0));
         assert_eq!(account.total, dec!(100.0));
+        assert_eq!(account.open_disputes, 1);
 
         // Resolve
         account.release_funds(dec!(100.0));
         assert_eq!(account.available, dec!(100.0));
         assert_eq!(account.held, dec!(0));
         assert_eq!(account.total, dec!(100.0));
+        assert_eq!(account.open_disputes, 0);
     }
 
     #[test]
@@ -253,6 +655,37 @@ mod tests {
         assert_eq!(account.held, dec!(0));
         assert_eq!(account.total, dec!(0));
         assert!(account.is_locked());
+        assert_eq!(account.lock_reason, Some(LockReason::Chargeback));
+        assert_eq!(account.open_disputes, 0);
+    }
+
+    #[test]
+    fn test_account_withdrawal_dispute_flow() {
+        let mut account = Account::new(1);
+        account.deposit(dec!(100.0));
+        assert!(account.withdraw(dec!(30.0)));
+
+        account.hold_withdrawn(dec!(30.0));
+        assert_eq!(account.available, dec!(70.0));
+        assert_eq!(account.held, dec!(30.0));
+        assert_eq!(account.total, dec!(100.0));
+        assert_eq!(account.open_disputes, 1);
+
+        // Resolve: the withdrawal stands
+        let mut resolved = account.clone();
+        resolved.release_withdrawn(dec!(30.0));
+        assert_eq!(resolved.available, dec!(70.0));
+        assert_eq!(resolved.held, dec!(0));
+        assert_eq!(resolved.total, dec!(70.0));
+        assert!(!resolved.is_locked());
+
+        // Chargeback: the client gets the funds back
+        account.chargeback_withdrawn(dec!(30.0));
+        assert_eq!(account.available, dec!(100.0));
+        assert_eq!(account.held, dec!(0));
+        assert_eq!(account.total, dec!(100.0));
+        assert!(account.is_locked());
+        assert_eq!(account.open_disputes, 0);
     }
 
     #[test]
@@ -260,11 +693,29 @@ mod tests {
         let tx = StoredTransaction::new(1, TransactionType::Deposit, dec!(100.0));
         assert!(tx.can_dispute());
 
-        let mut tx_disputed = tx.clone();
+        let mut tx_disputed = tx;
         tx_disputed.mark_disputed();
         assert!(!tx_disputed.can_dispute());
 
+        // Only stored when withdrawal disputes are allowed
         let tx_withdrawal = StoredTransaction::new(1, TransactionType::Withdrawal, dec!(50.0));
-        assert!(!tx_withdrawal.can_dispute());
+        assert!(tx_withdrawal.can_dispute());
+
+        let tx_dispute = StoredTransaction::new(1, TransactionType::Dispute, dec!(50.0));
+        assert!(!tx_dispute.can_dispute());
+    }
+
+    #[test]
+    fn test_stored_transaction_open_dispute() {
+        let mut tx = StoredTransaction::new(1, TransactionType::Deposit, dec!(100.0));
+        assert!(!tx.is_open_dispute());
+
+        tx.mark_disputed();
+        assert!(tx.is_open_dispute());
+
+        tx.mark_charged_back();
+        assert!(tx.is_disputed());
+        assert!(tx.is_charged_back());
+        assert!(!tx.is_open_dispute());
     }
 }
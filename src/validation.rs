@@ -0,0 +1,121 @@
+use crate::types::RejectionReason;
+use rust_decimal::Decimal;
+
+/// Decimal places amounts may carry, as in the output
+pub const MAX_DECIMAL_PLACES: u32 = 4;
+
+/// How strictly input is checked
+///
+/// Lenient (the default) follows the spec: bad records are skipped and the run
+/// carries on. Strict is meant for audits: dubious amounts are rejected, and
+/// rejections that point at bad input rather than at account state count as
+/// violations, which fail the run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ValidationConfig {
+    /// Reject zero and negative deposit/withdrawal amounts
+    pub reject_non_positive: bool,
+    /// Reject amounts with more than `MAX_DECIMAL_PLACES` decimal places
+    pub reject_excess_precision: bool,
+    /// Malformed rows are violations instead of being skipped quietly
+    pub fail_on_malformed: bool,
+    /// Reused tx ids are violations instead of being skipped quietly
+    pub fail_on_duplicate_tx: bool,
+    /// Stop processing at the first violation
+    pub abort_on_violation: bool,
+}
+
+impl ValidationConfig {
+    /// Spec behavior, nothing beyond the engine's own checks
+    pub fn lenient() -> Self {
+        Self::default()
+    }
+
+    /// Every check on; violations are collected unless `abort_on_violation` is set too
+    pub fn strict() -> Self {
+        Self {
+            reject_non_positive: true,
+            reject_excess_precision: true,
+            fail_on_malformed: true,
+            fail_on_duplicate_tx: true,
+            abort_on_violation: false,
+        }
+    }
+
+    /// Check the amount of a record against the enabled amount checks
+    pub fn check_amount(&self, amount: Decimal) -> Result<(), RejectionReason> {
+        if self.reject_non_positive && amount <= Decimal::ZERO {
+            return Err(RejectionReason::NonPositiveAmount);
+        }
+        // Trailing zeros don't count: 1.50000 is a 4 decimal place amount
+        if self.reject_excess_precision && amount.normalize().scale() > MAX_DECIMAL_PLACES {
+            return Err(RejectionReason::ExcessPrecision);
+        }
+        Ok(())
+    }
+
+    /// Whether a rejection for `reason` is a violation under this config
+    pub fn is_violation(&self, reason: RejectionReason) -> bool {
+        match reason {
+            RejectionReason::Malformed => self.fail_on_malformed,
+            RejectionReason::DuplicateTxId => self.fail_on_duplicate_tx,
+            // Only raised when their check is enabled
+            RejectionReason::NonPositiveAmount | RejectionReason::ExcessPrecision => true,
+            _ => false,
+        }
+    }
+
+    /// Whether a rejection for `reason` should stop processing
+    pub fn aborts_on(&self, reason: RejectionReason) -> bool {
+        self.abort_on_violation && self.is_violation(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_lenient_accepts_everything() {
+        let lenient = ValidationConfig::lenient();
+        assert_eq!(lenient.check_amount(dec!(-5)), Ok(()));
+        assert_eq!(lenient.check_amount(dec!(0.00001)), Ok(()));
+        assert!(!lenient.is_violation(RejectionReason::Malformed));
+        assert!(!lenient.aborts_on(RejectionReason::DuplicateTxId));
+    }
+
+    #[test]
+    fn test_strict_amounts() {
+        let strict = ValidationConfig::strict();
+        assert_eq!(strict.check_amount(dec!(1.2345)), Ok(()));
+        assert_eq!(strict.check_amount(dec!(1.50000)), Ok(()));
+        assert_eq!(
+            strict.check_amount(dec!(0)),
+            Err(RejectionReason::NonPositiveAmount)
+        );
+        assert_eq!(
+            strict.check_amount(dec!(-1)),
+            Err(RejectionReason::NonPositiveAmount)
+        );
+        assert_eq!(
+            strict.check_amount(dec!(1.23456)),
+            Err(RejectionReason::ExcessPrecision)
+        );
+    }
+
+    #[test]
+    fn test_violations() {
+        let strict = ValidationConfig::strict();
+        assert!(strict.is_violation(RejectionReason::Malformed));
+        assert!(strict.is_violation(RejectionReason::DuplicateTxId));
+        assert!(!strict.is_violation(RejectionReason::InsufficientFunds));
+        assert!(!strict.aborts_on(RejectionReason::Malformed));
+
+        let fail_fast = ValidationConfig {
+            abort_on_violation: true,
+            ..strict
+        };
+        assert!(fail_fast.aborts_on(RejectionReason::ExcessPrecision));
+        assert!(!fail_fast.aborts_on(RejectionReason::AccountLocked));
+    }
+}
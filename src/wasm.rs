@@ -0,0 +1,377 @@
+//! Risk rules and record transformers in sandboxed WebAssembly modules
+//!
+//! Partner logic runs in wasmtime with no imports, so a module can compute but
+//! not reach the file system or network. Each call gets a fresh fuel budget,
+//! and linear memory is capped; a module that traps, runs out of fuel or
+//! returns something unreadable rejects the record with `sandbox_fault`
+//! rather than stopping the engine.
+//!
+//! Data crosses as JSON in the module's memory, in the forms of `crate::plugin`.
+//! A module exports `memory` and `alloc(len) -> ptr`, which the runner calls
+//! for each input, plus at least one of:
+//! - `check(record_ptr, record_len, account_ptr, account_len) -> i32`: a risk
+//!   rule, 0 lets the record through, anything else rejects it with `risk_rule`
+//! - `transform(record_ptr, record_len) -> i64`: a transformer, returning the
+//!   new record as `ptr << 32 | len`, 0 to keep the record as is, or a negative
+//!   value to reject it with `risk_rule`
+//!
+//! The runner never frees: inputs are only needed until the call returns, so a
+//! module may hand out the same buffers again on the next call.
+
+use crate::plugin::{record_json, RecordTransformer, RiskRule};
+use crate::types::{Account, RejectionReason, TransactionRecord};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use wasmtime::{
+    Config, Engine, Instance, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+};
+
+/// Default fuel per call, roughly the number of wasm instructions
+pub const DEFAULT_FUEL: u64 = 1_000_000;
+
+/// Default cap on a module's linear memory (16 MiB)
+pub const DEFAULT_MEMORY: usize = 16 * 1024 * 1024;
+
+/// Resources a module may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WasmLimits {
+    /// Fuel available to each call
+    pub fuel: u64,
+    /// Largest linear memory, in bytes
+    pub memory: usize,
+}
+
+impl Default for WasmLimits {
+    fn default() -> Self {
+        Self {
+            fuel: DEFAULT_FUEL,
+            memory: DEFAULT_MEMORY,
+        }
+    }
+}
+
+/// A loaded module; clones share it, so one module can be both rule and transformer
+#[derive(Clone)]
+pub struct WasmModule {
+    name: String,
+    instance: Arc<Mutex<Sandbox>>,
+}
+
+struct Sandbox {
+    store: Store<StoreLimits>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    check: Option<TypedFunc<(i32, i32, i32, i32), i32>>,
+    transform: Option<TypedFunc<(i32, i32), i64>>,
+    fuel: u64,
+}
+
+impl WasmModule {
+    /// Load a module from a `.wasm` (or `.wat`) file
+    pub fn load(path: &Path, limits: WasmLimits) -> Result<Self, Box<dyn Error>> {
+        let bytes = std::fs::read(path)?;
+        let name = path.file_stem().map_or_else(
+            || path.display().to_string(),
+            |s| s.to_string_lossy().into_owned(),
+        );
+        Self::from_bytes(&name, &bytes, limits)
+            .map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// Compile and instantiate a module, binary or text format
+    pub fn from_bytes(
+        name: &str,
+        bytes: &[u8],
+        limits: WasmLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::new(&engine, bytes)?;
+
+        let limiter = StoreLimitsBuilder::new()
+            .memory_size(limits.memory)
+            .instances(1)
+            .build();
+        let mut store = Store::new(&engine, limiter);
+        store.limiter(|limits| limits);
+        // Start functions run on the first call's budget
+        store.set_fuel(limits.fuel)?;
+
+        // No imports: the module gets nothing from the host
+        let instance = Instance::new(&mut store, &module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or("module does not export its memory")?;
+        let alloc = instance.get_typed_func(&mut store, "alloc")?;
+        let check = instance.get_typed_func(&mut store, "check").ok();
+        let transform = instance.get_typed_func(&mut store, "transform").ok();
+        if check.is_none() && transform.is_none() {
+            return Err("module exports neither check nor transform".into());
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            instance: Arc::new(Mutex::new(Sandbox {
+                store,
+                memory,
+                alloc,
+                check,
+                transform,
+                fuel: limits.fuel,
+            })),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the module exports `check`
+    pub fn is_risk_rule(&self) -> bool {
+        self.sandbox().check.is_some()
+    }
+
+    /// Whether the module exports `transform`
+    pub fn is_transformer(&self) -> bool {
+        self.sandbox().transform.is_some()
+    }
+
+    fn sandbox(&self) -> std::sync::MutexGuard<'_, Sandbox> {
+        self.instance.lock().expect("wasm module lock poisoned")
+    }
+}
+
+impl Sandbox {
+    /// Copy `bytes` into module memory, returning pointer and length
+    fn write(&mut self, bytes: &[u8]) -> wasmtime::Result<(i32, i32)> {
+        let len = i32::try_from(bytes.len())?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, usize::try_from(ptr)?, bytes)?;
+        Ok((ptr, len))
+    }
+
+    fn check(&mut self, record: &[u8], account: &[u8]) -> wasmtime::Result<Option<i32>> {
+        let Some(check) = self.check.clone() else {
+            return Ok(None);
+        };
+        self.store.set_fuel(self.fuel)?;
+        let (record_ptr, record_len) = self.write(record)?;
+        let (account_ptr, account_len) = self.write(account)?;
+        let verdict = check.call(
+            &mut self.store,
+            (record_ptr, record_len, account_ptr, account_len),
+        )?;
+        Ok(Some(verdict))
+    }
+
+    fn transform(&mut self, record: &[u8]) -> wasmtime::Result<Transformed> {
+        let Some(transform) = self.transform.clone() else {
+            return Ok(Transformed::Keep);
+        };
+        self.store.set_fuel(self.fuel)?;
+        let (ptr, len) = self.write(record)?;
+        let packed = transform.call(&mut self.store, (ptr, len))?;
+        if packed < 0 {
+            return Ok(Transformed::Reject);
+        }
+        if packed == 0 {
+            return Ok(Transformed::Keep);
+        }
+        let ptr = (packed >> 32) as usize;
+        let len = (packed & 0xffff_ffff) as usize;
+        let data = self.memory.data(&self.store);
+        let output = data
+            .get(ptr..ptr.saturating_add(len))
+            .ok_or_else(|| wasmtime::Error::msg("output out of bounds"))?;
+        Ok(Transformed::Replace(output.to_vec()))
+    }
+}
+
+/// What `transform` returned
+enum Transformed {
+    Keep,
+    /// JSON of the record to use instead
+    Replace(Vec<u8>),
+    Reject,
+}
+
+impl fmt::Debug for WasmModule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmModule")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl RiskRule for WasmModule {
+    fn check(
+        &mut self,
+        record: &TransactionRecord,
+        account: Option<&Account>,
+    ) -> Result<(), RejectionReason> {
+        let record = record_json(record).to_string();
+        let account = serde_json::json!(account).to_string();
+        match self.sandbox().check(record.as_bytes(), account.as_bytes()) {
+            Ok(None | Some(0)) => Ok(()),
+            Ok(Some(_)) => Err(RejectionReason::RiskRule),
+            Err(_) => Err(RejectionReason::SandboxFault),
+        }
+    }
+}
+
+impl RecordTransformer for WasmModule {
+    fn transform(
+        &mut self,
+        record: TransactionRecord,
+    ) -> Result<TransactionRecord, RejectionReason> {
+        let json = record_json(&record).to_string();
+        match self.sandbox().transform(json.as_bytes()) {
+            Ok(Transformed::Keep) => Ok(record),
+            Ok(Transformed::Replace(output)) => {
+                let transformed: TransactionRecord =
+                    serde_json::from_slice(&output).map_err(|_| RejectionReason::SandboxFault)?;
+                // Still the same input line
+                Ok(TransactionRecord {
+                    line: record.line,
+                    ..transformed
+                })
+            }
+            Ok(Transformed::Reject) => Err(RejectionReason::RiskRule),
+            Err(_) => Err(RejectionReason::SandboxFault),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TransactionType;
+    use rust_decimal_macros::dec;
+
+    /// Bump allocator reset on every `check`/`transform` call
+    const ALLOC: &str = r#"
+        (memory (export "memory") 1)
+        (global $next (mut i32) (i32.const 1024))
+        (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+    "#;
+
+    fn module(body: &str, limits: WasmLimits) -> Result<WasmModule, Box<dyn Error>> {
+        WasmModule::from_bytes(
+            "test",
+            format!("(module {} {})", ALLOC, body).as_bytes(),
+            limits,
+        )
+    }
+
+    fn record(tx_type: TransactionType, client: u16) -> TransactionRecord {
+        TransactionRecord {
+            tx_type,
+            client,
+            tx: 1,
+            amount: Some(dec!(2)),
+            seq: None,
+            timestamp: None,
+            line: Some(5),
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        // Refuses clients without an account: the account JSON is `null`
+        let mut rule = module(
+            r#"(func (export "check") (param i32 i32 i32 i32) (result i32)
+                (global.set $next (i32.const 1024))
+                (i32.eq (local.get 3) (i32.const 4)))"#,
+            WasmLimits::default(),
+        )
+        .unwrap();
+        assert!(rule.is_risk_rule() && !rule.is_transformer());
+
+        let deposit = record(TransactionType::Deposit, 1);
+        assert_eq!(rule.check(&deposit, None), Err(RejectionReason::RiskRule));
+        assert_eq!(rule.check(&deposit, Some(&Account::new(1))), Ok(()));
+    }
+
+    #[test]
+    fn test_transform() {
+        let output = r#"{"type":"withdrawal","client":9,"tx":7,"amount":"1.5"}"#;
+        let mut transformer = module(
+            &format!(
+                r#"(data (i32.const 64) "{}")
+                (func (export "transform") (param i32 i32) (result i64)
+                    (global.set $next (i32.const 1024))
+                    (i64.or (i64.shl (i64.const 64) (i64.const 32)) (i64.const {})))"#,
+                output.replace('"', "\\\""),
+                output.len()
+            ),
+            WasmLimits::default(),
+        )
+        .unwrap();
+
+        let transformed = transformer
+            .transform(record(TransactionType::Deposit, 1))
+            .unwrap();
+        assert_eq!(transformed.tx_type, TransactionType::Withdrawal);
+        assert_eq!(transformed.client, 9);
+        assert_eq!(transformed.amount, Some(dec!(1.5)));
+        assert_eq!(transformed.line, Some(5));
+
+        let mut keep = module(
+            r#"(func (export "transform") (param i32 i32) (result i64) (i64.const 0))"#,
+            WasmLimits::default(),
+        )
+        .unwrap();
+        let deposit = record(TransactionType::Deposit, 1);
+        assert_eq!(keep.transform(deposit).map(|r| r.client), Ok(1));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut spin = module(
+            r#"(func (export "check") (param i32 i32 i32 i32) (result i32)
+                (loop $forever (br $forever))
+                (i32.const 0))"#,
+            WasmLimits::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            spin.check(&record(TransactionType::Deposit, 1), None),
+            Err(RejectionReason::SandboxFault)
+        );
+
+        // Growing past the cap fails inside the module (memory.grow returns -1)
+        let mut grow = module(
+            r#"(func (export "check") (param i32 i32 i32 i32) (result i32)
+                (global.set $next (i32.const 1024))
+                (i32.ne (memory.grow (i32.const 100)) (i32.const -1)))"#,
+            WasmLimits {
+                memory: 1024 * 1024,
+                ..WasmLimits::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            grow.check(&record(TransactionType::Deposit, 1), None),
+            Ok(())
+        );
+
+        // A module asking for more memory than allowed up front does not load
+        let big = "(module (memory (export \"memory\") 100) \
+                   (func (export \"alloc\") (param i32) (result i32) (i32.const 0)) \
+                   (func (export \"check\") (param i32 i32 i32 i32) (result i32) (i32.const 0)))";
+        let limits = WasmLimits {
+            memory: 1024 * 1024,
+            ..WasmLimits::default()
+        };
+        assert!(WasmModule::from_bytes("big", big.as_bytes(), limits).is_err());
+        assert!(module("", WasmLimits::default()).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use crate::types::{Account, ClientId, StoredTransaction, TransactionId, TransactionType};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Hypothetical end-of-run scenario
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scenario {
+    /// Every dispute still open at end of run is charged back
+    ChargebackAllOpen,
+}
+
+impl FromStr for Scenario {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chargeback-all-open" => Ok(Scenario::ChargebackAllOpen),
+            _ => Err(format!("Unknown what-if scenario: {}", s)),
+        }
+    }
+}
+
+/// Compute the balances a scenario would produce
+/// Works on a copy of the accounts, the real run state is never mutated
+pub fn evaluate(
+    scenario: Scenario,
+    accounts: &HashMap<ClientId, Account>,
+    transactions: &HashMap<TransactionId, StoredTransaction>,
+) -> HashMap<ClientId, Account> {
+    let mut accounts = accounts.clone();
+
+    match scenario {
+        Scenario::ChargebackAllOpen => {
+            for stored_tx in transactions.values().filter(|tx| tx.is_open_dispute()) {
+                // Open disputes always belong to an existing account
+                if let Some(account) = accounts.get_mut(&stored_tx.client_id) {
+                    if stored_tx.tx_type == TransactionType::Withdrawal {
+                        account.chargeback_withdrawn(stored_tx.amount);
+                    } else {
+                        account.chargeback(stored_tx.amount);
+                    }
+                }
+            }
+        }
+    }
+
+    accounts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_chargeback_all_open() {
+        let mut accounts = HashMap::new();
+        let mut transactions = HashMap::new();
+
+        // Client 1: two deposits, one under open dispute
+        let mut account = Account::new(1);
+        account.deposit(dec!(100));
+        account.deposit(dec!(40));
+        account.hold_funds(dec!(40));
+        accounts.insert(1, account);
+        transactions.insert(
+            1,
+            StoredTransaction::new(1, TransactionType::Deposit, dec!(100)),
+        );
+        let mut disputed = StoredTransaction::new(1, TransactionType::Deposit, dec!(40));
+        disputed.mark_disputed();
+        transactions.insert(2, disputed);
+
+        // Client 2: dispute already charged back, nothing left open
+        let mut account = Account::new(2);
+        account.deposit(dec!(10));
+        account.hold_funds(dec!(10));
+        account.chargeback(dec!(10));
+        accounts.insert(2, account);
+        let mut charged = StoredTransaction::new(2, TransactionType::Deposit, dec!(10));
+        charged.mark_disputed();
+        charged.mark_charged_back();
+        transactions.insert(3, charged);
+
+        let hypothetical = evaluate(Scenario::ChargebackAllOpen, &accounts, &transactions);
+
+        let client1 = &hypothetical[&1];
+        assert_eq!(client1.available, dec!(100));
+        assert_eq!(client1.held, dec!(0));
+        assert_eq!(client1.total, dec!(100));
+        assert!(client1.locked);
+
+        let client2 = &hypothetical[&2];
+        assert_eq!(client2.total, dec!(0));
+
+        // Original state untouched
+        assert_eq!(accounts[&1].held, dec!(40));
+        assert!(!accounts[&1].locked);
+    }
+}
@@ -0,0 +1,214 @@
+//! Synthetic transaction workloads, for benchmarks and regression runs
+//!
+//! `gen` writes a random but valid transaction file. Every record in it applies
+//! under the default engine settings: withdrawals never exceed the available
+//! funds, disputes name an undisputed deposit of the same client, resolves and
+//! chargebacks an open dispute, and a client locked by a chargeback gets no
+//! further records. Chargebacks stop once half the clients are locked. The same
+//! spec always gives the same file, so a failing `--check` run can be repeated.
+
+use crate::types::{ClientId, TransactionId, TransactionType};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::Write;
+
+/// Records `gen` writes unless `--rows` says otherwise
+pub const DEFAULT_ROWS: u64 = 100_000;
+
+/// Clients `gen` spreads records over unless `--clients` says otherwise
+pub const DEFAULT_CLIENTS: u16 = 1_000;
+
+/// Largest deposit, in units of 0.0001
+const MAX_DEPOSIT: u64 = 10_000_000;
+
+/// What to generate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkloadSpec {
+    pub rows: u64,
+    /// Clients 1 to `clients` get records
+    pub clients: u16,
+    /// Share of records that are disputes, resolves or chargebacks, 0 to 1
+    pub dispute_ratio: Decimal,
+    /// Seed of the random choices
+    pub seed: u64,
+}
+
+impl Default for WorkloadSpec {
+    fn default() -> Self {
+        Self {
+            rows: DEFAULT_ROWS,
+            clients: DEFAULT_CLIENTS,
+            dispute_ratio: Decimal::new(5, 2),
+            seed: 0,
+        }
+    }
+}
+
+/// splitmix64, small and good enough to pick records
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform-enough value below `n`, which must not be 0
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// What the generator knows of one client, amounts in units of 0.0001
+#[derive(Default)]
+struct Book {
+    /// Below zero once a dispute held funds already withdrawn
+    available: i64,
+    /// Deposits that can still be disputed
+    deposits: Vec<(TransactionId, i64)>,
+    /// Disputed deposits, oldest first
+    disputed: VecDeque<(TransactionId, i64)>,
+}
+
+/// Write a workload as CSV with a header, returning the records written
+pub fn write_workload<W: Write>(spec: &WorkloadSpec, writer: W) -> Result<u64, Box<dyn Error>> {
+    if spec.clients == 0 {
+        return Err("a workload needs at least one client".into());
+    }
+    if spec.rows > u64::from(TransactionId::MAX) {
+        return Err(format!("a workload has at most {} records", TransactionId::MAX).into());
+    }
+    if spec.dispute_ratio < Decimal::ZERO || spec.dispute_ratio > Decimal::ONE {
+        return Err("the dispute ratio must be between 0 and 1".into());
+    }
+    let per_million = (spec.dispute_ratio * Decimal::from(1_000_000))
+        .to_u64()
+        .unwrap_or_default();
+
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["type", "client", "tx", "amount"])?;
+    let mut write =
+        |tx_type: TransactionType, client: ClientId, tx: TransactionId, amount: Option<i64>| {
+            let amount = amount.map_or(String::new(), |units| {
+                Decimal::new(units, 4).normalize().to_string()
+            });
+            writer.write_record([
+                tx_type.as_str(),
+                &client.to_string(),
+                &tx.to_string(),
+                &amount,
+            ])
+        };
+
+    let mut rng = Rng(spec.seed);
+    let mut books: Vec<Book> = (0..spec.clients).map(|_| Book::default()).collect();
+    // Clients not locked by a chargeback
+    let mut active: Vec<ClientId> = (1..=spec.clients).collect();
+    let mut next_tx: TransactionId = 1;
+    let mut written = 0;
+
+    while written < spec.rows {
+        let slot = rng.below(active.len() as u64) as usize;
+        let client = active[slot];
+        let book = &mut books[usize::from(client - 1)];
+        written += 1;
+
+        if rng.below(1_000_000) < per_million {
+            let close =
+                !book.disputed.is_empty() && (book.deposits.is_empty() || rng.below(2) == 0);
+            if close {
+                let (tx, amount) = book.disputed.pop_front().expect("an open dispute");
+                // Leave at least half the clients unlocked to carry the workload
+                let locked = usize::from(spec.clients) - active.len();
+                if rng.below(10) == 0 && (locked + 1) * 2 <= usize::from(spec.clients) {
+                    write(TransactionType::Chargeback, client, tx, None)?;
+                    active.swap_remove(slot);
+                } else {
+                    write(TransactionType::Resolve, client, tx, None)?;
+                    book.available += amount;
+                }
+                continue;
+            }
+            if !book.deposits.is_empty() {
+                let index = rng.below(book.deposits.len() as u64) as usize;
+                let (tx, amount) = book.deposits.swap_remove(index);
+                write(TransactionType::Dispute, client, tx, None)?;
+                book.available -= amount;
+                book.disputed.push_back((tx, amount));
+                continue;
+            }
+        }
+
+        let tx = next_tx;
+        next_tx += 1;
+        if book.available > 0 && rng.below(10) < 4 {
+            let amount = 1 + rng.below(book.available as u64) as i64;
+            write(TransactionType::Withdrawal, client, tx, Some(amount))?;
+            book.available -= amount;
+        } else {
+            let amount = 1 + rng.below(MAX_DEPOSIT) as i64;
+            write(TransactionType::Deposit, client, tx, Some(amount))?;
+            book.available += amount;
+            book.deposits.push((tx, amount));
+        }
+    }
+
+    writer.flush()?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::csv_parser::TransactionReader;
+    use crate::engine::PaymentsEngine;
+
+    #[test]
+    fn test_workload_applies_cleanly() {
+        let spec = WorkloadSpec {
+            rows: 20_000,
+            clients: 20,
+            dispute_ratio: Decimal::new(2, 1),
+            seed: 7,
+        };
+        let mut csv = Vec::new();
+        assert_eq!(write_workload(&spec, &mut csv).unwrap(), 20_000);
+
+        let mut again = Vec::new();
+        write_workload(&spec, &mut again).unwrap();
+        assert_eq!(again, csv);
+
+        let mut engine = PaymentsEngine::new(EngineConfig {
+            check_invariants: true,
+            ..EngineConfig::default()
+        });
+        for record in TransactionReader::from_reader(csv.as_slice()).records() {
+            engine.process(record.unwrap()).unwrap();
+        }
+        let summary = engine.summary(0);
+        assert_eq!(summary.records_processed, 20_000);
+        assert!(summary.operations.chargeback.applied > 0);
+        assert!(summary.operations.resolve.applied > 0);
+        assert!(summary.locked_accounts <= 10);
+    }
+
+    #[test]
+    fn test_invalid_spec() {
+        let spec = WorkloadSpec {
+            clients: 0,
+            ..WorkloadSpec::default()
+        };
+        assert!(write_workload(&spec, Vec::new()).is_err());
+        let spec = WorkloadSpec {
+            dispute_ratio: Decimal::NEGATIVE_ONE,
+            ..WorkloadSpec::default()
+        };
+        assert!(write_workload(&spec, Vec::new()).is_err());
+    }
+}
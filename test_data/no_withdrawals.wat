@@ -0,0 +1,25 @@
+;; Risk rule refusing every withdrawal
+;; The record JSON has a `w` only in "withdrawal", so that is all it looks for
+(module
+  (memory (export "memory") 1)
+
+  ;; Inputs are consumed before the next call, so the buffer is reused
+  (global $next (mut i32) (i32.const 0))
+  (func (export "alloc") (param $len i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $next))
+    (global.set $next (i32.add (global.get $next) (local.get $len)))
+    (local.get $ptr))
+
+  (func (export "check") (param $record i32) (param $len i32) (param i32 i32) (result i32)
+    (local $end i32)
+    (global.set $next (i32.const 0))
+    (local.set $end (i32.add (local.get $record) (local.get $len)))
+    (block $done
+      (loop $scan
+        (br_if $done (i32.ge_u (local.get $record) (local.get $end)))
+        (if (i32.eq (i32.load8_u (local.get $record)) (i32.const 119))
+          (then (return (i32.const 1))))
+        (local.set $record (i32.add (local.get $record) (i32.const 1)))
+        (br $scan)))
+    (i32.const 0)))
@@ -0,0 +1,1137 @@
+use assert_cmd::Command;
+use core_tx_runner::{proof, repro};
+use predicates::prelude::*;
+use std::fs;
+use std::path::PathBuf;
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "core-tx-runner-cli-{}-{}",
+        name,
+        std::process::id()
+    ));
+    fs::create_dir_all(&dir).expect("Failed to create scratch dir");
+    dir
+}
+
+fn runner() -> Command {
+    Command::new(assert_cmd::cargo::cargo_bin!("core-tx-runner"))
+}
+
+#[test]
+fn test_stdout_output() {
+    runner()
+        .arg("test_data/simple.csv")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "client,available,held,total,locked\n",
+        ));
+}
+
+#[test]
+fn test_missing_arguments() {
+    runner()
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Usage:"));
+}
+
+#[test]
+fn test_output_file_written_atomically() {
+    let dir = scratch_dir("output");
+    let path = dir.join("accounts.csv");
+
+    runner()
+        .args(["test_data/simple.csv", "--output"])
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout("");
+
+    let text = fs::read_to_string(&path).expect("Output not written");
+    assert!(text.starts_with("client,available,held,total,locked\n"));
+    assert!(!dir.join("accounts.csv.tmp").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_output_file_not_created_on_failure() {
+    let dir = scratch_dir("output-fail");
+    let path = dir.join("accounts.csv");
+
+    runner()
+        .args(["test_data/does_not_exist.csv", "--output"])
+        .arg(&path)
+        .assert()
+        .failure();
+
+    assert!(!path.exists());
+    assert!(!dir.join("accounts.csv.tmp").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_what_if_chargeback_all_open() {
+    // Client 3 ends with an open 300.0 dispute
+    let dir = scratch_dir("what-if");
+    let what_if = dir.join("what_if.csv");
+    runner()
+        .args([
+            "test_data/invalid_references.csv",
+            "--what-if",
+            "chargeback-all-open",
+            "--what-if-output",
+        ])
+        .arg(&what_if)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("3,0,300,300,false"));
+    let hypothetical = fs::read_to_string(&what_if).unwrap();
+    assert!(hypothetical.contains("3,0,0,0,true"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_statement_csv() {
+    // disputes.csv, client 1: deposit, deposit, withdrawal, dispute, resolve, deposit
+    runner()
+        .args([
+            "statement",
+            "test_data/disputes.csv",
+            "--client",
+            "1",
+            "--from",
+            "4",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "position,type,tx,amount,available,held,total,locked\n,opening,,,150,0,150,false\n4,withdrawal,4,25,125,0,125,false\n",
+        ))
+        .stdout(predicate::str::ends_with(",closing,,,200,0,200,false\n"));
+}
+
+#[test]
+fn test_statement_requires_client() {
+    runner()
+        .args(["statement", "test_data/disputes.csv"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--client"));
+}
+
+#[test]
+fn test_replay_diff() {
+    // Lifting the plausibility bound lets the 19-digit deposit through
+    runner()
+        .args([
+            "replay",
+            "test_data/implausible.csv",
+            "--override-max-amount",
+            "none",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\n1,100,0,100,false,"))
+        .stdout(predicate::str::contains(
+            "\n2,,,,,1000000000000.5,0,1000000000000.5,false,1000000000000.5\n",
+        ));
+
+    // The same setting on both sides is no change at all
+    runner()
+        .args([
+            "replay",
+            "test_data/implausible.csv",
+            "--max-amount",
+            "none",
+            "--override-max-amount",
+            "none",
+        ])
+        .assert()
+        .success()
+        .stdout(
+            "client,original_available,original_held,original_total,original_locked,\
+             replayed_available,replayed_held,replayed_total,replayed_locked,total_delta\n",
+        );
+}
+
+#[test]
+fn test_output_formats() {
+    runner()
+        .args(["test_data/simple.csv", "--threads", "2"])
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,125,0,125,false\n2,100,0,100,false\n");
+
+    runner()
+        .args(["test_data/simple.csv", "--output-format", "table"])
+        .assert()
+        .success()
+        .stdout(
+            "client  available  held  total  locked\n\
+             ------  ---------  ----  -----  ------\n\
+             \x20    1        125     0    125  false\n\
+             \x20    2        100     0    100  false\n",
+        );
+
+    let output = runner()
+        .args([
+            "test_data/simple.csv",
+            "--output-format",
+            "json",
+            "--schema",
+            "v2",
+        ])
+        .output()
+        .expect("Failed to run");
+    assert!(output.status.success());
+    let rows: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(rows[0]["client"], 1);
+    assert_eq!(rows[0]["total"], "125.0000");
+    assert_eq!(rows[1]["last_tx"], 5);
+
+    runner()
+        .args(["test_data/simple.csv", "--output-format", "xml"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Unknown output format: xml"));
+}
+
+#[test]
+fn test_audit_with_deposit_hold() {
+    let dir = scratch_dir("audit-hold");
+    let input = dir.join("h.csv");
+    let accounts = dir.join("hacc.csv");
+    fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,1,1,100\ndeposit,1,2,50\n",
+    )
+    .unwrap();
+
+    runner()
+        .arg(&input)
+        .args(["--deposit-hold", "100", "--output"])
+        .arg(&accounts)
+        .assert()
+        .success();
+    runner()
+        .arg("audit")
+        .arg(&input)
+        .arg(&accounts)
+        .args(["--deposit-hold", "100"])
+        .assert()
+        .success()
+        .stdout("client,check,expected,actual\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_audit_round_trip() {
+    let dir = scratch_dir("audit");
+    let accounts = dir.join("accounts.csv");
+
+    runner()
+        .args(["test_data/edge_cases.csv", "--output"])
+        .arg(&accounts)
+        .assert()
+        .success();
+
+    runner()
+        .args(["audit", "test_data/edge_cases.csv"])
+        .arg(&accounts)
+        .assert()
+        .success()
+        .stdout("client,check,expected,actual\n")
+        .stderr(predicate::str::contains("Audit passed"));
+
+    // Tamper with client 1's balance
+    let text = fs::read_to_string(&accounts).unwrap();
+    let tampered = text.replace("1,900.5678,0,900.5678,false", "1,901.5678,0,901.5678,false");
+    assert_ne!(text, tampered);
+    fs::write(&accounts, tampered).unwrap();
+
+    runner()
+        .args(["audit", "test_data/edge_cases.csv"])
+        .arg(&accounts)
+        .assert()
+        .code(2)
+        .stdout(predicate::str::contains("1,available,900.5678,901.5678"))
+        .stderr(predicate::str::contains("Audit failed: 2 discrepancies"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_dead_letter_refeed() {
+    let dir = scratch_dir("dead-letter");
+    let dead = dir.join("dead.csv");
+
+    runner()
+        .args(["test_data/late_deposit.csv", "--dead-letter"])
+        .arg(&dead)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "1 reference transactions to unknown tx ids dead-lettered",
+        ));
+    assert_eq!(
+        fs::read_to_string(&dead).expect("Dead letters not written"),
+        "type,client,tx,amount\ndispute,1,5,\n"
+    );
+
+    // Once the deposit is known the re-fed dispute applies
+    runner()
+        .args(["test_data/late_deposit.csv", "--refeed"])
+        .arg(&dead)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1,10,40,50,false"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_group_totals() {
+    let dir = scratch_dir("groups");
+    let path = dir.join("groups_out.csv");
+
+    runner()
+        .args([
+            "test_data/simple.csv",
+            "--groups",
+            "test_data/groups.csv",
+            "--group-output",
+        ])
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1,125,0,125,false"));
+
+    assert_eq!(
+        fs::read_to_string(&path).expect("Group totals not written"),
+        "group,clients,available,held,total,locked\n,1,100,0,100,0\nprogram-a,1,125,0,125,0\n"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_reconcile_external() {
+    runner()
+        .args([
+            "reconcile",
+            "test_data/simple.csv",
+            "--external",
+            "test_data/bank_balances.csv",
+        ])
+        .assert()
+        .code(2)
+        .stdout(
+            "client,engine_total,external_total,difference,adjustment_type,adjustment_amount\n\
+             2,100,90.5,-9.5,withdrawal,9.5\n",
+        )
+        .stderr(predicate::str::contains(
+            "1 clients differ from the external balances",
+        ));
+}
+
+#[test]
+fn test_benchmark_gate() {
+    let dir = scratch_dir("bench");
+    let baseline = dir.join("throughput.json");
+
+    // First run records the baseline
+    runner()
+        .args(["test_data/simple.csv", "--benchmark-gate"])
+        .arg(&baseline)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Benchmark baseline recorded"));
+    assert!(baseline.exists());
+
+    // An unreachable baseline fails the gate
+    fs::write(&baseline, r#"{"records_per_sec": 1e18, "tolerance": 0.1}"#).unwrap();
+    runner()
+        .args(["test_data/simple.csv", "--benchmark-gate"])
+        .arg(&baseline)
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("records_per_sec regressed"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_rejects_stream() {
+    let dir = scratch_dir("rejects");
+    let csv_path = dir.join("rejects.csv");
+    let ndjson_path = dir.join("rejects.ndjson");
+
+    runner()
+        .args(["test_data/rejects.csv", "--rejects"])
+        .arg(&csv_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1,0,0,0,true"));
+    assert_eq!(
+        fs::read_to_string(&csv_path).expect("Rejects not written"),
+        "line,type,client,tx,reason\n\
+         3,withdrawal,1,2,insufficient_funds\n\
+         4,deposit,1,1,duplicate_tx_id\n\
+         5,,,,malformed\n\
+         6,dispute,1,99,unknown_tx\n\
+         9,deposit,1,4,account_locked\n"
+    );
+
+    runner()
+        .args(["test_data/rejects.csv", "--rejects"])
+        .arg(&ndjson_path)
+        .assert()
+        .success();
+    let text = fs::read_to_string(&ndjson_path).expect("Rejects not written");
+    assert_eq!(text.lines().count(), 5);
+    assert!(text.starts_with(
+        "{\"line\":3,\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"reason\":\"insufficient_funds\"}\n"
+    ));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Accounts output lines, sorted since row order is not deterministic
+fn sorted_lines(stdout: Vec<u8>) -> Vec<String> {
+    let mut lines: Vec<_> = String::from_utf8(stdout)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn test_threads_match_single_threaded() {
+    let sorted_output = |threads: &str| {
+        let output = runner()
+            .args([
+                "test_data/edge_cases.csv",
+                "--schema",
+                "v2",
+                "--threads",
+                threads,
+            ])
+            .output()
+            .expect("Failed to run");
+        assert!(output.status.success());
+        sorted_lines(output.stdout)
+    };
+
+    let expected = sorted_output("1");
+    assert!(expected.len() > 2);
+    assert_eq!(sorted_output("3"), expected);
+}
+
+#[test]
+fn test_disk_store_matches_memory() {
+    let dir = scratch_dir("disk-store");
+    let store = dir.join("tx.store");
+
+    let memory = runner()
+        .args(["test_data/disputes.csv", "--schema", "v2"])
+        .output()
+        .expect("Failed to run");
+    let disk = runner()
+        .args(["test_data/disputes.csv", "--schema", "v2", "--store"])
+        .arg(format!("disk:{}", store.display()))
+        .output()
+        .expect("Failed to run");
+    assert!(disk.status.success());
+    assert_eq!(sorted_lines(disk.stdout), sorted_lines(memory.stdout));
+    assert!(store.exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_ndjson_input() {
+    let csv = runner()
+        .arg("test_data/simple.csv")
+        .output()
+        .expect("Failed to run");
+
+    for format in ["auto", "ndjson"] {
+        let ndjson = runner()
+            .args(["test_data/simple.ndjson", "--format", format])
+            .output()
+            .expect("Failed to run");
+        assert!(ndjson.status.success());
+        assert_eq!(
+            sorted_lines(ndjson.stdout),
+            sorted_lines(csv.stdout.clone())
+        );
+    }
+}
+
+#[test]
+fn test_stdin_and_multiple_inputs() {
+    let simple = fs::read_to_string("test_data/simple.csv").unwrap();
+    let disputes = fs::read_to_string("test_data/disputes.csv").unwrap();
+    let dir = scratch_dir("multi-input");
+    let combined = dir.join("combined.csv");
+    let body: String = disputes
+        .lines()
+        .skip(1)
+        .map(|l| format!("{}\n", l))
+        .collect();
+    fs::write(&combined, format!("{}{}", simple, body)).unwrap();
+
+    let expected = runner().arg(&combined).output().unwrap();
+    let piped = runner()
+        .args(["-", "test_data/disputes.csv"])
+        .write_stdin(simple)
+        .output()
+        .unwrap();
+
+    assert!(piped.status.success());
+    assert_eq!(sorted_lines(piped.stdout), sorted_lines(expected.stdout));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_tier_withdrawal_rules() {
+    let dir = scratch_dir("tier-rules");
+    let rejects = dir.join("rejects.csv");
+
+    let output = runner()
+        .args([
+            "test_data/tier_withdrawals.csv",
+            "--tier-rules",
+            "test_data/tier_rules.csv",
+            "--tiers",
+            "test_data/client_tiers.csv",
+            "--rejects",
+        ])
+        .arg(&rejects)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        sorted_lines(output.stdout),
+        vec![
+            "1,10,0,10,false",
+            "2,4.8766,0,4.8766,false",
+            "client,available,held,total,locked",
+        ]
+    );
+    assert_eq!(
+        fs::read_to_string(&rejects).expect("Rejects not written"),
+        "line,type,client,tx,reason\n\
+         4,withdrawal,1,3,amount_increment\n\
+         5,withdrawal,1,4,minimum_balance\n"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_save_and_load_state() {
+    let dir = scratch_dir("state");
+    let state = dir.join("day1.state");
+
+    runner()
+        .args(["test_data/simple.csv", "-o"])
+        .arg(dir.join("day1.csv"))
+        .arg("--save-state")
+        .arg(&state)
+        .assert()
+        .success();
+    assert!(!dir.join("day1.state.tmp").exists());
+
+    let resumed = runner()
+        .args(["test_data/disputes.csv", "--load-state"])
+        .arg(&state)
+        .output()
+        .unwrap();
+    let from_scratch = runner()
+        .args(["test_data/simple.csv", "test_data/disputes.csv"])
+        .output()
+        .unwrap();
+
+    assert!(resumed.status.success());
+    assert_eq!(
+        sorted_lines(resumed.stdout),
+        sorted_lines(from_scratch.stdout)
+    );
+
+    fs::write(dir.join("garbage.state"), "not a snapshot").unwrap();
+    runner()
+        .args(["test_data/disputes.csv", "--load-state"])
+        .arg(dir.join("garbage.state"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not a snapshot file"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_dedup_events_on_resume() {
+    let dir = scratch_dir("dedup-events");
+    let state = dir.join("day1.state");
+
+    runner()
+        .args(["test_data/simple.csv", "--dedup-events", "--save-state"])
+        .arg(&state)
+        .assert()
+        .success();
+
+    // The withdrawal was already in day 1
+    runner()
+        .args(["-", "--dedup-events", "--load-state"])
+        .arg(&state)
+        .write_stdin("type,client,tx,amount\nwithdrawal,1,4,25.0\ndeposit,1,6,1.0\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1,101,0,101,false"))
+        .stderr(predicate::str::contains(
+            "1 events already sent in an earlier run, not sent to event sinks again",
+        ));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_strict_validation() {
+    runner()
+        .arg("test_data/strict.csv")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1,8.7346,"));
+
+    runner()
+        .args(["test_data/strict.csv", "--strict"])
+        .assert()
+        .code(2)
+        .stdout(predicate::str::contains("1,7.5,0,7.5,false"))
+        .stderr(predicate::str::contains(
+            "line 4: deposit tx 3 for client 1 excess_precision",
+        ))
+        .stderr(predicate::str::contains("line 6: malformed"))
+        .stderr(predicate::str::contains(
+            "4 records failed strict validation",
+        ));
+
+    runner()
+        .args(["test_data/strict.csv", "--fail-fast"])
+        .assert()
+        .code(1)
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains(
+            "validation failed: line 3: deposit tx 2 for client 1 non_positive_amount",
+        ));
+}
+
+#[cfg(not(feature = "plugins"))]
+#[test]
+fn test_plugins_need_feature() {
+    runner()
+        .args(["test_data/simple.csv", "--plugins", "plugins/"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("rebuild with --features plugins"));
+}
+
+#[test]
+fn test_audit_log() {
+    let dir = scratch_dir("audit_log");
+    let path = dir.join("events.jsonl");
+
+    runner()
+        .args(["test_data/disputes.csv", "--audit"])
+        .arg(&path)
+        .assert()
+        .success();
+
+    let log = fs::read_to_string(&path).expect("Audit log not written");
+    let events: Vec<serde_json::Value> = log
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    // The deposit to the locked account changes nothing and is not logged
+    assert_eq!(events.len(), 10);
+    assert_eq!(events[5]["event"], "funds_released");
+    assert_eq!(events[5]["after"]["available"], "125");
+    assert_eq!(events[8]["event"], "chargeback");
+    assert_eq!(events[9]["event"], "account_locked");
+    assert_eq!(events[9]["client"], 2);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_summary() {
+    let dir = scratch_dir("summary");
+    let accounts = dir.join("accounts.csv");
+
+    for threads in ["1", "2"] {
+        runner()
+            .args([
+                "test_data/rejects.csv",
+                "--summary",
+                "-",
+                "--threads",
+                threads,
+            ])
+            .arg("--output")
+            .arg(&accounts)
+            .assert()
+            .success()
+            .stdout(predicate::str::starts_with(
+                "Run summary\nrecords processed: 8 (5 rejected)\n",
+            ))
+            .stdout(predicate::str::contains("  deposit: 1/2\n"))
+            .stdout(predicate::str::contains("  malformed: 1\n"))
+            .stdout(predicate::str::contains(
+                "accounts: 1 (1 locked, 0 closed)\n",
+            ))
+            .stdout(predicate::str::ends_with(
+                "top 1 clients by total:\n  client 1: 0\n",
+            ));
+    }
+    assert!(accounts.exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_proof() {
+    let dir = scratch_dir("proof");
+    let accounts = dir.join("accounts.csv");
+    let proof = dir.join("proof.json");
+    let key = dir.join("proof.key");
+    fs::write(&key, "secret\n").unwrap();
+
+    runner()
+        .args(["test_data/disputes.csv", "--output"])
+        .arg(&accounts)
+        .arg("--proof")
+        .arg(&proof)
+        .arg("--proof-key")
+        .arg(&key)
+        .assert()
+        .success();
+
+    let bytes = fs::read(&proof).expect("Proof not written");
+    let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(json["balanced"], true);
+    assert_eq!(json["records_processed"], 10);
+    assert_eq!(json["flows"]["deposits"], "425");
+    assert_eq!(json["flows"]["chargebacks"], "200");
+    assert_eq!(json["accounts_total"], "200");
+    assert_eq!(json["counts"]["deposit"]["rejected"], 1);
+    assert_eq!(
+        json["accounts_sha256"],
+        proof::sha256_hex(&fs::read(&accounts).unwrap())
+    );
+    assert_eq!(
+        json["inputs"][0]["sha256"],
+        proof::sha256_hex(&fs::read("test_data/disputes.csv").unwrap())
+    );
+
+    let mut sig = proof.clone().into_os_string();
+    sig.push(".sig");
+    let signature = fs::read_to_string(sig).expect("Signature not written");
+    assert!(proof::verify(&bytes, b"secret", &signature));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(feature = "wasm")]
+#[test]
+fn test_wasm_rule() {
+    runner()
+        .args([
+            "test_data/simple.csv",
+            "--wasm",
+            "test_data/no_withdrawals.wat",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1,150,0,150,false"))
+        .stdout(predicate::str::contains("2,200,0,200,false"));
+
+    // Too little fuel to scan a record: everything faults, nothing is applied
+    runner()
+        .args([
+            "test_data/simple.csv",
+            "--wasm",
+            "test_data/no_withdrawals.wat",
+        ])
+        .args(["--wasm-fuel", "20"])
+        .assert()
+        .success()
+        .stdout("");
+}
+
+#[cfg(not(feature = "wasm"))]
+#[test]
+fn test_wasm_needs_feature() {
+    runner()
+        .args([
+            "test_data/simple.csv",
+            "--wasm",
+            "test_data/no_withdrawals.wat",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("rebuild with --features wasm"));
+}
+
+#[test]
+fn test_shadow_engine() {
+    let dir = scratch_dir("shadow");
+    let log = dir.join("shadow.csv");
+    runner()
+        .args(["test_data/simple.csv", "--shadow", "--max-amount 150"])
+        .arg("--shadow-log")
+        .arg(&log)
+        .assert()
+        .success()
+        // Only the primary's accounts are written
+        .stdout("client,available,held,total,locked\n1,125,0,125,false\n2,100,0,100,false\n")
+        .stderr(predicate::str::contains(
+            "Shadow: 2 of 5 records and 1 accounts diverged",
+        ));
+    assert_eq!(
+        fs::read_to_string(&log).unwrap(),
+        "position,type,client,tx,primary,shadow\n\
+         2,deposit,2,2,applied,implausible_amount\n\
+         5,withdrawal,2,5,applied,insufficient_funds\n"
+    );
+}
+
+#[test]
+fn test_as_of_cutoff() {
+    // Records after the cutoff are left out wherever they are in the file
+    runner()
+        .args([
+            "test_data/timestamps.csv",
+            "--as-of",
+            "2024-01-31T23:59:59Z",
+        ])
+        .args(["--threads", "2"])
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,70,0,70,false\n2,30,0,30,false\n");
+    runner()
+        .args([
+            "test_data/timestamps.csv",
+            "--as-of",
+            "2024-01-31T11:00:00Z",
+        ])
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,100,0,100,false\n2,40,0,40,false\n");
+
+    runner()
+        .args(["test_data/simple.csv", "--as-of", "2024-01-31T23:59:59Z"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "line 2: no timestamp, --as-of needs one on every record",
+        ));
+}
+
+#[test]
+fn test_generated_workload_passes_check() {
+    let dir = scratch_dir("gen");
+    let workload = dir.join("workload.csv");
+    runner()
+        .args(["gen", "--rows", "5000", "--clients", "25"])
+        .args(["--dispute-ratio", "0.2", "--seed", "3", "--output"])
+        .arg(&workload)
+        .assert()
+        .success()
+        .stderr("Generated 5000 records for 25 clients\n");
+    let csv = fs::read_to_string(&workload).unwrap();
+    assert!(csv.starts_with("type,client,tx,amount\n"));
+    assert_eq!(csv.lines().count(), 5001);
+
+    let rejects = dir.join("rejects.csv");
+    runner()
+        .arg(&workload)
+        .args(["--check", "--threads", "2", "-o", "/dev/null", "--rejects"])
+        .arg(&rejects)
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(&rejects).unwrap(),
+        "line,type,client,tx,reason\n"
+    );
+}
+
+#[test]
+fn test_schema_describes_layouts() {
+    let output = runner()
+        .args(["schema", "--format", "json"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let schema: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(schema["version"], env!("CARGO_PKG_VERSION"));
+    let columns: Vec<&str> = schema["outputs"]["accounts_v1"]["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(columns, ["client", "available", "held", "total", "locked"]);
+
+    runner()
+        .args(["schema", "--format", "yaml"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid --format value: yaml"));
+}
+
+#[test]
+fn test_record_repro_bundle() {
+    let dir = scratch_dir("record-repro");
+    let bundle = dir.join("bundle");
+    let _ = fs::remove_dir_all(&bundle);
+
+    // A clean run leaves nothing behind
+    runner()
+        .args(["test_data/simple.csv", "--record-repro"])
+        .arg(&bundle)
+        .assert()
+        .success();
+    assert!(!bundle.exists());
+
+    // Bundle as the runner writes it, of a run that no longer fails
+    let args: Vec<String> = ["test_data/simple.csv", "--threads", "2", "--record-repro"]
+        .iter()
+        .map(|s| s.to_string())
+        .chain([bundle.to_string_lossy().into_owned()])
+        .collect();
+    repro::ReproManifest::record(
+        &bundle,
+        &args,
+        &["test_data/simple.csv"],
+        "invariant check failed: x",
+    )
+    .unwrap();
+    runner()
+        .args(["repro", "run"])
+        .arg(&bundle)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Recorded failure: invariant check failed: x",
+        ))
+        .stderr(predicate::str::contains("Did not reproduce"));
+
+    // An earlier bundle is never overwritten
+    runner()
+        .args(["test_data/simple.csv", "--record-repro"])
+        .arg(&bundle)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "already holds a reproduction bundle",
+        ));
+
+    runner()
+        .args(["repro", "run"])
+        .arg(dir.join("missing"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Error replaying bundle"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(not(feature = "serve"))]
+#[test]
+fn test_serve_needs_feature() {
+    runner()
+        .args(["serve", "--http", "127.0.0.1:0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("rebuild with --features serve"));
+}
+
+#[cfg(not(feature = "kafka"))]
+#[test]
+fn test_consume_needs_feature() {
+    let dir = scratch_dir("consume");
+    runner()
+        .args(["consume", "--brokers", "localhost:9092", "--topic", "tx"])
+        .arg("--save-state")
+        .arg(dir.join("state.bin"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("rebuild with --features kafka"));
+    assert!(!dir.join("state.bin").exists());
+}
+
+#[test]
+fn test_close_accounts() {
+    let dir = scratch_dir("closures");
+    let closures = dir.join("closures.csv");
+    let rejects = dir.join("rejects.csv");
+
+    let output = runner()
+        .args([
+            "test_data/closures.csv",
+            "--close-dust",
+            "0.01",
+            "--closures",
+        ])
+        .arg(&closures)
+        .arg("--rejects")
+        .arg(&rejects)
+        .args(["--schema", "v2"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "2 accounts closed, 74.5 to pay out, 0.005 written off",
+        ))
+        .get_output()
+        .stdout
+        .clone();
+    assert_eq!(
+        sorted_lines(output),
+        [
+            "1,0.0000,0.0000,0.0000,true,closed,0,10,false",
+            "2,0.0000,0.0000,0.0000,true,closed,0,11,false",
+            "3,0.0000,10.0000,10.0000,false,,1,5,false",
+            "client,available,held,total,locked,lock_reason,open_disputes,last_tx,dormant",
+        ]
+    );
+    assert_eq!(
+        fs::read_to_string(&closures).expect("Closures not written"),
+        "position,client,tx,payout,written_off\n4,1,10,74.5,0\n6,2,11,0,0.005\n"
+    );
+    let rejects = fs::read_to_string(&rejects).expect("Rejects not written");
+    assert!(rejects.contains(",deposit,1,4,account_closed"));
+    assert!(rejects.contains(",close,3,12,funds_held"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_dormant_accounts() {
+    let dir = scratch_dir("dormant");
+    let dormant = dir.join("dormant.csv");
+
+    for threads in ["1", "2"] {
+        runner()
+            .args(["test_data/simple.csv", "--dormant-after", "1", "--dormant"])
+            .arg(&dormant)
+            .args(["--threads", threads])
+            .assert()
+            .success()
+            .stderr(predicate::str::contains(
+                "1 accounts dormant (no activity in the last 1 records), 125 in funds",
+            ));
+        assert_eq!(
+            fs::read_to_string(&dormant).expect("Dormant accounts not written"),
+            "client,last_active,idle,idle_days,available,held,total,locked\n1,4,1,0,125,0,125,false\n"
+        );
+    }
+
+    runner()
+        .args(["test_data/simple.csv", "--dormant-after", "6", "--dormant"])
+        .arg(&dormant)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("dormant").not());
+    assert_eq!(
+        fs::read_to_string(&dormant).unwrap(),
+        "client,last_active,idle,idle_days,available,held,total,locked\n"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_dormant_after_days() {
+    let dir = scratch_dir("dormant-days");
+    let input = dir.join("tx.csv");
+    fs::write(
+        &input,
+        "type,client,tx,amount,timestamp\n\
+         deposit,1,1,10,2024-01-01T00:00:00Z\n\
+         deposit,2,2,20,2024-01-01T00:00:00Z\n\
+         deposit,2,3,5,2024-03-01T00:00:00Z\n",
+    )
+    .unwrap();
+
+    runner()
+        .arg(&input)
+        .args(["--virtual-time-from-timestamps", "--dormant-after", "30d"])
+        .args(["--schema", "v2"])
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked,lock_reason,open_disputes,last_tx,dormant\n\
+             1,10.0000,0.0000,10.0000,false,,0,1,true\n\
+             2,25.0000,0.0000,25.0000,false,,0,3,false\n",
+        )
+        .stderr(predicate::str::contains(
+            "1 accounts dormant (no activity in the last 30 days), 10 in funds",
+        ));
+
+    // On the wall clock the whole run is a moment
+    runner()
+        .arg(&input)
+        .args(["--dormant-after", "30d", "--schema", "v2"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "1,10.0000,0.0000,10.0000,false,,0,1,false",
+        ))
+        .stderr(predicate::str::contains("dormant").not());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_locked_account_policy() {
+    let run = |flags: &[&str], expected: &str| {
+        runner()
+            .arg("test_data/locked_policy.csv")
+            .args(flags)
+            .args(["--schema", "v2"])
+            .assert()
+            .success()
+            .stdout(format!(
+                "client,available,held,total,locked,lock_reason,open_disputes,last_tx,dormant\n{}\n",
+                expected
+            ));
+    };
+    run(&[], "1,0.0000,20.0000,20.0000,true,chargeback,1,1,false");
+    run(
+        &["--locked-accepts", "resolve"],
+        "1,20.0000,0.0000,20.0000,true,chargeback,0,2,false",
+    );
+    run(
+        &["--locked-accepts", "resolve", "--allow-unlock"],
+        "1,15.0000,0.0000,15.0000,false,,0,4,false",
+    );
+}
+
+#[test]
+fn test_recovery_sweep() {
+    let dir = scratch_dir("recovery");
+    let path = dir.join("recoveries.csv");
+
+    runner()
+        .args(["test_data/recovery.csv", "--recovery-sweep", "--recoveries"])
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1,2,0,2,true"))
+        .stderr(predicate::str::contains(
+            "2 deposits recovered 8 of negative balances, 1 deficits cleared",
+        ));
+    assert_eq!(
+        fs::read_to_string(&path).expect("Recoveries not written"),
+        "position,client,tx,recovered,remaining_deficit\n4,1,3,5,3\n5,1,4,3,0\n"
+    );
+
+    runner()
+        .arg("test_data/recovery.csv")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("recovered").not());
+    fs::remove_dir_all(dir).unwrap();
+}